@@ -0,0 +1,375 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::config::{ConfigFile, Settings};
+use crate::{capture, Event, Pipe2Moq, Stats, StatsSnapshot};
+
+/// How often all-sinks mode re-lists sinks.
+const SINK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+pub struct AgentConfig {
+    pub config_dir: PathBuf,
+    pub restart_delay: Duration,
+    pub max_restart_delay: Duration,
+    pub status_interval: Duration,
+    pub status_file: Option<PathBuf>,
+    /// One metrics and health endpoint for every stream. The streams' own
+    /// `metrics_addr` is not served.
+    pub metrics_addr: Option<SocketAddr>,
+    pub shutdown_grace: Duration,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            config_dir: PathBuf::from("/etc/pipe2moq/streams.d"),
+            restart_delay: Duration::from_secs(2),
+            max_restart_delay: Duration::from_secs(60),
+            status_interval: Duration::from_secs(30),
+            status_file: None,
+            metrics_addr: None,
+            shutdown_grace: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamState {
+    Starting,
+    Live,
+    Restarting,
+    Failed,
+}
+
+/// Supervisor view of one managed stream.
+#[derive(Clone, serde::Serialize)]
+pub struct StreamStatus {
+    pub name: String,
+    pub broadcast_path: String,
+    pub state: StreamState,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    pub stats: Option<StatsSnapshot>,
+    /// The running instance's counters, which `stats` is refreshed from.
+    #[serde(skip)]
+    live: Option<Arc<Stats>>,
+}
+
+pub type StatusMap = Arc<Mutex<BTreeMap<String, StreamStatus>>>;
+
+/// Every stream's status, with the stats of running ones as of now.
+pub fn statuses(status: &StatusMap) -> Vec<StreamStatus> {
+    status.lock().unwrap().values().cloned()
+        .map(|mut stream| {
+            if let Some(ref live) = stream.live {
+                stream.stats = Some(live.snapshot());
+            }
+            stream
+        })
+        .collect()
+}
+
+fn serve_metrics(config: &AgentConfig, status: &StatusMap) {
+    if let Some(addr) = config.metrics_addr {
+        let status = status.clone();
+        crate::tasks::spawn("metrics", async move {
+            if let Err(e) = crate::metrics::serve_agent(addr, status).await {
+                error!("Metrics endpoint failed: {e}");
+            }
+        });
+    }
+}
+
+/// Per-stream config files in `dir`, sorted by name. The file stem names the stream.
+fn stream_configs(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut configs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            configs.push((name, path));
+        }
+    }
+    configs.sort();
+    Ok(configs)
+}
+
+/// Run every stream defined in `config.config_dir` as an independently
+/// supervised task, restarting each with backoff when it stops.
+///
+/// When `shutdown` resolves every stream is drained and the agent returns.
+pub async fn run(config: AgentConfig, shutdown: impl Future<Output = ()>) -> Result<()> {
+    let streams = stream_configs(&config.config_dir)?;
+    if streams.is_empty() {
+        return Err(anyhow::anyhow!("No *.toml stream definitions in {}", config.config_dir.display()));
+    }
+    info!("Agent managing {} streams from {}", streams.len(), config.config_dir.display());
+
+    let status: StatusMap = Arc::default();
+    serve_metrics(&config, &status);
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut tasks = tokio::task::JoinSet::new();
+    for (name, path) in streams {
+        let load = move || {
+            let mut settings = ConfigFile::load_file(&path)?.into_settings();
+            settings.validate()?;
+            Ok(settings)
+        };
+        tasks.spawn(supervise(name, load, config.clone(), status.clone(), stop_rx.clone()));
+    }
+
+    tokio::pin!(shutdown);
+    let mut interval = tokio::time::interval(config.status_interval);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => report(&status, config.status_file.as_deref()),
+            _ = &mut shutdown, if !*stop_tx.borrow() => {
+                info!("Shutdown requested, draining all streams");
+                let _ = stop_tx.send(true);
+            }
+            done = tasks.join_next() => match done {
+                Some(Ok(())) => {}
+                Some(Err(e)) => error!("Stream supervisor task failed: {e}"),
+                None => break,
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Publish the monitor of every sink as its own broadcast, following sinks
+/// as they come and go. `{sink}` in the broadcast path is replaced by the
+/// sink name; without it the name is appended as a path segment. Sinks
+/// whose name matches `exclude` are skipped.
+pub async fn run_all_sinks(
+    config: AgentConfig,
+    base: Settings,
+    exclude: Option<regex::Regex>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let template = if base.moq.broadcast_path.contains("{sink}") {
+        base.moq.broadcast_path.clone()
+    } else {
+        format!("{}/{{sink}}", base.moq.broadcast_path.trim_end_matches('/'))
+    };
+    info!("Publishing every sink as {}", template);
+
+    let status: StatusMap = Arc::default();
+    serve_metrics(&config, &status);
+    let mut stopping = false;
+    // Per-sink stop signals, set when the sink disappears or on shutdown.
+    let mut running: BTreeMap<String, watch::Sender<bool>> = BTreeMap::new();
+    let mut tasks = tokio::task::JoinSet::new();
+
+    tokio::pin!(shutdown);
+    let mut poll = tokio::time::interval(SINK_POLL_INTERVAL);
+    let mut interval = tokio::time::interval(config.status_interval);
+    interval.tick().await;
+    while !(stopping && tasks.is_empty()) {
+        tokio::select! {
+            _ = poll.tick(), if !stopping => {
+                let sinks = match tokio::task::spawn_blocking(capture::sink_names).await? {
+                    Ok(sinks) => sinks,
+                    Err(e) => {
+                        warn!("Could not list sinks: {e:#}");
+                        continue;
+                    }
+                };
+                let sinks: Vec<String> = sinks.into_iter()
+                    .filter(|sink| !exclude.as_ref().is_some_and(|exclude| exclude.is_match(sink)))
+                    .collect();
+
+                running.retain(|sink, stop| {
+                    let present = sinks.contains(sink);
+                    if !present {
+                        info!("Sink {} removed, stopping its broadcast", sink);
+                        let _ = stop.send(true);
+                    }
+                    present
+                });
+                for sink in sinks {
+                    if running.contains_key(&sink) {
+                        continue;
+                    }
+                    info!("Sink {} found, publishing it", sink);
+                    let (sink_stop, sink_stop_rx) = watch::channel(false);
+                    running.insert(sink.clone(), sink_stop);
+                    let mut settings = base.clone();
+                    settings.pipeline.sink_name = Some(sink.clone());
+                    settings.pipeline.follow_active_sink = false;
+                    settings.pipeline.follow_app = None;
+                    settings.moq.broadcast_path = template.replace("{sink}", &sink);
+                    let load = move || Ok(settings.clone());
+                    let name = sink.clone();
+                    let supervisor = supervise(name.clone(), load, config.clone(), status.clone(), sink_stop_rx);
+                    tasks.spawn(async move {
+                        supervisor.await;
+                        name
+                    });
+                }
+            }
+            _ = interval.tick() => report(&status, config.status_file.as_deref()),
+            _ = &mut shutdown, if !stopping => {
+                info!("Shutdown requested, draining all streams");
+                stopping = true;
+                for stop in running.values() {
+                    let _ = stop.send(true);
+                }
+            }
+            Some(done) = tasks.join_next(), if !tasks.is_empty() => match done {
+                Ok(sink) => {
+                    // Only a removed sink's stream ends without shutdown.
+                    if !running.contains_key(&sink) {
+                        status.lock().unwrap().remove(&sink);
+                    }
+                }
+                Err(e) => error!("Stream supervisor task failed: {e}"),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+async fn supervise(
+    name: String,
+    load: impl Fn() -> Result<Settings>,
+    config: AgentConfig,
+    status: StatusMap,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut delay = config.restart_delay;
+    let mut restarts = 0;
+
+    while !*stop.borrow() {
+        let settings = match load() {
+            Ok(settings) => settings,
+            Err(e) => {
+                error!("[{}] Invalid config: {e}", name);
+                update(&status, &name, "", StreamState::Failed, restarts, Some(e.to_string()), None);
+                return;
+            }
+        };
+        let broadcast_path = settings.moq.broadcast_path.clone();
+        update(&status, &name, &broadcast_path, StreamState::Starting, restarts, None, None);
+
+        let app = Pipe2Moq::new(settings.pipeline, settings.moq)
+            .with_alerts(settings.alerts)
+            .with_retry(settings.retry)
+            .with_stats_log(settings.stats_log_interval);
+        let stats = app.stats();
+        if let Some(entry) = status.lock().unwrap().get_mut(&name) {
+            entry.live = Some(stats.clone());
+        }
+        let mut events = app.subscribe();
+        let watcher = crate::tasks::spawn("agent-watcher", {
+            let status = status.clone();
+            let name = name.clone();
+            async move {
+                while let Ok(event) = events.recv().await {
+                    if let Event::RelayConnected { .. } = event {
+                        if let Some(entry) = status.lock().unwrap().get_mut(&name) {
+                            entry.state = StreamState::Live;
+                        }
+                    }
+                }
+            }
+        });
+
+        let started = Instant::now();
+        let stopping = {
+            let mut stop = stop.clone();
+            async move {
+                let _ = stop.wait_for(|stop| *stop).await;
+            }
+        };
+        let result = app.run_until(stopping, config.shutdown_grace).await;
+        watcher.abort();
+
+        if *stop.borrow() {
+            if let Err(e) = result {
+                warn!("[{}] Drain incomplete: {e}", name);
+            }
+            info!("[{}] Stopped", name);
+            return;
+        }
+
+        let last_error = match result {
+            Ok(()) => {
+                warn!("[{}] Stream ended, restarting", name);
+                None
+            }
+            Err(e) => {
+                error!("[{}] Stream failed: {e}", name);
+                Some(e.to_string())
+            }
+        };
+
+        // A stream that ran for a while earns a fresh backoff.
+        if started.elapsed() > config.max_restart_delay {
+            delay = config.restart_delay;
+        }
+        restarts += 1;
+        update(&status, &name, &broadcast_path, StreamState::Restarting, restarts, last_error, Some(stats.snapshot()));
+
+        info!("[{}] Restarting in {:?}", name, delay);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop.wait_for(|stop| *stop) => return,
+        }
+        delay = (delay * 2).min(config.max_restart_delay);
+    }
+}
+
+fn update(
+    status: &StatusMap,
+    name: &str,
+    broadcast_path: &str,
+    state: StreamState,
+    restarts: u32,
+    last_error: Option<String>,
+    stats: Option<StatsSnapshot>,
+) {
+    status.lock().unwrap().insert(name.to_string(), StreamStatus {
+        name: name.to_string(),
+        broadcast_path: broadcast_path.to_string(),
+        state,
+        restarts,
+        last_error,
+        stats,
+        live: None,
+    });
+}
+
+fn report(status: &StatusMap, status_file: Option<&Path>) {
+    let streams = statuses(status);
+    let live = streams.iter().filter(|s| s.state == StreamState::Live).count();
+    info!("Agent status: {}/{} streams live", live, streams.len());
+    for stream in &streams {
+        info!("  {} ({}): {:?}, {} restarts{}",
+              stream.name, stream.broadcast_path, stream.state, stream.restarts,
+              stream.last_error.as_ref().map(|e| format!(", last error: {}", e)).unwrap_or_default());
+    }
+
+    if let Some(path) = status_file {
+        match serde_json::to_vec_pretty(&streams) {
+            Ok(json) => {
+                if let Err(e) = std::fs::write(path, json) {
+                    warn!("Failed to write status file {}: {e}", path.display());
+                }
+            }
+            Err(e) => warn!("Failed to serialize agent status: {e}"),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::events::{self, Event, EventSender};
+use crate::webhook::post_json;
+use crate::{Controller, Stats};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do when a session accumulates too many xruns.
+#[derive(Clone, Default)]
+pub struct AlertConfig {
+    /// Xruns (capture discontinuities, gaps and overlaps) tolerated per session.
+    pub max_xruns: Option<u64>,
+    /// Stop the stream and exit non-zero once `max_xruns` is exceeded.
+    pub exit_on_threshold: bool,
+    /// POST a JSON report here once `max_xruns` is exceeded (plain `http://` only).
+    pub webhook_url: Option<url::Url>,
+}
+
+/// Resolve with the xrun count once it exceeds the configured threshold,
+/// after firing the webhook and, if configured, asking the stream to stop.
+/// Never resolves when no threshold is set.
+pub(crate) async fn watch(
+    config: &AlertConfig,
+    broadcast_path: &str,
+    stats: &Arc<Stats>,
+    events: &EventSender,
+    controller: &Controller,
+) -> u64 {
+    let Some(max_xruns) = config.max_xruns else {
+        return std::future::pending().await;
+    };
+
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let snapshot = loop {
+        interval.tick().await;
+        let snapshot = stats.snapshot();
+        if snapshot.xruns() > max_xruns {
+            break snapshot;
+        }
+    };
+
+    let xruns = snapshot.xruns();
+    warn!("{} xruns this session exceeds the threshold of {}", xruns, max_xruns);
+    events::emit(events, Event::XrunThresholdExceeded { xruns, threshold: max_xruns });
+
+    if let Some(ref url) = config.webhook_url {
+        let body = serde_json::json!({
+            "event": "xrun_threshold_exceeded",
+            "broadcast_path": broadcast_path,
+            "xruns": xruns,
+            "threshold": max_xruns,
+            "stats": snapshot,
+        });
+        match post_json(url, &body).await {
+            Ok(()) => info!("Xrun alert sent to {}", url),
+            Err(e) => warn!("Failed to send xrun alert to {}: {e:#}", url),
+        }
+    }
+
+    if config.exit_on_threshold {
+        controller.stop();
+    }
+    xruns
+}
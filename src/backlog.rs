@@ -0,0 +1,88 @@
+//! Frames held for the relay while it can't be reached, so a long outage
+//! costs the oldest audio instead of stalling capture.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::frame::Frame;
+
+pub(crate) struct Backlog {
+    /// Most audio to hold, in μs; `None` leaves frames in the queue.
+    limit_us: Option<u64>,
+    frames: VecDeque<Frame>,
+    held_us: u64,
+    dropped: u64,
+}
+
+/// Leaves frames in the queue, as without `reconnect_buffer`.
+impl Default for Backlog {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Backlog {
+    pub(crate) fn new(limit: Option<Duration>) -> Self {
+        Self {
+            limit_us: limit.map(|limit| limit.as_micros() as u64),
+            frames: VecDeque::new(),
+            held_us: 0,
+            dropped: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The oldest held frame, to publish before anything newer.
+    pub(crate) fn pop(&mut self) -> Option<Frame> {
+        let frame = self.frames.pop_front()?;
+        self.held_us -= frame.duration_us;
+        Some(frame)
+    }
+
+    fn push(&mut self, frame: Frame, limit_us: u64) {
+        self.held_us += frame.duration_us;
+        self.frames.push_back(frame);
+        while self.held_us > limit_us && self.frames.len() > 1 {
+            if let Some(oldest) = self.frames.pop_front() {
+                self.held_us -= oldest.duration_us;
+                self.dropped += 1;
+            }
+        }
+    }
+
+    /// Frames dropped for being too old since the last call.
+    pub(crate) fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// Wait for `until`, meanwhile taking frames off `frames` so the
+    /// pipeline never waits on a publisher that isn't connected. Stem
+    /// frames are discarded; stems restart with the next session. Without
+    /// a limit, frames stay in the queue and capture waits once it's full.
+    pub(crate) async fn hold<F: Future>(
+        &mut self,
+        frames: &mut mpsc::Receiver<Frame>,
+        mut stems: Option<&mut mpsc::Receiver<(usize, Frame)>>,
+        until: F,
+    ) -> F::Output {
+        let Some(limit_us) = self.limit_us else { return until.await };
+        tokio::pin!(until);
+        loop {
+            tokio::select! {
+                output = &mut until => return output,
+                Some(frame) = frames.recv() => self.push(frame, limit_us),
+                Some(_) = async {
+                    match stems.as_mut() {
+                        Some(stems) => stems.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! `pipe2moq bench`: encode synthetic audio as fast as possible at each
+//! combination of settings, to pick settings for weak hardware.
+
+use anyhow::Result;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::resources::cpu_time;
+use crate::AudioConfig;
+
+#[derive(Clone)]
+pub struct BenchConfig {
+    /// Sample rate, channels, frame size and application to encode with.
+    pub audio: AudioConfig,
+    /// Amount of synthetic audio encoded per run.
+    pub duration: Duration,
+    pub complexities: Vec<u32>,
+    pub bitrates: Vec<u32>,
+}
+
+/// One encoder run.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct BenchResult {
+    pub complexity: u32,
+    pub bitrate: u32,
+    pub frames: u64,
+    pub frames_per_sec: f64,
+    /// Seconds of audio encoded per second of wall-clock time.
+    pub realtime_factor: f64,
+    /// CPU of one core needed to encode in real time, in percent.
+    pub cpu_percent: f64,
+    pub encode_p50: Duration,
+    pub encode_p99: Duration,
+    pub encode_max: Duration,
+}
+
+impl std::fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "complexity {:2}  {:4} kbps  {:8.0} fps  {:6.1}x realtime  {:5.1}% cpu  encode p50 {:4} μs  p99 {:4} μs  max {:5} μs",
+               self.complexity,
+               self.bitrate / 1000,
+               self.frames_per_sec,
+               self.realtime_factor,
+               self.cpu_percent,
+               self.encode_p50.as_micros(),
+               self.encode_p99.as_micros(),
+               self.encode_max.as_micros())
+    }
+}
+
+/// Run every complexity/bitrate combination in turn, calling `on_result`
+/// after each. Blocks; call from a blocking thread.
+pub fn run(config: &BenchConfig, mut on_result: impl FnMut(&BenchResult)) -> Result<Vec<BenchResult>> {
+    gst::init()?;
+    let mut results = Vec::new();
+    for &complexity in &config.complexities {
+        for &bitrate in &config.bitrates {
+            let result = run_one(config, complexity, bitrate)?;
+            on_result(&result);
+            results.push(result);
+        }
+    }
+    Ok(results)
+}
+
+fn run_one(config: &BenchConfig, complexity: u32, bitrate: u32) -> Result<BenchResult> {
+    let audio = &config.audio;
+    let samples_per_frame = audio.sample_rate as u64 * audio.frame_size as u64 / 1000;
+    let frames = (config.duration.as_millis() as u64 / audio.frame_size.max(1) as u64).max(1);
+
+    let pipeline = gst::Pipeline::default();
+    // Pink noise keeps the encoder busy the way real program audio does.
+    let source = gst::ElementFactory::make("audiotestsrc")
+        .property_from_str("wave", "pink-noise")
+        .property("samplesperbuffer", samples_per_frame as i32)
+        .property("num-buffers", frames as i32)
+        .build()?;
+    let caps = gst::ElementFactory::make("capsfilter")
+        .property("caps", gst::Caps::builder("audio/x-raw")
+            .field("rate", audio.sample_rate as i32)
+            .field("channels", audio.channels as i32)
+            .build())
+        .build()?;
+    let opusenc = gst::ElementFactory::make("opusenc")
+        .property("bitrate", bitrate as i32)
+        .property_from_str("audio-type", audio.opus_audio_type())
+        .property("complexity", complexity as i32)
+        .property_from_str("frame-size", &audio.frame_size.to_string())
+        .build()?;
+    let sink = gst::ElementFactory::make("fakesink")
+        .property("sync", false)
+        .build()?;
+
+    let chain = [source, caps, opusenc.clone(), sink];
+    pipeline.add_many(&chain)?;
+    gst::Element::link_many(&chain)?;
+
+    // The encoder works synchronously in its sink pad's chain function, so
+    // the time from a buffer entering to a packet leaving is its encode time.
+    let entered = Arc::new(Mutex::new(None::<Instant>));
+    let latencies = Arc::new(Mutex::new(Vec::with_capacity(frames as usize)));
+    if let Some(pad) = opusenc.static_pad("sink") {
+        let entered = entered.clone();
+        pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+            *entered.lock().unwrap() = Some(Instant::now());
+            gst::PadProbeReturn::Ok
+        });
+    }
+    if let Some(pad) = opusenc.static_pad("src") {
+        let entered = entered.clone();
+        let latencies = latencies.clone();
+        pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+            if let Some(start) = entered.lock().unwrap().take() {
+                latencies.lock().unwrap().push(start.elapsed());
+            }
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    let cpu_before = cpu_time()?;
+    let started = Instant::now();
+    pipeline.set_state(gst::State::Playing)?;
+
+    let bus = pipeline.bus().ok_or_else(|| anyhow::anyhow!("Pipeline has no bus"))?;
+    let outcome = bus.timed_pop_filtered(gst::ClockTime::NONE, &[gst::MessageType::Eos, gst::MessageType::Error]);
+    let wall = started.elapsed();
+    let cpu = cpu_time()?.saturating_sub(cpu_before);
+    pipeline.set_state(gst::State::Null)?;
+
+    if let Some(msg) = outcome {
+        if let gst::MessageView::Error(err) = msg.view() {
+            return Err(anyhow::anyhow!("Benchmark pipeline error: {}", err.error()));
+        }
+    }
+
+    let mut latencies = std::mem::take(&mut *latencies.lock().unwrap());
+    latencies.sort();
+    let percentile = |p: f64| {
+        latencies.get(((latencies.len() as f64 - 1.0) * p).round() as usize)
+            .copied()
+            .unwrap_or_default()
+    };
+
+    let audio_secs = frames as f64 * audio.frame_size as f64 / 1000.0;
+    let wall_secs = wall.as_secs_f64().max(f64::EPSILON);
+    Ok(BenchResult {
+        complexity,
+        bitrate,
+        frames,
+        frames_per_sec: frames as f64 / wall_secs,
+        realtime_factor: audio_secs / wall_secs,
+        cpu_percent: cpu.as_secs_f64() / audio_secs * 100.0,
+        encode_p50: percentile(0.5),
+        encode_p99: percentile(0.99),
+        encode_max: latencies.last().copied().unwrap_or_default(),
+    })
+}
@@ -0,0 +1,408 @@
+//! `pipe2moq calibrate`: play chirps through a sink, capture them back
+//! from its monitor, encode them and optionally loop them through a relay,
+//! timing each segment to give a measured latency budget.
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::{AppSink, AppSinkCallbacks, AppSrc};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::playback::{self, PlaybackConfig};
+use crate::transport::{self, BroadcastPublisher, TrackPublisher, TrackSubscriber, Transport};
+use crate::{AudioConfig, MoqConfig, TransportKind};
+
+/// Everything runs mono at this rate; the encoder settings come from the config.
+const RATE: u32 = 48000;
+const CHIRP_LENGTH: Duration = Duration::from_millis(50);
+const CHIRP_START_HZ: f64 = 500.0;
+const CHIRP_END_HZ: f64 = 6000.0;
+/// Time allowed for each chirp to make it all the way round.
+const CHIRP_SPACING: Duration = Duration::from_secs(1);
+/// Let the sink and capture settle before the first chirp.
+const SETTLE: Duration = Duration::from_millis(1500);
+/// Normalized correlation with the chirp that counts as detecting it.
+const DETECT_THRESHOLD: f32 = 0.6;
+/// Correlation is evaluated every few samples (~83 μs at 48 kHz).
+const DETECT_HOP: usize = 4;
+const TRACK: &str = "calibrate";
+
+#[derive(Clone)]
+pub struct CalibrationConfig {
+    /// Encoder settings to measure.
+    pub audio: AudioConfig,
+    /// Sink to play the chirps to; its monitor is captured.
+    pub output: PlaybackConfig,
+    pub chirps: u32,
+    /// Loop the encoded chirps through this relay as a throwaway broadcast.
+    pub relay: Option<MoqConfig>,
+}
+
+/// Median delays over the chirps that were detected.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct CalibrationReport {
+    pub sink: String,
+    pub chirps: u32,
+    pub detected: u32,
+    /// Chirp sent to the sink until it arrives back from the monitor:
+    /// playback buffering, the audio server and capture buffering.
+    pub capture: Option<Duration>,
+    /// Captured until the Opus packet holding its start leaves the encoder.
+    pub encode: Option<Duration>,
+    /// Published until received back from the relay (uplink plus downlink).
+    pub relay: Option<Duration>,
+}
+
+impl CalibrationReport {
+    pub fn total(&self) -> Option<Duration> {
+        Some(self.capture? + self.encode? + self.relay.unwrap_or_default())
+    }
+}
+
+impl std::fmt::Display for CalibrationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Option<Duration>| match d {
+            Some(d) => format!("{:7.1} ms", d.as_secs_f64() * 1000.0),
+            None => "      n/a".to_string(),
+        };
+        writeln!(f, "Sink:          {}", self.sink)?;
+        writeln!(f, "Chirps:        {} detected of {}", self.detected, self.chirps)?;
+        writeln!(f, "Play+capture:  {}", ms(self.capture))?;
+        writeln!(f, "Encode:        {}", ms(self.encode))?;
+        if self.relay.is_some() {
+            writeln!(f, "Relay loop:    {}", ms(self.relay))?;
+        }
+        writeln!(f, "Total:         {}", ms(self.total()))
+    }
+}
+
+enum Probe {
+    /// A chirp's first sample, at capture PTS `pts_ns`, was captured at about `at`.
+    Onset { at: Instant, pts_ns: u64 },
+    /// An encoded packet covering capture time up to `end_ns`.
+    Encoded { at: Instant, end_ns: u64, data: Bytes },
+}
+
+/// One encoded packet's journey, while its chirp is being timed.
+struct Packet {
+    end_ns: u64,
+    encoded: Instant,
+    published: Option<Instant>,
+    echoed: Option<Instant>,
+}
+
+pub async fn run(config: CalibrationConfig, kind: TransportKind) -> Result<CalibrationReport> {
+    match kind {
+        TransportKind::MoqLite => run_with::<transport::MoqLite>(config).await,
+    }
+}
+
+async fn run_with<T: Transport>(config: CalibrationConfig) -> Result<CalibrationReport> {
+    gst::init()?;
+    let sink = match config.output.sink {
+        Some(ref pattern) => playback::resolve_sink(pattern)?,
+        None => "@DEFAULT_SINK@".to_string(),
+    };
+    let chirp = chirp();
+    let (probes_tx, mut probes) = mpsc::unbounded_channel();
+    let rig = Rig::new(&config, &sink, chirp.clone(), probes_tx)?;
+
+    let (mut track, mut subscription, _broadcast) = match config.relay {
+        Some(ref relay) => {
+            let publish = MoqConfig {
+                broadcast_path: format!("pipe2moq-calibrate-{}", std::process::id()),
+                ..relay.clone()
+            };
+            let mut broadcast = T::publish(&publish).await?;
+            let track = broadcast.create_track(TRACK, publish.priorities.audio);
+            let url = url::Url::parse(&publish.relay_url)?;
+            let subscription = T::subscribe(&url, &publish.broadcast_path, TRACK, Duration::from_secs(10)).await?;
+            info!("Looping chirps through {} as {}", publish.relay_url, publish.broadcast_path);
+            (Some(track), Some(subscription), Some(broadcast))
+        }
+        None => (None, None, None),
+    };
+
+    tokio::time::sleep(SETTLE).await;
+    let mut capture = Vec::new();
+    let mut encode = Vec::new();
+    let mut relay_loop = Vec::new();
+    // Packets are numbered on the relay track so echoes can be matched up.
+    let mut sequence = 0u64;
+    for n in 0..config.chirps {
+        rig.play(&chirp)?;
+        let sent = Instant::now();
+        let deadline = tokio::time::Instant::from_std(sent + CHIRP_SPACING);
+        let mut onset = None;
+        let first_sequence = sequence;
+        let mut packets: Vec<Packet> = Vec::new();
+
+        // Run out the whole spacing, forwarding every packet, so the relay
+        // sees a steady stream rather than only the chirps.
+        loop {
+            tokio::select! {
+                probe = probes.recv() => match probe {
+                    Some(Probe::Onset { at, pts_ns }) => {
+                        onset.get_or_insert((at, pts_ns));
+                    }
+                    Some(Probe::Encoded { at, end_ns, data }) => {
+                        let mut packet = Packet { end_ns, encoded: at, published: None, echoed: None };
+                        if let Some(ref mut track) = track {
+                            let mut object = BytesMut::with_capacity(8 + data.len());
+                            object.put_u64(sequence);
+                            object.extend_from_slice(&data);
+                            track.write_group([object.freeze()]);
+                            packet.published = Some(Instant::now());
+                        }
+                        packets.push(packet);
+                        sequence += 1;
+                    }
+                    None => anyhow::bail!("Calibration pipeline stopped"),
+                },
+                object = async {
+                    match subscription.as_mut() {
+                        Some(subscription) => subscription.next_object().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    let Some(object) = object? else { anyhow::bail!("Relay closed the calibration track") };
+                    let echoed = <[u8; 8]>::try_from(object.get(..8).unwrap_or_default()).map(u64::from_be_bytes);
+                    // Echoes of an earlier chirp's packets are ignored.
+                    if let Some(packet) = echoed.ok()
+                        .and_then(|seq| seq.checked_sub(first_sequence))
+                        .and_then(|index| packets.get_mut(index as usize)) {
+                        packet.echoed.get_or_insert_with(Instant::now);
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+            rig.check()?;
+        }
+
+        // The packet holding the chirp's first sample.
+        let Some(((onset, pts_ns), packet)) = onset.and_then(|(at, pts_ns)| {
+            packets.iter().find(|packet| packet.end_ns > pts_ns).map(|packet| ((at, pts_ns), packet))
+        }) else {
+            warn!("Chirp {} was not detected; is the sink muted or its volume too low?", n + 1);
+            continue;
+        };
+        capture.push(onset.saturating_duration_since(sent));
+        encode.push(packet.encoded.saturating_duration_since(onset));
+        match (packet.published, packet.echoed) {
+            (Some(published), Some(echoed)) => relay_loop.push(echoed.saturating_duration_since(published)),
+            (Some(_), None) => warn!("Chirp {} did not come back from the relay", n + 1),
+            _ => {}
+        }
+        debug!("Chirp {} at capture PTS {} ns: capture {:?}, encode {:?}", n + 1, pts_ns, capture.last(), encode.last());
+    }
+
+    Ok(CalibrationReport {
+        sink,
+        chirps: config.chirps,
+        detected: capture.len() as u32,
+        capture: median(&mut capture),
+        encode: median(&mut encode),
+        relay: median(&mut relay_loop),
+    })
+}
+
+fn median(samples: &mut [Duration]) -> Option<Duration> {
+    samples.sort();
+    samples.get(samples.len() / 2).copied()
+}
+
+/// A Hann-windowed linear sweep, mono f32 at [`RATE`].
+fn chirp() -> Vec<f32> {
+    let len = (RATE as f64 * CHIRP_LENGTH.as_secs_f64()) as usize;
+    let secs = CHIRP_LENGTH.as_secs_f64();
+    (0..len).map(|i| {
+        let t = i as f64 / RATE as f64;
+        let phase = 2.0 * std::f64::consts::PI * (CHIRP_START_HZ * t + (CHIRP_END_HZ - CHIRP_START_HZ) * t * t / (2.0 * secs));
+        let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / (len - 1) as f64).cos();
+        (0.5 * window * phase.sin()) as f32
+    }).collect()
+}
+
+/// Matched filter finding the chirp in a stream of samples.
+struct ChirpDetector {
+    template: Vec<f32>,
+    template_energy: f32,
+    window: Vec<f32>,
+    /// Stream index of `window[0]`.
+    start: u64,
+}
+
+impl ChirpDetector {
+    fn new(template: Vec<f32>) -> Self {
+        let template_energy = template.iter().map(|s| s * s).sum();
+        Self { template, template_energy, window: Vec::new(), start: 0 }
+    }
+
+    /// Add captured samples, returning the stream index of a chirp's first
+    /// sample if one is now complete in the window.
+    fn push(&mut self, samples: &[f32]) -> Option<u64> {
+        self.window.extend_from_slice(samples);
+        let len = self.template.len();
+        let mut pos = 0;
+        let mut found = None;
+        while pos + len <= self.window.len() {
+            let candidate = &self.window[pos..pos + len];
+            let energy: f32 = candidate.iter().map(|s| s * s).sum();
+            if energy > 0.0 {
+                let dot: f32 = candidate.iter().zip(&self.template).map(|(a, b)| a * b).sum();
+                if dot / (energy * self.template_energy).sqrt() >= DETECT_THRESHOLD {
+                    found = Some(self.start + pos as u64);
+                    // Don't find the same chirp again.
+                    pos += len;
+                    break;
+                }
+            }
+            pos += DETECT_HOP;
+        }
+        let keep_from = pos.min(self.window.len());
+        self.window.drain(..keep_from);
+        self.start += keep_from as u64;
+        found
+    }
+}
+
+/// `appsrc ! volume ! sink` playing chirps, and `<sink>.monitor` captured
+/// into a detector and an encoder.
+struct Rig {
+    pipeline: gst::Pipeline,
+    player: AppSrc,
+}
+
+impl Rig {
+    fn new(config: &CalibrationConfig, sink: &str, chirp: Vec<f32>, probes: mpsc::UnboundedSender<Probe>) -> Result<Self> {
+        let pipeline = gst::Pipeline::default();
+        let raw_caps = gst::Caps::builder("audio/x-raw")
+            .field("format", "F32LE")
+            .field("layout", "interleaved")
+            .field("rate", RATE as i32)
+            .field("channels", 1i32)
+            .build();
+
+        let player = AppSrc::builder()
+            .caps(&raw_caps)
+            .format(gst::Format::Time)
+            .is_live(true)
+            .do_timestamp(true)
+            .build();
+        let mut play_chain = vec![
+            player.upcast_ref::<gst::Element>().clone(),
+            gst::ElementFactory::make("audioconvert").build()?,
+            gst::ElementFactory::make("audioresample").build()?,
+        ];
+        play_chain.extend(playback::output_elements(&PlaybackConfig {
+            sink: Some(sink.to_string()).filter(|sink| sink != "@DEFAULT_SINK@"),
+            ..config.output.clone()
+        })?);
+
+        let monitor = if sink == "@DEFAULT_SINK@" { "@DEFAULT_MONITOR@".to_string() } else { format!("{}.monitor", sink) };
+        let source = gst::ElementFactory::make("pulsesrc")
+            .property("device", monitor)
+            .property("buffer-time", 20000i64)
+            .property("latency-time", 10000i64)
+            .build()?;
+        let capsfilter = gst::ElementFactory::make("capsfilter").property("caps", &raw_caps).build()?;
+        let tee = gst::ElementFactory::make("tee").build()?;
+        let raw_queue = gst::ElementFactory::make("queue").build()?;
+        let raw_sink = AppSink::builder().caps(&raw_caps).sync(false).build();
+        let encode_queue = gst::ElementFactory::make("queue").build()?;
+        let opusenc = gst::ElementFactory::make("opusenc")
+            .property("bitrate", config.audio.bitrate as i32)
+            .property_from_str("audio-type", config.audio.opus_audio_type())
+            .property("complexity", config.audio.complexity as i32)
+            .property_from_str("frame-size", &config.audio.frame_size.to_string())
+            .build()?;
+        let encoded_sink = AppSink::builder().sync(false).build();
+
+        pipeline.add_many(&play_chain)?;
+        gst::Element::link_many(&play_chain)?;
+        let capture_chain = [
+            source,
+            gst::ElementFactory::make("audioconvert").build()?,
+            gst::ElementFactory::make("audioresample").build()?,
+            capsfilter,
+            tee.clone(),
+        ];
+        pipeline.add_many(&capture_chain)?;
+        gst::Element::link_many(&capture_chain)?;
+        pipeline.add_many([&raw_queue, raw_sink.upcast_ref(), &encode_queue, &opusenc, encoded_sink.upcast_ref()])?;
+        gst::Element::link_many([&tee, &raw_queue, raw_sink.upcast_ref()])?;
+        gst::Element::link_many([&tee, &encode_queue, &opusenc, encoded_sink.upcast_ref()])?;
+
+        let detector = Mutex::new((ChirpDetector::new(chirp), None::<u64>));
+        let raw_probes = probes.clone();
+        raw_sink.set_callbacks(
+            AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                    let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                    let samples: Vec<f32> = map.as_slice()
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect();
+
+                    let mut state = detector.lock().unwrap();
+                    let (ref mut detector, ref mut first_pts_ns) = *state;
+                    let first_pts_ns = *first_pts_ns.get_or_insert(buffer.pts().map(|pts| pts.nseconds()).unwrap_or(0));
+                    if let Some(onset) = detector.push(&samples) {
+                        // The newest sample arrived now; the onset as much
+                        // earlier as there is audio after it.
+                        let captured = detector.start + detector.window.len() as u64;
+                        let behind = Duration::from_secs_f64(captured.saturating_sub(onset) as f64 / RATE as f64);
+                        let at = Instant::now().checked_sub(behind).unwrap_or_else(Instant::now);
+                        let pts_ns = first_pts_ns + onset * 1_000_000_000 / RATE as u64;
+                        let _ = raw_probes.send(Probe::Onset { at, pts_ns });
+                    }
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+        encoded_sink.set_callbacks(
+            AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                    let end_ns = buffer.pts().map(|pts| pts.nseconds()).unwrap_or(0)
+                        + buffer.duration().map(|d| d.nseconds()).unwrap_or(0);
+                    let data = Bytes::copy_from_slice(buffer.map_readable().map_err(|_| gst::FlowError::Error)?.as_slice());
+                    let _ = probes.send(Probe::Encoded { at: Instant::now(), end_ns, data });
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+        pipeline.set_state(gst::State::Playing)?;
+        Ok(Self { pipeline, player })
+    }
+
+    fn play(&self, chirp: &[f32]) -> Result<()> {
+        let bytes: Vec<u8> = chirp.iter().flat_map(|s| s.to_le_bytes()).collect();
+        self.player.push_buffer(gst::Buffer::from_slice(bytes))?;
+        Ok(())
+    }
+
+    fn check(&self) -> Result<()> {
+        if let Some(msg) = self.pipeline.bus().and_then(|bus| bus.pop_filtered(&[gst::MessageType::Error])) {
+            if let gst::MessageView::Error(err) = msg.view() {
+                anyhow::bail!("Calibration pipeline error: {}", err.error());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Rig {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
@@ -0,0 +1,182 @@
+//! `pipe2moq capabilities`: what this build and host can do, so tooling
+//! can decide how to configure each machine before starting a stream.
+
+use gstreamer as gst;
+
+/// A GStreamer element pipe2moq uses, and what for.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ElementStatus {
+    pub name: &'static str,
+    pub available: bool,
+    /// Needed for every stream, as opposed to a single feature.
+    pub required: bool,
+    pub used_for: &'static str,
+}
+
+/// Sources capture can use; at least one is needed.
+const CAPTURE_SOURCES: &[&str] = &["pipewiresrc", "pulsesrc"];
+
+/// Elements pipe2moq may build, with whether each is required and what
+/// depends on it.
+const ELEMENTS: &[(&str, bool, &str)] = &[
+    ("pulsesrc", false, "capture (pulse)"),
+    ("audioconvert", true, "capture"),
+    ("audioresample", true, "capture"),
+    ("capsfilter", true, "capture"),
+    ("queue", true, "capture"),
+    ("tee", true, "capture"),
+    ("level", true, "level warnings"),
+    ("volume", true, "mute"),
+    ("opusenc", true, "encoding"),
+    ("pipewiresrc", false, "capture (pipewire), follow_app, stems and pipewire video"),
+    ("audiomixer", false, "microphone mix"),
+    ("audiomixmatrix", false, "channel remapping"),
+    ("audiorate", false, "gap filling"),
+    ("webrtcdsp", false, "AGC, noise suppression and echo cancellation"),
+    ("webrtcechoprobe", false, "echo cancellation"),
+    ("audiocheblimit", false, "high-pass filter"),
+    ("audiornnoise", false, "RNNoise noise suppression"),
+    ("oggmux", false, "file output"),
+    ("filesink", false, "file output"),
+    ("rtpopuspay", false, "RTP multicast (opus)"),
+    ("rtpL16pay", false, "RTP multicast (pcm)"),
+    ("udpsink", false, "RTP multicast"),
+    ("opusdec", false, "listening locally and calibration"),
+    ("pulsesink", false, "listening locally and calibration"),
+    ("videoconvert", false, "video"),
+    ("videoscale", false, "video"),
+    ("videorate", false, "video"),
+    ("ximagesrc", false, "video (x11)"),
+    ("videotestsrc", false, "video (test)"),
+    ("x264enc", false, "video (h264)"),
+    ("h264parse", false, "video (h264)"),
+    ("svtav1enc", false, "video (av1)"),
+    ("av1parse", false, "video (av1)"),
+    ("audiotestsrc", false, "bench"),
+    ("fakesink", false, "bench"),
+];
+
+/// Cargo features this binary was built with.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Features {
+    pub notify: bool,
+    pub tray: bool,
+    pub media_keys: bool,
+    pub console: bool,
+    pub scripting: bool,
+}
+
+impl Features {
+    pub fn compiled() -> Self {
+        Self {
+            notify: cfg!(feature = "notify"),
+            tray: cfg!(feature = "tray"),
+            media_keys: cfg!(feature = "media-keys"),
+            console: cfg!(feature = "console"),
+            scripting: cfg!(feature = "scripting"),
+        }
+    }
+
+    /// Names of the enabled features, as given to `--features`.
+    pub fn enabled(&self) -> Vec<&'static str> {
+        [
+            (self.notify, "notify"),
+            (self.tray, "tray"),
+            (self.media_keys, "media-keys"),
+            (self.console, "console"),
+            (self.scripting, "scripting"),
+        ].into_iter().filter_map(|(enabled, name)| enabled.then_some(name)).collect()
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub features: Features,
+    pub codecs: Vec<&'static str>,
+    /// MoQ transports, publisher backends and frame formats, by the names
+    /// the config file takes.
+    pub transports: Vec<&'static str>,
+    pub backends: Vec<&'static str>,
+    pub frame_formats: Vec<&'static str>,
+    pub rtp_payloads: Vec<&'static str>,
+    /// Ways to control a running stream.
+    pub controls: Vec<&'static str>,
+    /// `None` if GStreamer failed to initialize.
+    pub gstreamer: Option<String>,
+    pub elements: Vec<ElementStatus>,
+}
+
+impl Capabilities {
+    /// Whether every required element, and a capture source, was found.
+    pub fn ok(&self) -> bool {
+        self.gstreamer.is_some()
+            && self.elements.iter().all(|element| element.available || !element.required)
+            && self.elements.iter().any(|element| element.available && CAPTURE_SOURCES.contains(&element.name))
+    }
+}
+
+/// Look at the build and probe the GStreamer registry.
+pub fn detect() -> Capabilities {
+    let features = Features::compiled();
+    let gstreamer = gst::init().ok().map(|()| gst::version_string().to_string());
+    let elements = ELEMENTS.iter()
+        .map(|&(name, required, used_for)| ElementStatus {
+            name,
+            available: gstreamer.is_some() && gst::ElementFactory::find(name).is_some(),
+            required,
+            used_for,
+        })
+        .collect();
+
+    let backends = vec!["lite", "karp"];
+    let mut controls = vec!["hotkeys", "signals", "metrics", "webhook"];
+    for (enabled, control) in [
+        (features.tray, "tray"),
+        (features.media_keys, "media-keys"),
+        (features.scripting, "scripting"),
+        (features.notify, "notify"),
+    ] {
+        if enabled {
+            controls.push(control);
+        }
+    }
+
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        features,
+        codecs: vec!["opus", "h264", "av1"],
+        transports: vec!["moq-lite"],
+        backends,
+        frame_formats: vec!["raw", "v1"],
+        rtp_payloads: vec!["opus", "pcm"],
+        controls,
+        gstreamer,
+        elements,
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "pipe2moq {}", self.version)?;
+        writeln!(f, "GStreamer:      {}", self.gstreamer.as_deref().unwrap_or("failed to initialize"))?;
+        let features = self.features.enabled();
+        writeln!(f, "Features:       {}", if features.is_empty() { "none".to_string() } else { features.join(", ") })?;
+        writeln!(f, "Codecs:         {}", self.codecs.join(", "))?;
+        writeln!(f, "Transports:     {}", self.transports.join(", "))?;
+        writeln!(f, "Backends:       {}", self.backends.join(", "))?;
+        writeln!(f, "Frame formats:  {}", self.frame_formats.join(", "))?;
+        writeln!(f, "RTP payloads:   {}", self.rtp_payloads.join(", "))?;
+        writeln!(f, "Controls:       {}", self.controls.join(", "))?;
+        writeln!(f, "Elements:")?;
+        for element in &self.elements {
+            let state = match (element.available, element.required) {
+                (true, _) => "ok",
+                (false, true) => "MISSING",
+                (false, false) => "missing",
+            };
+            writeln!(f, "  {:16} {:8} {}", element.name, state, element.used_for)?;
+        }
+        Ok(())
+    }
+}
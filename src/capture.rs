@@ -0,0 +1,536 @@
+use anyhow::Result;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::events::{self, Event, EventSender};
+use crate::{processing, CaptureBackend, PipelineConfig, Stats};
+
+/// The capture half of the pipeline: sources up to the processed raw audio.
+pub(crate) struct Capture {
+    /// Element whose src pad carries the captured (and, in mix mode, mixed) audio.
+    pub tail: gst::Element,
+    /// Capture sources with their device names, for error attribution.
+    pub sources: Vec<(gst::Element, String)>,
+    /// PipeWire node currently captured in follow-app mode.
+    app_node: Option<u64>,
+}
+
+/// A capture source failed, as when its device was unplugged; the
+/// pipeline is rebuilt under the device retry policy.
+#[derive(Debug, thiserror::Error)]
+#[error("lost capture device {device}")]
+pub(crate) struct DeviceLost {
+    pub device: String,
+}
+
+/// Capture sent EOS without being asked to stop; see [`crate::EosPolicy`].
+#[derive(Debug, thiserror::Error)]
+#[error("capture ended unexpectedly (EOS)")]
+pub(crate) struct CaptureEnded;
+
+/// One application's playback captured on its own, published as a stem
+/// alongside the full mix.
+pub(crate) struct Stem {
+    pub app: String,
+    /// Element whose src pad carries the stem's raw audio.
+    pub tail: gst::Element,
+    source: gst::Element,
+    node: Option<u64>,
+}
+
+const ECHO_PROBE_NAME: &str = "echoprobe";
+
+/// Index of the capture source reading the desktop monitor.
+const MONITOR_SOURCE: usize = 0;
+
+/// The default sink's monitor, in Pulse's naming. Captured without a
+/// lookup, so the default sink needs no `pactl`.
+const DEFAULT_MONITOR: &str = "@DEFAULT_MONITOR@";
+
+/// The backend `config` asks for, with `auto` settled.
+fn capture_backend(config: &PipelineConfig) -> CaptureBackend {
+    match config.capture_backend {
+        CaptureBackend::Auto if pipewire_available() => CaptureBackend::Pipewire,
+        CaptureBackend::Auto => CaptureBackend::Pulse,
+        backend => backend,
+    }
+}
+
+/// Whether `pipewiresrc` is installed and a PipeWire server socket is
+/// there to connect to, as it is in a Flatpak granted PipeWire access.
+fn pipewire_available() -> bool {
+    if gst::ElementFactory::find("pipewiresrc").is_none() {
+        return false;
+    }
+    let remote = std::env::var_os("PIPEWIRE_REMOTE").unwrap_or_else(|| "pipewire-0".into());
+    let runtime = std::env::var_os("PIPEWIRE_RUNTIME_DIR").or_else(|| std::env::var_os("XDG_RUNTIME_DIR"));
+    Path::new(&remote).exists() || runtime.is_some_and(|dir| Path::new(&dir).join(&remote).exists())
+}
+
+fn pactl_json(what: &str) -> Result<Vec<serde_json::Value>> {
+    let output = Command::new("pactl").args(["-f", "json", "list", what]).output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("pactl list {} failed: {}", what, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// Names of all sinks, in server order.
+pub(crate) fn sink_names() -> Result<Vec<String>> {
+    Ok(pactl_json("sinks")?
+        .iter()
+        .filter_map(|sink| Some(sink.get("name")?.as_str()?.to_string()))
+        .collect())
+}
+
+/// Number of uncorked sink inputs per sink, from `pactl -f json`.
+fn sink_activity() -> Result<Vec<(String, usize)>> {
+    let inputs = pactl_json("sink-inputs")?;
+    let sinks = pactl_json("sinks")?;
+    Ok(sinks.iter()
+        .filter_map(|sink| {
+            let index = sink.get("index")?.as_u64()?;
+            let name = sink.get("name")?.as_str()?.to_string();
+            let playing = inputs.iter()
+                .filter(|input| input.get("sink").and_then(|s| s.as_u64()) == Some(index))
+                .filter(|input| !input.get("corked").and_then(|c| c.as_bool()).unwrap_or(false))
+                .count();
+            Some((name, playing))
+        })
+        .collect())
+}
+
+/// The sink with the most playing streams, preferring `current` on a tie
+/// so capture doesn't flap between equally busy sinks.
+fn active_sink(current: Option<&str>) -> Result<Option<String>> {
+    let activity = sink_activity()?;
+    let busiest = activity.iter().map(|(_, playing)| *playing).max().unwrap_or(0);
+    if busiest == 0 {
+        return Ok(None);
+    }
+    let mut candidates = activity.into_iter().filter(|(_, playing)| *playing == busiest).map(|(name, _)| name);
+    let first = candidates.next();
+    if let Some(current) = current {
+        if first.as_deref() == Some(current) || candidates.any(|name| name == current) {
+            return Ok(Some(current.to_string()));
+        }
+    }
+    Ok(first)
+}
+
+/// `object.serial` of the first playback stream whose application name
+/// or binary matches `app`, case-insensitively.
+fn app_stream(app: &str) -> Result<Option<u64>> {
+    let inputs = pactl_json("sink-inputs")?;
+    let app = app.to_lowercase();
+    Ok(inputs.iter().find_map(|input| {
+        let properties = input.get("properties")?;
+        let matches = ["application.name", "application.process.binary"].iter().any(|key| {
+            properties.get(*key).and_then(|v| v.as_str()).is_some_and(|v| v.to_lowercase() == app)
+        });
+        if !matches {
+            return None;
+        }
+        properties.get("object.serial")?.as_str()?.parse().ok()
+    }))
+}
+
+/// A PipeWire source capturing one application's output stream. Without
+/// a node it stays unconnected until [`Capture::follow_app`] finds one.
+fn app_source(node: Option<u64>) -> Result<gst::Element> {
+    // Never fall back to the default source when the app goes away.
+    let properties = gst::Structure::builder("props")
+        .field("node.dont-reconnect", true)
+        .build();
+    let source = gst::ElementFactory::make("pipewiresrc")
+        .property("stream-properties", &properties)
+        .property("autoconnect", node.is_some())
+        .build()?;
+    if let Some(node) = node {
+        source.set_property("target-object", node.to_string());
+    }
+    Ok(source)
+}
+
+/// The desktop capture source: a followed application, or a sink monitor.
+fn desktop_source(config: &PipelineConfig) -> Result<(gst::Element, String, Option<u64>)> {
+    if let Some(ref app) = config.follow_app {
+        let node = app_stream(app).unwrap_or_else(|e| {
+            warn!("Could not list playback streams: {e:#}");
+            None
+        });
+        match node {
+            Some(node) => info!("Audio source: {} (node {})", app, node),
+            None => info!("Audio source: {} (waiting for it to play)", app),
+        }
+        return Ok((app_source(node)?, app.clone(), node));
+    }
+
+    let monitor = monitor_device(config);
+    info!("Audio source: {}", monitor);
+    Ok((audio_source(config, &monitor)?, monitor, None))
+}
+
+fn monitor_device(config: &PipelineConfig) -> String {
+    if config.follow_active_sink {
+        match active_sink(None) {
+            Ok(Some(sink)) => return format!("{}.monitor", sink),
+            Ok(None) => debug!("No sink is playing audio yet"),
+            Err(e) => warn!("Could not find the active sink: {e:#}"),
+        }
+    }
+    match config.sink_name {
+        Some(ref sink) => format!("{}.monitor", sink),
+        None => DEFAULT_MONITOR.to_string(),
+    }
+}
+
+/// A source reading `device`: a `<sink>.monitor`, [`DEFAULT_MONITOR`]
+/// or a capture device such as a microphone.
+fn audio_source(config: &PipelineConfig, device: &str) -> Result<gst::Element> {
+    if capture_backend(config) == CaptureBackend::Pipewire {
+        pipewiresrc(config, device)
+    } else {
+        pulsesrc(config, device)
+    }
+}
+
+/// `device` as a PipeWire capture stream: a monitor becomes a capture of
+/// the sink itself, by node name or serial.
+fn pipewiresrc(config: &PipelineConfig, device: &str) -> Result<gst::Element> {
+    let target = match device.strip_suffix(".monitor") {
+        Some(sink) => Some(sink),
+        None if device == DEFAULT_MONITOR => None,
+        None => Some(device),
+    };
+    // No buffer-time here; ask the graph for latency_time-sized quanta.
+    let rate = config.audio.sample_rate as u64;
+    let quantum = (config.latency_time as u64 * rate / 1_000_000).max(1);
+    let mut properties = gst::Structure::builder("props")
+        .field("node.latency", format!("{}/{}", quantum, rate));
+    if target != Some(device) {
+        properties = properties.field("stream.capture.sink", true);
+    }
+    let source = gst::ElementFactory::make("pipewiresrc")
+        .property("stream-properties", &properties.build())
+        .build()?;
+    if let Some(target) = target {
+        source.set_property("target-object", target);
+    }
+    Ok(source)
+}
+
+fn pulsesrc(config: &PipelineConfig, device: &str) -> Result<gst::Element> {
+    Ok(gst::ElementFactory::make("pulsesrc")
+        .property("device", device)
+        .property("buffer-time", config.buffer_time as i64)
+        .property("latency-time", config.latency_time as i64)
+        .build()?)
+}
+
+fn raw_caps(config: &PipelineConfig) -> Result<gst::Element> {
+    caps_with_channels(config, config.audio.channels)
+}
+
+fn caps_with_channels(config: &PipelineConfig, channels: u32) -> Result<gst::Element> {
+    let mut caps = gst::Caps::builder("audio/x-raw")
+        .field("rate", config.audio.sample_rate as i32)
+        .field("channels", channels as i32);
+    if config.audio.ambisonics && channels == config.audio.channels {
+        // No positions: opusenc then uses mapping family 255, one
+        // uncoupled stream per channel, rather than surround pairs.
+        caps = caps.field("channel-mask", gst::Bitmask::new(0));
+    }
+    Ok(gst::ElementFactory::make("capsfilter")
+        .property("caps", &caps.build())
+        .build()?)
+}
+
+/// Check a channel matrix has one row per output channel and equal-length rows.
+pub(crate) fn validate_channel_matrix(matrix: &[Vec<f64>], out_channels: u32) -> Result<()> {
+    if matrix.len() != out_channels as usize {
+        return Err(anyhow::anyhow!(
+            "channel_matrix has {} rows but {} output channels are configured",
+            matrix.len(), out_channels));
+    }
+    let in_channels = matrix[0].len();
+    if in_channels == 0 || matrix.iter().any(|row| row.len() != in_channels) {
+        return Err(anyhow::anyhow!("channel_matrix rows must all have the same, non-zero, number of input channels"));
+    }
+    Ok(())
+}
+
+/// A device source up to converted audio at the configured rate and
+/// channel count, remapped through `channel_matrix` when configured.
+fn source_chain(config: &PipelineConfig, source: &gst::Element) -> Result<Vec<gst::Element>> {
+    let mut chain = vec![source.clone()];
+
+    if let Some(ref matrix) = config.channel_matrix {
+        validate_channel_matrix(matrix, config.audio.channels)?;
+        let in_channels = matrix[0].len() as u32;
+        let rows = gst::Array::new(matrix.iter().map(|row| gst::Array::new(row.iter().map(|v| *v as f32))));
+        chain.push(caps_with_channels(config, in_channels)?);
+        chain.push(gst::ElementFactory::make("audioconvert").build()?);
+        chain.push(gst::ElementFactory::make("audiomixmatrix")
+            .property_from_str("mode", "manual")
+            .property("in-channels", in_channels)
+            .property("out-channels", config.audio.channels)
+            .property("matrix", rows)
+            .build()?);
+        info!("Remapping {} input channels to {} through channel_matrix", in_channels, config.audio.channels);
+    }
+
+    chain.push(raw_caps(config)?);
+    chain.push(gst::ElementFactory::make("audioconvert").build()?);
+    chain.push(gst::ElementFactory::make("audioresample").build()?);
+
+    Ok(chain)
+}
+
+fn add_chain(pipeline: &gst::Pipeline, chain: &[gst::Element]) -> Result<()> {
+    pipeline.add_many(chain)?;
+    gst::Element::link_many(chain)?;
+    Ok(())
+}
+
+pub(crate) fn build(pipeline: &gst::Pipeline, config: &PipelineConfig) -> Result<Capture> {
+    if config.follow_app.is_none() {
+        match capture_backend(config) {
+            CaptureBackend::Pipewire => info!("Capturing through PipeWire"),
+            _ => info!("Capturing through PulseAudio"),
+        }
+    }
+    let (monitor_source, monitor, app_node) = desktop_source(config)?;
+
+    let voice = config.audio.is_voice();
+
+    let Some(ref microphone) = config.microphone else {
+        if config.processing.echo_cancel {
+            warn!("Echo cancellation needs a microphone to mix with; ignoring");
+        }
+        let mut chain = source_chain(config, &monitor_source)?;
+        chain.extend(processing::build_elements(&config.processing, voice, None)?);
+        add_chain(pipeline, &chain)?;
+
+        return Ok(Capture {
+            tail: chain.last().cloned().expect("capture chain is never empty"),
+            sources: vec![(monitor_source, monitor)],
+            app_node,
+        });
+    };
+
+    info!("Mixing microphone: {}", microphone);
+    let mixer = gst::ElementFactory::make("audiomixer").build()?;
+    let mixed_caps = raw_caps(config)?;
+    add_chain(pipeline, &[mixer.clone(), mixed_caps.clone()])?;
+
+    // Desktop playback; doubles as the far-end reference for echo cancellation.
+    let mut monitor_chain = source_chain(config, &monitor_source)?;
+    if config.processing.echo_cancel {
+        monitor_chain.push(gst::ElementFactory::make("webrtcechoprobe")
+            .name(ECHO_PROBE_NAME)
+            .build()?);
+    }
+    monitor_chain.push(gst::ElementFactory::make("queue").build()?);
+    add_chain(pipeline, &monitor_chain)?;
+    monitor_chain.last().expect("monitor chain is never empty").link(&mixer)?;
+
+    let mic_source = audio_source(config, microphone)?;
+    let mut mic_chain = vec![
+        mic_source.clone(),
+        gst::ElementFactory::make("audioconvert").build()?,
+        gst::ElementFactory::make("audioresample").build()?,
+        raw_caps(config)?,
+    ];
+    let probe = config.processing.echo_cancel.then_some(ECHO_PROBE_NAME);
+    mic_chain.extend(processing::build_elements(&config.processing, voice, probe)?);
+    mic_chain.push(gst::ElementFactory::make("queue").build()?);
+    add_chain(pipeline, &mic_chain)?;
+    mic_chain.last().expect("mic chain is never empty").link(&mixer)?;
+
+    Ok(Capture {
+        tail: mixed_caps,
+        sources: vec![(monitor_source, monitor), (mic_source, microphone.clone())],
+        app_node,
+    })
+}
+
+/// Capture `app`'s playback on its own, converted to the stream format.
+/// The channel matrix is not applied: that describes the capture device.
+pub(crate) fn build_stem(pipeline: &gst::Pipeline, config: &PipelineConfig, app: &str) -> Result<Stem> {
+    let node = app_stream(app).unwrap_or_else(|e| {
+        warn!("Could not list playback streams: {e:#}");
+        None
+    });
+    match node {
+        Some(node) => info!("Stem source: {} (node {})", app, node),
+        None => info!("Stem source: {} (waiting for it to play)", app),
+    }
+    let source = app_source(node)?;
+    let chain = [
+        source.clone(),
+        gst::ElementFactory::make("audioconvert").build()?,
+        gst::ElementFactory::make("audioresample").build()?,
+        raw_caps(config)?,
+    ];
+    add_chain(pipeline, &chain)?;
+    Ok(Stem {
+        app: app.to_string(),
+        tail: chain[chain.len() - 1].clone(),
+        source,
+        node,
+    })
+}
+
+/// Point an application source at `app`'s current playback stream, if
+/// that changed since `node`.
+fn retarget_app(source: &gst::Element, app: &str, node: &mut Option<u64>) -> Result<()> {
+    let current = match app_stream(app) {
+        Ok(current) => current,
+        Err(e) => {
+            debug!("Could not poll playback streams: {e:#}");
+            return Ok(());
+        }
+    };
+    if current == *node {
+        return Ok(());
+    }
+    let Some(current) = current else {
+        info!("{} stopped playing; waiting for it to return", app);
+        *node = None;
+        return Ok(());
+    };
+
+    info!("Capturing {} (node {})", app, current);
+    source.set_state(gst::State::Null)?;
+    source.set_property("target-object", current.to_string());
+    source.set_property("autoconnect", true);
+    source.sync_state_with_parent()?;
+    *node = Some(current);
+    Ok(())
+}
+
+impl Stem {
+    /// Re-attach when the application's stream (re)appears.
+    pub(crate) fn follow(&mut self) -> Result<()> {
+        retarget_app(&self.source, &self.app, &mut self.node)
+    }
+}
+
+impl Capture {
+    /// Point the monitor source at whichever sink is now playing, if that
+    /// changed. The source restarts, so the switch shows up as a gap.
+    pub(crate) fn follow_active_sink(&mut self) -> Result<()> {
+        let (source, device) = &mut self.sources[MONITOR_SOURCE];
+        let current = device.strip_suffix(".monitor");
+        let sink = match active_sink(current) {
+            Ok(Some(sink)) => sink,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                debug!("Could not poll sink activity: {e:#}");
+                return Ok(());
+            }
+        };
+        if current == Some(sink.as_str()) {
+            return Ok(());
+        }
+
+        info!("Playback moved to {}, switching capture", sink);
+        let monitor = format!("{}.monitor", sink);
+        // Either source only picks up a new device when (re)opened.
+        source.set_state(gst::State::Null)?;
+        if source.factory().is_some_and(|factory| factory.name() == "pipewiresrc") {
+            source.set_property("target-object", &sink);
+        } else {
+            source.set_property("device", &monitor);
+        }
+        source.sync_state_with_parent()?;
+        *device = monitor;
+        Ok(())
+    }
+
+    /// Re-attach to `app`'s playback stream when it (re)appears, e.g.
+    /// after the application restarted.
+    pub(crate) fn follow_app(&mut self, app: &str) -> Result<()> {
+        let (source, _) = &self.sources[MONITOR_SOURCE];
+        retarget_app(source, app, &mut self.app_node)
+    }
+
+    pub(crate) fn is_source(&self, object: Option<&gst::Object>) -> bool {
+        self.sources.iter().any(|(source, _)| object == Some(source.upcast_ref()))
+    }
+
+    /// Count DISCONT buffers leaving each source: audiobasesrc marks
+    /// the buffer after samples were dropped because it couldn't keep up.
+    pub(crate) fn watch_discontinuities(&self, stats: &Arc<Stats>) {
+        for (source, device) in &self.sources {
+            let Some(pad) = source.static_pad("src") else { continue };
+            let stats = stats.clone();
+            let device = device.clone();
+            // The first buffer is always DISCONT.
+            let started = AtomicBool::new(false);
+            pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+                    if buffer.flags().contains(gst::BufferFlags::DISCONT) && started.swap(true, Ordering::Relaxed) {
+                        stats.record_capture_discontinuity();
+                        debug!("Capture discontinuity on {}", device);
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+    }
+
+    /// Compare each captured buffer's PTS against where the previous one
+    /// ended, reporting jumps beyond `tolerance` as gaps or overlaps.
+    pub(crate) fn watch_timeline(&self, tolerance: Duration, stats: &Arc<Stats>, events: &EventSender) {
+        let Some(pad) = self.tail.static_pad("src") else { return };
+        let stats = stats.clone();
+        let events = events.clone();
+        let tolerance = gst::ClockTime::try_from(tolerance).unwrap_or(gst::ClockTime::ZERO);
+        let expected = Mutex::new(None::<gst::ClockTime>);
+        pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let (Some(pts), Some(duration)) = (buffer.pts(), buffer.duration()) else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let mut expected = expected.lock().unwrap();
+            if let Some(expected) = *expected {
+                if pts > expected + tolerance {
+                    let missing = Duration::from(pts - expected);
+                    warn!("Capture gap: {:.1} ms of audio missing", missing.as_secs_f64() * 1000.0);
+                    stats.record_capture_gap(missing);
+                    events::emit(&events, Event::CaptureGap { missing });
+                } else if pts + tolerance < expected {
+                    let excess = Duration::from(expected - pts);
+                    warn!("Capture overlap: {:.1} ms of audio repeated", excess.as_secs_f64() * 1000.0);
+                    stats.record_capture_overlap();
+                    events::emit(&events, Event::CaptureOverlap { excess });
+                }
+            }
+            *expected = Some(pts + duration);
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    /// Buffer- and latency-time actually granted to the first source, in microseconds.
+    pub(crate) fn actual_buffering(&self) -> (u64, u64) {
+        let Some((source, _)) = self.sources.first() else { return (0, 0) };
+        // Only pulsesrc reports these; pipewiresrc doesn't.
+        if source.find_property("actual-buffer-time").is_none() {
+            return (0, 0);
+        }
+        let buffer_time = source.property::<i64>("actual-buffer-time").max(0) as u64;
+        let latency_time = source.property::<i64>("actual-latency-time").max(0) as u64;
+        (buffer_time, latency_time)
+    }
+}
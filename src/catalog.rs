@@ -0,0 +1,161 @@
+//! The broadcast's `catalog.json` track, in the format hang players and
+//! moq-web read: the codec, sample rate and channel layout of the audio
+//! track and, with video, its codec, size and rate. With metadata
+//! compression, it also names the compressed tracks.
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde_json::json;
+use tracing::info;
+
+use crate::compression::Compression;
+use crate::transport::TrackPublisher;
+use crate::video::VideoConfig;
+use crate::{AudioConfig, ControlState};
+
+pub const CATALOG_TRACK: &str = "catalog.json";
+
+#[derive(Clone, Debug)]
+struct VideoEntry {
+    config: VideoConfig,
+    priority: u8,
+    /// WebCodecs codec string; the rendition is left out until it's known.
+    codec: Option<String>,
+}
+
+/// What the catalog describes.
+#[derive(Clone, Debug)]
+pub(crate) struct Catalog {
+    track: String,
+    priority: u8,
+    sample_rate: u32,
+    channels: u32,
+    ambisonics: bool,
+    bitrate: u32,
+    video: Option<VideoEntry>,
+    /// Metadata tracks, with how they're compressed and their priority.
+    metadata: Option<(Vec<String>, Compression, u8)>,
+}
+
+impl Catalog {
+    pub(crate) fn new(audio: &AudioConfig, track_name: &str, priority: u8) -> Self {
+        Self {
+            track: track_name.to_string(),
+            priority,
+            sample_rate: audio.sample_rate,
+            channels: audio.channels,
+            ambisonics: audio.ambisonics,
+            bitrate: audio.bitrate,
+            video: None,
+            metadata: None,
+        }
+    }
+
+    /// Start from the runtime state rather than the configured one, as
+    /// after a reconnect.
+    pub(crate) fn following(mut self, state: &ControlState) -> Self {
+        self.bitrate = state.bitrate;
+        self
+    }
+
+    pub(crate) fn with_video(mut self, video: &VideoConfig, priority: u8) -> Self {
+        self.video = Some(VideoEntry {
+            config: video.clone(),
+            priority,
+            codec: video.codec_string(None),
+        });
+        self
+    }
+
+    /// List `tracks` as compressed with `compression`, so receivers know
+    /// to decompress them. Uncompressed tracks are left out, as before.
+    pub(crate) fn with_metadata(mut self, tracks: &[&str], compression: Compression, priority: u8) -> Self {
+        if compression != Compression::None && !tracks.is_empty() {
+            self.metadata = Some((tracks.iter().map(|track| track.to_string()).collect(), compression, priority));
+        }
+        self
+    }
+
+    fn encode(&self) -> Result<Bytes> {
+        let mut audio = json!({
+            "codec": "opus",
+            "sampleRate": self.sample_rate,
+            "numberOfChannels": self.channels,
+            "bitrate": self.bitrate,
+        });
+        if self.ambisonics {
+            audio["ambisonics"] = json!({ "order": 1, "channel_order": "acn", "normalization": "sn3d" });
+        }
+        let mut catalog = json!({
+            "audio": {
+                "renditions": { self.track.as_str(): audio },
+                "priority": self.priority,
+            },
+        });
+        if let Some(VideoEntry { config, priority, codec: Some(codec) }) = &self.video {
+            catalog["video"] = json!({
+                "renditions": {
+                    config.track_name.as_str(): {
+                        "codec": codec,
+                        "codedWidth": config.width,
+                        "codedHeight": config.height,
+                        "framerate": config.framerate,
+                        "bitrate": config.bitrate,
+                        "optimizeForLatency": true,
+                    },
+                },
+                "priority": priority,
+            });
+        }
+        if let Some((tracks, compression, priority)) = &self.metadata {
+            let renditions: serde_json::Map<String, serde_json::Value> = tracks.iter()
+                .map(|track| (track.clone(), json!({ "compression": compression.name() })))
+                .collect();
+            catalog["metadata"] = json!({ "renditions": renditions, "priority": priority });
+        }
+        Ok(Bytes::from(serde_json::to_vec(&catalog)?))
+    }
+}
+
+/// The catalog track of one relay session. Each change goes out as a new
+/// group, so a subscriber always starts from the current catalog.
+pub(crate) struct CatalogTrack<T: TrackPublisher> {
+    track: T,
+    catalog: Catalog,
+}
+
+impl<T: TrackPublisher> CatalogTrack<T> {
+    pub(crate) fn new(mut track: T, catalog: Catalog) -> Result<Self> {
+        track.write_group([catalog.encode()?]);
+        Ok(Self { track, catalog })
+    }
+
+    fn republish(&mut self, change: &str) -> Result<()> {
+        info!("Catalog updated: {}", change);
+        self.track.write_group([self.catalog.encode()?]);
+        Ok(())
+    }
+
+    /// Follow the runtime controls. Of what they change, only the bitrate
+    /// is in the catalog format.
+    pub(crate) fn follow(&mut self, state: &ControlState) -> Result<()> {
+        let before = std::mem::replace(&mut self.catalog.bitrate, state.bitrate);
+        if before != state.bitrate {
+            self.republish(&format!("audio bitrate {} -> {} kbps", before / 1000, state.bitrate / 1000))?;
+        }
+        Ok(())
+    }
+
+    /// Check the video codec string against each keyframe, so a
+    /// restarted encoder with a new profile or level is described again.
+    pub(crate) fn keyframe(&mut self, data: &[u8]) -> Result<()> {
+        let Some(ref mut video) = self.catalog.video else { return Ok(()) };
+        let Some(codec) = video.config.codec_string(Some(data)) else { return Ok(()) };
+        if video.codec.as_ref() != Some(&codec) {
+            let change = format!("video codec {}", codec);
+            video.codec = Some(codec);
+            self.republish(&change)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+//! Clock-sync track: periodic objects relating the sender's monotonic,
+//! wall-clock and media timelines, plus a receiver-side estimator.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::VecDeque;
+
+pub const CLOCK_TRACK: &str = "clock";
+pub const CLOCK_SAMPLE_VERSION: u8 = 1;
+const CLOCK_SAMPLE_LEN: usize = 25;
+
+/// One clock-sync object, all fields big-endian on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockSample {
+    /// Sender monotonic time in microseconds since publishing started.
+    pub monotonic_us: u64,
+    /// Sender wall-clock time in nanoseconds since the Unix epoch.
+    pub wall_ns: i64,
+    /// PTS (microseconds) of the most recently published frame.
+    pub media_us: u64,
+}
+
+impl ClockSample {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(CLOCK_SAMPLE_LEN);
+        buf.put_u8(CLOCK_SAMPLE_VERSION);
+        buf.put_u64(self.monotonic_us);
+        buf.put_i64(self.wall_ns);
+        buf.put_u64(self.media_us);
+        buf.freeze()
+    }
+
+    pub fn decode(object: &[u8]) -> Option<Self> {
+        if object.len() < CLOCK_SAMPLE_LEN || object[0] != CLOCK_SAMPLE_VERSION {
+            return None;
+        }
+        let u64_at = |at: usize| u64::from_be_bytes(object[at..at + 8].try_into().unwrap());
+        Some(Self {
+            monotonic_us: u64_at(1),
+            wall_ns: u64_at(9) as i64,
+            media_us: u64_at(17),
+        })
+    }
+}
+
+/// Estimates how the sender's clocks map onto the local ones.
+///
+/// The offset uses the sample with the smallest apparent delay in the
+/// window (the one least inflated by queuing); drift is the least-squares
+/// slope of local against sender monotonic time.
+pub struct ClockEstimator {
+    window: usize,
+    // (sender monotonic us, local monotonic us, local wall ns - sender wall ns)
+    samples: VecDeque<(f64, f64, i64)>,
+}
+
+impl ClockEstimator {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(2),
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Record a sample received at the given local monotonic and wall times.
+    pub fn add(&mut self, sample: &ClockSample, local_monotonic_us: u64, local_wall_ns: i64) {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((
+            sample.monotonic_us as f64,
+            local_monotonic_us as f64,
+            local_wall_ns - sample.wall_ns,
+        ));
+    }
+
+    /// Local wall clock minus sender wall clock, including the minimal
+    /// one-way delay seen in the window.
+    pub fn offset_ns(&self) -> Option<i64> {
+        self.samples.iter().map(|&(_, _, offset)| offset).min()
+    }
+
+    /// Sender clock drift relative to ours, in parts per million.
+    pub fn drift_ppm(&self) -> Option<f64> {
+        if self.samples.len() < 2 {
+            return None;
+        }
+        let n = self.samples.len() as f64;
+        let mean_x = self.samples.iter().map(|s| s.0).sum::<f64>() / n;
+        let mean_y = self.samples.iter().map(|s| s.1).sum::<f64>() / n;
+        let (mut cov, mut var) = (0.0, 0.0);
+        for &(x, y, _) in &self.samples {
+            cov += (x - mean_x) * (y - mean_y);
+            var += (x - mean_x) * (x - mean_x);
+        }
+        if var == 0.0 {
+            return None;
+        }
+        Some((cov / var - 1.0) * 1_000_000.0)
+    }
+
+    /// Convert a sender wall-clock time to local wall-clock time.
+    pub fn to_local_wall_ns(&self, sender_wall_ns: i64) -> Option<i64> {
+        self.offset_ns().map(|offset| sender_wall_ns + offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFSET_NS: i64 = 3_000_000;
+
+    /// A sample sent at `sender_us` on a sender whose clock runs `drift_ppm`
+    /// slow against ours, arriving `delay_us` later.
+    fn receive(estimator: &mut ClockEstimator, sender_us: u64, drift_ppm: f64, delay_us: u64) {
+        let sample = ClockSample {
+            monotonic_us: sender_us,
+            wall_ns: 1_700_000_000_000_000_000 + sender_us as i64 * 1000,
+            media_us: sender_us,
+        };
+        let local_us = (sender_us as f64 * (1.0 + drift_ppm / 1_000_000.0)) as u64 + delay_us;
+        let local_wall_ns = sample.wall_ns + OFFSET_NS + delay_us as i64 * 1000;
+        estimator.add(&sample, local_us, local_wall_ns);
+    }
+
+    #[test]
+    fn round_trips_samples() {
+        let sample = ClockSample { monotonic_us: 1, wall_ns: -2, media_us: 3 };
+        assert_eq!(ClockSample::decode(&sample.encode()), Some(sample));
+        assert_eq!(ClockSample::decode(&sample.encode()[..CLOCK_SAMPLE_LEN - 1]), None);
+    }
+
+    #[test]
+    fn offset_takes_the_least_delayed_sample() {
+        let mut estimator = ClockEstimator::new(16);
+        assert_eq!(estimator.offset_ns(), None);
+        for (i, delay_us) in [4_000, 1_000, 7_000, 2_500].into_iter().enumerate() {
+            receive(&mut estimator, i as u64 * 1_000_000, 0.0, delay_us);
+        }
+        assert_eq!(estimator.offset_ns(), Some(OFFSET_NS + 1_000_000));
+        assert_eq!(estimator.to_local_wall_ns(10), Some(10 + OFFSET_NS + 1_000_000));
+    }
+
+    #[test]
+    fn drift_converges_despite_jitter() {
+        let mut estimator = ClockEstimator::new(64);
+        receive(&mut estimator, 0, 50.0, 2_000);
+        assert_eq!(estimator.drift_ppm(), None);
+        for i in 1..64u64 {
+            // A sample every 10 s, with 1-5 ms of delay varying between them.
+            receive(&mut estimator, i * 10_000_000, 50.0, 1_000 + (i * 7_919) % 4_000);
+        }
+        let drift = estimator.drift_ppm().unwrap();
+        assert!((drift - 50.0).abs() < 5.0, "drift {} ppm", drift);
+    }
+
+    #[test]
+    fn window_forgets_old_samples() {
+        let mut estimator = ClockEstimator::new(4);
+        // A sample that arrived impossibly early skews the offset...
+        let sample = ClockSample { monotonic_us: 0, wall_ns: 0, media_us: 0 };
+        estimator.add(&sample, 0, -1_000_000_000);
+        assert_eq!(estimator.offset_ns(), Some(-1_000_000_000));
+        // ...until the window has moved past it.
+        for i in 1..=4 {
+            receive(&mut estimator, i * 1_000_000, 0.0, 1_000);
+        }
+        assert_eq!(estimator.offset_ns(), Some(OFFSET_NS + 1_000_000));
+    }
+}
@@ -0,0 +1,102 @@
+//! Automatic Opus complexity: watch how long each frame takes to encode
+//! and step the complexity down when the encoder is close to falling
+//! behind real time, back up once there's headroom again.
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// How often the encode times are looked at.
+pub(crate) const TUNE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Complexity is only raised once the encoder uses less than this
+/// fraction of the budget, so it doesn't flap between two levels.
+const RAISE_BELOW: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug)]
+pub struct AutoComplexity {
+    /// Share of real time encoding may take: 0.5 means a 20 ms frame
+    /// should encode in 10 ms. Judged on the slowest tenth of frames.
+    pub budget: f64,
+    /// Lowest complexity to step down to. The configured complexity is
+    /// the highest it steps back up to.
+    pub min: u32,
+}
+
+impl Default for AutoComplexity {
+    fn default() -> Self {
+        Self { budget: 0.5, min: 0 }
+    }
+}
+
+/// Times the encoder and picks its next complexity.
+pub(crate) struct ComplexityTuner {
+    config: AutoComplexity,
+    max: u32,
+    complexity: u32,
+    frame_duration: Duration,
+    samples: Arc<Mutex<Vec<Duration>>>,
+}
+
+impl ComplexityTuner {
+    /// Start timing `opusenc`, which was built at `complexity`.
+    pub(crate) fn watch(opusenc: &gst::Element, config: AutoComplexity, complexity: u32, frame_duration: Duration) -> Self {
+        let entered = Arc::new(Mutex::new(None::<Instant>));
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        // The same measurement as the benchmark: opusenc encodes inside
+        // its sink pad's chain function, so entry to exit is encode time.
+        if let Some(pad) = opusenc.static_pad("sink") {
+            let entered = entered.clone();
+            pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                *entered.lock().unwrap() = Some(Instant::now());
+                gst::PadProbeReturn::Ok
+            });
+        }
+        if let Some(pad) = opusenc.static_pad("src") {
+            let entered = entered.clone();
+            let samples = samples.clone();
+            pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                if let Some(start) = entered.lock().unwrap().take() {
+                    samples.lock().unwrap().push(start.elapsed());
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+        info!("Tuning Opus complexity between {} and {} to encode within {:.0}% of real time",
+              config.min, complexity, config.budget * 100.0);
+        Self { config, max: complexity, complexity, frame_duration, samples }
+    }
+
+    /// Judge later encodes against frames of this length.
+    pub(crate) fn set_frame_duration(&mut self, frame_duration: Duration) {
+        self.frame_duration = frame_duration;
+        self.samples.lock().unwrap().clear();
+    }
+
+    /// The complexity to switch to, if the encode times since the last
+    /// call warrant a change.
+    pub(crate) fn adjust(&mut self) -> Option<u32> {
+        let mut samples = std::mem::take(&mut *self.samples.lock().unwrap());
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort();
+        let slow = samples[(samples.len() - 1) * 9 / 10];
+        let load = slow.as_secs_f64() / self.frame_duration.as_secs_f64().max(f64::EPSILON);
+        debug!("Encode load {:.0}% of real time at complexity {}", load * 100.0, self.complexity);
+
+        let next = if load > self.config.budget && self.complexity > self.config.min {
+            self.complexity - 1
+        } else if load < self.config.budget * RAISE_BELOW && self.complexity < self.max {
+            self.complexity + 1
+        } else {
+            return None;
+        };
+        info!("Encoding takes {:.0}% of real time (budget {:.0}%); complexity {} -> {}",
+              load * 100.0, self.config.budget * 100.0, self.complexity, next);
+        self.complexity = next;
+        Some(next)
+    }
+}
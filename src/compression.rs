@@ -0,0 +1,93 @@
+//! Optional compression of the metadata tracks (session, heartbeat and
+//! script objects), for constrained uplinks. The catalog itself stays
+//! plain, so any player can read it, and names what each of the other
+//! tracks is compressed with.
+
+use anyhow::Result;
+use bytes::Bytes;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+/// Largest object [`decompress`] inflates, so a hostile publisher can't
+/// have a receiver allocate without bound.
+const MAX_DECOMPRESSED: usize = 1 << 20;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    /// zlib-wrapped deflate, what browsers' `DecompressionStream("deflate")` reads.
+    Deflate,
+    Zstd,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" | "off" => Ok(Self::None),
+            "deflate" => Ok(Self::Deflate),
+            "zstd" => Ok(Self::Zstd),
+            other => Err(anyhow::anyhow!("Unknown compression '{}', expected 'none', 'deflate' or 'zstd'", other)),
+        }
+    }
+}
+
+impl Compression {
+    /// The name the catalog gives it.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Deflate => "deflate",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// `object` compressed, or as it is without compression. Every object
+    /// of a compressed track is compressed, however small, since the
+    /// catalog promises it.
+    pub fn compress(self, object: Bytes) -> Bytes {
+        let compressed = match self {
+            Self::None => return object,
+            Self::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&object).and_then(|()| encoder.finish())
+            }
+            Self::Zstd => zstd::bulk::compress(&object, 0),
+        };
+        compressed.map(Bytes::from).expect("compressing into memory can't fail")
+    }
+}
+
+/// Undo the compression of an object from a metadata track, telling zstd
+/// and zlib apart by their headers. Objects that are neither, or don't
+/// decompress, are returned as they are, so plain JSON reads either way.
+pub fn decompress(object: &[u8]) -> Cow<'_, [u8]> {
+    let inflated = if object.starts_with(&ZSTD_MAGIC) {
+        zstd::bulk::decompress(object, MAX_DECOMPRESSED).ok()
+    } else if is_zlib(object) {
+        let mut inflated = Vec::new();
+        flate2::read::ZlibDecoder::new(object)
+            .take(MAX_DECOMPRESSED as u64)
+            .read_to_end(&mut inflated)
+            .ok()
+            .map(|_| inflated)
+    } else {
+        None
+    };
+    inflated.map_or(Cow::Borrowed(object), Cow::Owned)
+}
+
+/// A zlib header: deflate with a window of at most 32 KiB, and a check
+/// value making the first two bytes a multiple of 31. JSON never starts
+/// like that.
+fn is_zlib(object: &[u8]) -> bool {
+    match object {
+        [cmf, flg, ..] => cmf & 0x0F == 8 && cmf >> 4 <= 7 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
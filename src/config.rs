@@ -0,0 +1,952 @@
+use anyhow::Result;
+use figment2::{Figment, providers::{Env, Format, Toml}, value::{Uncased, UncasedStr}};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::catalog::CATALOG_TRACK;
+use crate::elements::InsertionPoints;
+use crate::latency::LatencyPlan;
+use crate::playback::{LISTEN_VOLUME, PlaybackConfig};
+use crate::retry::{GiveUp, RetryConfig, RetryPolicy};
+use crate::stats::STATS_LOG_INTERVAL;
+use crate::token::{self, TokenConfig, TokenSource};
+use crate::transport;
+use crate::webhook::WebhookConfig;
+use crate::{clock, heartbeat, PROBE_TRACK, SCRIPT_TRACK, SESSION_TRACK};
+use crate::{AMBISONIC_CHANNELS, AlertConfig, AudioConfig, AutoComplexity, CaptureBackend, Compression, ConflictPolicy, EosPolicy, FrameFormat, FrameQueueConfig, LevelConfig, MoqConfig, NoiseSuppression, PipelineConfig, Preroll, ProcessingConfig, PublisherBackend, RetentionConfig, RtpConfig, RtpPayload, TrackAudio, TrackPriorities, TransportKind, VideoCodec, VideoConfig, VideoSource};
+
+/// Everything a config file describes, mapped onto the library configs.
+#[derive(Clone)]
+pub struct Settings {
+    pub pipeline: PipelineConfig,
+    pub moq: MoqConfig,
+    /// Derive frame size, capture buffering and the frame queue from this,
+    /// replacing whatever they were set to.
+    pub target_latency: Option<Duration>,
+    pub notifications: bool,
+    pub shutdown_grace: Duration,
+    pub alerts: AlertConfig,
+    pub metrics_addr: Option<SocketAddr>,
+    /// Log a stats summary this often.
+    pub stats_log_interval: Option<Duration>,
+    /// Reject audio settings Opus can't encode instead of correcting them.
+    pub strict_audio: bool,
+    /// Lua automation script (needs the `scripting` feature).
+    pub script: Option<PathBuf>,
+    /// Play the encoded stream back locally.
+    pub listen: Option<PlaybackConfig>,
+    pub webhook: Option<WebhookConfig>,
+    pub retry: RetryConfig,
+}
+
+impl Settings {
+    /// Check settings that can't be expressed in the types, correcting
+    /// what can be corrected.
+    pub fn validate(&mut self) -> Result<()> {
+        if let Some(target) = self.target_latency {
+            let plan = LatencyPlan::for_target(target, &self.pipeline.audio)?;
+            let pipeline = &mut self.pipeline;
+            plan.apply(&mut pipeline.audio, &mut pipeline.buffer_time, &mut pipeline.latency_time, &mut self.moq.frame_queue);
+            tracing::info!("Target latency {} ms: {}", target.as_millis(), plan);
+        }
+        self.pipeline.audio.validate(self.strict_audio)?;
+        if self.moq.enabled {
+            self.moq.relay_url = transport::relay_url(&self.moq.relay_url)?.to_string();
+        }
+        let catalog = self.moq.catalog || self.moq.backend == PublisherBackend::Karp;
+        if self.moq.metadata_compression != Compression::None && !catalog {
+            anyhow::bail!("metadata_compression needs catalog = true, which tells receivers the tracks are compressed");
+        }
+        if self.pipeline.latency_time > self.pipeline.buffer_time {
+            anyhow::bail!("latency_time ({} μs) must not exceed buffer_time ({} μs)",
+                          self.pipeline.latency_time, self.pipeline.buffer_time);
+        }
+        if self.pipeline.follow_app.is_some() && self.pipeline.follow_active_sink {
+            anyhow::bail!("follow_app and follow_active_sink are mutually exclusive");
+        }
+        if self.pipeline.audio.ambisonics && (self.pipeline.microphone.is_some() || !self.pipeline.stems.is_empty()) {
+            anyhow::bail!("Ambisonic capture can't be mixed with a microphone or published with stems");
+        }
+        let audio = &self.pipeline.audio;
+        if let Some(ref video) = self.pipeline.video {
+            video.validate()?;
+            if self.moq.backend == PublisherBackend::Karp {
+                anyhow::bail!("The karp backend doesn't frame video yet; publish video with the lite backend");
+            }
+            let taken = [
+                self.moq.track_name.as_str(), CATALOG_TRACK, clock::CLOCK_TRACK, heartbeat::HEARTBEAT_TRACK,
+                PROBE_TRACK, SCRIPT_TRACK, SESSION_TRACK,
+            ];
+            if taken.contains(&video.track_name.as_str()) {
+                anyhow::bail!("The video track can't be named '{}'; another track uses that name", video.track_name);
+            }
+        }
+        if self.pipeline.on_eos == EosPolicy::Silence && (audio.ambisonics || audio.channels > 2) {
+            anyhow::bail!("on_eos = \"silence\" is only supported for mono and stereo streams");
+        }
+        if let Some(auto) = audio.auto_complexity {
+            if !(auto.budget > 0.0 && auto.budget <= 1.0) {
+                anyhow::bail!("complexity_budget must be a share of real time in (0, 1], not {}", auto.budget);
+            }
+            if auto.min > audio.complexity {
+                anyhow::bail!("complexity_min ({}) must not exceed complexity ({})", auto.min, audio.complexity);
+            }
+        }
+        let queue = &self.moq.frame_queue;
+        if queue.capacity == 0 {
+            anyhow::bail!("frame_queue must hold at least one frame");
+        }
+        if !(0.0..=1.0).contains(&queue.low_watermark) || !(0.0..=1.0).contains(&queue.high_watermark)
+            || queue.low_watermark >= queue.high_watermark {
+            anyhow::bail!("Queue watermarks must satisfy 0 <= low ({}) < high ({}) <= 1",
+                          queue.low_watermark, queue.high_watermark);
+        }
+        if self.moq.flush_timeout >= self.shutdown_grace {
+            anyhow::bail!("flush_timeout ({:?}) must be shorter than the shutdown grace period ({:?})",
+                          self.moq.flush_timeout, self.shutdown_grace);
+        }
+        if self.listen.as_ref().is_some_and(|listen| !(listen.volume >= 0.0)) {
+            anyhow::bail!("Listen volume must be a non-negative gain");
+        }
+        for (name, policy) in [("relay", &self.retry.relay), ("device", &self.retry.device), ("pipeline", &self.retry.pipeline)] {
+            if !(0.0..=1.0).contains(&policy.jitter) {
+                anyhow::bail!("retry.{} jitter must be between 0 and 1", name);
+            }
+            if policy.initial_delay > policy.max_delay {
+                anyhow::bail!("retry.{} initial_delay_ms must not exceed max_delay_ms", name);
+            }
+        }
+        let stems = &self.pipeline.stems;
+        if let Some((index, app)) = stems.iter().enumerate().find(|(index, app)| stems[..*index].contains(app)) {
+            anyhow::bail!("Stem '{}' is listed twice (entry {})", app, index + 1);
+        }
+        for (app, audio) in &self.pipeline.stem_audio {
+            if !stems.contains(app) {
+                anyhow::bail!("[audio.stems.{}] configures a stem that isn't in pipeline.stems", app);
+            }
+            audio.validate(&format!("stem '{}'", app))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+pub struct ConfigFile {
+    #[serde(default)]
+    relay: RelayConfig,
+    #[serde(default)]
+    audio: AudioFileConfig,
+    #[serde(default)]
+    pipeline: PipelineFileConfig,
+    #[serde(default)]
+    rtp: RtpFileConfig,
+    #[serde(default)]
+    video: VideoFileConfig,
+    #[serde(default)]
+    notifications: NotificationsFileConfig,
+    #[serde(default)]
+    levels: LevelsFileConfig,
+    #[serde(default)]
+    processing: ProcessingFileConfig,
+    #[serde(default)]
+    shutdown: ShutdownFileConfig,
+    #[serde(default)]
+    alerts: AlertsFileConfig,
+    #[serde(default)]
+    metrics: MetricsFileConfig,
+    #[serde(default)]
+    script: ScriptFileConfig,
+    #[serde(default)]
+    listen: ListenFileConfig,
+    #[serde(default)]
+    webhook: WebhookFileConfig,
+    #[serde(default)]
+    retry: RetryFileConfig,
+    #[serde(default)]
+    elements: BTreeMap<String, BTreeMap<String, PropertyValue>>,
+}
+
+/// A property value as written in TOML; applied in `gst-launch` syntax.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum PropertyValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+impl std::fmt::Display for PropertyValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PropertyValue::Bool(v) => write!(f, "{}", v),
+            PropertyValue::Integer(v) => write!(f, "{}", v),
+            PropertyValue::Float(v) => write!(f, "{}", v),
+            PropertyValue::String(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct RelayConfig {
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    broadcast_path: String,
+    #[serde(default)]
+    track_name: String,
+    #[serde(default)]
+    frame_format: Option<FrameFormat>,
+    #[serde(default)]
+    backend: Option<PublisherBackend>,
+    #[serde(default)]
+    transport: Option<TransportKind>,
+    #[serde(default)]
+    clock_interval_ms: Option<u64>,
+    #[serde(default)]
+    max_bitrate: Option<u32>,
+    #[serde(default)]
+    priorities: Option<TrackPriorities>,
+    #[serde(default)]
+    retain_groups: Option<usize>,
+    #[serde(default)]
+    retain_max_age_secs: Option<u64>,
+    #[serde(default)]
+    session_suffix: Option<bool>,
+    #[serde(default)]
+    on_conflict: Option<ConflictPolicy>,
+    #[serde(default)]
+    preroll: Option<Preroll>,
+    #[serde(default)]
+    preroll_ms: Option<u64>,
+    #[serde(default)]
+    capture_time: Option<bool>,
+    #[serde(default)]
+    catalog: Option<bool>,
+    #[serde(default)]
+    metadata_compression: Option<Compression>,
+    #[serde(default)]
+    heartbeat_interval_ms: Option<u64>,
+    #[serde(default)]
+    probe_interval_ms: Option<u64>,
+    #[serde(default)]
+    share_session: Option<bool>,
+    #[serde(default)]
+    frame_queue: Option<usize>,
+    #[serde(default)]
+    frame_queue_ms: Option<u64>,
+    #[serde(default)]
+    queue_high_watermark: Option<f64>,
+    #[serde(default)]
+    queue_low_watermark: Option<f64>,
+    #[serde(default)]
+    reconnect_buffer_secs: Option<u64>,
+    /// Read before every connect; `token_command` wins if both are set.
+    #[serde(default)]
+    token_file: Option<PathBuf>,
+    #[serde(default)]
+    token_command: Option<String>,
+    #[serde(default)]
+    token_refresh_secs: Option<u64>,
+    /// For tokens without an `exp` claim.
+    #[serde(default)]
+    token_lifetime_secs: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct AudioFileConfig {
+    #[serde(default)]
+    sample_rate: Option<u32>,
+    #[serde(default)]
+    channels: Option<u32>,
+    #[serde(default)]
+    bitrate: Option<u32>,
+    #[serde(default)]
+    application: Option<String>,
+    #[serde(default)]
+    complexity: Option<u32>,
+    /// Turns on automatic complexity, encoding within this share of real time.
+    #[serde(default)]
+    complexity_budget: Option<f64>,
+    #[serde(default)]
+    complexity_min: Option<u32>,
+    #[serde(default)]
+    frame_size: Option<u32>,
+    #[serde(default)]
+    ambisonics: Option<bool>,
+    #[serde(default)]
+    strict: Option<bool>,
+    /// `[audio.stems.<app>]`: encoder settings of a single stem.
+    #[serde(default)]
+    stems: BTreeMap<String, TrackAudioFileConfig>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct TrackAudioFileConfig {
+    #[serde(default)]
+    bitrate: Option<u32>,
+    #[serde(default)]
+    application: Option<String>,
+    #[serde(default)]
+    complexity: Option<u32>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct PipelineFileConfig {
+    #[serde(default)]
+    target_latency_ms: Option<u64>,
+    #[serde(default)]
+    buffer_time: Option<u32>,
+    #[serde(default)]
+    latency_time: Option<u32>,
+    #[serde(default)]
+    sink_name: Option<String>,
+    #[serde(default)]
+    capture_backend: Option<CaptureBackend>,
+    #[serde(default)]
+    follow_active_sink: Option<bool>,
+    #[serde(default)]
+    follow_app: Option<String>,
+    #[serde(default)]
+    stems: Option<Vec<String>>,
+    #[serde(default)]
+    microphone: Option<String>,
+    #[serde(default)]
+    channel_matrix: Option<Vec<Vec<f64>>>,
+    #[serde(default)]
+    gap_tolerance_ms: Option<u64>,
+    #[serde(default)]
+    fill_gaps: Option<bool>,
+    #[serde(default)]
+    on_eos: Option<EosPolicy>,
+    #[serde(default)]
+    insert: InsertFileConfig,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct InsertFileConfig {
+    #[serde(default)]
+    post_capture: Option<String>,
+    #[serde(default)]
+    pre_encode: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct RtpFileConfig {
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    address: Option<String>,
+    #[serde(default)]
+    port: Option<u16>,
+    #[serde(default)]
+    ttl: Option<u32>,
+    #[serde(default)]
+    payload: Option<RtpPayload>,
+    #[serde(default)]
+    interface: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct VideoFileConfig {
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    source: Option<VideoSource>,
+    #[serde(default)]
+    device: Option<String>,
+    #[serde(default)]
+    codec: Option<VideoCodec>,
+    #[serde(default)]
+    width: Option<u32>,
+    #[serde(default)]
+    height: Option<u32>,
+    #[serde(default)]
+    framerate: Option<u32>,
+    #[serde(default)]
+    bitrate: Option<u32>,
+    #[serde(default)]
+    keyframe_interval_ms: Option<u64>,
+    #[serde(default)]
+    track: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct NotificationsFileConfig {
+    #[serde(default)]
+    enabled: Option<bool>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct LevelsFileConfig {
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    clip_threshold_db: Option<f64>,
+    #[serde(default)]
+    clip_duration_ms: Option<u64>,
+    #[serde(default)]
+    silence_threshold_db: Option<f64>,
+    #[serde(default)]
+    silence_duration_secs: Option<u64>,
+    #[serde(default)]
+    exit_after_silence_secs: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct ProcessingFileConfig {
+    #[serde(default)]
+    agc: Option<bool>,
+    #[serde(default)]
+    agc_target_level_dbfs: Option<u32>,
+    #[serde(default)]
+    noise_suppression: Option<NoiseSuppression>,
+    #[serde(default)]
+    noise_suppression_level: Option<String>,
+    #[serde(default)]
+    echo_cancel: Option<bool>,
+    #[serde(default)]
+    highpass_hz: Option<u32>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct ShutdownFileConfig {
+    #[serde(default)]
+    grace_secs: Option<u64>,
+    #[serde(default)]
+    flush_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct AlertsFileConfig {
+    #[serde(default)]
+    max_xruns: Option<u64>,
+    #[serde(default)]
+    exit_on_threshold: Option<bool>,
+    #[serde(default)]
+    webhook_url: Option<url::Url>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct MetricsFileConfig {
+    #[serde(default)]
+    listen: Option<SocketAddr>,
+    /// 0 turns the periodic stats log off.
+    #[serde(default)]
+    log_interval_secs: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct ScriptFileConfig {
+    #[serde(default)]
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct ListenFileConfig {
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    sink: Option<String>,
+    #[serde(default)]
+    volume: Option<f64>,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct WebhookFileConfig {
+    #[serde(default)]
+    url: Option<url::Url>,
+    #[serde(default)]
+    events: Option<Vec<String>>,
+}
+
+/// `[retry]` holds the defaults; `[retry.relay]`, `[retry.device]` and
+/// `[retry.pipeline]` override them field by field.
+#[derive(Debug, serde::Deserialize, Default)]
+struct RetryFileConfig {
+    #[serde(flatten)]
+    defaults: RetryPolicyFileConfig,
+    #[serde(default)]
+    relay: RetryPolicyFileConfig,
+    #[serde(default)]
+    device: RetryPolicyFileConfig,
+    #[serde(default)]
+    pipeline: RetryPolicyFileConfig,
+}
+
+#[derive(Debug, serde::Deserialize, Default)]
+struct RetryPolicyFileConfig {
+    /// Negative retries forever.
+    #[serde(default)]
+    max_attempts: Option<i64>,
+    #[serde(default)]
+    initial_delay_ms: Option<u64>,
+    #[serde(default)]
+    max_delay_ms: Option<u64>,
+    #[serde(default)]
+    jitter: Option<f64>,
+    #[serde(default)]
+    give_up: Option<GiveUp>,
+}
+
+impl RetryFileConfig {
+    fn policy(&self, kind: &RetryPolicyFileConfig) -> RetryPolicy {
+        let defaults = RetryPolicy::default();
+        let base = &self.defaults;
+        RetryPolicy {
+            max_attempts: match kind.max_attempts.or(base.max_attempts) {
+                Some(n) => u32::try_from(n).ok(),
+                None => defaults.max_attempts,
+            },
+            initial_delay: kind.initial_delay_ms.or(base.initial_delay_ms)
+                .map_or(defaults.initial_delay, Duration::from_millis),
+            max_delay: kind.max_delay_ms.or(base.max_delay_ms)
+                .map_or(defaults.max_delay, Duration::from_millis),
+            jitter: kind.jitter.or(base.jitter).unwrap_or(defaults.jitter),
+            give_up: kind.give_up.or(base.give_up).unwrap_or(defaults.give_up),
+        }
+    }
+}
+
+/// Top-level config file sections, for the single-underscore env form.
+const SECTIONS: &[&str] = &[
+    "relay", "audio", "pipeline", "rtp", "video", "notifications", "levels",
+    "processing", "shutdown", "alerts", "metrics", "script", "listen", "webhook",
+    "retry",
+];
+
+/// Map an env var name (prefix stripped) to a config key path.
+///
+/// `__` separates nesting levels (`RELAY__PRIORITIES__AUDIO` is
+/// `relay.priorities.audio`). Without `__`, a leading section name and
+/// one underscore are accepted too (`RELAY_URL` is `relay.url`).
+fn env_key(key: &UncasedStr) -> Uncased<'_> {
+    let key = key.as_str().to_ascii_lowercase();
+    if key.contains("__") {
+        return key.replace("__", ".").into();
+    }
+    for section in SECTIONS {
+        if let Some(rest) = key.strip_prefix(section).and_then(|rest| rest.strip_prefix('_')) {
+            return format!("{}.{}", section, rest).into();
+        }
+    }
+    key.into()
+}
+
+/// The config file used when none is given on the command line: the
+/// first that exists of `./config.toml`,
+/// `$XDG_CONFIG_HOME/pipe2moq/config.toml` (default `~/.config`) and
+/// `/etc/pipe2moq/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    let xdg = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    let mut candidates = vec![PathBuf::from("config.toml")];
+    if let Some(dir) = xdg {
+        candidates.push(dir.join("pipe2moq").join("config.toml"));
+    }
+    candidates.push(PathBuf::from("/etc/pipe2moq/config.toml"));
+    candidates.into_iter().find(|path| path.is_file())
+}
+
+#[derive(serde::Deserialize, Default)]
+struct Includes {
+    #[serde(default)]
+    include: Vec<PathBuf>,
+}
+
+/// Merge `path` onto `figment`, after the files it includes.
+///
+/// Includes are resolved relative to the including file and merged in
+/// order, so the including file and later includes win. `stack` holds the
+/// files being merged, to reject include cycles.
+fn merge_file(figment: Figment, path: &Path, stack: &mut Vec<PathBuf>) -> Result<Figment> {
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if stack.contains(&canonical) {
+        anyhow::bail!("Config include cycle through {}", path.display());
+    }
+
+    let includes: Includes = Figment::from(Toml::file(path)).extract()?;
+    stack.push(canonical);
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut figment = figment;
+    for include in includes.include {
+        let include = base.join(include);
+        if !include.is_file() {
+            anyhow::bail!("{} includes missing config file {}", path.display(), include.display());
+        }
+        figment = merge_file(figment, &include, stack)?;
+    }
+    stack.pop();
+
+    Ok(figment.merge(Toml::file(path)))
+}
+
+fn merge_files(paths: &[PathBuf]) -> Result<Figment> {
+    let mut figment = Figment::new();
+    for path in paths {
+        figment = merge_file(figment, path, &mut Vec::new())?;
+    }
+    Ok(figment)
+}
+
+impl ConfigFile {
+    /// Load TOML files merged in order (later files win), with
+    /// `PIPE2MOQ_`-prefixed environment overrides on top.
+    pub fn load(paths: &[PathBuf]) -> Result<Self> {
+        Ok(merge_files(paths)?
+            .merge(Env::prefixed("PIPE2MOQ_").map(env_key))
+            .extract()?)
+    }
+
+    /// Load a TOML file and its includes, without environment overrides.
+    pub fn load_file(path: &Path) -> Result<Self> {
+        Ok(merge_files(&[path.to_path_buf()])?.extract()?)
+    }
+
+    pub fn into_settings(self) -> Settings {
+        let non_empty = |s: String| if s.is_empty() { None } else { Some(s) };
+        let moq_defaults = MoqConfig::default();
+
+        let ambisonics = self.audio.ambisonics.unwrap_or(false);
+        let audio = AudioConfig {
+            sample_rate: self.audio.sample_rate.unwrap_or(48000),
+            channels: self.audio.channels.unwrap_or(if ambisonics { AMBISONIC_CHANNELS } else { 2 }),
+            bitrate: self.audio.bitrate.unwrap_or(96000),
+            application: self.audio.application.unwrap_or_else(|| "voip".to_string()),
+            complexity: self.audio.complexity.unwrap_or(5),
+            frame_size: self.audio.frame_size.unwrap_or(20),
+            ambisonics,
+            auto_complexity: self.audio.complexity_budget.map(|budget| AutoComplexity {
+                budget,
+                min: self.audio.complexity_min.unwrap_or(AutoComplexity::default().min),
+            }),
+        };
+
+        let rtp_defaults = RtpConfig::default();
+        let rtp = RtpConfig {
+            enabled: self.rtp.enabled.unwrap_or(false),
+            address: self.rtp.address.unwrap_or(rtp_defaults.address),
+            port: self.rtp.port.unwrap_or(rtp_defaults.port),
+            ttl: self.rtp.ttl.unwrap_or(rtp_defaults.ttl),
+            payload: self.rtp.payload.unwrap_or(rtp_defaults.payload),
+            interface: self.rtp.interface,
+        };
+
+        let video_defaults = VideoConfig::default();
+        let video = self.video.enabled.unwrap_or(false).then(|| VideoConfig {
+            source: self.video.source.unwrap_or(video_defaults.source),
+            device: self.video.device.and_then(non_empty),
+            codec: self.video.codec.unwrap_or(video_defaults.codec),
+            width: self.video.width.unwrap_or(video_defaults.width),
+            height: self.video.height.unwrap_or(video_defaults.height),
+            framerate: self.video.framerate.unwrap_or(video_defaults.framerate),
+            bitrate: self.video.bitrate.unwrap_or(video_defaults.bitrate),
+            keyframe_interval: self.video.keyframe_interval_ms
+                .map_or(video_defaults.keyframe_interval, Duration::from_millis),
+            track_name: self.video.track.and_then(non_empty).unwrap_or(video_defaults.track_name),
+        });
+
+        let level_defaults = LevelConfig::default();
+        let levels = LevelConfig {
+            enabled: self.levels.enabled.unwrap_or(level_defaults.enabled),
+            clip_threshold_db: self.levels.clip_threshold_db.unwrap_or(level_defaults.clip_threshold_db),
+            clip_duration: self.levels.clip_duration_ms
+                .map(Duration::from_millis)
+                .unwrap_or(level_defaults.clip_duration),
+            silence_threshold_db: self.levels.silence_threshold_db.unwrap_or(level_defaults.silence_threshold_db),
+            silence_duration: self.levels.silence_duration_secs
+                .map(Duration::from_secs)
+                .unwrap_or(level_defaults.silence_duration),
+            exit_after_silence: self.levels.exit_after_silence_secs.filter(|&secs| secs > 0).map(Duration::from_secs),
+        };
+
+        let processing_defaults = ProcessingConfig::default();
+        let processing = ProcessingConfig {
+            agc: self.processing.agc.unwrap_or(processing_defaults.agc),
+            agc_target_level_dbfs: self.processing.agc_target_level_dbfs
+                .unwrap_or(processing_defaults.agc_target_level_dbfs),
+            noise_suppression: self.processing.noise_suppression
+                .unwrap_or(processing_defaults.noise_suppression),
+            noise_suppression_level: self.processing.noise_suppression_level
+                .unwrap_or(processing_defaults.noise_suppression_level),
+            echo_cancel: self.processing.echo_cancel.unwrap_or(processing_defaults.echo_cancel),
+            highpass_hz: self.processing.highpass_hz,
+        };
+
+        let pipeline_defaults = PipelineConfig::default();
+        let pipeline = PipelineConfig {
+            audio,
+            buffer_time: self.pipeline.buffer_time.unwrap_or(20000),
+            latency_time: self.pipeline.latency_time.unwrap_or(10000),
+            sink_name: self.pipeline.sink_name,
+            capture_backend: self.pipeline.capture_backend.unwrap_or(pipeline_defaults.capture_backend),
+            follow_active_sink: self.pipeline.follow_active_sink.unwrap_or(pipeline_defaults.follow_active_sink),
+            follow_app: non_empty(self.pipeline.follow_app.unwrap_or_default()),
+            stems: self.pipeline.stems.unwrap_or_default(),
+            stem_audio: self.audio.stems.into_iter()
+                .map(|(app, audio)| (app, TrackAudio {
+                    bitrate: audio.bitrate,
+                    complexity: audio.complexity,
+                    application: audio.application,
+                }))
+                .collect(),
+            microphone: self.pipeline.microphone,
+            channel_matrix: self.pipeline.channel_matrix,
+            rtp,
+            output_file: None,
+            levels,
+            processing,
+            gap_tolerance: self.pipeline.gap_tolerance_ms
+                .map(Duration::from_millis)
+                .unwrap_or(pipeline_defaults.gap_tolerance),
+            fill_gaps: self.pipeline.fill_gaps.unwrap_or(pipeline_defaults.fill_gaps),
+            on_eos: self.pipeline.on_eos.unwrap_or(pipeline_defaults.on_eos),
+            video,
+            insert: InsertionPoints {
+                post_capture: self.pipeline.insert.post_capture.filter(|d| !d.trim().is_empty()),
+                pre_encode: self.pipeline.insert.pre_encode.filter(|d| !d.trim().is_empty()),
+            },
+            element_overrides: self.elements.into_iter()
+                .map(|(factory, properties)| {
+                    (factory, properties.into_iter().map(|(name, value)| (name, value.to_string())).collect())
+                })
+                .collect(),
+        };
+
+        let moq = MoqConfig {
+            enabled: self.relay.enabled.unwrap_or(true),
+            relay_url: non_empty(self.relay.url).unwrap_or(moq_defaults.relay_url),
+            broadcast_path: non_empty(self.relay.broadcast_path).unwrap_or(moq_defaults.broadcast_path),
+            track_name: non_empty(self.relay.track_name).unwrap_or(moq_defaults.track_name),
+            target_playtime_delay: None,
+            frame_format: self.relay.frame_format.unwrap_or(moq_defaults.frame_format),
+            backend: self.relay.backend.unwrap_or(moq_defaults.backend),
+            transport: self.relay.transport.unwrap_or(moq_defaults.transport),
+            clock_interval: self.relay.clock_interval_ms.filter(|&ms| ms > 0).map(Duration::from_millis),
+            max_bitrate: self.relay.max_bitrate.filter(|&bps| bps > 0),
+            priorities: self.relay.priorities.unwrap_or(moq_defaults.priorities),
+            retention: RetentionConfig {
+                max_groups: self.relay.retain_groups.unwrap_or(moq_defaults.retention.max_groups).max(1),
+                max_age: self.relay.retain_max_age_secs.map(Duration::from_secs),
+            },
+            session_suffix: self.relay.session_suffix.unwrap_or(false),
+            on_conflict: self.relay.on_conflict.unwrap_or(moq_defaults.on_conflict),
+            heartbeat_interval: self.relay.heartbeat_interval_ms.filter(|&ms| ms > 0).map(Duration::from_millis),
+            probe_interval: self.relay.probe_interval_ms.filter(|&ms| ms > 0).map(Duration::from_millis),
+            share_session: self.relay.share_session.unwrap_or(moq_defaults.share_session),
+            frame_queue: FrameQueueConfig {
+                // A duration wins: it means the same thing at any frame size.
+                capacity: match self.relay.frame_queue_ms {
+                    Some(ms) => ms.div_ceil(pipeline.audio.frame_size.max(1) as u64) as usize,
+                    None => self.relay.frame_queue.unwrap_or(moq_defaults.frame_queue.capacity),
+                },
+                high_watermark: self.relay.queue_high_watermark.unwrap_or(moq_defaults.frame_queue.high_watermark),
+                low_watermark: self.relay.queue_low_watermark.unwrap_or(moq_defaults.frame_queue.low_watermark),
+                reconnect_buffer: self.relay.reconnect_buffer_secs.filter(|&secs| secs > 0).map(Duration::from_secs),
+            },
+            flush_timeout: self.shutdown.flush_timeout_ms.map_or(moq_defaults.flush_timeout, Duration::from_millis),
+            preroll: self.relay.preroll.unwrap_or(moq_defaults.preroll),
+            preroll_duration: self.relay.preroll_ms.map(Duration::from_millis),
+            capture_time: self.relay.capture_time.unwrap_or(moq_defaults.capture_time),
+            catalog: self.relay.catalog.unwrap_or(moq_defaults.catalog),
+            metadata_compression: self.relay.metadata_compression.unwrap_or(moq_defaults.metadata_compression),
+            token: self.relay.token_command.filter(|command| !command.trim().is_empty()).map(TokenSource::Command)
+                .or(self.relay.token_file.map(TokenSource::File))
+                .map(|source| TokenConfig {
+                    refresh_before: self.relay.token_refresh_secs.map_or(token::DEFAULT_REFRESH_BEFORE, Duration::from_secs),
+                    lifetime: self.relay.token_lifetime_secs.filter(|&secs| secs > 0).map(Duration::from_secs),
+                    ..TokenConfig::new(source)
+                }),
+        };
+
+        Settings {
+            target_latency: self.pipeline.target_latency_ms.map(Duration::from_millis),
+            pipeline,
+            moq,
+            notifications: self.notifications.enabled.unwrap_or(false),
+            shutdown_grace: Duration::from_secs(self.shutdown.grace_secs.unwrap_or(5)),
+            alerts: AlertConfig {
+                max_xruns: self.alerts.max_xruns,
+                exit_on_threshold: self.alerts.exit_on_threshold.unwrap_or(false),
+                webhook_url: self.alerts.webhook_url,
+            },
+            metrics_addr: self.metrics.listen,
+            stats_log_interval: match self.metrics.log_interval_secs {
+                Some(secs) => (secs > 0).then(|| Duration::from_secs(secs)),
+                None => Some(STATS_LOG_INTERVAL),
+            },
+            strict_audio: self.audio.strict.unwrap_or(false),
+            script: self.script.path,
+            listen: self.listen.enabled.unwrap_or(false).then(|| PlaybackConfig {
+                sink: self.listen.sink.filter(|sink| !sink.is_empty()),
+                volume: self.listen.volume.unwrap_or(LISTEN_VOLUME),
+            }),
+            webhook: self.webhook.url.map(|url| {
+                let mut webhook = WebhookConfig::new(url);
+                if let Some(events) = self.webhook.events {
+                    webhook.events = events;
+                }
+                webhook
+            }),
+            retry: RetryConfig {
+                relay: self.retry.policy(&self.retry.relay),
+                device: self.retry.policy(&self.retry.device),
+                pipeline: self.retry.policy(&self.retry.pipeline),
+            },
+        }
+    }
+}
+
+/// `-c` arguments that name an `http://` or `https://` URL instead of a file.
+pub fn remote_url(path: &Path) -> Option<url::Url> {
+    let s = path.to_str()?;
+    if s.starts_with("http://") || s.starts_with("https://") {
+        url::Url::parse(s).ok()
+    } else {
+        None
+    }
+}
+
+/// Where a fetched copy of `url` is kept:
+/// `$XDG_CACHE_HOME/pipe2moq/remote-<hash>.toml` (default `~/.cache`).
+fn remote_cache_path(url: &url::Url) -> Result<PathBuf> {
+    use std::hash::{Hash, Hasher};
+
+    let dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .ok_or_else(|| anyhow::anyhow!("Neither XDG_CACHE_HOME nor HOME is set"))?
+        .join("pipe2moq");
+    std::fs::create_dir_all(&dir)?;
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    url.as_str().hash(&mut hasher);
+    Ok(dir.join(format!("remote-{:016x}.toml", hasher.finish())))
+}
+
+async fn download(url: &url::Url) -> Result<String> {
+    let body = reqwest::get(url.clone()).await?
+        .error_for_status()?
+        .text().await?;
+    // Never replace a working cached copy with something unusable.
+    Figment::from(Toml::string(&body)).extract::<ConfigFile>()
+        .map_err(|e| anyhow::anyhow!("Remote config {} is invalid: {e}", url))?;
+    Ok(body)
+}
+
+/// Fetch `url` into the local cache and return the cached file's path.
+///
+/// When the fetch fails, the previously cached copy is used so a box can
+/// still start while the config server is unreachable.
+pub async fn fetch_remote(url: &url::Url) -> Result<PathBuf> {
+    let cached = remote_cache_path(url)?;
+    match download(url).await {
+        Ok(body) => {
+            std::fs::write(&cached, body)?;
+            Ok(cached)
+        }
+        Err(e) if cached.is_file() => {
+            tracing::warn!("Failed to fetch {}: {e:#}; using cached copy {}", url, cached.display());
+            Ok(cached)
+        }
+        Err(e) => Err(e.context(format!("Failed to fetch config from {}", url))),
+    }
+}
+
+/// Re-fetch each remote config every `interval`, refreshing its cached
+/// copy. Resolves once any of them changes.
+pub async fn watch_remote(urls: Vec<url::Url>, interval: std::time::Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        for url in &urls {
+            let body = match download(url).await {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::warn!("Failed to refresh config from {}: {e:#}", url);
+                    continue;
+                }
+            };
+            let Ok(cached) = remote_cache_path(url) else { continue };
+            if std::fs::read_to_string(&cached).ok().as_deref() == Some(body.as_str()) {
+                continue;
+            }
+            if let Err(e) = std::fs::write(&cached, &body) {
+                tracing::warn!("Failed to cache config from {}: {e}", url);
+                continue;
+            }
+            tracing::info!("Config at {} changed", url);
+            return;
+        }
+    }
+}
+
+/// How long to wait after a change for the rest of the same save to land.
+const WATCH_SETTLE: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Watch local config files with inotify. Resolves once one of them is
+/// saved with different contents and the set still loads, so a
+/// half-typed edit doesn't take the stream down.
+///
+/// Watches the parent directories, since many editors save by renaming
+/// a new file over the old one. Included files are not watched.
+pub async fn watch_local(paths: Vec<PathBuf>) -> Result<()> {
+    use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    let mut watched = Vec::new();
+    for path in &paths {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let wd = inotify.add_watch(dir, AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO)
+            .map_err(|e| anyhow::anyhow!("Failed to watch {}: {}", dir.display(), e))?;
+        watched.push((wd, path.file_name().map(|name| name.to_os_string())));
+    }
+
+    // A plain thread rather than a blocking task, so a pending read never
+    // holds up runtime shutdown.
+    let (changed_tx, mut changed_rx) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::Builder::new()
+        .name("config-watch".to_string())
+        .spawn(move || {
+            while let Ok(events) = inotify.read_events() {
+                let relevant = events.iter().any(|event| {
+                    watched.iter().any(|(wd, name)| *wd == event.wd && event.name == *name)
+                });
+                if relevant && changed_tx.send(()).is_err() {
+                    return;
+                }
+            }
+        })?;
+
+    let read_all = |paths: &[PathBuf]| -> Vec<Option<String>> {
+        paths.iter().map(|path| std::fs::read_to_string(path).ok()).collect()
+    };
+    let mut contents = read_all(&paths);
+    loop {
+        changed_rx.recv().await.ok_or_else(|| anyhow::anyhow!("Config watcher stopped"))?;
+        tokio::time::sleep(WATCH_SETTLE).await;
+        while changed_rx.try_recv().is_ok() {}
+
+        let current = read_all(&paths);
+        if current == contents {
+            continue;
+        }
+        contents = current;
+        match ConfigFile::load(&paths) {
+            Ok(_) => {
+                tracing::info!("Config file changed");
+                return Ok(());
+            }
+            Err(e) => tracing::warn!("Ignoring config change that doesn't load: {e:#}"),
+        }
+    }
+}
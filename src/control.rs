@@ -0,0 +1,117 @@
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+use crate::OPUS_FRAME_SIZES;
+
+pub const OPUS_MIN_BITRATE: u32 = 6000;
+pub const OPUS_MAX_BITRATE: u32 = 510000;
+
+/// Desired runtime state of a running stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlState {
+    pub muted: bool,
+    pub bitrate: u32,
+    /// Encoder frame size in ms.
+    pub frame_size: u32,
+    pub stopping: bool,
+    /// Bumped by [`Controller::rotate`].
+    pub rotation: u64,
+}
+
+/// Cheap, cloneable handle for adjusting a running [`crate::Pipe2Moq`].
+///
+/// The GStreamer thread watches the shared state and applies changes
+/// to the live pipeline.
+#[derive(Clone)]
+pub struct Controller {
+    state: Arc<watch::Sender<ControlState>>,
+}
+
+impl Controller {
+    pub(crate) fn new(bitrate: u32, frame_size: u32) -> Self {
+        let (state, _) = watch::channel(ControlState {
+            muted: false,
+            bitrate,
+            frame_size,
+            stopping: false,
+            rotation: 0,
+        });
+        Self { state: Arc::new(state) }
+    }
+
+    pub fn mute(&self) {
+        self.state.send_if_modified(|state| !std::mem::replace(&mut state.muted, true));
+    }
+
+    pub fn unmute(&self) {
+        self.state.send_if_modified(|state| std::mem::replace(&mut state.muted, false));
+    }
+
+    /// Toggle mute, returning the new muted state.
+    pub fn toggle_mute(&self) -> bool {
+        self.state.send_modify(|state| state.muted = !state.muted);
+        self.is_muted()
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.state.borrow().muted
+    }
+
+    pub fn set_bitrate(&self, bitrate: u32) {
+        let bitrate = bitrate.clamp(OPUS_MIN_BITRATE, OPUS_MAX_BITRATE);
+        self.state.send_if_modified(|state| std::mem::replace(&mut state.bitrate, bitrate) != bitrate);
+    }
+
+    /// Change the encoder bitrate by `delta` bits per second, returning the new value.
+    pub fn adjust_bitrate(&self, delta: i32) -> u32 {
+        let bitrate = self.state.borrow().bitrate.saturating_add_signed(delta);
+        self.set_bitrate(bitrate);
+        self.state.borrow().bitrate
+    }
+
+    /// Switch the encoder to `frame_size` ms frames without restarting
+    /// the stream. Below 10 ms Opus encodes CELT only.
+    pub fn set_frame_size(&self, frame_size: u32) -> Result<()> {
+        if !OPUS_FRAME_SIZES.contains(&frame_size) {
+            anyhow::bail!("Opus does not support {} ms frames; use one of {:?}", frame_size, OPUS_FRAME_SIZES);
+        }
+        self.state.send_if_modified(|state| std::mem::replace(&mut state.frame_size, frame_size) != frame_size);
+        Ok(())
+    }
+
+    /// Step to the next shorter or longer supported frame size, returning
+    /// the new one.
+    pub fn step_frame_size(&self, shorter: bool) -> u32 {
+        let current = self.state.borrow().frame_size;
+        let next = if shorter {
+            OPUS_FRAME_SIZES.iter().copied().rev().find(|&size| size < current)
+        } else {
+            OPUS_FRAME_SIZES.iter().copied().find(|&size| size > current)
+        };
+        if let Some(frame_size) = next {
+            self.state.send_modify(|state| state.frame_size = frame_size);
+        }
+        self.state.borrow().frame_size
+    }
+
+    /// Ask the pipeline to finish: EOS is sent through the pipeline so
+    /// buffered frames reach the publisher before it closes.
+    pub fn stop(&self) {
+        self.state.send_if_modified(|state| !std::mem::replace(&mut state.stopping, true));
+    }
+
+    /// Close the current broadcast and publish a new one, re-expanding
+    /// `{date}` and `{time}` in its path. The stream itself carries on.
+    pub fn rotate(&self) {
+        self.state.send_modify(|state| state.rotation += 1);
+    }
+
+    pub fn state(&self) -> ControlState {
+        self.state.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ControlState> {
+        self.state.subscribe()
+    }
+}
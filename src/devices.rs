@@ -0,0 +1,150 @@
+//! The sinks, monitors and microphones capture can use, under the names
+//! `--sink-name` and `--microphone` take: `pipe2moq list-devices`, and
+//! device pickers in applications embedding pipe2moq.
+
+use anyhow::Result;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    /// Captured through its monitor; what `sink_name` takes.
+    Sink,
+    /// A sink's monitor listed as a source of its own, as PulseAudio does.
+    Monitor,
+    /// What `microphone` takes.
+    Microphone,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Device {
+    pub kind: DeviceKind,
+    pub name: String,
+    pub description: String,
+    /// PipeWire `object.serial`, which `sink_name` also takes with the
+    /// PipeWire capture backend.
+    pub serial: Option<u64>,
+    /// Most channels the device offers.
+    pub channels: Option<u32>,
+    /// Rates the device offers, ascending; the ends of the range if it
+    /// offers any rate in one.
+    pub sample_rates: Vec<u32>,
+    pub default: bool,
+}
+
+/// Audio devices GStreamer's device providers report, sinks first and
+/// each kind's default first among them. A device seen by both the
+/// PipeWire and PulseAudio providers is listed once. Probing blocks for
+/// a moment, so it runs off the async runtime's threads.
+pub async fn list() -> Result<Vec<Device>> {
+    tokio::task::spawn_blocking(probe).await?
+}
+
+fn probe() -> Result<Vec<Device>> {
+    gst::init()?;
+    let monitor = gst::DeviceMonitor::new();
+    monitor.add_filter(Some("Audio/Sink"), None);
+    monitor.add_filter(Some("Audio/Source"), None);
+    monitor.start()?;
+    let found = monitor.devices();
+    monitor.stop();
+
+    let mut devices: Vec<Device> = Vec::new();
+    for device in found.iter().filter_map(describe) {
+        if !devices.iter().any(|seen| seen.kind == device.kind && seen.name == device.name) {
+            devices.push(device);
+        }
+    }
+    devices.sort_by(|a, b| a.kind.cmp(&b.kind).then_with(|| b.default.cmp(&a.default)));
+    Ok(devices)
+}
+
+fn describe(device: &gst::Device) -> Option<Device> {
+    let properties = device.properties()?;
+    let string = |key: &str| properties.get::<String>(key).ok().filter(|value| !value.is_empty());
+    // PulseAudio devices carry the server's name as a property of their
+    // own; PipeWire ones as `node.name`.
+    let name = device.find_property("internal-name")
+        .map(|_| device.property::<String>("internal-name"))
+        .filter(|name| !name.is_empty())
+        .or_else(|| string("node.name"))?;
+    let kind = if device.has_classes("Audio/Sink") {
+        DeviceKind::Sink
+    } else if string("device.class").as_deref() == Some("monitor") || name.ends_with(".monitor") {
+        DeviceKind::Monitor
+    } else {
+        DeviceKind::Microphone
+    };
+    let serial = properties.get::<u64>("object.serial").ok()
+        .or_else(|| string("object.serial").and_then(|serial| serial.parse().ok()));
+    let caps = device.caps();
+    let structures = || caps.iter().flat_map(|caps| caps.iter());
+    let mut sample_rates: Vec<u32> = structures().flat_map(|structure| caps_values(structure, "rate")).collect();
+    sample_rates.sort_unstable();
+    sample_rates.dedup();
+    Some(Device {
+        kind,
+        description: device.display_name().to_string(),
+        serial,
+        channels: structures().flat_map(|structure| caps_values(structure, "channels")).max(),
+        sample_rates,
+        default: properties.get::<bool>("is-default").unwrap_or(false),
+        name,
+    })
+}
+
+/// An integer caps field, whether fixed, a list or a range (as its ends).
+fn caps_values(structure: &gst::StructureRef, field: &str) -> Vec<u32> {
+    let Ok(value) = structure.value(field) else { return Vec::new() };
+    let values = if let Ok(value) = value.get::<i32>() {
+        vec![value]
+    } else if let Ok(list) = value.get::<gst::List>() {
+        list.iter().filter_map(|value| value.get::<i32>().ok()).collect()
+    } else if let Ok(range) = value.get::<gst::IntRange<i32>>() {
+        vec![range.min(), range.max()]
+    } else {
+        Vec::new()
+    };
+    values.into_iter().filter(|&value| value > 0).map(|value| value as u32).collect()
+}
+
+/// Devices grouped by kind, one per line, the default marked with `*`.
+pub fn render(devices: &[Device]) -> String {
+    let mut out = String::new();
+    for (kind, heading) in [
+        (DeviceKind::Sink, "Sinks (--sink-name):"),
+        (DeviceKind::Monitor, "Monitors:"),
+        (DeviceKind::Microphone, "Microphones (--microphone):"),
+    ] {
+        let group: Vec<_> = devices.iter().filter(|device| device.kind == kind).collect();
+        if group.is_empty() {
+            continue;
+        }
+        out.push_str(heading);
+        out.push('\n');
+        for device in group {
+            let mut details = Vec::new();
+            if let Some(channels) = device.channels {
+                details.push(format!("{} ch", channels));
+            }
+            match device.sample_rates.as_slice() {
+                [] => {}
+                [rate] => details.push(format!("{} Hz", rate)),
+                [lowest, .., highest] => details.push(format!("{}-{} Hz", lowest, highest)),
+            }
+            if let Some(serial) = device.serial {
+                details.push(format!("serial {}", serial));
+            }
+            out.push_str(&format!("  {} {}\n      {}", if device.default { "*" } else { " " }, device.name, device.description));
+            if !details.is_empty() {
+                out.push_str(&format!(" ({})", details.join(", ")));
+            }
+            out.push('\n');
+        }
+    }
+    if out.is_empty() {
+        out.push_str("No audio devices found\n");
+    }
+    out
+}
@@ -0,0 +1,69 @@
+//! User-supplied GStreamer pieces: verbatim property overrides from the
+//! `[elements.<factory>]` tables, and bins spliced in at named insertion
+//! points.
+
+use anyhow::Result;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::collections::BTreeMap;
+use tracing::{info, warn};
+
+/// Factory name → property name → value in `gst-launch` syntax.
+pub type ElementOverrides = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Set each override on every element in `pipeline` made by that factory.
+/// Runs after the graph is built, so it reaches elements added by any
+/// branch or processing stage.
+pub(crate) fn apply(pipeline: &gst::Pipeline, overrides: &ElementOverrides) -> Result<()> {
+    for (factory, properties) in overrides {
+        let elements: Vec<gst::Element> = pipeline.iterate_recurse()
+            .into_iter()
+            .filter_map(|element| element.ok())
+            .filter(|element| element.factory().is_some_and(|f| f.name() == factory.as_str()))
+            .collect();
+        if elements.is_empty() {
+            warn!("[elements.{}]: no {} element in this pipeline", factory, factory);
+            continue;
+        }
+
+        for element in &elements {
+            for (name, value) in properties {
+                let pspec = element.find_property(name).ok_or_else(|| {
+                    anyhow::anyhow!("[elements.{}]: {} has no property '{}'", factory, factory, name)
+                })?;
+                let value = gst::glib::Value::deserialize(value, pspec.value_type()).map_err(|_| {
+                    anyhow::anyhow!("[elements.{}]: invalid value '{}' for {} ({})",
+                                    factory, value, name, pspec.value_type().name())
+                })?;
+                element.set_property_from_value(name, &value);
+            }
+        }
+        info!("Applied {} property override(s) to {} {} element(s)", properties.len(), elements.len(), factory);
+    }
+    Ok(())
+}
+
+/// Named places in the pipeline where `[pipeline.insert]` can splice in a
+/// `gst-launch` style bin description.
+#[derive(Clone, Debug, Default)]
+pub struct InsertionPoints {
+    /// Right after capture (and mixing), before gap filling and level metering.
+    pub post_capture: Option<String>,
+    /// Right before the Opus encoder, after mute.
+    pub pre_encode: Option<String>,
+}
+
+/// Build the bin for one insertion point, followed by a converter so it
+/// may change the sample format or rate without breaking negotiation.
+pub(crate) fn insertion(point: &str, description: Option<&str>) -> Result<Vec<gst::Element>> {
+    let Some(description) = description else { return Ok(Vec::new()) };
+    let bin = gst::parse::bin_from_description(description, true)
+        .map_err(|e| anyhow::anyhow!("[pipeline.insert] {}: {}", point, e))?;
+    bin.set_property("name", format!("insert-{}", point.replace('_', "-")));
+    info!("Inserting at {}: {}", point, description);
+    Ok(vec![
+        bin.upcast(),
+        gst::ElementFactory::make("audioconvert").build()?,
+        gst::ElementFactory::make("audioresample").build()?,
+    ])
+}
@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use crate::EosPolicy;
+
+/// Lifecycle events emitted by [`crate::Pipe2Moq`] while streaming.
+#[derive(Clone, Debug)]
+pub enum Event {
+    Started,
+    RelayConnected { url: String },
+    RelayDisconnected { reason: String },
+    DeviceLost { device: String, reason: String },
+    PipelineError { message: String },
+    /// Capture sent EOS on its own; `action` is what happens next.
+    CaptureEnded { action: EosPolicy },
+    ClippingDetected { peak_db: f64 },
+    ClippingCleared,
+    SilenceDetected { duration: Duration },
+    SilenceCleared,
+    /// The capture timeline jumped forward: audio is missing from the device.
+    CaptureGap { missing: Duration },
+    /// The capture timeline ran backwards: more audio arrived than time passed.
+    CaptureOverlap { excess: Duration },
+    XrunThresholdExceeded { xruns: u64, threshold: u64 },
+    /// Stopping because the input has been silent for too long.
+    IdleTimeout { silence: Duration },
+    /// Encoded frames are piling up ahead of the publisher, past the high
+    /// watermark.
+    FrameQueueHigh { queued: usize, capacity: usize },
+    /// The frame queue fell back below the low watermark.
+    FrameQueueCleared,
+    /// The broadcast was closed so a new one could start; see
+    /// [`crate::Controller::rotate`].
+    BroadcastRotated { broadcast_path: String },
+    /// The number of subscriptions to the audio track changed.
+    SubscribersChanged { subscribers: usize },
+    Stopped,
+}
+
+impl Event {
+    pub fn summary(&self) -> &'static str {
+        match self {
+            Event::Started => "Stream started",
+            Event::RelayConnected { .. } => "Relay connected",
+            Event::RelayDisconnected { .. } => "Relay disconnected",
+            Event::DeviceLost { .. } => "Audio device lost",
+            Event::PipelineError { .. } => "Pipeline error",
+            Event::CaptureEnded { .. } => "Capture ended",
+            Event::ClippingDetected { .. } => "Input clipping",
+            Event::ClippingCleared => "Input clipping stopped",
+            Event::SilenceDetected { .. } => "Input silent",
+            Event::SilenceCleared => "Input audio resumed",
+            Event::CaptureGap { .. } => "Capture underrun",
+            Event::CaptureOverlap { .. } => "Capture overrun",
+            Event::XrunThresholdExceeded { .. } => "Stream degraded",
+            Event::IdleTimeout { .. } => "Stopping idle stream",
+            Event::FrameQueueHigh { .. } => "Publisher falling behind",
+            Event::FrameQueueCleared => "Publisher caught up",
+            Event::BroadcastRotated { .. } => "Broadcast rotated",
+            Event::SubscribersChanged { subscribers: 0 } => "No subscribers",
+            Event::SubscribersChanged { .. } => "Subscribers changed",
+            Event::Stopped => "Stream stopped",
+        }
+    }
+
+    /// Stable snake_case name, for scripts and other machine consumers.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::RelayConnected { .. } => "relay_connected",
+            Event::RelayDisconnected { .. } => "relay_disconnected",
+            Event::DeviceLost { .. } => "device_lost",
+            Event::PipelineError { .. } => "pipeline_error",
+            Event::CaptureEnded { .. } => "capture_ended",
+            Event::ClippingDetected { .. } => "clipping_detected",
+            Event::ClippingCleared => "clipping_cleared",
+            Event::SilenceDetected { .. } => "silence_detected",
+            Event::SilenceCleared => "silence_cleared",
+            Event::CaptureGap { .. } => "capture_gap",
+            Event::CaptureOverlap { .. } => "capture_overlap",
+            Event::XrunThresholdExceeded { .. } => "xrun_threshold_exceeded",
+            Event::IdleTimeout { .. } => "idle_timeout",
+            Event::FrameQueueHigh { .. } => "frame_queue_high",
+            Event::FrameQueueCleared => "frame_queue_cleared",
+            Event::BroadcastRotated { .. } => "broadcast_rotated",
+            Event::SubscribersChanged { .. } => "subscribers_changed",
+            Event::Stopped => "stopped",
+        }
+    }
+
+    pub fn detail(&self) -> String {
+        match self {
+            Event::Started | Event::Stopped | Event::ClippingCleared | Event::SilenceCleared
+            | Event::FrameQueueCleared => String::new(),
+            Event::RelayConnected { url } => url.clone(),
+            Event::RelayDisconnected { reason } => reason.clone(),
+            Event::DeviceLost { device, reason } => format!("{}: {}", device, reason),
+            Event::PipelineError { message } => message.clone(),
+            Event::CaptureEnded { action } => action.describe().to_string(),
+            Event::ClippingDetected { peak_db } => format!("peak {:.1} dBFS", peak_db),
+            Event::SilenceDetected { duration } => format!("no signal for {}s", duration.as_secs()),
+            Event::CaptureGap { missing } => format!("{:.1} ms of audio missing", missing.as_secs_f64() * 1000.0),
+            Event::CaptureOverlap { excess } => format!("{:.1} ms of overlapping audio", excess.as_secs_f64() * 1000.0),
+            Event::XrunThresholdExceeded { xruns, threshold } => format!("{} xruns (threshold {})", xruns, threshold),
+            Event::IdleTimeout { silence } => format!("no signal for {}s", silence.as_secs()),
+            Event::FrameQueueHigh { queued, capacity } => format!("{} of {} frames queued", queued, capacity),
+            Event::BroadcastRotated { broadcast_path } => format!("closed {}", broadcast_path),
+            Event::SubscribersChanged { subscribers } => format!("{} subscribers", subscribers),
+        }
+    }
+}
+
+pub type EventSender = broadcast::Sender<Event>;
+
+pub(crate) fn emit(events: &EventSender, event: Event) {
+    // No receivers is the common case when nothing is listening.
+    let _ = events.send(event);
+}
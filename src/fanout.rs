@@ -0,0 +1,105 @@
+//! Hands each encoded frame to every interested consumer, so the
+//! publisher, a local monitor or an embedding application can all take
+//! the stream without adding branches to the pipeline.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::frame::Frame;
+
+/// How a consumer that isn't keeping up is treated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Wait for room, holding up the encoder and every other consumer.
+    /// For the publisher, which must not lose frames.
+    Lossless,
+    /// Drop frames for this consumer while its queue is full.
+    Lossy,
+}
+
+struct Consumer {
+    id: u64,
+    name: String,
+    sender: mpsc::Sender<Frame>,
+    delivery: Delivery,
+    dropped: u64,
+}
+
+/// Cloneable handle; all clones feed the same set of consumers.
+#[derive(Clone, Default)]
+pub struct FrameFanout {
+    consumers: Arc<Mutex<Vec<Consumer>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl FrameFanout {
+    /// Receive every frame from now on, through a queue of `capacity`
+    /// frames. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self, name: impl Into<String>, capacity: usize, delivery: Delivery) -> mpsc::Receiver<Frame> {
+        self.attach(name, capacity, delivery).1
+    }
+
+    /// [`subscribe`](Self::subscribe), with an id to [`detach`](Self::detach)
+    /// by, for a consumer whose receiver should see the stream end.
+    pub(crate) fn attach(&self, name: impl Into<String>, capacity: usize, delivery: Delivery) -> (u64, mpsc::Receiver<Frame>) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let name = name.into();
+        debug!("Frame consumer {} subscribed ({:?}, {} frames)", name, delivery, capacity);
+        self.consumers.lock().unwrap().push(Consumer { id, name, sender, delivery, dropped: 0 });
+        (id, receiver)
+    }
+
+    /// Stop feeding a consumer. Its receiver ends once it has drained
+    /// what's queued.
+    pub(crate) fn detach(&self, id: u64) {
+        self.consumers.lock().unwrap().retain(|consumer| consumer.id != id);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.consumers.lock().unwrap().iter().all(|consumer| consumer.sender.is_closed())
+    }
+
+    /// Deliver `frame` to every consumer, blocking on lossless ones. Call
+    /// from a non-async thread, such as a GStreamer streaming thread.
+    pub(crate) fn send_blocking(&self, frame: Frame) {
+        // Lossless sends can block, so they happen outside the lock.
+        let lossless: Vec<_> = {
+            let mut consumers = self.consumers.lock().unwrap();
+            consumers.retain(|consumer| {
+                let open = !consumer.sender.is_closed();
+                if !open {
+                    debug!("Frame consumer {} unsubscribed", consumer.name);
+                }
+                open
+            });
+            for consumer in consumers.iter_mut().filter(|consumer| consumer.delivery == Delivery::Lossy) {
+                match consumer.sender.try_send(frame.clone()) {
+                    Ok(()) => {
+                        if consumer.dropped > 0 {
+                            warn!("Frame consumer {} caught up after {} dropped frames", consumer.name, consumer.dropped);
+                            consumer.dropped = 0;
+                        }
+                    }
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        if consumer.dropped == 0 {
+                            warn!("Frame consumer {} is falling behind; dropping frames for it", consumer.name);
+                        }
+                        consumer.dropped += 1;
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => {}
+                }
+            }
+            consumers.iter()
+                .filter(|consumer| consumer.delivery == Delivery::Lossless)
+                .map(|consumer| consumer.sender.clone())
+                .collect()
+        };
+        for sender in lossless {
+            // A consumer that went away meanwhile is pruned on the next frame.
+            let _ = sender.blocking_send(frame.clone());
+        }
+    }
+}
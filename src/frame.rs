@@ -0,0 +1,303 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::time::{Duration, Instant};
+
+use crate::karp;
+use crate::PublisherBackend;
+
+/// One encoded Opus packet as it leaves the pipeline.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub data: Bytes,
+    /// Pipeline PTS in microseconds.
+    pub timestamp_us: u64,
+    pub duration_us: u64,
+    /// Monotonic time the frame's first sample was captured, if known.
+    pub captured: Option<Instant>,
+    /// Wall-clock time (ns since the Unix epoch) the sender wants the
+    /// frame played, from a received v1 header.
+    pub target_playtime_ns: Option<i64>,
+}
+
+/// When a frame was captured and when it was handed to the relay, in μs
+/// of sender monotonic time since publishing started: the timeline of
+/// the clock track's `monotonic_us`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CaptureTime {
+    pub capture_us: i64,
+    pub publish_us: i64,
+}
+
+impl CaptureTime {
+    /// Time spent in the sender, from capture to publish.
+    pub fn pipeline_delay(&self) -> Duration {
+        Duration::from_micros(self.publish_us.saturating_sub(self.capture_us).max(0) as u64)
+    }
+}
+
+/// Recover the Opus packet and its timing from a published object.
+///
+/// v1 objects carry their own timestamps, and karp objects a start time.
+/// Anything else is treated as a bare Opus packet of the configured frame
+/// duration.
+pub(crate) fn unframe(object: Bytes, backend: PublisherBackend, next_timestamp_us: &mut u64, frame_duration_us: u64) -> Frame {
+    let karp_frame = match backend {
+        PublisherBackend::Karp => karp::decode_frame(&object),
+        PublisherBackend::Lite => None,
+    };
+    if let Some((timestamp_us, payload)) = karp_frame {
+        *next_timestamp_us = timestamp_us + frame_duration_us;
+        return Frame {
+            data: payload,
+            timestamp_us,
+            duration_us: frame_duration_us,
+            captured: None,
+            target_playtime_ns: None,
+        };
+    }
+    let frame = match FrameHeader::decode(&object) {
+        Some((header, payload)) => Frame {
+            data: payload,
+            timestamp_us: header.timestamp_us,
+            duration_us: header.duration_us as u64,
+            captured: None,
+            target_playtime_ns: header.target_playtime_ns,
+        },
+        None => Frame {
+            data: object,
+            timestamp_us: *next_timestamp_us,
+            duration_us: frame_duration_us,
+            captured: None,
+            target_playtime_ns: None,
+        },
+    };
+    *next_timestamp_us = frame.timestamp_us + frame.duration_us;
+    frame
+}
+
+/// A silent Opus packet lasting `duration_us`, with `streams` streams
+/// of which the first `coupled` are stereo, as
+/// [`AudioConfig::opus_streams`](crate::AudioConfig::opus_streams) lays
+/// them out.
+///
+/// Fullband CELT frames of two bytes (`FF FE`) decode as digital silence;
+/// 40 and 60 ms are two or three 20 ms frames in one packet. Multistream
+/// packets join one packet per stream, all but the last self-delimited.
+pub(crate) fn opus_silence(duration_us: u64, (streams, coupled): (u32, u32)) -> Option<Bytes> {
+    let (config, frames) = match duration_us {
+        2_500 => (28u8, 1u8),
+        5_000 => (29, 1),
+        10_000 => (30, 1),
+        20_000 => (31, 1),
+        40_000 => (31, 2),
+        60_000 => (31, 3),
+        _ => return None,
+    };
+    if streams == 0 || coupled > streams {
+        return None;
+    }
+    let mut packet = BytesMut::with_capacity(streams as usize * (3 + 2 * frames as usize));
+    for stream in 0..streams {
+        let stereo = u8::from(stream < coupled);
+        let toc = config << 3 | stereo << 2;
+        if frames == 1 {
+            packet.put_u8(toc);
+        } else {
+            // Code 3: a count byte follows, then equal-sized (CBR) frames.
+            packet.put_u8(toc | 3);
+            packet.put_u8(frames);
+        }
+        if stream + 1 < streams {
+            // Self-delimiting: the size of each (equal-sized) frame.
+            packet.put_u8(2);
+        }
+        for _ in 0..frames {
+            packet.put_slice(&[0xFF, 0xFE]);
+        }
+    }
+    Some(packet.freeze())
+}
+
+/// What a script sees of each published frame.
+#[derive(Clone, Debug)]
+pub struct FrameInfo {
+    pub sequence: u64,
+    pub timestamp_us: u64,
+    pub duration_us: u64,
+    /// Size of the published object, header included.
+    pub bytes: usize,
+    /// `None` for frames whose capture time the pipeline couldn't tell.
+    pub capture: Option<CaptureTime>,
+}
+
+/// How frames are laid out in published MoQ objects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameFormat {
+    /// Bare Opus payload, optionally prefixed by an 8-byte target playtime.
+    Raw,
+    /// Versioned header (see `doc/frame-format.md`) followed by the payload.
+    V1,
+}
+
+impl std::str::FromStr for FrameFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "v1" => Ok(Self::V1),
+            other => Err(anyhow::anyhow!("Unknown frame format '{}', expected 'raw' or 'v1'", other)),
+        }
+    }
+}
+
+pub const FRAME_HEADER_VERSION: u8 = 1;
+/// Length of the fixed part of a v1 header.
+pub const FRAME_HEADER_LEN: usize = 24;
+
+pub const FLAG_TARGET_PLAYTIME: u8 = 0x01;
+pub const FLAG_CAPTURE_TIME: u8 = 0x02;
+
+/// Fields carried by the v1 frame header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub sequence: u64,
+    pub timestamp_us: u64,
+    pub duration_us: u32,
+    /// Wall-clock time (ns since the Unix epoch) the receiver should play the frame.
+    pub target_playtime_ns: Option<i64>,
+    pub capture: Option<CaptureTime>,
+}
+
+impl FrameHeader {
+    /// Total encoded header length, including optional fields.
+    pub fn encoded_len(&self) -> usize {
+        FRAME_HEADER_LEN
+            + if self.target_playtime_ns.is_some() { 8 } else { 0 }
+            + if self.capture.is_some() { 16 } else { 0 }
+    }
+
+    pub fn encode(&self, payload: &[u8]) -> Bytes {
+        let header_len = self.encoded_len();
+        let mut flags = 0;
+        if self.target_playtime_ns.is_some() {
+            flags |= FLAG_TARGET_PLAYTIME;
+        }
+        if self.capture.is_some() {
+            flags |= FLAG_CAPTURE_TIME;
+        }
+
+        let mut buf = BytesMut::with_capacity(header_len + payload.len());
+        buf.put_u8(FRAME_HEADER_VERSION);
+        buf.put_u8(flags);
+        buf.put_u16(header_len as u16);
+        buf.put_u64(self.sequence);
+        buf.put_u64(self.timestamp_us);
+        buf.put_u32(self.duration_us);
+        if let Some(target) = self.target_playtime_ns {
+            buf.put_i64(target);
+        }
+        if let Some(capture) = self.capture {
+            buf.put_i64(capture.capture_us);
+            buf.put_i64(capture.publish_us);
+        }
+        buf.extend_from_slice(payload);
+        buf.freeze()
+    }
+
+    /// Parse a v1 header, returning it with the payload.
+    ///
+    /// Unknown trailing header bytes (from a newer minor revision) are
+    /// skipped using the header length field.
+    pub fn decode(object: &Bytes) -> Option<(Self, Bytes)> {
+        if object.len() < FRAME_HEADER_LEN || object[0] != FRAME_HEADER_VERSION {
+            return None;
+        }
+        let flags = object[1];
+        let header_len = u16::from_be_bytes([object[2], object[3]]) as usize;
+        if header_len < FRAME_HEADER_LEN || object.len() < header_len {
+            return None;
+        }
+        let u64_at = |at: usize| u64::from_be_bytes(object[at..at + 8].try_into().unwrap());
+
+        // Optional fields follow the fixed part in flag bit order.
+        let mut at = FRAME_HEADER_LEN;
+        let target_playtime_ns = if flags & FLAG_TARGET_PLAYTIME != 0 {
+            if header_len < at + 8 {
+                return None;
+            }
+            at += 8;
+            Some(u64_at(at - 8) as i64)
+        } else {
+            None
+        };
+        let capture = if flags & FLAG_CAPTURE_TIME != 0 {
+            if header_len < at + 16 {
+                return None;
+            }
+            Some(CaptureTime { capture_us: u64_at(at) as i64, publish_us: u64_at(at + 8) as i64 })
+        } else {
+            None
+        };
+
+        let header = FrameHeader {
+            sequence: u64_at(4),
+            timestamp_us: u64_at(12),
+            duration_us: u32::from_be_bytes(object[20..24].try_into().unwrap()),
+            target_playtime_ns,
+            capture,
+        };
+        Some((header, object.slice(header_len..)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(target_playtime_ns: Option<i64>, capture: Option<CaptureTime>) -> FrameHeader {
+        FrameHeader { sequence: 7, timestamp_us: 120_000, duration_us: 20_000, target_playtime_ns, capture }
+    }
+
+    #[test]
+    fn round_trips_every_flag_combination() {
+        let capture = CaptureTime { capture_us: -5, publish_us: 1_000 };
+        for (target, capture) in [
+            (None, None),
+            (Some(1_700_000_000_000_000_000), None),
+            (None, Some(capture)),
+            (Some(-1), Some(capture)),
+        ] {
+            let header = header(target, capture);
+            let object = header.encode(b"opus");
+            assert_eq!(object.len(), header.encoded_len() + 4);
+            assert_eq!(FrameHeader::decode(&object), Some((header, Bytes::from_static(b"opus"))));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let object = header(Some(1), Some(CaptureTime { capture_us: 2, publish_us: 3 })).encode(b"");
+        for len in 0..object.len() {
+            assert_eq!(FrameHeader::decode(&object.slice(..len)), None, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_short_header_len() {
+        let mut object = BytesMut::from(&header(None, None).encode(b"opus")[..]);
+        object[2..4].copy_from_slice(&(FRAME_HEADER_LEN as u16 - 1).to_be_bytes());
+        assert_eq!(FrameHeader::decode(&object.freeze()), None);
+    }
+
+    #[test]
+    fn skips_unknown_header_bytes() {
+        let header = header(Some(42), None);
+        let encoded = header.encode(b"");
+        let mut object = BytesMut::from(&encoded[..]);
+        object[2..4].copy_from_slice(&(encoded.len() as u16 + 6).to_be_bytes());
+        object.extend_from_slice(&[0xFF; 6]);
+        object.extend_from_slice(b"opus");
+        assert_eq!(FrameHeader::decode(&object.freeze()), Some((header, Bytes::from_static(b"opus"))));
+    }
+}
@@ -0,0 +1,222 @@
+//! Gateway mode: subscribe to a broadcast on one relay and republish it
+//! on another, optionally re-encoding at a different bitrate.
+
+use anyhow::Result;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSrc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, warn};
+use url::Url;
+
+use crate::events::{self, Event, EventSender};
+use crate::frame::{self, Frame};
+use crate::stats::{self, STATS_LOG_INTERVAL};
+use crate::tasks;
+use crate::transport::{self, TrackSubscriber, Transport};
+use crate::{AudioConfig, MoqConfig, Pipe2Moq, PublisherBackend, PublisherSession, Stats, TransportKind};
+
+#[derive(Clone)]
+pub struct GatewayConfig {
+    pub source_url: Url,
+    pub source_broadcast: String,
+    pub source_track: String,
+    pub source_transport: TransportKind,
+    /// Format of the source stream; also the encoder settings when re-encoding.
+    pub audio: AudioConfig,
+    /// Re-encode to this bitrate instead of forwarding packets untouched.
+    pub bitrate: Option<u32>,
+}
+
+/// Bridges one broadcast between relays. The target side is an ordinary
+/// publisher, so it honors the usual [`MoqConfig`] options.
+pub struct Gateway {
+    config: GatewayConfig,
+    target: MoqConfig,
+    events: EventSender,
+    stats: Arc<Stats>,
+}
+
+impl Gateway {
+    pub fn new(config: GatewayConfig, mut target: MoqConfig) -> Self {
+        let (events, _) = broadcast::channel(64);
+        target.expand_names("gateway", &AudioConfig {
+            bitrate: config.bitrate.unwrap_or(config.audio.bitrate),
+            ..config.audio.clone()
+        });
+        Self {
+            config,
+            target,
+            events,
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        info!("Gateway {}{} -> {}{}",
+              self.config.source_url, self.config.source_broadcast,
+              self.target.relay_url, self.target.broadcast_path);
+
+        let (frame_sender, mut frame_receiver) = mpsc::channel::<Frame>(self.target.frame_queue.capacity);
+        let mut session = PublisherSession::default();
+
+        let publisher = Pipe2Moq::run_moq_publisher(
+            self.target.clone(),
+            AudioConfig {
+                bitrate: self.config.bitrate.unwrap_or(self.config.audio.bitrate),
+                ..self.config.audio.clone()
+            },
+            &mut frame_receiver,
+            self.events.clone(),
+            self.stats.clone(),
+            &mut session,
+        );
+        let subscriber = match self.config.source_transport {
+            TransportKind::MoqLite => self.forward::<transport::MoqLite>(frame_sender),
+        };
+
+        let _stats_log = tasks::AbortOnDrop(tasks::spawn("stats-log", stats::log_periodically(self.stats.clone(), STATS_LOG_INTERVAL)));
+        events::emit(&self.events, Event::Started);
+        // The publisher finishes once the subscriber side drops its sender.
+        let (subscriber_result, publisher_result) = tokio::join!(subscriber, publisher);
+        events::emit(&self.events, Event::Stopped);
+        subscriber_result.and(publisher_result)
+    }
+
+    async fn forward<T: Transport>(&self, frames: mpsc::Sender<Frame>) -> Result<()> {
+        let mut source = T::subscribe(
+            &self.config.source_url,
+            &self.config.source_broadcast,
+            &self.config.source_track,
+            Duration::from_secs(10),
+        )
+        .await?;
+        info!("Subscribed to {} on {}", self.config.source_broadcast, self.config.source_url);
+
+        let transcoder = match self.config.bitrate {
+            Some(bitrate) => {
+                info!("Re-encoding at {} kbps", bitrate / 1000);
+                Some(Transcoder::new(&self.config.audio, bitrate, frames.clone(), self.stats.clone())?)
+            }
+            None => None,
+        };
+
+        let frame_duration_us = self.config.audio.frame_size as u64 * 1000;
+        let mut next_timestamp_us = 0;
+        while let Some(object) = source.next_object().await? {
+            let frame = frame::unframe(object, PublisherBackend::Lite, &mut next_timestamp_us, frame_duration_us);
+            match transcoder {
+                Some(ref transcoder) => transcoder.push(frame)?,
+                None => {
+                    self.stats.record_captured();
+                    self.stats.record_encoded(frame.data.len(), frame.duration_us);
+                    if frames.send(frame).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        warn!("Source track {} ended", self.config.source_track);
+        if let Some(transcoder) = transcoder {
+            transcoder.finish();
+        }
+        Ok(())
+    }
+}
+
+/// `appsrc ! opusdec ! opusenc ! appsink`, feeding re-encoded frames to the publisher.
+struct Transcoder {
+    pipeline: gst::Pipeline,
+    appsrc: AppSrc,
+}
+
+impl Transcoder {
+    fn new(audio: &AudioConfig, bitrate: u32, frames: mpsc::Sender<Frame>, stats: Arc<Stats>) -> Result<Self> {
+        if audio.ambisonics {
+            anyhow::bail!("Re-encoding ambisonic streams is not supported; forward them as they are");
+        }
+        gst::init()?;
+        let pipeline = gst::Pipeline::default();
+
+        let caps = gst::Caps::builder("audio/x-opus")
+            .field("channel-mapping-family", 0i32)
+            .field("channels", audio.channels as i32)
+            .field("rate", audio.sample_rate as i32)
+            .build();
+        let appsrc = AppSrc::builder()
+            .caps(&caps)
+            .format(gst::Format::Time)
+            .is_live(true)
+            .build();
+
+        let opusdec = gst::ElementFactory::make("opusdec")
+            .property("plc", true)
+            .build()?;
+        let opusenc = gst::ElementFactory::make("opusenc")
+            .property("bitrate", bitrate as i32)
+            .property_from_str("audio-type", audio.opus_audio_type())
+            .property("complexity", audio.complexity as i32)
+            .property_from_str("frame-size", &audio.frame_size.to_string())
+            .build()?;
+
+        let chain = [
+            appsrc.upcast_ref::<gst::Element>().clone(),
+            opusdec,
+            gst::ElementFactory::make("audioconvert").build()?,
+            gst::ElementFactory::make("audioresample").build()?,
+            opusenc.clone(),
+        ];
+        pipeline.add_many(&chain)?;
+        gst::Element::link_many(&chain)?;
+        Pipe2Moq::add_appsink_branch(&pipeline, &opusenc, frames, stats)?;
+
+        pipeline.set_state(gst::State::Playing)?;
+        Ok(Self { pipeline, appsrc })
+    }
+
+    fn push(&self, frame: Frame) -> Result<()> {
+        if let Some(bus) = self.pipeline.bus() {
+            if let Some(msg) = bus.pop_filtered(&[gst::MessageType::Error]) {
+                if let gst::MessageView::Error(err) = msg.view() {
+                    return Err(anyhow::anyhow!("Transcoder error: {}", err.error()));
+                }
+            }
+        }
+
+        let mut buffer = gst::Buffer::from_slice(frame.data);
+        {
+            let buffer = buffer.get_mut().expect("new buffer is writable");
+            buffer.set_pts(gst::ClockTime::from_useconds(frame.timestamp_us));
+            buffer.set_duration(gst::ClockTime::from_useconds(frame.duration_us));
+        }
+        self.appsrc.push_buffer(buffer)?;
+        Ok(())
+    }
+
+    /// Drain the encoder so the last frames reach the publisher.
+    fn finish(self) {
+        let _ = self.appsrc.end_of_stream();
+        if let Some(bus) = self.pipeline.bus() {
+            tokio::task::block_in_place(|| {
+                bus.timed_pop_filtered(gst::ClockTime::from_seconds(2), &[gst::MessageType::Eos, gst::MessageType::Error]);
+            });
+        }
+    }
+}
+
+impl Drop for Transcoder {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
@@ -0,0 +1,37 @@
+//! Heartbeat track: small periodic objects that keep flowing while audio
+//! is muted, silent or stalled, so receivers can tell "alive but quiet"
+//! from "gone".
+
+use bytes::Bytes;
+
+use crate::compression;
+
+pub const HEARTBEAT_TRACK: &str = "heartbeat";
+
+/// One heartbeat object, JSON on the wire.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Heartbeat {
+    /// Counts up from 0 for each publishing session.
+    pub sequence: u64,
+    /// Sender wall-clock time in nanoseconds since the Unix epoch.
+    pub wall_ns: i64,
+    pub frames_published: u64,
+    /// Milliseconds since the last audio frame was published, if any was.
+    pub audio_age_ms: Option<u64>,
+    /// Input has been below the silence threshold for a while.
+    pub silent: bool,
+    /// The publisher is muted and sending silence on purpose.
+    #[serde(default)]
+    pub muted: bool,
+}
+
+impl Heartbeat {
+    pub fn encode(&self) -> Bytes {
+        Bytes::from(serde_json::to_vec(self).expect("heartbeat serializes"))
+    }
+
+    /// Also reads heartbeats published with metadata compression.
+    pub fn decode(object: &[u8]) -> Option<Self> {
+        serde_json::from_slice(&compression::decompress(object)).ok()
+    }
+}
@@ -0,0 +1,79 @@
+use std::io::{IsTerminal, Read};
+use std::os::fd::AsFd;
+use std::sync::Arc;
+
+use nix::sys::termios::{self, LocalFlags, SetArg, Termios};
+use tracing::{info, warn};
+
+use crate::{Controller, Stats};
+
+const BITRATE_STEP: i32 = 16000;
+
+/// Restores the terminal's original line discipline when dropped.
+pub struct TerminalGuard {
+    original: Termios,
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(std::io::stdin().as_fd(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// Start reading single keypresses from stdin when it is a TTY.
+///
+/// Only canonical mode and echo are disabled so log output keeps its
+/// normal line endings. Returns `None` when stdin is not a terminal.
+pub fn spawn(controller: Controller, stats: Arc<Stats>) -> Option<TerminalGuard> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return None;
+    }
+
+    let original = match termios::tcgetattr(stdin.as_fd()) {
+        Ok(original) => original,
+        Err(e) => {
+            warn!("Failed to read terminal attributes, hotkeys disabled: {e}");
+            return None;
+        }
+    };
+    let mut raw = original.clone();
+    raw.local_flags.remove(LocalFlags::ICANON | LocalFlags::ECHO);
+    if let Err(e) = termios::tcsetattr(stdin.as_fd(), SetArg::TCSANOW, &raw) {
+        warn!("Failed to set terminal attributes, hotkeys disabled: {e}");
+        return None;
+    }
+
+    let guard = TerminalGuard { original };
+
+    info!("Hotkeys: [m] mute/unmute  [+/-] bitrate  [s] stats  [q] quit");
+
+    std::thread::Builder::new()
+        .name("hotkeys".to_string())
+        .spawn(move || {
+            let mut byte = [0u8; 1];
+            let mut stdin = std::io::stdin().lock();
+            while let Ok(1) = stdin.read(&mut byte) {
+                match byte[0] {
+                    b'm' | b'M' => {
+                        let muted = controller.toggle_mute();
+                        info!("{}", if muted { "Muted" } else { "Unmuted" });
+                    }
+                    b'+' | b'=' => info!("Bitrate: {} kbps", controller.adjust_bitrate(BITRATE_STEP) / 1000),
+                    b'-' | b'_' => info!("Bitrate: {} kbps", controller.adjust_bitrate(-BITRATE_STEP) / 1000),
+                    b'[' => info!("Frame size: {} ms", controller.step_frame_size(true)),
+                    b']' => info!("Frame size: {} ms", controller.step_frame_size(false)),
+                    b's' | b'S' => info!("Stats: {}", stats.snapshot()),
+                    b'q' | b'Q' => {
+                        info!("Quitting");
+                        controller.stop();
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .ok()?;
+
+    Some(guard)
+}
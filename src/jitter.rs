@@ -0,0 +1,309 @@
+//! Receiver-side jitter buffer: reorders frames, holds a target amount of
+//! audio before and during playout, conceals gaps, and slews the playout
+//! rate so sender clock drift doesn't slowly fill or drain the buffer.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::frame::Frame;
+
+/// Largest playout rate deviation used to follow drift (0.5%).
+const MAX_SLEW: f64 = 0.005;
+/// Weight of each new occupancy sample in the smoothed occupancy.
+const OCCUPANCY_SMOOTHING: f64 = 1.0 / 64.0;
+/// Stable playout needed before an adaptive target shrinks by one frame.
+const SHRINK_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub struct JitterConfig {
+    /// Audio to hold before starting playout, and to aim for during it.
+    pub target_latency: Duration,
+    /// Oldest frames are dropped beyond this much buffered audio.
+    pub max_latency: Duration,
+    /// Raise the target by a frame after each underrun, and lower it back
+    /// towards `target_latency` while playout stays stable.
+    pub adaptive: bool,
+}
+
+impl Default for JitterConfig {
+    fn default() -> Self {
+        Self {
+            target_latency: Duration::from_millis(60),
+            max_latency: Duration::from_millis(500),
+            adaptive: true,
+        }
+    }
+}
+
+/// What to play next.
+#[derive(Debug)]
+pub enum Playout {
+    Frame(Frame),
+    /// The next frame is missing but later ones arrived: conceal this much
+    /// audio (e.g. Opus PLC) and move on.
+    Conceal { duration_us: u64 },
+    /// Not enough audio buffered; play nothing (or silence) for now.
+    Buffering,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct JitterStats {
+    /// Audio currently buffered ahead of playout.
+    pub occupancy: Duration,
+    /// Current target, which moves when adaptive.
+    pub target: Duration,
+    /// Playout rate to apply; above 1.0 when the buffer runs full.
+    pub rate: f64,
+    pub played: u64,
+    pub concealed: u64,
+    pub underruns: u64,
+    /// Frames that arrived after their playout time.
+    pub late: u64,
+    /// Frames dropped because the buffer exceeded `max_latency`.
+    pub overflow: u64,
+}
+
+pub struct JitterBuffer {
+    config: JitterConfig,
+    target: Duration,
+    frames: BTreeMap<u64, Frame>,
+    /// Media timestamp of the next frame due, once playout has started.
+    next_us: Option<u64>,
+    playing: bool,
+    frame_duration_us: u64,
+    smoothed_occupancy_us: f64,
+    stable_us: u64,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    pub fn new(config: JitterConfig) -> Self {
+        let target = config.target_latency;
+        Self {
+            config,
+            target,
+            frames: BTreeMap::new(),
+            next_us: None,
+            playing: false,
+            frame_duration_us: 20_000,
+            smoothed_occupancy_us: target.as_micros() as f64,
+            stable_us: 0,
+            stats: JitterStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, frame: Frame) {
+        if frame.duration_us > 0 {
+            self.frame_duration_us = frame.duration_us;
+        }
+        if self.next_us.is_some_and(|next| frame.timestamp_us + frame.duration_us / 2 < next) {
+            self.stats.late += 1;
+            return;
+        }
+        self.frames.insert(frame.timestamp_us, frame);
+
+        let max_us = self.config.max_latency.as_micros() as u64;
+        while self.occupancy_us() > max_us {
+            let Some((_, dropped)) = self.frames.pop_first() else { break };
+            self.stats.overflow += 1;
+            if self.next_us.is_some() {
+                self.next_us = Some(dropped.timestamp_us + dropped.duration_us);
+            }
+        }
+    }
+
+    /// Take the next frame to play. Call once per frame duration of
+    /// playback, stretched by [`rate`](Self::rate).
+    pub fn pop(&mut self) -> Playout {
+        if !self.playing {
+            if self.occupancy_us() < self.target.as_micros() as u64 {
+                return Playout::Buffering;
+            }
+            self.playing = true;
+            self.next_us = self.frames.first_key_value().map(|(&ts, _)| ts);
+        }
+        let occupancy = self.occupancy_us();
+        self.smoothed_occupancy_us += (occupancy as f64 - self.smoothed_occupancy_us) * OCCUPANCY_SMOOTHING;
+
+        let next = self.next_us.unwrap_or_default();
+        let Some((&ts, _)) = self.frames.first_key_value() else {
+            self.underrun();
+            return Playout::Buffering;
+        };
+
+        if ts <= next + self.frame_duration_us / 2 {
+            let (_, frame) = self.frames.pop_first().expect("first frame exists");
+            self.next_us = Some(frame.timestamp_us + frame.duration_us);
+            self.stats.played += 1;
+            self.settle(frame.duration_us);
+            Playout::Frame(frame)
+        } else {
+            let duration_us = self.frame_duration_us.min(ts - next);
+            self.next_us = Some(next + duration_us);
+            self.stats.concealed += 1;
+            self.settle(duration_us);
+            Playout::Conceal { duration_us }
+        }
+    }
+
+    /// Playout rate that steers the buffer back to its target: slightly
+    /// fast when it's too full, slightly slow when it's running low.
+    pub fn rate(&self) -> f64 {
+        if !self.playing {
+            return 1.0;
+        }
+        let target_us = self.target.as_micros().max(1) as f64;
+        let error = (self.smoothed_occupancy_us - target_us) / target_us;
+        1.0 + (error * MAX_SLEW).clamp(-MAX_SLEW, MAX_SLEW)
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        JitterStats {
+            occupancy: Duration::from_micros(self.occupancy_us()),
+            target: self.target,
+            rate: self.rate(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Buffered audio from the playout point to the end of the newest frame.
+    fn occupancy_us(&self) -> u64 {
+        let (Some((&first, _)), Some((_, last))) = (self.frames.first_key_value(), self.frames.last_key_value()) else {
+            return 0;
+        };
+        // While rebuffering, audio lost before the underrun doesn't count.
+        let start = match self.next_us {
+            Some(next) if self.playing => next.min(first),
+            _ => first,
+        };
+        (last.timestamp_us + last.duration_us).saturating_sub(start)
+    }
+
+    fn underrun(&mut self) {
+        self.stats.underruns += 1;
+        self.playing = false;
+        self.stable_us = 0;
+        if self.config.adaptive {
+            let grown = self.target + Duration::from_micros(self.frame_duration_us);
+            self.target = grown.min(self.config.max_latency);
+        }
+    }
+
+    fn settle(&mut self, played_us: u64) {
+        if !self.config.adaptive || self.target <= self.config.target_latency {
+            return;
+        }
+        self.stable_us += played_us;
+        if self.stable_us >= SHRINK_AFTER.as_micros() as u64 {
+            self.stable_us = 0;
+            let shrunk = self.target.saturating_sub(Duration::from_micros(self.frame_duration_us));
+            self.target = shrunk.max(self.config.target_latency);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn frame(timestamp_us: u64) -> Frame {
+        Frame { data: Bytes::new(), timestamp_us, duration_us: 20_000, captured: None, target_playtime_ns: None }
+    }
+
+    fn buffer(target_ms: u64, adaptive: bool) -> JitterBuffer {
+        JitterBuffer::new(JitterConfig {
+            target_latency: Duration::from_millis(target_ms),
+            max_latency: Duration::from_millis(200),
+            adaptive,
+        })
+    }
+
+    fn played(playout: Playout) -> u64 {
+        match playout {
+            Playout::Frame(frame) => frame.timestamp_us,
+            other => panic!("expected a frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn buffers_until_target_depth() {
+        let mut jitter = buffer(60, false);
+        jitter.push(frame(0));
+        jitter.push(frame(20_000));
+        assert!(matches!(jitter.pop(), Playout::Buffering));
+        jitter.push(frame(40_000));
+        assert_eq!(played(jitter.pop()), 0);
+    }
+
+    #[test]
+    fn reorders_frames_by_timestamp() {
+        let mut jitter = buffer(60, false);
+        for ts in [40_000, 0, 20_000] {
+            jitter.push(frame(ts));
+        }
+        assert_eq!(played(jitter.pop()), 0);
+        assert_eq!(played(jitter.pop()), 20_000);
+        assert_eq!(played(jitter.pop()), 40_000);
+    }
+
+    #[test]
+    fn conceals_a_missing_frame() {
+        let mut jitter = buffer(60, false);
+        for ts in [0, 40_000, 60_000] {
+            jitter.push(frame(ts));
+        }
+        assert_eq!(played(jitter.pop()), 0);
+        assert!(matches!(jitter.pop(), Playout::Conceal { duration_us: 20_000 }));
+        assert_eq!(played(jitter.pop()), 40_000);
+        assert_eq!(jitter.stats().concealed, 1);
+    }
+
+    #[test]
+    fn drops_frames_arriving_after_their_turn() {
+        let mut jitter = buffer(60, false);
+        for ts in [0, 40_000, 60_000] {
+            jitter.push(frame(ts));
+        }
+        played(jitter.pop());
+        jitter.pop();
+        jitter.push(frame(20_000));
+        assert_eq!(jitter.stats().late, 1);
+        assert_eq!(played(jitter.pop()), 40_000);
+    }
+
+    #[test]
+    fn drops_the_oldest_beyond_max_latency() {
+        let mut jitter = buffer(60, false);
+        for i in 0..12 {
+            jitter.push(frame(i * 20_000));
+        }
+        assert_eq!(jitter.stats().overflow, 2);
+        assert_eq!(jitter.stats().occupancy, Duration::from_millis(200));
+        assert_eq!(played(jitter.pop()), 40_000);
+    }
+
+    #[test]
+    fn grows_the_target_after_an_underrun() {
+        let mut jitter = buffer(40, true);
+        jitter.push(frame(0));
+        jitter.push(frame(20_000));
+        played(jitter.pop());
+        played(jitter.pop());
+        assert!(matches!(jitter.pop(), Playout::Buffering));
+        let stats = jitter.stats();
+        assert_eq!(stats.underruns, 1);
+        assert_eq!(stats.target, Duration::from_millis(60));
+    }
+
+    #[test]
+    fn speeds_up_when_running_full() {
+        let mut jitter = buffer(40, false);
+        for i in 0..10 {
+            jitter.push(frame(i * 20_000));
+        }
+        assert_eq!(jitter.rate(), 1.0);
+        played(jitter.pop());
+        assert!(jitter.rate() > 1.0 && jitter.rate() <= 1.0 + MAX_SLEW);
+    }
+}
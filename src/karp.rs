@@ -0,0 +1,36 @@
+//! moq-karp (now "hang") container: media frames prefixed by a varint
+//! microsecond timestamp. The catalog is in [`crate::catalog`].
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Encode a media frame: QUIC varint timestamp followed by the codec payload.
+pub(crate) fn encode_frame(timestamp_us: u64, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(8 + payload.len());
+    put_varint(&mut buf, timestamp_us);
+    buf.extend_from_slice(payload);
+    buf.freeze()
+}
+
+/// Split a media frame into its timestamp and codec payload.
+pub(crate) fn decode_frame(object: &Bytes) -> Option<(u64, Bytes)> {
+    let first = *object.first()?;
+    let len = 1usize << (first >> 6);
+    if object.len() < len {
+        return None;
+    }
+    let timestamp_us = object[1..len].iter().fold(u64::from(first & 0x3f), |v, &b| v << 8 | u64::from(b));
+    Some((timestamp_us, object.slice(len..)))
+}
+
+/// QUIC variable-length integer (RFC 9000 §16), 62-bit max.
+pub(crate) fn put_varint(buf: &mut BytesMut, v: u64) {
+    if v < (1 << 6) {
+        buf.put_u8(v as u8);
+    } else if v < (1 << 14) {
+        buf.put_u16(0x4000 | v as u16);
+    } else if v < (1 << 30) {
+        buf.put_u32(0x8000_0000 | v as u32);
+    } else {
+        buf.put_u64(0xc000_0000_0000_0000 | (v & 0x3fff_ffff_ffff_ffff));
+    }
+}
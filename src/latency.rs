@@ -0,0 +1,66 @@
+//! One latency target in place of separately tuned capture buffering,
+//! frame size and queueing.
+
+use anyhow::Result;
+use std::time::Duration;
+
+use crate::{AudioConfig, FrameQueueConfig, OPUS_FRAME_SIZES};
+
+/// Capture, encoder and queue settings that fit a latency target, with
+/// the capture-to-publish delay they add up to. Network and receiver
+/// delay come on top.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LatencyPlan {
+    pub frame_size: u32,
+    /// Capture period and buffer, in μs.
+    pub latency_time: u32,
+    pub buffer_time: u32,
+    pub frame_queue: usize,
+    pub expected: Duration,
+}
+
+/// Opus look-ahead on top of the frame itself: 2.5 ms in CELT-only
+/// low-delay mode, 6.5 ms otherwise.
+fn lookahead_us(audio: &AudioConfig) -> u64 {
+    if audio.application == "lowdelay" { 2_500 } else { 6_500 }
+}
+
+impl LatencyPlan {
+    /// The largest frame size that fits `target`; larger frames cost less
+    /// bandwidth and CPU. Capture runs with a period of half a frame and
+    /// a buffer of two periods, so a frame is waiting for capture at most
+    /// one frame long, then for the encoder one frame plus look-ahead.
+    pub fn for_target(target: Duration, audio: &AudioConfig) -> Result<Self> {
+        let target_us = target.as_micros() as u64;
+        let expected_us = |frame_ms: u32| 2 * frame_ms as u64 * 1000 + lookahead_us(audio);
+        let Some(frame_size) = OPUS_FRAME_SIZES.iter().copied().rev().find(|&size| expected_us(size) <= target_us) else {
+            let smallest = OPUS_FRAME_SIZES[0];
+            anyhow::bail!("A {} ms latency target is not achievable; the lowest is {:.1} ms with {} ms frames{}",
+                          target.as_millis(), expected_us(smallest) as f64 / 1000.0, smallest,
+                          if audio.application == "lowdelay" { "" } else { " (less with application = \"lowdelay\")" });
+        };
+        Ok(Self {
+            frame_size,
+            latency_time: frame_size * 500,
+            buffer_time: frame_size * 1000,
+            // A stall may add no more than the target before audio drops.
+            frame_queue: (target_us.div_ceil(frame_size as u64 * 1000) as usize).max(1),
+            expected: Duration::from_micros(expected_us(frame_size)),
+        })
+    }
+
+    pub(crate) fn apply(&self, audio: &mut AudioConfig, buffer_time: &mut u32, latency_time: &mut u32, queue: &mut FrameQueueConfig) {
+        audio.frame_size = self.frame_size;
+        *buffer_time = self.buffer_time;
+        *latency_time = self.latency_time;
+        queue.capacity = self.frame_queue;
+    }
+}
+
+impl std::fmt::Display for LatencyPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ms frames, latency-time {} μs, buffer-time {} μs, frame queue {} frames; {:.1} ms from capture to publish",
+               self.frame_size, self.latency_time, self.buffer_time, self.frame_queue,
+               self.expected.as_secs_f64() * 1000.0)
+    }
+}
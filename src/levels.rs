@@ -0,0 +1,114 @@
+use std::time::{Duration, Instant};
+
+use gstreamer as gst;
+
+use crate::Event;
+
+#[derive(Clone)]
+pub struct LevelConfig {
+    pub enabled: bool,
+    pub clip_threshold_db: f64,
+    pub clip_duration: Duration,
+    pub silence_threshold_db: f64,
+    pub silence_duration: Duration,
+    /// Stop streaming after this much continuous silence. Measures levels
+    /// even when `enabled` is off.
+    pub exit_after_silence: Option<Duration>,
+}
+
+impl Default for LevelConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            clip_threshold_db: -0.5,
+            clip_duration: Duration::from_millis(500),
+            silence_threshold_db: -60.0,
+            silence_duration: Duration::from_secs(10),
+            exit_after_silence: None,
+        }
+    }
+}
+
+/// Turns `level` element measurements into sustained clipping and
+/// silence transitions, so a single loud transient doesn't warn.
+pub(crate) struct LevelMonitor {
+    config: LevelConfig,
+    loud_since: Option<Instant>,
+    quiet_since: Option<Instant>,
+    clipping: bool,
+    silent: bool,
+}
+
+impl LevelMonitor {
+    pub(crate) fn new(config: LevelConfig) -> Self {
+        Self {
+            config,
+            loud_since: None,
+            quiet_since: None,
+            clipping: false,
+            silent: false,
+        }
+    }
+
+    pub(crate) fn clipping(&self) -> bool {
+        self.clipping
+    }
+
+    pub(crate) fn silent(&self) -> bool {
+        self.silent
+    }
+
+    /// How long the input has been below the silence threshold, not
+    /// counting time spent muted.
+    pub(crate) fn quiet_for(&self) -> Duration {
+        self.quiet_since.map(|since| since.elapsed()).unwrap_or_default()
+    }
+
+    /// Feed one `level` message; returns an event when a state changes.
+    ///
+    /// Silence is not reported while `muted`, since that is intentional.
+    pub(crate) fn update(&mut self, structure: &gst::StructureRef, muted: bool) -> Option<Event> {
+        let peak_db = max_channel_db(structure, "peak")?;
+        let rms_db = max_channel_db(structure, "rms")?;
+        let now = Instant::now();
+
+        if peak_db >= self.config.clip_threshold_db {
+            let since = *self.loud_since.get_or_insert(now);
+            if !self.clipping && now.duration_since(since) >= self.config.clip_duration {
+                self.clipping = true;
+                return Some(Event::ClippingDetected { peak_db });
+            }
+        } else {
+            self.loud_since = None;
+            if self.clipping {
+                self.clipping = false;
+                return Some(Event::ClippingCleared);
+            }
+        }
+
+        if rms_db <= self.config.silence_threshold_db && !muted {
+            let since = *self.quiet_since.get_or_insert(now);
+            let duration = now.duration_since(since);
+            if !self.silent && duration >= self.config.silence_duration {
+                self.silent = true;
+                return Some(Event::SilenceDetected { duration });
+            }
+        } else {
+            self.quiet_since = None;
+            if self.silent {
+                self.silent = false;
+                return Some(Event::SilenceCleared);
+            }
+        }
+
+        None
+    }
+}
+
+fn max_channel_db(structure: &gst::StructureRef, field: &str) -> Option<f64> {
+    let values = structure.get::<gst::glib::ValueArray>(field).ok()?;
+    values
+        .iter()
+        .filter_map(|v| v.get::<f64>().ok())
+        .reduce(f64::max)
+}
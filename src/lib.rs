@@ -1,17 +1,133 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bytes::{Bytes, BytesMut};
 use gstreamer as gst;
 use gstreamer::prelude::*;
 use gstreamer_app::{AppSink, AppSinkCallbacks};
 
-use std::process::Command;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc, watch};
 use tracing::{error, info, debug, warn};
-use url::Url;
+
+use crate::backlog::Backlog;
+use crate::catalog::{Catalog, CatalogTrack, CATALOG_TRACK};
+use crate::complexity::ComplexityTuner;
+use crate::retry::Backoff;
+use crate::token::TokenConfig;
+use crate::video::{VideoFrame, VideoPosition, VideoTrack};
+use crate::control::{OPUS_MAX_BITRATE, OPUS_MIN_BITRATE};
+
+pub mod agent;
+pub mod alerts;
+mod backlog;
+pub mod bench;
+pub mod calibrate;
+pub mod capabilities;
+mod capture;
+pub mod catalog;
+pub mod clock;
+pub mod complexity;
+pub mod compression;
+pub mod config;
+pub mod control;
+pub mod devices;
+pub mod elements;
+pub mod events;
+pub mod fanout;
+pub mod frame;
+pub mod gateway;
+pub mod heartbeat;
+pub mod hotkeys;
+pub mod jitter;
+pub mod karp;
+pub mod levels;
+mod listen;
+pub mod latency;
+pub mod lock;
+#[cfg(feature = "media-keys")]
+pub mod media_keys;
+pub mod metrics;
+pub mod monitor;
+#[cfg(feature = "notify")]
+pub mod notify;
+mod pacing;
+pub mod ping;
+pub mod play;
+pub mod playback;
+pub mod processing;
+pub mod resources;
+pub mod retry;
+pub mod rtp;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod stats;
+pub mod sync;
+pub mod tasks;
+pub mod token;
+pub mod transport;
+#[cfg(feature = "tray")]
+pub mod tray;
+pub mod video;
+pub mod webhook;
+
+pub use alerts::AlertConfig;
+pub use control::{ControlState, Controller};
+pub use devices::{Device, DeviceKind};
+pub use events::{Event, EventSender};
+pub use fanout::{Delivery, FrameFanout};
+pub use frame::{CaptureTime, Frame, FrameFormat, FrameHeader, FrameInfo};
+pub use levels::LevelConfig;
+pub use play::{Moq2Pipe, PlayConfig};
+pub use complexity::AutoComplexity;
+pub use compression::Compression;
+pub use processing::{NoiseSuppression, ProcessingConfig};
+pub use retry::{RetryConfig, RetryPolicy};
+pub use rtp::{RtpConfig, RtpPayload};
+pub use stats::{SessionSummary, Stats, StatsSnapshot};
+pub use transport::{Announced, PathStats, TransportKind};
+pub use video::{VideoCodec, VideoConfig, VideoSource};
+
+use transport::{BroadcastPublisher, TrackPublisher, TrackSubscriber, Transport};
 
 pub const TARGET_PLAYTIME_EXTENSION_TYPE: u64 = 0xE3;
 
+/// Metadata track naming the canonical path of a session-suffixed broadcast.
+pub const SESSION_TRACK: &str = "session.json";
+
+/// Track carrying relay delay probes: 8-byte big-endian microseconds
+/// since publishing started.
+pub const PROBE_TRACK: &str = "probe";
+
+/// Track carrying the objects a script passes to `pipe2moq.emit`.
+pub const SCRIPT_TRACK: &str = "script";
+
+/// Longest the pipeline thread waits on the bus before rechecking controls.
+const BUS_POLL: gst::ClockTime = gst::ClockTime::from_mseconds(100);
+
+/// Name of the OS thread running the GStreamer pipeline and its bus loop.
+const PIPELINE_THREAD: &str = "gst-pipeline";
+
+const PUBLISHER_TASK: &str = "moq-publisher";
+const LISTEN_TASK: &str = "listen";
+const STATS_LOG_TASK: &str = "stats-log";
+#[cfg(feature = "scripting")]
+const SCRIPT_TASK: &str = "script";
+
+/// How long to listen for existing announcements before publishing.
+const CONFLICT_CHECK_WAIT: Duration = Duration::from_millis(500);
+
+/// How often to look for playback moving to another sink or a followed
+/// application (re)appearing.
+const SOURCE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+const CAPTURE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const PATH_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Clone)]
 pub struct AudioConfig {
     pub sample_rate: u32,
@@ -20,6 +136,128 @@ pub struct AudioConfig {
     pub application: String,
     pub complexity: u32,
     pub frame_size: u32,
+    /// First-order ambisonics (4 channels, ACN order). The channels are
+    /// captured unpositioned so Opus codes each on its own instead of
+    /// coupling them as stereo pairs.
+    pub ambisonics: bool,
+    /// Lower `complexity` at runtime when encoding can't keep up.
+    pub auto_complexity: Option<AutoComplexity>,
+}
+
+/// Encoder settings of one track that differ from the stream's
+/// [`AudioConfig`]. Sample rate, channels and frame size are shared, so
+/// tracks stay time-aligned.
+#[derive(Clone, Debug, Default)]
+pub struct TrackAudio {
+    pub bitrate: Option<u32>,
+    pub complexity: Option<u32>,
+    pub application: Option<String>,
+}
+
+impl TrackAudio {
+    /// `base` with these settings applied.
+    pub fn apply(&self, base: &AudioConfig) -> AudioConfig {
+        AudioConfig {
+            bitrate: self.bitrate.unwrap_or(base.bitrate),
+            complexity: self.complexity.unwrap_or(base.complexity),
+            application: self.application.clone().unwrap_or_else(|| base.application.clone()),
+            // Runtime tuning follows the mix encoder, not this one.
+            auto_complexity: None,
+            ..base.clone()
+        }
+    }
+
+    pub fn validate(&self, track: &str) -> Result<()> {
+        if let Some(bitrate) = self.bitrate.filter(|bitrate| !(OPUS_MIN_BITRATE..=OPUS_MAX_BITRATE).contains(bitrate)) {
+            anyhow::bail!("Track {}: bitrate {} is outside Opus's {}-{} bps", track, bitrate, OPUS_MIN_BITRATE, OPUS_MAX_BITRATE);
+        }
+        if let Some(complexity) = self.complexity.filter(|&complexity| complexity > 10) {
+            anyhow::bail!("Track {}: complexity {} is above the maximum of 10", track, complexity);
+        }
+        if let Some(application) = self.application.as_deref().filter(|application| !OPUS_APPLICATIONS.contains(application)) {
+            anyhow::bail!("Track {}: unknown application '{}', expected one of {:?}", track, application, OPUS_APPLICATIONS);
+        }
+        Ok(())
+    }
+}
+
+/// Channels of first-order ambisonics: W, Y, Z, X.
+pub const AMBISONIC_CHANNELS: u32 = 4;
+
+/// Accepted values for [`AudioConfig::application`].
+pub const OPUS_APPLICATIONS: &[&str] = &["voip", "voice", "audio", "generic", "lowdelay"];
+
+/// Frame durations opusenc accepts, in milliseconds.
+pub const OPUS_FRAME_SIZES: &[u32] = &[5, 10, 20, 40, 60];
+
+/// Sample rates Opus encodes natively.
+pub const OPUS_SAMPLE_RATES: &[u32] = &[8000, 12000, 16000, 24000, 48000];
+
+impl AudioConfig {
+    pub fn is_voice(&self) -> bool {
+        matches!(self.application.as_str(), "voice" | "voip")
+    }
+
+    /// Bring settings Opus can't encode into range, logging each change.
+    /// With `strict`, fail with an explanation instead.
+    pub fn validate(&mut self, strict: bool) -> Result<()> {
+        if self.ambisonics && self.channels != AMBISONIC_CHANNELS {
+            anyhow::bail!("First-order ambisonics needs {} channels, not {}", AMBISONIC_CHANNELS, self.channels);
+        }
+        if !OPUS_SAMPLE_RATES.contains(&self.sample_rate) {
+            if strict {
+                anyhow::bail!("Opus does not support a sample rate of {} Hz; use one of {:?}",
+                              self.sample_rate, OPUS_SAMPLE_RATES);
+            }
+            // The capture chain resamples, so round up to keep the full bandwidth.
+            let corrected = OPUS_SAMPLE_RATES.iter().copied()
+                .find(|&rate| rate >= self.sample_rate)
+                .unwrap_or(48000);
+            warn!("Opus does not support {} Hz; resampling to {} Hz", self.sample_rate, corrected);
+            self.sample_rate = corrected;
+        }
+        if !OPUS_FRAME_SIZES.contains(&self.frame_size) {
+            if strict {
+                anyhow::bail!("Opus does not support {} ms frames; use one of {:?}",
+                              self.frame_size, OPUS_FRAME_SIZES);
+            }
+            // Ties go to the shorter frame, for latency.
+            let nearest = OPUS_FRAME_SIZES.iter().copied()
+                .min_by_key(|&size| size.abs_diff(self.frame_size))
+                .unwrap_or(20);
+            warn!("Opus does not support {} ms frames; using {} ms", self.frame_size, nearest);
+            self.frame_size = nearest;
+        }
+        Ok(())
+    }
+
+    /// Streams in each Opus packet, and how many of them are coupled
+    /// (stereo), as opusenc lays out the configured channels: one
+    /// uncoupled stream per ambisonic channel, Vorbis surround order for
+    /// up to 8 channels, and one stream per channel beyond that.
+    pub fn opus_streams(&self) -> (u32, u32) {
+        match self.channels {
+            channels if self.ambisonics => (channels, 0),
+            1 => (1, 0),
+            2 => (1, 1),
+            3 => (2, 1),
+            4 => (2, 2),
+            5 => (3, 2),
+            6 => (4, 2),
+            7 => (4, 3),
+            8 => (5, 3),
+            channels => (channels, 0),
+        }
+    }
+
+    /// opusenc `audio-type` for the configured application.
+    pub fn opus_audio_type(&self) -> &'static str {
+        match self.application.as_str() {
+            "voice" | "voip" => "voice",
+            "lowdelay" => "restricted-lowdelay",
+            _ => "generic",
+        }
+    }
 }
 
 impl Default for AudioConfig {
@@ -31,6 +269,8 @@ impl Default for AudioConfig {
             application: "generic".to_string(),
             complexity: 5,
             frame_size: 20,
+            ambisonics: false,
+            auto_complexity: None,
         }
     }
 }
@@ -40,7 +280,41 @@ pub struct PipelineConfig {
     pub audio: AudioConfig,
     pub buffer_time: u32,
     pub latency_time: u32,
+    /// Sink whose monitor is captured; the default sink when unset. With
+    /// PipeWire this may also be a node serial.
     pub sink_name: Option<String>,
+    pub capture_backend: CaptureBackend,
+    /// Capture whichever sink has audio playing, following playback
+    /// between sinks. `sink_name` is then only the initial fallback.
+    pub follow_active_sink: bool,
+    /// Capture this application's playback stream instead of a sink
+    /// monitor, re-attaching whenever the application restarts.
+    pub follow_app: Option<String>,
+    pub microphone: Option<String>,
+    /// Output-by-input gain matrix applied to the captured device, one
+    /// row per published channel.
+    pub channel_matrix: Option<Vec<Vec<f64>>>,
+    pub rtp: RtpConfig,
+    pub output_file: Option<PathBuf>,
+    pub levels: LevelConfig,
+    pub processing: ProcessingConfig,
+    /// Timestamp jumps smaller than this are treated as jitter, not gaps.
+    pub gap_tolerance: Duration,
+    /// Insert silence for missing audio so the published timeline stays continuous.
+    pub fill_gaps: bool,
+    /// Raw GStreamer properties applied to elements by factory name.
+    pub element_overrides: elements::ElementOverrides,
+    pub insert: elements::InsertionPoints,
+    /// Applications whose playback is also published on its own track,
+    /// `<track_name>.<app>`, next to the full mix.
+    pub stems: Vec<String>,
+    /// Encoder settings of individual stems, by application; the rest
+    /// encode like the mix.
+    pub stem_audio: BTreeMap<String, TrackAudio>,
+    /// What happens when capture ends unexpectedly.
+    pub on_eos: EosPolicy,
+    /// Screen capture published on its own track next to the audio.
+    pub video: Option<VideoConfig>,
 }
 
 impl Default for PipelineConfig {
@@ -50,139 +324,1145 @@ impl Default for PipelineConfig {
             buffer_time: 20000,
             latency_time: 10000,
             sink_name: None,
+            capture_backend: CaptureBackend::Auto,
+            follow_active_sink: false,
+            follow_app: None,
+            microphone: None,
+            channel_matrix: None,
+            rtp: RtpConfig::default(),
+            output_file: None,
+            levels: LevelConfig::default(),
+            processing: ProcessingConfig::default(),
+            gap_tolerance: Duration::from_millis(10),
+            fill_gaps: false,
+            element_overrides: Default::default(),
+            insert: Default::default(),
+            stems: Vec::new(),
+            stem_audio: BTreeMap::new(),
+            on_eos: EosPolicy::Stop,
+            video: None,
+        }
+    }
+}
+
+/// Media container layered on the moq-lite transport.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PublisherBackend {
+    /// Bare moq-lite track using [`MoqConfig::frame_format`].
+    Lite,
+    /// moq-karp catalog and frame semantics, chosen at runtime with `backend`
+    /// or `--backend`.
+    Karp,
+}
+
+impl std::str::FromStr for PublisherBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lite" => Ok(Self::Lite),
+            "karp" | "hang" => Ok(Self::Karp),
+            other => Err(anyhow::anyhow!("Unknown publisher backend '{}', expected 'lite' or 'karp'", other)),
+        }
+    }
+}
+
+/// What to do when the broadcast path is already announced on the relay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Refuse to publish.
+    Fail,
+    /// Publish anyway; the relay routes new subscribers to the newest announcement.
+    Takeover,
+    /// Publish under the first free `<path>-2`, `<path>-3`, ...
+    Alternate,
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "takeover" => Ok(Self::Takeover),
+            "alternate" => Ok(Self::Alternate),
+            other => Err(anyhow::anyhow!("Unknown conflict policy '{}', expected 'fail', 'takeover' or 'alternate'", other)),
+        }
+    }
+}
+
+/// What an audio group opened for a new subscriber carries ahead of its
+/// frame, so a player has something to start its decoder on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preroll {
+    #[default]
+    Off,
+    /// Silence packets standing in for the frames just before.
+    Silence,
+    /// The frames just before, as published.
+    Buffered,
+}
+
+impl Preroll {
+    /// Silence only has to get a decoder going, while buffered audio is
+    /// worth a second, as much as a player typically buffers.
+    pub fn default_duration(self) -> Duration {
+        match self {
+            Self::Off => Duration::ZERO,
+            Self::Silence => Duration::from_millis(100),
+            Self::Buffered => Duration::from_secs(1),
+        }
+    }
+}
+
+impl std::str::FromStr for Preroll {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "silence" => Ok(Self::Silence),
+            "buffered" => Ok(Self::Buffered),
+            other => Err(anyhow::anyhow!("Unknown preroll '{}', expected 'off', 'silence' or 'buffered'", other)),
+        }
+    }
+}
+
+/// What to do when capture ends without having been asked to, as when a
+/// Pulse source goes away cleanly instead of failing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EosPolicy {
+    /// End the stream cleanly.
+    #[default]
+    Stop,
+    /// Rebuild the pipeline, backing off like the device retry policy
+    /// but without giving up.
+    Restart,
+    /// Keep the audio track going with silence until stopped.
+    Silence,
+    /// End the stream with an error, so the process exits non-zero.
+    Fail,
+}
+
+impl EosPolicy {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Stop => "stopping",
+            Self::Restart => "restarting capture",
+            Self::Silence => "publishing silence",
+            Self::Fail => "failing",
+        }
+    }
+}
+
+impl std::str::FromStr for EosPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stop" => Ok(Self::Stop),
+            "restart" => Ok(Self::Restart),
+            "silence" => Ok(Self::Silence),
+            "fail" => Ok(Self::Fail),
+            other => Err(anyhow::anyhow!("Unknown EOS policy '{}', expected 'stop', 'restart', 'silence' or 'fail'", other)),
+        }
+    }
+}
+
+/// The sound server API audio is captured through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureBackend {
+    /// PipeWire when its GStreamer elements and server are there,
+    /// PulseAudio otherwise.
+    #[default]
+    Auto,
+    /// `pipewiresrc`, which can target nodes by name or serial.
+    Pipewire,
+    /// `pulsesrc`, for PulseAudio or pipewire-pulse.
+    Pulse,
+}
+
+impl std::str::FromStr for CaptureBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "pipewire" => Ok(Self::Pipewire),
+            "pulse" | "pulseaudio" => Ok(Self::Pulse),
+            other => Err(anyhow::anyhow!("Unknown capture backend '{}', expected 'auto', 'pipewire' or 'pulse'", other)),
+        }
+    }
+}
+
+/// Relative MoQ priorities per kind of track; under congestion the relay
+/// delivers higher values first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct TrackPriorities {
+    pub audio: u8,
+    pub video: u8,
+    /// Catalog and clock tracks.
+    pub metadata: u8,
+}
+
+impl Default for TrackPriorities {
+    fn default() -> Self {
+        Self {
+            audio: 2,
+            video: 1,
+            metadata: 0,
+        }
+    }
+}
+
+/// How many past groups each track keeps alive for late joiners.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetentionConfig {
+    /// Groups retained per track, including the latest.
+    pub max_groups: usize,
+    /// Drop retained groups older than this, even under `max_groups`.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            max_groups: 1,
+            max_age: None,
         }
     }
 }
 
 #[derive(Clone)]
 pub struct MoqConfig {
+    pub enabled: bool,
     pub relay_url: String,
     pub broadcast_path: String,
     pub track_name: String,
     pub target_playtime_delay: Option<u64>,
+    pub frame_format: FrameFormat,
+    pub backend: PublisherBackend,
+    pub transport: TransportKind,
+    /// Publish a clock-sync object on the `clock` track at this interval.
+    pub clock_interval: Option<Duration>,
+    /// Cap on outbound bits per second across all tracks. Lower-priority
+    /// tracks are shed to stay under it; audio is never dropped.
+    pub max_bitrate: Option<u32>,
+    pub priorities: TrackPriorities,
+    pub retention: RetentionConfig,
+    /// Publish under `{broadcast_path}/{session}` with a per-process
+    /// session id, so identical configs on one relay don't collide. A
+    /// `{session}` placeholder in the path is always substituted.
+    pub session_suffix: bool,
+    pub on_conflict: ConflictPolicy,
+    /// Publish a [`heartbeat::Heartbeat`] this often on its own track.
+    pub heartbeat_interval: Option<Duration>,
+    /// Publish a probe this often and subscribe to it back through the
+    /// relay to measure relay-induced delay.
+    pub probe_interval: Option<Duration>,
+    /// Publish over the session another broadcast in this process already
+    /// has open to the same relay, instead of connecting again.
+    pub share_session: bool,
+    pub frame_queue: FrameQueueConfig,
+    /// How long to wait, once the stream ends, for the last frames to
+    /// reach the relay before the broadcast is closed.
+    pub flush_timeout: Duration,
+    pub preroll: Preroll,
+    /// Audio the preroll covers; by default [`Preroll::default_duration`].
+    pub preroll_duration: Option<Duration>,
+    /// Carry each audio frame's capture and publish time in its v1 header.
+    pub capture_time: bool,
+    /// Describe the tracks on a `catalog.json` track, as hang players
+    /// expect. Always on with the karp backend.
+    pub catalog: bool,
+    /// Fetch the relay token on every connect instead of taking the one in
+    /// `relay_url`, reconnecting with a new one before it expires.
+    pub token: Option<TokenConfig>,
+    /// Compress the session, heartbeat and script tracks. The catalog
+    /// tells receivers so, and is required for it.
+    pub metadata_compression: Compression,
+}
+
+/// The queue of encoded frames between the pipeline and the publisher.
+/// Everything queued is latency, so size it in audio time: 100 frames is
+/// 2 s at 20 ms frames but 6 s at 60 ms.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameQueueConfig {
+    pub capacity: usize,
+    /// Fraction of `capacity` at which [`Event::FrameQueueHigh`] fires.
+    pub high_watermark: f64,
+    /// Fraction of `capacity` the queue must fall back to before
+    /// [`Event::FrameQueueCleared`] fires.
+    pub low_watermark: f64,
+    /// While the relay can't be reached, hold up to this much audio for
+    /// it apart from the queue, dropping the oldest beyond that, so
+    /// capture keeps running however long the outage. Without it, capture
+    /// waits once the queue is full.
+    pub reconnect_buffer: Option<Duration>,
+}
+
+impl Default for FrameQueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 100,
+            high_watermark: 0.75,
+            low_watermark: 0.25,
+            reconnect_buffer: None,
+        }
+    }
+}
+
+impl FrameQueueConfig {
+    /// Queue lengths at which the high and cleared events fire.
+    fn thresholds(&self) -> (usize, usize) {
+        let high = ((self.capacity as f64 * self.high_watermark).ceil() as usize).clamp(1, self.capacity);
+        let low = (self.capacity as f64 * self.low_watermark) as usize;
+        (high, low.min(high - 1))
+    }
 }
 
 impl Default for MoqConfig {
     fn default() -> Self {
         Self {
+            enabled: true,
             relay_url: "https://localhost:4443/anon".to_string(),
             broadcast_path: "/live/audio".to_string(),
             track_name: "audio".to_string(),
             target_playtime_delay: None,
+            frame_format: FrameFormat::V1,
+            backend: PublisherBackend::Lite,
+            transport: TransportKind::MoqLite,
+            clock_interval: None,
+            max_bitrate: None,
+            priorities: TrackPriorities::default(),
+            retention: RetentionConfig::default(),
+            session_suffix: false,
+            on_conflict: ConflictPolicy::Takeover,
+            heartbeat_interval: None,
+            probe_interval: None,
+            share_session: true,
+            frame_queue: FrameQueueConfig::default(),
+            flush_timeout: Duration::from_secs(2),
+            preroll: Preroll::Off,
+            preroll_duration: None,
+            capture_time: false,
+            catalog: false,
+            token: None,
+            metadata_compression: Compression::None,
+        }
+    }
+}
+
+impl MoqConfig {
+    /// Substitute `{sink}`, `{codec}` and `{bitrate}` (in kbps, e.g.
+    /// `96k`) in the broadcast path and track name. `{session}` is left
+    /// for [`Pipe2Moq::new`], and `{date}` and `{time}` for each publish.
+    pub fn expand_names(&mut self, sink: &str, audio: &AudioConfig) {
+        let vars = [
+            ("{sink}", sink.to_string()),
+            ("{codec}", "opus".to_string()),
+            ("{bitrate}", format!("{}k", audio.bitrate / 1000)),
+        ];
+        for name in [&mut self.broadcast_path, &mut self.track_name] {
+            for (placeholder, value) in &vars {
+                if name.contains(placeholder) {
+                    *name = name.replace(placeholder, value);
+                }
+            }
         }
     }
 }
 
+impl PipelineConfig {
+    /// What `{sink}` names: the followed application, the configured
+    /// sink, or `active`/`default` when the sink is picked at runtime.
+    pub fn sink_label(&self) -> String {
+        self.follow_app.clone()
+            .or_else(|| self.sink_name.clone())
+            .unwrap_or_else(|| if self.follow_active_sink { "active" } else { "default" }.to_string())
+    }
+}
+
 pub struct Pipe2Moq {
+    /// Configured broadcast path, before any session suffix.
+    canonical_path: String,
     pipeline_config: PipelineConfig,
     moq_config: MoqConfig,
+    events: EventSender,
+    controller: Controller,
+    stats: Arc<Stats>,
+    alerts: AlertConfig,
+    /// Next frame sequence number, carried across publisher sessions so
+    /// a reconnect continues the numbering instead of restarting at zero.
+    next_sequence: Arc<AtomicU64>,
+    script: Option<PathBuf>,
+    frames: FrameFanout,
+    listen: Option<playback::PlaybackConfig>,
+    retry: RetryConfig,
+    stats_log: Option<Duration>,
+}
+
+/// What a publisher session takes from its [`Pipe2Moq`] besides config.
+#[derive(Default)]
+pub(crate) struct PublisherSession {
+    next_sequence: Arc<AtomicU64>,
+    /// Configured broadcast path, announced on [`SESSION_TRACK`] when the
+    /// actual path differs.
+    canonical_path: Option<String>,
+    /// Metadata of each published frame, for a script.
+    frame_tap: Option<broadcast::Sender<FrameInfo>>,
+    /// Objects a script emits, published on its own track.
+    script_objects: Option<mpsc::Receiver<Bytes>>,
+    /// Stem applications, and their encoded frames tagged by index.
+    stems: Vec<String>,
+    stem_frames: Option<mpsc::Receiver<(usize, Frame)>>,
+    /// Video settings, its encoded frames, and where its numbering
+    /// continues.
+    video: Option<VideoConfig>,
+    video_frames: Option<mpsc::Receiver<VideoFrame>>,
+    video_position: VideoPosition,
+    /// Frames that arrived while connecting, published first.
+    backlog: Backlog,
+    /// Watched for [`Controller::rotate`].
+    controls: Option<watch::Receiver<ControlState>>,
+    /// Set when the session ended to reconnect with a fresh token.
+    token_expiring: bool,
+}
+
+/// Where the pipeline sends what it encodes besides the main audio.
+#[derive(Clone, Default)]
+struct TrackSenders {
+    /// Stem frames, tagged with the stem's index.
+    stems: Option<mpsc::Sender<(usize, Frame)>>,
+    video: Option<mpsc::Sender<VideoFrame>>,
 }
 
 impl Pipe2Moq {
-    pub fn new(pipeline_config: PipelineConfig, moq_config: MoqConfig) -> Self {
+    pub fn new(pipeline_config: PipelineConfig, mut moq_config: MoqConfig) -> Self {
+        let (events, _) = broadcast::channel(64);
+        let controller = Controller::new(pipeline_config.audio.bitrate, pipeline_config.audio.frame_size);
+
+        moq_config.expand_names(&pipeline_config.sink_label(), &pipeline_config.audio);
+        let canonical_path = moq_config.broadcast_path.clone();
+        if moq_config.broadcast_path.contains("{session}") || moq_config.session_suffix {
+            let session = session_id();
+            if moq_config.broadcast_path.contains("{session}") {
+                moq_config.broadcast_path = moq_config.broadcast_path.replace("{session}", &session);
+            } else {
+                moq_config.broadcast_path = format!("{}/{}", canonical_path.trim_end_matches('/'), session);
+            }
+            info!("Session {}: publishing {} as {}", session, canonical_path, moq_config.broadcast_path);
+        }
+
         Self {
+            canonical_path,
             pipeline_config,
             moq_config,
+            events,
+            controller,
+            stats: Arc::new(Stats::default()),
+            alerts: AlertConfig::default(),
+            next_sequence: Arc::new(AtomicU64::new(0)),
+            script: None,
+            frames: FrameFanout::default(),
+            listen: None,
+            retry: RetryConfig::default(),
+            stats_log: Some(stats::STATS_LOG_INTERVAL),
         }
     }
 
+    /// Encoded frames as they leave the pipeline. Consumers subscribed
+    /// here get them alongside the publisher, across restarts of
+    /// [`run`](Self::run). Without MoQ, subscribe before running.
+    pub fn frames(&self) -> FrameFanout {
+        self.frames.clone()
+    }
+
+    /// Decode the encoded stream and play it to `output`, to hear what
+    /// listeners receive.
+    pub fn with_listen(mut self, output: playback::PlaybackConfig) -> Self {
+        self.listen = Some(output);
+        self
+    }
+
+    /// Retry relay connections and pipeline failures instead of stopping.
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Log a stats line this often, or never with `None`.
+    pub fn with_stats_log(mut self, interval: Option<Duration>) -> Self {
+        self.stats_log = interval;
+        self
+    }
+
+    pub fn with_alerts(mut self, alerts: AlertConfig) -> Self {
+        self.alerts = alerts;
+        self
+    }
+
+    /// Load a Lua script that can react to events and frames. Needs the
+    /// `scripting` feature; without it [`run`](Self::run) fails.
+    pub fn with_script(mut self, path: impl Into<PathBuf>) -> Self {
+        self.script = Some(path.into());
+        self
+    }
+
+    pub fn controller(&self) -> Controller {
+        self.controller.clone()
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    pub fn moq_config(&self) -> &MoqConfig {
+        &self.moq_config
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
     pub async fn run(&self) -> Result<()> {
         info!("Starting Pipe2Moq");
-        info!("Relay URL: {}", self.moq_config.relay_url);
-        info!("Broadcast path: {}", self.moq_config.broadcast_path);
+        if self.moq_config.enabled {
+            info!("Relay URL: {}", self.moq_config.relay_url);
+            info!("Broadcast path: {}", self.moq_config.broadcast_path);
+        } else {
+            info!("MoQ publishing disabled");
+        }
         info!("Audio config: {}Hz, {} channels, {} kbps",
               self.pipeline_config.audio.sample_rate,
               self.pipeline_config.audio.channels,
               self.pipeline_config.audio.bitrate / 1000);
 
-        let (frame_sender, mut frame_receiver) = mpsc::channel::<(Bytes, u64)>(100);
+        events::emit(&self.events, Event::Started);
+        let inner = self.run_inner();
+        let watchdog = alerts::watch(
+            &self.alerts, &self.moq_config.broadcast_path, &self.stats, &self.events, &self.controller);
+        tokio::pin!(inner, watchdog);
 
-        let pipeline_handle = tokio::task::spawn_blocking({
-            let pipeline_config = self.pipeline_config.clone();
-            move || Self::run_gstreamer_pipeline(pipeline_config, frame_sender)
+        let mut exceeded = None;
+        let mut result = loop {
+            tokio::select! {
+                result = &mut inner => break result,
+                xruns = &mut watchdog, if exceeded.is_none() => exceeded = Some(xruns),
+            }
+        };
+        if let Some(xruns) = exceeded.filter(|_| self.alerts.exit_on_threshold && result.is_ok()) {
+            result = Err(anyhow::anyhow!("Stopped after {} xruns (threshold {})",
+                                         xruns, self.alerts.max_xruns.unwrap_or_default()));
+        }
+        events::emit(&self.events, Event::Stopped);
+        result
+    }
+
+    /// Run until `shutdown` resolves, then drain: stop capture, let the
+    /// publisher flush buffered frames and close the broadcast, and give
+    /// up after `grace`.
+    pub async fn run_until(&self, shutdown: impl Future<Output = ()>, grace: Duration) -> Result<()> {
+        let run = self.run();
+        tokio::pin!(run);
+
+        tokio::select! {
+            result = &mut run => return result,
+            _ = shutdown => {}
+        }
+
+        info!("Shutdown requested, draining (grace period {:?})", grace);
+        self.controller.stop();
+        match tokio::time::timeout(grace, run).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!("Drain did not finish within the {:?} grace period", grace)),
+        }
+    }
+
+    async fn run_inner(&self) -> Result<()> {
+        // The script runs as long as this stream does, even without a relay.
+        let (_script, frame_tap, script_objects): (Option<tasks::AbortOnDrop<()>>, _, _) = match self.script {
+            None => (None, None, None),
+            #[cfg(feature = "scripting")]
+            Some(ref path) => {
+                let (frame_tap, frames) = broadcast::channel(256);
+                let (objects, script_objects) = mpsc::channel(64);
+                let host = script::ScriptHost::load(path, self.controller.clone(), objects)?;
+                let task = tasks::spawn(SCRIPT_TASK, host.run(self.events.subscribe(), frames));
+                (Some(tasks::AbortOnDrop(task)), Some(frame_tap), Some(script_objects))
+            }
+            #[cfg(not(feature = "scripting"))]
+            Some(_) => anyhow::bail!("Scripts require pipe2moq to be built with the `scripting` feature"),
+        };
+
+        // Subscribed before the pipeline starts so it hears the first frame.
+        let _listen = match self.listen {
+            Some(ref output) => {
+                let listener = listen::Listener::new(&self.pipeline_config.audio, output)?;
+                let frames = self.frames.subscribe(LISTEN_TASK, listen::LISTEN_QUEUE, Delivery::Lossy);
+                Some(tasks::AbortOnDrop(tasks::spawn(LISTEN_TASK, listener.run(frames))))
+            }
+            None => None,
+        };
+
+        let _stats_log = self.stats_log.map(|interval| {
+            tasks::AbortOnDrop(tasks::spawn(STATS_LOG_TASK, stats::log_periodically(self.stats.clone(), interval)))
         });
 
-        let moq_handle = tokio::task::spawn({
+        if !self.moq_config.enabled {
+            if !self.pipeline_config.stems.is_empty() {
+                warn!("Stems are only published over MoQ; ignoring them");
+            }
+            if self.pipeline_config.video.is_some() {
+                warn!("Video is only published over MoQ; ignoring it");
+            }
+            let frames = (!self.frames.is_empty()).then(|| self.frames.clone());
+            return self.run_pipeline(frames, TrackSenders::default()).await;
+        }
+
+        let capacity = self.moq_config.frame_queue.capacity;
+        let (publisher_id, mut frame_receiver) = self.frames.attach(PUBLISHER_TASK, capacity, Delivery::Lossless);
+        let (stem_sender, stem_frames) = if self.pipeline_config.stems.is_empty() {
+            (None, None)
+        } else {
+            let (sender, receiver) = mpsc::channel(capacity * self.pipeline_config.stems.len());
+            (Some(sender), Some(receiver))
+        };
+        let (video_sender, video_frames) = match self.pipeline_config.video {
+            Some(ref video) => {
+                let (sender, receiver) = mpsc::channel(video.queue_capacity());
+                (Some(sender), Some(receiver))
+            }
+            None => (None, None),
+        };
+        let senders = TrackSenders { stems: stem_sender, video: video_sender };
+
+        let pipeline_handle = self.run_pipeline(Some(self.frames.clone()), senders);
+        // Set once capture is over for good, so the publisher drains what
+        // is queued but stops reconnecting for frames that won't come.
+        let (capture_ended, mut capture_over) = watch::channel(false);
+
+        let moq_handle = tasks::spawn(PUBLISHER_TASK, {
             let moq_config = self.moq_config.clone();
-            async move { Self::run_moq_publisher(moq_config, &mut frame_receiver).await }
+            let audio = self.pipeline_config.audio.clone();
+            let events = self.events.clone();
+            let stats = self.stats.clone();
+            let controller = self.controller.clone();
+            let retry = self.retry.relay.clone();
+            let mut session = PublisherSession {
+                next_sequence: self.next_sequence.clone(),
+                canonical_path: Some(self.canonical_path.clone()),
+                frame_tap,
+                script_objects,
+                stems: self.pipeline_config.stems.clone(),
+                stem_frames,
+                video: self.pipeline_config.video.clone(),
+                video_frames,
+                video_position: VideoPosition::default(),
+                backlog: Backlog::new(self.moq_config.frame_queue.reconnect_buffer),
+                controls: Some(self.controller.subscribe()),
+                token_expiring: false,
+            };
+            async move {
+                // Frames queue up while reconnecting, and the new session
+                // continues the sequence where the old one stopped.
+                let mut backoff = Backoff::new(&retry);
+                let mut controls = controller.subscribe();
+                loop {
+                    let started = Instant::now();
+                    let rotation = controller.state().rotation;
+                    let result = Self::run_moq_publisher(
+                        moq_config.clone(), audio.clone(), &mut frame_receiver, events.clone(), stats.clone(), &mut session,
+                    ).await;
+                    let Err(e) = result else {
+                        let state = controller.state();
+                        if state.rotation != rotation && !state.stopping {
+                            continue;
+                        }
+                        return Ok(());
+                    };
+                    if controller.state().stopping || *capture_over.borrow() {
+                        return Err(e);
+                    }
+                    let Some(delay) = backoff.next(started.elapsed()) else {
+                        return backoff.policy().give_up("the relay connection", e);
+                    };
+                    warn!("Reconnecting to the relay in {:.1}s (attempt {}): {e:#}", delay.as_secs_f64(), backoff.attempts());
+                    stats.record_reconnect();
+                    let interrupted = session.backlog.hold(&mut frame_receiver, session.stem_frames.as_mut(), async {
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => false,
+                            _ = controls.wait_for(|state| state.stopping) => true,
+                            _ = capture_over.wait_for(|&over| over) => true,
+                        }
+                    }).await;
+                    if interrupted {
+                        return Err(e);
+                    }
+                }
+            }
         });
 
-        tokio::select! {
-            result = pipeline_handle => {
-                if let Err(e) = result {
-                    error!("GStreamer pipeline error: {e}");
-                    return Err(e.into());
-                }
+        // Each side retries its own failures (see `run_pipeline` and the
+        // loop above), so one ending here is final. When capture ends,
+        // however it ends, the publisher still drains the frames already
+        // in the channel and closes the broadcast, but no longer waits
+        // out reconnects. When the publisher ends first, stop capture too
+        // rather than leave it running against a closed channel. Errors
+        // from both sides are logged; the first one is returned.
+        let moq_handle = tasks::join(PUBLISHER_TASK, moq_handle);
+        tokio::pin!(pipeline_handle, moq_handle);
+        let (pipeline_result, moq_result, publisher_first) = tokio::select! {
+            pipeline_result = &mut pipeline_handle => {
+                // Lets the publisher see the end of the stream.
+                self.frames.detach(publisher_id);
+                let _ = capture_ended.send(true);
+                (pipeline_result, moq_handle.await, false)
             }
-            result = moq_handle => {
-                if let Err(e) = result {
-                    error!("MoQ publisher error: {e}");
-                    return Err(e.into());
-                }
+            moq_result = &mut moq_handle => {
+                self.controller.stop();
+                (pipeline_handle.await, moq_result, true)
             }
+        };
+        let pipeline_result = pipeline_result.context("Capture pipeline failed");
+        let moq_result = moq_result.and_then(|result| result).context("MoQ publisher failed");
+
+        if let Err(ref e) = pipeline_result {
+            error!("{e:#}");
         }
+        if let Err(ref e) = moq_result {
+            error!("{e:#}");
+        }
+        // Report whichever side failed first; the other usually failed
+        // because of it.
+        if publisher_first {
+            moq_result.and(pipeline_result)
+        } else {
+            pipeline_result.and(moq_result)
+        }
+    }
 
-        Ok(())
+    /// Run the pipeline on its own thread, rebuilding it after failures as
+    /// the device and pipeline retry policies allow.
+    async fn run_pipeline(&self, frames: Option<FrameFanout>, senders: TrackSenders) -> Result<()> {
+        let mut device_backoff = Backoff::new(&self.retry.device);
+        let mut pipeline_backoff = Backoff::new(&self.retry.pipeline);
+        let mut eos_backoff = Backoff::new(&RetryPolicy { max_attempts: None, ..self.retry.device.clone() });
+        loop {
+            let started = Instant::now();
+            let pipeline_config = self.pipeline_config.clone();
+            let events = self.events.clone();
+            let controls = self.controller.subscribe();
+            let stats = self.stats.clone();
+            let frames = frames.clone();
+            let senders = senders.clone();
+            let result = tasks::spawn_thread(PIPELINE_THREAD, move |cancelled| {
+                Self::run_gstreamer_pipeline(pipeline_config, frames, senders, events, controls, stats, cancelled)
+            })?
+            .await
+            .and_then(|result| result);
+
+            let Err(e) = result else { return Ok(()) };
+            if self.controller.state().stopping {
+                return Err(e);
+            }
+            let ended = e.chain().any(|cause| cause.is::<capture::CaptureEnded>());
+            if ended && self.pipeline_config.on_eos == EosPolicy::Fail {
+                return Err(e);
+            }
+            let (what, backoff) = if e.chain().any(|cause| cause.is::<capture::DeviceLost>()) {
+                ("the capture device", &mut device_backoff)
+            } else if ended {
+                ("capture", &mut eos_backoff)
+            } else {
+                ("the pipeline", &mut pipeline_backoff)
+            };
+            let Some(delay) = backoff.next(started.elapsed()) else {
+                return backoff.policy().give_up(what, e);
+            };
+            warn!("Rebuilding the pipeline for {} in {:.1}s (attempt {}): {e:#}", what, delay.as_secs_f64(), backoff.attempts());
+            let mut controls = self.controller.subscribe();
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = controls.wait_for(|state| state.stopping) => return Ok(()),
+            }
+        }
     }
 
     fn run_gstreamer_pipeline(
-        config: PipelineConfig,
-        frame_sender: mpsc::Sender<(Bytes, u64)>,
+        mut config: PipelineConfig,
+        frames: Option<FrameFanout>,
+        senders: TrackSenders,
+        events: EventSender,
+        mut controls: watch::Receiver<ControlState>,
+        stats: Arc<Stats>,
+        cancelled: tasks::Cancelled,
     ) -> Result<()> {
         gst::init()?;
 
+        // A rebuilt pipeline keeps the frame size switched to at runtime.
+        config.audio.frame_size = controls.borrow().frame_size;
+        let mut frame_size = config.audio.frame_size;
+
         let pipeline = gst::Pipeline::default();
 
-        let source_device = if let Some(ref sink) = config.sink_name {
-            format!("{}.monitor", sink)
+        let mut capture = capture::build(&pipeline, &config)?;
+        capture.watch_discontinuities(&stats);
+        capture.watch_timeline(config.gap_tolerance, &stats, &events);
+
+        let level = gst::ElementFactory::make("level")
+            .property("interval", gst::ClockTime::from_mseconds(100).nseconds())
+            .property("post-messages", config.levels.enabled || config.levels.exit_after_silence.is_some())
+            .build()?;
+        let volume = gst::ElementFactory::make("volume")
+            .property("mute", controls.borrow().muted)
+            .build()?;
+
+        let make_encoder = |audio: &AudioConfig| {
+            gst::ElementFactory::make("opusenc")
+                .property("bitrate", audio.bitrate as i32)
+                .property_from_str("audio-type", audio.opus_audio_type())
+                .property("complexity", audio.complexity as i32)
+                .property_from_str("frame-size", &audio.frame_size.to_string())
+                .build()
+        };
+        let opusenc = make_encoder(&config.audio)?;
+        let mut tuner = config.audio.auto_complexity.map(|auto| {
+            ComplexityTuner::watch(&opusenc, auto, config.audio.complexity,
+                                   Duration::from_millis(config.audio.frame_size as u64))
+        });
+
+        let encoded_tee = gst::ElementFactory::make("tee")
+            .name("encoded")
+            .build()?;
+
+        let mut chain = vec![capture.tail.clone()];
+        chain.extend(elements::insertion("post_capture", config.insert.post_capture.as_deref())?);
+        if config.fill_gaps {
+            // Fills gaps with silence and trims overlaps, keeping the
+            // published timestamps continuous.
+            chain.push(gst::ElementFactory::make("audiorate")
+                .property("tolerance", gst::ClockTime::try_from(config.gap_tolerance)?.nseconds())
+                .property("skip-to-first", true)
+                .build()?);
+        }
+        chain.push(level.clone());
+        chain.push(volume.clone());
+
+        // PCM-only RTP with nothing else to feed leaves the encoder out, as
+        // a tee with no branches fails the stream as not-linked.
+        let pcm_rtp = config.rtp.enabled && config.rtp.payload == RtpPayload::Pcm;
+        let encodes = frames.is_some() || config.output_file.is_some() || (config.rtp.enabled && !pcm_rtp);
+        let raw_tee = if pcm_rtp {
+            let raw_tee = gst::ElementFactory::make("tee").name("raw").build()?;
+            chain.push(raw_tee.clone());
+            if encodes {
+                chain.push(gst::ElementFactory::make("queue").build()?);
+            }
+            Some(raw_tee)
         } else {
-            let output = Command::new("pactl")
-                .args(&["get-default-sink"])
-                .output()?;
-            let sink_name = String::from_utf8_lossy(&output.stdout).trim().to_string();
-            format!("{}.monitor", sink_name)
+            None
         };
 
-        info!("Audio source: {}", source_device);
+        if encodes {
+            chain.extend(elements::insertion("pre_encode", config.insert.pre_encode.as_deref())?);
+            chain.push(opusenc.clone());
+            chain.push(encoded_tee.clone());
+        }
 
-        let pulsesrc = gst::ElementFactory::make("pulsesrc")
-            .property("device", &source_device)
-            .property("buffer-time", config.buffer_time as i64)
-            .property("latency-time", config.latency_time as i64)
-            .build()?;
+        pipeline.add_many(&chain[1..])?;
+        gst::Element::link_many(&chain)?;
 
-        let capsfilter = gst::ElementFactory::make("capsfilter")
-            .property("caps", &gst::Caps::builder("audio/x-raw")
-                .field("rate", config.audio.sample_rate as i32)
-                .field("channels", config.audio.channels as i32)
-                .build())
-            .build()?;
+        if config.rtp.enabled {
+            let tee = raw_tee.as_ref().unwrap_or(&encoded_tee);
+            rtp::add_branch(&pipeline, tee, &config.rtp)?;
+            info!("RTP multicast output: {}:{} ({:?})",
+                  config.rtp.address, config.rtp.port, config.rtp.payload);
+        }
+
+        if let Some(ref path) = config.output_file {
+            Self::add_file_branch(&pipeline, &encoded_tee, path)?;
+            info!("Writing Ogg/Opus to {}", path.display());
+        }
 
-        let audioconvert = gst::ElementFactory::make("audioconvert").build()?;
-        let audioresample = gst::ElementFactory::make("audioresample").build()?;
+        if let (Some(video), Some(sender)) = (config.video.as_ref(), senders.video.clone()) {
+            video::add_branch(&pipeline, video, sender)?;
+            info!("Video: {:?} at {}x{} {} fps, {:?} {} kbps, a keyframe every {:?}",
+                  video.source, video.width, video.height, video.framerate,
+                  video.codec, video.bitrate / 1000, video.keyframe_interval);
+        }
+
+        // Each stem: its own capture, mute and encoder, into the shared stem
+        // channel. Stems don't count towards the stream's statistics.
+        let mut stems = Vec::new();
+        let mut stem_encoders = Vec::new();
+        if let Some(ref sender) = senders.stems {
+            let stem_stats = Arc::new(Stats::default());
+            for (index, app) in config.stems.iter().enumerate() {
+                let stem = capture::build_stem(&pipeline, &config, app)?;
+                let stem_volume = gst::ElementFactory::make("volume")
+                    .property("mute", controls.borrow().muted)
+                    .build()?;
+                let stem_audio = match config.stem_audio.get(app) {
+                    Some(settings) => {
+                        let audio = settings.apply(&config.audio);
+                        info!("Stem {}: {} kbps, complexity {}, {}", app, audio.bitrate / 1000, audio.complexity, audio.application);
+                        audio
+                    }
+                    None => config.audio.clone(),
+                };
+                let stem_encoder = make_encoder(&stem_audio)?;
+                stem_encoders.push(stem_encoder.clone());
+                pipeline.add_many([&stem_volume, &stem_encoder])?;
+                gst::Element::link_many([&stem.tail, &stem_volume, &stem_encoder])?;
+                let sender = sender.clone();
+                Self::add_frame_sink(&pipeline, &stem_encoder, stem_stats.clone(), move |frame| {
+                    sender.blocking_send((index, frame)).is_ok()
+                })?;
+                stems.push((stem, stem_volume));
+            }
+        }
+
+        // Where silence picks up if capture ends under EosPolicy::Silence.
+        let next_timestamp_us = Arc::new(AtomicU64::new(0));
+        if let Some(ref frames) = frames {
+            let frames = frames.clone();
+            let next_timestamp_us = next_timestamp_us.clone();
+            Self::add_frame_sink(&pipeline, &encoded_tee, stats.clone(), move |frame| {
+                next_timestamp_us.store(frame.timestamp_us + frame.duration_us, Ordering::Relaxed);
+                frames.send_blocking(frame);
+                true
+            })?;
+        } else if !config.rtp.enabled && config.output_file.is_none() {
+            return Err(anyhow::anyhow!("No outputs configured: enable MoQ publishing, RTP multicast or a file output"));
+        }
+
+        elements::apply(&pipeline, &config.element_overrides)?;
+        pipeline.set_state(gst::State::Playing)?;
+
+        let bus = pipeline.bus().expect("Pipeline without bus");
+        let mut level_monitor = levels::LevelMonitor::new(config.levels.clone());
+        let mut eos_sent = false;
+        let mut silence_from = None;
+        let mut buffering_reported = Instant::now();
+        let mut reported_discontinuities = 0;
+        let mut resources = resources::ResourceSampler::default();
+        let mut source_polled = Instant::now();
+        let mut tuned = Instant::now();
+        // The bus is polled with a short timeout rather than blocked on, so
+        // control changes and cancellation are picked up within BUS_POLL.
+        loop {
+            if cancelled.is_set() {
+                warn!("Pipeline abandoned before it finished; shutting it down");
+                break;
+            }
+            if source_polled.elapsed() >= SOURCE_POLL_INTERVAL {
+                source_polled = Instant::now();
+                if let Some(ref app) = config.follow_app {
+                    capture.follow_app(app)?;
+                } else if config.follow_active_sink {
+                    capture.follow_active_sink()?;
+                }
+                for (stem, _) in &mut stems {
+                    stem.follow()?;
+                }
+            }
+
+            if buffering_reported.elapsed() >= CAPTURE_REPORT_INTERVAL {
+                buffering_reported = Instant::now();
+                let (buffer_time, latency_time) = capture.actual_buffering();
+                let mut latency = gst::query::Latency::new();
+                let pipeline_latency = if pipeline.query(&mut latency) {
+                    latency.result().1.useconds()
+                } else {
+                    0
+                };
+                stats.set_capture_buffering(buffer_time, latency_time, pipeline_latency);
+                match resources.sample() {
+                    Ok(usage) => stats.set_resources(usage),
+                    Err(e) => debug!("Could not sample resource usage: {e:#}"),
+                }
+
+                let snapshot = stats.snapshot();
+                if snapshot.capture_discontinuities > reported_discontinuities {
+                    warn!("Capture underruns: {} discontinuities so far (buffer-time {} μs, latency-time {} μs, pipeline latency {:.1} ms)",
+                          snapshot.capture_discontinuities, buffer_time, latency_time,
+                          pipeline_latency as f64 / 1000.0);
+                    reported_discontinuities = snapshot.capture_discontinuities;
+                } else {
+                    debug!("Capture buffering: buffer-time {} μs, latency-time {} μs, pipeline latency {:.1} ms",
+                           buffer_time, latency_time, pipeline_latency as f64 / 1000.0);
+                }
+            }
+
+            if let Some(ref mut tuner) = tuner {
+                if tuned.elapsed() >= complexity::TUNE_INTERVAL {
+                    tuned = Instant::now();
+                    if let Some(complexity) = tuner.adjust() {
+                        opusenc.set_property("complexity", complexity as i32);
+                    }
+                }
+            }
+
+            if controls.has_changed().unwrap_or(false) {
+                let state = controls.borrow_and_update().clone();
+                if volume.property::<bool>("mute") != state.muted {
+                    volume.set_property("mute", state.muted);
+                    for (_, stem_volume) in &stems {
+                        stem_volume.set_property("mute", state.muted);
+                    }
+                    info!("Audio {}", if state.muted { "muted" } else { "unmuted" });
+                }
+                if opusenc.property::<i32>("bitrate") != state.bitrate as i32 {
+                    opusenc.set_property("bitrate", state.bitrate as i32);
+                    info!("Encoder bitrate set to {} kbps", state.bitrate / 1000);
+                }
+                if state.frame_size != frame_size {
+                    frame_size = state.frame_size;
+                    for encoder in std::iter::once(&opusenc).chain(stem_encoders.iter()) {
+                        switch_frame_size(encoder, frame_size);
+                    }
+                    if let Some(ref mut tuner) = tuner {
+                        tuner.set_frame_duration(Duration::from_millis(frame_size as u64));
+                    }
+                    let _ = pipeline.recalculate_latency();
+                    info!("Encoder frame size set to {} ms", frame_size);
+                }
+                if state.stopping && !eos_sent {
+                    info!("Stop requested, sending EOS");
+                    pipeline.send_event(gst::event::Eos::new());
+                    eos_sent = true;
+                }
+            }
+
+            let Some(msg) = bus.timed_pop(BUS_POLL) else {
+                continue;
+            };
+            use gst::MessageView;
+            match msg.view() {
+                MessageView::Eos(..) if eos_sent => {
+                    info!("GStreamer pipeline EOS");
+                    break;
+                }
+                MessageView::Eos(..) => {
+                    warn!("Capture ended unexpectedly (EOS); {}", config.on_eos.describe());
+                    events::emit(&events, Event::CaptureEnded { action: config.on_eos });
+                    match config.on_eos {
+                        EosPolicy::Stop => break,
+                        EosPolicy::Silence => {
+                            silence_from = Some(next_timestamp_us.load(Ordering::Relaxed));
+                            break;
+                        }
+                        EosPolicy::Restart | EosPolicy::Fail => {
+                            pipeline.set_state(gst::State::Null)?;
+                            return Err(capture::CaptureEnded.into());
+                        }
+                    }
+                }
+                MessageView::Error(err) => {
+                    pipeline.set_state(gst::State::Null)?;
+                    error!("GStreamer error: {} ({:?})", err.error(), err.debug());
+                    let lost = capture.sources.iter()
+                        .find(|(source, _)| msg.src() == Some(source.upcast_ref::<gst::Object>()));
+                    if let Some((_, device)) = lost {
+                        events::emit(&events, Event::DeviceLost {
+                            device: device.clone(),
+                            reason: err.error().to_string(),
+                        });
+                        return Err(anyhow::Error::new(capture::DeviceLost { device: device.clone() })
+                            .context(format!("GStreamer pipeline error: {}", err.error())));
+                    }
+                    events::emit(&events, Event::PipelineError { message: err.error().to_string() });
+                    return Err(anyhow::anyhow!("GStreamer pipeline error: {}", err.error()));
+                }
+                MessageView::Warning(warn_msg) => {
+                    warn!("GStreamer warning: {:?}", warn_msg.message());
+                    if capture.is_source(msg.src()) {
+                        stats.record_capture_warning();
+                    }
+                }
+                MessageView::Element(element) if msg.src() == Some(level.upcast_ref()) => {
+                    let Some(structure) = element.structure() else { continue };
+                    // Levels may only be measured for exit_after_silence; stay quiet then.
+                    let event = level_monitor.update(structure, controls.borrow().muted)
+                        .filter(|_| config.levels.enabled);
+                    if let Some(event) = event {
+                        match event {
+                            Event::ClippingDetected { peak_db } => {
+                                warn!("Sustained clipping at encoder input (peak {:.1} dBFS)", peak_db);
+                            }
+                            Event::SilenceDetected { duration } => {
+                                warn!("No audio at encoder input for {}s", duration.as_secs());
+                            }
+                            _ => info!("Input level: {}", event.summary()),
+                        }
+                        stats.set_levels(level_monitor.clipping(), level_monitor.silent());
+                        events::emit(&events, event);
+                    }
+                    if let Some(limit) = config.levels.exit_after_silence {
+                        if !eos_sent && level_monitor.quiet_for() >= limit {
+                            info!("No audio for {}s, stopping", limit.as_secs());
+                            events::emit(&events, Event::IdleTimeout { silence: limit });
+                            pipeline.send_event(gst::event::Eos::new());
+                            eos_sent = true;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        pipeline.set_state(gst::State::Null)?;
+        if let (Some(timestamp_us), Some(frames)) = (silence_from, frames) {
+            publish_silence(&frames, timestamp_us, config.audio.opus_streams(), &controls, &cancelled);
+        }
+        Ok(())
+    }
 
-        let opusenc = gst::ElementFactory::make("opusenc")
-            .property("bitrate", config.audio.bitrate as i32)
-            .property_from_str("audio-type", if config.audio.application == "voice" { "voice" } else { "generic" })
-            .property("complexity", config.audio.complexity as i32)
-            .property_from_str("frame-size", &config.audio.frame_size.to_string())
+    fn add_file_branch(pipeline: &gst::Pipeline, tee: &gst::Element, path: &std::path::Path) -> Result<()> {
+        let queue = gst::ElementFactory::make("queue").build()?;
+        let oggmux = gst::ElementFactory::make("oggmux").build()?;
+        let filesink = gst::ElementFactory::make("filesink")
+            .property("location", path.to_string_lossy().as_ref())
+            .property("sync", false)
             .build()?;
 
+        pipeline.add_many([&queue, &oggmux, &filesink])?;
+        gst::Element::link_many([tee, &queue, &oggmux, &filesink])?;
+
+        Ok(())
+    }
+
+    fn add_appsink_branch(
+        pipeline: &gst::Pipeline,
+        tee: &gst::Element,
+        sender: mpsc::Sender<Frame>,
+        stats: Arc<Stats>,
+    ) -> Result<()> {
+        Self::add_frame_sink(pipeline, tee, stats, move |frame| sender.blocking_send(frame).is_ok())
+    }
+
+    /// `queue ! appsink` after `tee`, handing each encoded packet to
+    /// `deliver`, which returns false once nobody is receiving.
+    fn add_frame_sink(
+        pipeline: &gst::Pipeline,
+        tee: &gst::Element,
+        stats: Arc<Stats>,
+        deliver: impl Fn(Frame) -> bool + Send + Sync + 'static,
+    ) -> Result<()> {
+        let queue = gst::ElementFactory::make("queue").build()?;
         let appsink = AppSink::builder()
             .sync(false)
             .build();
 
-        pipeline.add_many([
-            &pulsesrc, &capsfilter, &audioconvert,
-            &audioresample, &opusenc, appsink.upcast_ref(),
-        ])?;
-
-        gst::Element::link_many([
-            &pulsesrc, &capsfilter, &audioconvert,
-            &audioresample, &opusenc, appsink.upcast_ref(),
-        ])?;
-
-        let sender = frame_sender;
+        pipeline.add_many([&queue, appsink.upcast_ref()])?;
+        gst::Element::link_many([tee, &queue, appsink.upcast_ref()])?;
 
         appsink.set_callbacks(
             AppSinkCallbacks::builder()
@@ -197,6 +1477,13 @@ impl Pipe2Moq {
 
                     let pts = buffer.pts().unwrap_or(gst::ClockTime::ZERO);
                     let timestamp_us = pts.nseconds() / 1000;
+                    let duration_us = buffer.duration().map(|d| d.nseconds() / 1000).unwrap_or(0);
+                    // The PTS is the running time the first sample was
+                    // captured at; how far the sink's running time has
+                    // moved on since is how long ago that was.
+                    let captured = appsink.current_running_time()
+                        .and_then(|now| now.checked_sub(pts))
+                        .and_then(|age| Instant::now().checked_sub(Duration::from_nanos(age.nseconds())));
 
                     let size = buffer.size();
                     let mut data = Vec::with_capacity(size);
@@ -209,9 +1496,12 @@ impl Pipe2Moq {
                     }
 
                     let bytes = Bytes::from(data);
+                    stats.record_captured();
+                    stats.record_encoded(size, duration_us);
                     debug!("Sending Opus frame: {} bytes, timestamp {} μs", size, timestamp_us);
 
-                    if sender.blocking_send((bytes, timestamp_us)).is_err() {
+                    let frame = Frame { data: bytes, timestamp_us, duration_us, captured, target_playtime_ns: None };
+                    if !deliver(frame) {
                         error!("Failed to send frame to MoQ publisher");
                         return Err(gst::FlowError::Error);
                     }
@@ -221,54 +1511,226 @@ impl Pipe2Moq {
                 .build(),
         );
 
-        pipeline.set_state(gst::State::Playing)?;
+        Ok(())
+    }
 
-        let bus = pipeline.bus().expect("Pipeline without bus");
-        for msg in bus.iter_timed(gst::ClockTime::NONE) {
-            use gst::MessageView;
-            match msg.view() {
-                MessageView::Eos(..) => {
-                    info!("GStreamer pipeline EOS");
-                    break;
-                }
-                MessageView::Error(err) => {
-                    pipeline.set_state(gst::State::Null)?;
-                    error!("GStreamer error: {} ({:?})", err.error(), err.debug());
-                    return Err(anyhow::anyhow!("GStreamer pipeline error: {}", err.error()));
-                }
-                MessageView::Warning(warn_msg) => {
-                    warn!("GStreamer warning: {:?}", warn_msg.message());
+    async fn run_moq_publisher(
+        config: MoqConfig,
+        audio: AudioConfig,
+        frame_receiver: &mut mpsc::Receiver<Frame>,
+        events: EventSender,
+        stats: Arc<Stats>,
+        session: &mut PublisherSession,
+    ) -> Result<()> {
+        // A session ended for its token is followed straight away by one
+        // with a new token; the frames wait in the queue meanwhile.
+        loop {
+            match config.transport {
+                TransportKind::MoqLite => {
+                    Self::publish::<transport::MoqLite>(
+                        config.clone(), audio.clone(), frame_receiver, events.clone(), stats.clone(), session,
+                    ).await?
                 }
-                _ => (),
+            }
+            if !std::mem::take(&mut session.token_expiring) {
+                return Ok(());
             }
         }
+    }
 
-        pipeline.set_state(gst::State::Null)?;
-        Ok(())
+    /// Apply [`MoqConfig::on_conflict`] if someone already announces our path.
+    async fn resolve_conflict<T: Transport>(config: &mut MoqConfig) -> Result<()> {
+        let url = url::Url::parse(&config.relay_url)?;
+        let announced = match T::browse(&url, &config.broadcast_path, CONFLICT_CHECK_WAIT).await {
+            Ok(announced) => announced,
+            Err(e) => {
+                warn!("Could not check for existing broadcasts at {}: {e:#}", config.broadcast_path);
+                return Ok(());
+            }
+        };
+        let normalize = |path: &str| path.trim_start_matches('/').to_string();
+        let taken: Vec<String> = announced.iter()
+            .filter(|entry| entry.active)
+            .map(|entry| normalize(&entry.path))
+            .collect();
+        if !taken.contains(&normalize(&config.broadcast_path)) {
+            return Ok(());
+        }
+
+        match config.on_conflict {
+            ConflictPolicy::Fail => Err(anyhow::anyhow!(
+                "Broadcast {} is already announced on {}", config.broadcast_path, config.relay_url
+            )),
+            ConflictPolicy::Takeover => {
+                warn!("Broadcast {} is already announced; taking it over", config.broadcast_path);
+                Ok(())
+            }
+            ConflictPolicy::Alternate => {
+                let alternate = (2..)
+                    .map(|n| format!("{}-{}", config.broadcast_path, n))
+                    .find(|path| !taken.contains(&normalize(path)))
+                    .expect("finitely many announcements");
+                warn!("Broadcast {} is already announced; publishing as {}", config.broadcast_path, alternate);
+                config.broadcast_path = alternate;
+                Ok(())
+            }
+        }
     }
 
-    async fn run_moq_publisher(
-        config: MoqConfig,
-        frame_receiver: &mut mpsc::Receiver<(Bytes, u64)>,
+    async fn publish<T: Transport>(
+        mut config: MoqConfig,
+        audio: AudioConfig,
+        frame_receiver: &mut mpsc::Receiver<Frame>,
+        events: EventSender,
+        stats: Arc<Stats>,
+        session: &mut PublisherSession,
     ) -> Result<()> {
+        let PublisherSession {
+            next_sequence, canonical_path, frame_tap, script_objects, stems, stem_frames,
+            video, video_frames, video_position, backlog, controls, token_expiring,
+        } = session;
         info!("Creating MoQ origin for relay at {}", config.relay_url);
+        let rotation = controls.as_ref().map(|controls| controls.borrow().rotation);
+        config.broadcast_path = expand_timestamp(&config.broadcast_path, SystemTime::now());
 
-        let origin = moq_native::moq_lite::Origin::produce();
-        let client = moq_native::Client::new(moq_native::ClientConfig::default())?
-            .with_publish(origin.consumer);
-        let url = Url::parse(&config.relay_url)?;
-        let _session = client.connect(url).await?;
+        // Reported without the fetched token.
+        let relay_url = config.relay_url.clone();
+        let (mut broadcast, token_refresh) = backlog.hold(frame_receiver, stem_frames.as_mut(), async {
+            let token_refresh = match config.token {
+                Some(ref token) => {
+                    let (url, refresh) = token.authorize(&config.relay_url).await?;
+                    config.relay_url = url.to_string();
+                    refresh
+                }
+                None => None,
+            };
+            Self::resolve_conflict::<T>(&mut config).await?;
+            Ok::<_, anyhow::Error>((T::publish(&config).await?, token_refresh))
+        }).await?;
         info!("Connected to MoQ relay");
+        events::emit(&events, Event::RelayConnected { url: relay_url });
+        let token_refresh = token_refresh.map(|refresh| {
+            info!("Reconnecting with a new relay token in {}s", refresh.as_secs());
+            tokio::time::Instant::now() + refresh
+        });
+
+        let priorities = config.priorities;
+        let compression = config.metadata_compression;
+        if priorities.audio <= priorities.metadata {
+            warn!("Audio priority {} is not above metadata priority {}; clock samples may delay audio under congestion",
+                  priorities.audio, priorities.metadata);
+        }
+        // Receivers that only know the canonical path can find out which
+        // session broadcast they landed on.
+        let session_track = canonical_path.clone().filter(|canonical| *canonical != config.broadcast_path).map(|canonical| {
+            let mut track = broadcast.create_track(SESSION_TRACK, priorities.metadata);
+            track.write_group([compression.compress(Bytes::from(serde_json::json!({
+                "canonical_path": canonical,
+                "broadcast_path": config.broadcast_path,
+                "track": config.track_name,
+            }).to_string()))]);
+            track
+        });
 
-        let mut broadcast = origin.producer.create_broadcast(&config.broadcast_path)
-            .expect("Failed to create broadcast");
+        let mut track_producer = broadcast.create_track(&config.track_name, priorities.audio);
+        // One group per frame, numbered like the frames, so both continue
+        // where the previous session left off.
+        let mut sequence = next_sequence.load(Ordering::Relaxed);
+        // Frames dropped from the backlog leave a gap, like lost ones.
+        let dropped = backlog.take_dropped();
+        if dropped > 0 {
+            warn!("Dropped the oldest {} frames held while the relay was unreachable", dropped);
+            sequence += dropped;
+            next_sequence.store(sequence, Ordering::Relaxed);
+        }
+        if sequence > 0 {
+            info!("Continuing from frame sequence {}", sequence);
+        }
+        track_producer.resume_at(sequence);
 
-        let audio_track = moq_native::moq_lite::Track {
-            name: config.track_name.clone(),
-            priority: 1,
+        // Kept alive for the whole broadcast so late joiners can fetch it.
+        let mut catalog_track = if config.catalog || config.backend == PublisherBackend::Karp {
+            let mut catalog = Catalog::new(&audio, &config.track_name, priorities.audio);
+            if let Some(controls) = controls.as_ref() {
+                catalog = catalog.following(&controls.borrow());
+            }
+            if let Some(video) = video.as_ref() {
+                catalog = catalog.with_video(video, priorities.video);
+            }
+            let metadata: Vec<&str> = [
+                (session_track.is_some(), SESSION_TRACK),
+                (config.heartbeat_interval.is_some(), heartbeat::HEARTBEAT_TRACK),
+                (script_objects.is_some(), SCRIPT_TRACK),
+            ].into_iter().filter_map(|(published, track)| published.then_some(track)).collect();
+            catalog = catalog.with_metadata(&metadata, compression, priorities.metadata);
+            let catalog_track = CatalogTrack::new(broadcast.create_track(CATALOG_TRACK, priorities.metadata), catalog)?;
+            info!("Publishing catalog on {}", CATALOG_TRACK);
+            Some(catalog_track)
+        } else {
+            None
         };
+        let started = Instant::now();
+        let mut clock_track = config.clock_interval.map(|interval| {
+            info!("Publishing clock sync every {:?} on track {}", interval, clock::CLOCK_TRACK);
+            (broadcast.create_track(clock::CLOCK_TRACK, priorities.metadata), tokio::time::interval(interval))
+        });
+        let mut last_media_us = 0u64;
+        let mut heartbeat_track = config.heartbeat_interval.map(|interval| {
+            info!("Publishing heartbeat every {:?} on track {}", interval, heartbeat::HEARTBEAT_TRACK);
+            (broadcast.create_track(heartbeat::HEARTBEAT_TRACK, priorities.metadata), tokio::time::interval(interval))
+        });
+        let mut heartbeat_sequence = 0u64;
+        let mut last_frame_at: Option<Instant> = None;
 
-        let mut track_producer = broadcast.create_track(audio_track);
+        // Probes ride at audio priority so they see the delay audio sees.
+        let mut probe_track = config.probe_interval.map(|interval| {
+            info!("Probing relay delay every {:?} on track {}", interval, PROBE_TRACK);
+            (broadcast.create_track(PROBE_TRACK, priorities.audio), tokio::time::interval(interval))
+        });
+        let probe_loopback = {
+            let url = url::Url::parse(&config.relay_url);
+            let broadcast_path = config.broadcast_path.clone();
+            let stats = stats.clone();
+            async move {
+                let mut subscription = T::subscribe(&url?, &broadcast_path, PROBE_TRACK, Duration::from_secs(10)).await?;
+                while let Some(object) = subscription.next_object().await? {
+                    let Ok(sent_us) = <[u8; 8]>::try_from(object.as_ref()).map(u64::from_be_bytes) else { continue };
+                    let delay = started.elapsed().saturating_sub(Duration::from_micros(sent_us));
+                    debug!("Relay delay {:.1} ms", delay.as_secs_f64() * 1000.0);
+                    stats.set_relay_delay(delay);
+                }
+                Ok::<_, anyhow::Error>(())
+            }
+        };
+        tokio::pin!(probe_loopback);
+        let mut probing = probe_track.is_some();
+        // Its own receiver, since the rotation branch waits on `controls`.
+        let mut catalog_controls = controls.as_ref().filter(|_| catalog_track.is_some()).cloned();
+        // Just below the mix, so under congestion the full mix goes first.
+        let mut stem_tracks: Vec<_> = stems.iter().map(|app| {
+            let name = format!("{}.{}", config.track_name, app);
+            info!("Publishing {} stem on track {}", app, name);
+            (broadcast.create_track(&name, priorities.audio.saturating_sub(1)), 0u64)
+        }).collect();
+        // Below audio, so under congestion audio goes first.
+        let mut video_track = video.as_ref().map(|video| {
+            info!("Publishing video on track {}", video.track_name);
+            VideoTrack::new(broadcast.create_track(&video.track_name, priorities.video), video_position)
+        });
+        let mut script_track = script_objects.as_ref().map(|_| {
+            info!("Publishing script objects on track {}", SCRIPT_TRACK);
+            broadcast.create_track(SCRIPT_TRACK, priorities.metadata)
+        });
+
+        let mut pacer = config.max_bitrate.map(|max_bitrate| {
+            info!("Outbound rate capped at {} kbps", max_bitrate / 1000);
+            if audio.bitrate > max_bitrate {
+                warn!("Audio bitrate {} kbps exceeds the outbound cap of {} kbps",
+                      audio.bitrate / 1000, max_bitrate / 1000);
+            }
+            pacing::Pacer::new(max_bitrate)
+        });
+        let mut over_budget = false;
 
         let target_playtime_delay_ns = config.target_playtime_delay.map(|ms| ms * 1_000_000);
         if target_playtime_delay_ns.is_some() {
@@ -278,34 +1740,470 @@ impl Pipe2Moq {
         info!("Publishing broadcast {} with track {}",
               config.broadcast_path, config.track_name);
 
-        let mut frame_count = 0u64;
-        while let Some((data, _timestamp_us)) = frame_receiver.recv().await {
-            frame_count += 1;
-            if frame_count % 100 == 0 {
-                info!("Published {} frames", frame_count);
+        let (queue_high, queue_low) = config.frame_queue.thresholds();
+        let mut queue_backed_up = false;
+
+        let mut subscribers = None;
+        // Objects of the frames just published, for the next primed group.
+        let mut preroll: VecDeque<(u64, Bytes)> = VecDeque::new();
+        let mut prime = false;
+        stats.set_subscribers(None);
+
+        let mut path_reported = Instant::now();
+        loop {
+            let mut frame = tokio::select! {
+                frame = async {
+                    match backlog.pop() {
+                        Some(frame) => Some(frame),
+                        None => frame_receiver.recv().await,
+                    }
+                } => match frame {
+                    Some(frame) => frame,
+                    None => break,
+                },
+                err = broadcast.closed() => {
+                    warn!("MoQ session closed: {err}");
+                    events::emit(&events, Event::RelayDisconnected { reason: err.to_string() });
+                    return Err(err);
+                }
+                Some(track) = async {
+                    match clock_track.as_mut() {
+                        Some((track, interval)) => {
+                            interval.tick().await;
+                            Some(track)
+                        }
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    let sample = clock::ClockSample {
+                        monotonic_us: started.elapsed().as_micros() as u64,
+                        wall_ns: wall_clock_ns(),
+                        media_us: last_media_us,
+                    };
+                    let sample = sample.encode();
+                    if pacer.as_mut().is_none_or(|pacer| pacer.admit(sample.len())) {
+                        track.write_group([sample]);
+                    } else {
+                        stats.record_shed();
+                    }
+                    continue;
+                }
+                Some(track) = async {
+                    match heartbeat_track.as_mut() {
+                        Some((track, interval)) => {
+                            interval.tick().await;
+                            Some(track)
+                        }
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    // Not subject to the rate cap: it's tiny, and shedding
+                    // it would look exactly like the publisher going away.
+                    let snapshot = stats.snapshot();
+                    let beat = heartbeat::Heartbeat {
+                        sequence: heartbeat_sequence,
+                        wall_ns: wall_clock_ns(),
+                        frames_published: snapshot.frames_published,
+                        audio_age_ms: last_frame_at.map(|at| at.elapsed().as_millis() as u64),
+                        silent: snapshot.silent,
+                        muted: controls.as_ref().is_some_and(|controls| controls.borrow().muted),
+                    };
+                    heartbeat_sequence += 1;
+                    track.write_group([compression.compress(beat.encode())]);
+                    continue;
+                }
+                Some(track) = async {
+                    match probe_track.as_mut() {
+                        Some((track, interval)) => {
+                            interval.tick().await;
+                            Some(track)
+                        }
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    let sent_us = started.elapsed().as_micros() as u64;
+                    track.write_group([Bytes::copy_from_slice(&sent_us.to_be_bytes())]);
+                    continue;
+                }
+                Some((index, frame)) = async {
+                    match stem_frames.as_mut() {
+                        Some(frames) => frames.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    if let Some((track, stem_sequence)) = stem_tracks.get_mut(index) {
+                        let frame = silenced(frame, audio.opus_streams(), controls.as_ref());
+                        let object = stem_object(config.backend, config.frame_format, stem_sequence, frame);
+                        if pacer.as_mut().is_none_or(|pacer| pacer.admit(object.len())) {
+                            track.write_group([object]);
+                        } else {
+                            stats.record_shed();
+                        }
+                    }
+                    continue;
+                }
+                Some(frame) = async {
+                    match video_frames.as_mut() {
+                        Some(frames) => frames.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    if let Some(catalog_track) = catalog_track.as_mut().filter(|_| frame.keyframe) {
+                        catalog_track.keyframe(&frame.data)?;
+                    }
+                    let published = video_track.as_mut()
+                        .map(|track| track.publish(frame, config.frame_format, pacer.as_mut()));
+                    if published == Some(video::Published::Shed) {
+                        stats.record_shed();
+                    }
+                    continue;
+                }
+                Ok(()) = async {
+                    match catalog_controls.as_mut() {
+                        Some(controls) => controls.changed().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    if let Some((catalog_track, controls)) = catalog_track.as_mut().zip(catalog_controls.as_mut()) {
+                        catalog_track.follow(&controls.borrow_and_update())?;
+                    }
+                    continue;
+                }
+                Some(object) = async {
+                    match script_objects.as_mut() {
+                        Some(objects) => objects.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    if let Some(ref mut track) = script_track {
+                        let object = compression.compress(object);
+                        if pacer.as_mut().is_none_or(|pacer| pacer.admit(object.len())) {
+                            track.write_group([object]);
+                        } else {
+                            stats.record_shed();
+                        }
+                    }
+                    continue;
+                }
+                _ = async {
+                    match (controls.as_mut(), rotation) {
+                        (Some(controls), Some(rotation)) => {
+                            let rotated = controls.wait_for(|state| state.rotation != rotation).await.is_ok();
+                            if !rotated {
+                                std::future::pending::<()>().await;
+                            }
+                        }
+                        _ => std::future::pending().await,
+                    }
+                } => {
+                    info!("Rotating broadcast {}", config.broadcast_path);
+                    events::emit(&events, Event::BroadcastRotated { broadcast_path: config.broadcast_path.clone() });
+                    break;
+                }
+                _ = async {
+                    match token_refresh {
+                        Some(at) => tokio::time::sleep_until(at).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    info!("Relay token due for refresh; ending the session to reconnect with a new one");
+                    *token_expiring = true;
+                    break;
+                }
+                result = &mut probe_loopback, if probing => {
+                    match result {
+                        Ok(()) => warn!("Probe loopback ended; relay delay no longer measured"),
+                        Err(e) => warn!("Probe loopback failed, relay delay not measured: {e:#}"),
+                    }
+                    probing = false;
+                    continue;
+                }
+            };
+            last_media_us = frame.timestamp_us;
+            last_frame_at = Some(Instant::now());
+            frame = silenced(frame, audio.opus_streams(), controls.as_ref());
+
+            let queued = frame_receiver.len() + backlog.len();
+            stats.set_frame_queue(queued);
+            if !queue_backed_up && queued >= queue_high {
+                queue_backed_up = true;
+                warn!("Frame queue backing up: {} of {} frames ({:.0} ms) waiting to be published",
+                      queued, config.frame_queue.capacity, queued as f64 * frame.duration_us as f64 / 1000.0);
+                events::emit(&events, Event::FrameQueueHigh { queued, capacity: config.frame_queue.capacity });
+            } else if queue_backed_up && queued <= queue_low {
+                queue_backed_up = false;
+                info!("Frame queue drained");
+                events::emit(&events, Event::FrameQueueCleared);
             }
 
-            let frame_data = if let Some(delay_ns) = target_playtime_delay_ns {
-                let now_ns = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("System time before Unix epoch")
-                    .as_nanos() as i64;
-                let target_playtime = now_ns + delay_ns as i64;
+            if path_reported.elapsed() >= PATH_REPORT_INTERVAL {
+                path_reported = Instant::now();
+                let path = broadcast.path_stats();
+                if let Some(ref path) = path {
+                    info!("QUIC path: rtt {:.1} ms, cwnd {} bytes, {} lost of {} packets sent, {} congestion events",
+                          path.rtt.as_secs_f64() * 1000.0, path.cwnd,
+                          path.lost_packets, path.sent_packets, path.congestion_events);
+                }
+                stats.set_path(path);
+            }
 
-                let mut frame = BytesMut::with_capacity(8 + data.len());
-                frame.extend_from_slice(&target_playtime.to_be_bytes());
-                frame.extend_from_slice(&data);
-                frame.freeze()
-            } else {
-                data
+            let target_playtime_ns = target_playtime_delay_ns.map(|delay_ns| wall_clock_ns() + delay_ns as i64);
+
+            let capture = frame.captured.map(|captured| CaptureTime {
+                capture_us: signed_micros(started, captured),
+                publish_us: started.elapsed().as_micros() as i64,
+            });
+
+            let frame_data = audio_object(&config, sequence, frame.clone(), target_playtime_ns, capture);
+            let preroll_object = match config.preroll {
+                Preroll::Off => None,
+                Preroll::Silence => frame::opus_silence(frame.duration_us, audio.opus_streams()).map(|silence| {
+                    audio_object(&config, sequence, Frame { data: silence, ..frame.clone() }, target_playtime_ns, capture)
+                }),
+                Preroll::Buffered => Some(frame_data.clone()),
             };
+            if let Some(tap) = frame_tap {
+                let _ = tap.send(FrameInfo {
+                    sequence,
+                    timestamp_us: frame.timestamp_us,
+                    duration_us: frame.duration_us,
+                    bytes: frame_data.len(),
+                    capture,
+                });
+            }
+            sequence += 1;
+            next_sequence.store(sequence, Ordering::Relaxed);
+
+            let mut group_bytes = frame_data.len();
+            if prime {
+                group_bytes += preroll.iter().map(|(_, object)| object.len()).sum::<usize>();
+            }
+            if let Some(ref mut pacer) = pacer {
+                let within = pacer.charge(group_bytes);
+                if !within && !over_budget {
+                    warn!("Audio alone exceeds the outbound cap; shedding all other tracks");
+                }
+                over_budget = !within;
+            }
+
+            if prime {
+                prime = false;
+                debug!("Priming the group for a new subscriber with {} preroll objects", preroll.len());
+                track_producer.write_group(preroll.iter().map(|(_, object)| object.clone()).chain([frame_data]));
+            } else {
+                track_producer.write_group([frame_data]);
+            }
+            stats.record_published(group_bytes);
 
-            let mut group = track_producer.append_group();
-            group.write_frame(frame_data);
-            group.close();
+            if let Some(object) = preroll_object {
+                preroll.push_back((frame.duration_us, object));
+                let budget_us = config.preroll_duration.unwrap_or(config.preroll.default_duration()).as_micros() as u64;
+                while preroll.iter().map(|&(duration_us, _)| duration_us).sum::<u64>() > budget_us {
+                    preroll.pop_front();
+                }
+            }
+
+            let now_subscribed = track_producer.subscribers();
+            if now_subscribed.unwrap_or(0) > subscribers.unwrap_or(0) && config.preroll != Preroll::Off {
+                prime = true;
+            }
+            if now_subscribed != subscribers {
+                if let Some(count) = now_subscribed {
+                    info!("{} subscribers to track {}", count, config.track_name);
+                    events::emit(&events, Event::SubscribersChanged { subscribers: count });
+                }
+                stats.set_subscribers(now_subscribed);
+                subscribers = now_subscribed;
+            }
         }
 
+        // Stems and script objects already queued belong to the audio
+        // published so far.
+        if let Some(stem_frames) = stem_frames.as_mut() {
+            while let Ok((index, frame)) = stem_frames.try_recv() {
+                if let Some((track, stem_sequence)) = stem_tracks.get_mut(index) {
+                    track.write_group([stem_object(config.backend, config.frame_format, stem_sequence, frame)]);
+                }
+            }
+        }
+        if let (Some(objects), Some(track)) = (script_objects.as_mut(), script_track.as_mut()) {
+            while let Ok(object) = objects.try_recv() {
+                track.write_group([compression.compress(object)]);
+            }
+        }
+
+        debug!("Flushing the broadcast (up to {:?})", config.flush_timeout);
+        if tokio::time::timeout(config.flush_timeout, broadcast.flush()).await.is_err() {
+            warn!("Broadcast not flushed within {:?}; the relay may miss the last frames", config.flush_timeout);
+        }
         info!("MoQ publisher finished");
         Ok(())
     }
 }
+
+/// Switch a running opusenc to `frame_size` ms frames. The change is
+/// made from an idle probe, between two buffers, so no frame mixes the
+/// old and new size; the encoder then renegotiates its frame size with
+/// the base class and carries on with what it has buffered.
+fn switch_frame_size(encoder: &gst::Element, frame_size: u32) {
+    let Some(pad) = encoder.static_pad("sink") else { return };
+    let encoder = encoder.clone();
+    pad.add_probe(gst::PadProbeType::IDLE, move |_, _| {
+        encoder.set_property_from_str("frame-size", &frame_size.to_string());
+        gst::PadProbeReturn::Remove
+    });
+}
+
+/// Keep the audio track going with silence, continuing the timeline from
+/// `timestamp_us`, until the stream is stopped.
+fn publish_silence(
+    frames: &FrameFanout,
+    mut timestamp_us: u64,
+    streams: (u32, u32),
+    controls: &watch::Receiver<ControlState>,
+    cancelled: &tasks::Cancelled,
+) {
+    let mut deadline = Instant::now();
+    while !cancelled.is_set() && !controls.borrow().stopping {
+        let duration_us = controls.borrow().frame_size as u64 * 1000;
+        let Some(data) = frame::opus_silence(duration_us, streams) else { return };
+        frames.send_blocking(Frame { data, timestamp_us, duration_us, captured: None, target_playtime_ns: None });
+        timestamp_us += duration_us;
+        deadline += Duration::from_micros(duration_us);
+        std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// `frame`, or silence of the same length while muted. Muting silences
+/// the pipeline too, but frames queued before that still carry audio.
+fn silenced(mut frame: Frame, streams: (u32, u32), controls: Option<&watch::Receiver<ControlState>>) -> Frame {
+    if controls.is_some_and(|controls| controls.borrow().muted) {
+        if let Some(silence) = frame::opus_silence(frame.duration_us, streams) {
+            frame.data = silence;
+        }
+    }
+    frame
+}
+
+/// Microseconds from `started` to `at`, negative if `at` came first.
+fn signed_micros(started: Instant, at: Instant) -> i64 {
+    match at.checked_duration_since(started) {
+        Some(after) => after.as_micros() as i64,
+        None => -(started.duration_since(at).as_micros() as i64),
+    }
+}
+
+/// An audio frame as published on the audio track.
+fn audio_object(config: &MoqConfig, sequence: u64, frame: Frame, target_playtime_ns: Option<i64>, capture: Option<CaptureTime>) -> Bytes {
+    match config.frame_format {
+        _ if config.backend == PublisherBackend::Karp => karp::encode_frame(frame.timestamp_us, &frame.data),
+        FrameFormat::V1 => FrameHeader {
+            sequence,
+            timestamp_us: frame.timestamp_us,
+            duration_us: frame.duration_us as u32,
+            target_playtime_ns,
+            capture: capture.filter(|_| config.capture_time),
+        }
+        .encode(&frame.data),
+        FrameFormat::Raw => if let Some(target_playtime) = target_playtime_ns {
+            let mut object = BytesMut::with_capacity(8 + frame.data.len());
+            object.extend_from_slice(&target_playtime.to_be_bytes());
+            object.extend_from_slice(&frame.data);
+            object.freeze()
+        } else {
+            frame.data
+        },
+    }
+}
+
+/// A stem frame as published on its track, advancing the track's sequence.
+fn stem_object(backend: PublisherBackend, format: FrameFormat, sequence: &mut u64, frame: Frame) -> Bytes {
+    let object = match format {
+        _ if backend == PublisherBackend::Karp => karp::encode_frame(frame.timestamp_us, &frame.data),
+        FrameFormat::V1 => FrameHeader {
+            sequence: *sequence,
+            timestamp_us: frame.timestamp_us,
+            duration_us: frame.duration_us as u32,
+            target_playtime_ns: None,
+            capture: None,
+        }
+        .encode(&frame.data),
+        FrameFormat::Raw => frame.data,
+    };
+    *sequence += 1;
+    object
+}
+
+/// Substitute `{date}` (`YYYY-MM-DD`) and `{time}` (`HHMMSS`), in UTC.
+fn expand_timestamp(path: &str, now: SystemTime) -> String {
+    if !path.contains("{date}") && !path.contains("{time}") {
+        return path.to_string();
+    }
+    let secs = now.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, time) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    path.replace("{date}", &format!("{:04}-{:02}-{:02}", year, month, day))
+        .replace("{time}", &format!("{:02}{:02}{:02}", time / 3600, time % 3600 / 60, time % 60))
+}
+
+/// Short random id distinguishing this process's broadcasts.
+fn session_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_i64(wall_clock_ns());
+    hasher.write_u32(std::process::id());
+    format!("{:08x}", hasher.finish() as u32)
+}
+
+pub(crate) fn wall_clock_ns() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time before Unix epoch")
+        .as_nanos() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn expands_date_and_time() {
+        // 2026-10-14 09:30:00 UTC.
+        let now = at(1_791_970_200);
+        assert_eq!(expand_timestamp("/radio/{date}", now), "/radio/2026-10-14");
+        assert_eq!(expand_timestamp("/radio/{time}", now), "/radio/093000");
+        assert_eq!(expand_timestamp("/radio/{date}/{time}-{time}", now), "/radio/2026-10-14/093000-093000");
+    }
+
+    #[test]
+    fn expands_calendar_edges() {
+        assert_eq!(expand_timestamp("{date} {time}", at(0)), "1970-01-01 000000");
+        assert_eq!(expand_timestamp("{date} {time}", at(1_709_251_199)), "2024-02-29 235959");
+        assert_eq!(expand_timestamp("{date}", at(951_868_800)), "2000-03-01");
+        // Before the epoch reads as the epoch.
+        assert_eq!(expand_timestamp("{date}", UNIX_EPOCH - Duration::from_secs(1)), "1970-01-01");
+    }
+
+    #[test]
+    fn leaves_everything_else_literal() {
+        let now = at(1_791_970_200);
+        assert_eq!(expand_timestamp("/live/audio", now), "/live/audio");
+        // Not strftime: `%` sequences and unknown placeholders stay as written.
+        assert_eq!(expand_timestamp("/radio/%Y-%m-%d/100%", now), "/radio/%Y-%m-%d/100%");
+        assert_eq!(expand_timestamp("/radio/%Y/{date}/{hour}", now), "/radio/%Y/2026-10-14/{hour}");
+    }
+}
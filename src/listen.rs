@@ -0,0 +1,88 @@
+//! Local monitor: decode the stream exactly as it is published and play
+//! it to a local sink, so encoding artifacts are audible at the source.
+
+use anyhow::Result;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSrc;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::frame::Frame;
+use crate::playback::{self, PlaybackConfig};
+use crate::AudioConfig;
+
+/// Frames queued for the monitor before it starts dropping them. Kept
+/// short: a late monitor is worse than a glitching one.
+pub(crate) const LISTEN_QUEUE: usize = 10;
+
+/// `appsrc ! opusdec ! audioconvert ! audioresample ! volume ! pulsesink`.
+pub(crate) struct Listener {
+    pipeline: gst::Pipeline,
+    appsrc: AppSrc,
+}
+
+impl Listener {
+    pub(crate) fn new(audio: &AudioConfig, output: &PlaybackConfig) -> Result<Self> {
+        if audio.ambisonics || audio.channels > 2 {
+            anyhow::bail!("Listening locally is only supported for mono and stereo streams");
+        }
+        gst::init()?;
+        let pipeline = gst::Pipeline::default();
+
+        let caps = gst::Caps::builder("audio/x-opus")
+            .field("channel-mapping-family", 0i32)
+            .field("channels", audio.channels as i32)
+            .field("rate", audio.sample_rate as i32)
+            .build();
+        // Frames are timestamped on arrival: their capture timestamps
+        // belong to the publishing pipeline's clock, not this one's.
+        let appsrc = AppSrc::builder()
+            .caps(&caps)
+            .format(gst::Format::Time)
+            .is_live(true)
+            .do_timestamp(true)
+            .build();
+
+        let mut chain = vec![
+            appsrc.upcast_ref::<gst::Element>().clone(),
+            gst::ElementFactory::make("opusdec").property("plc", true).build()?,
+            gst::ElementFactory::make("audioconvert").build()?,
+            gst::ElementFactory::make("audioresample").build()?,
+        ];
+        chain.extend(playback::output_elements(output)?);
+        pipeline.add_many(&chain)?;
+        gst::Element::link_many(&chain)?;
+
+        pipeline.set_state(gst::State::Playing)?;
+        info!("Listening locally at volume {:.2}", output.volume);
+        Ok(Self { pipeline, appsrc })
+    }
+
+    /// Play frames until the stream ends. An output error stops the
+    /// monitor without affecting the stream.
+    pub(crate) async fn run(self, mut frames: mpsc::Receiver<Frame>) {
+        while let Some(frame) = frames.recv().await {
+            if let Some(msg) = self.pipeline.bus().and_then(|bus| bus.pop_filtered(&[gst::MessageType::Error])) {
+                if let gst::MessageView::Error(err) = msg.view() {
+                    warn!("Local monitor stopped: {}", err.error());
+                }
+                return;
+            }
+            let mut buffer = gst::Buffer::from_slice(frame.data);
+            buffer.get_mut()
+                .expect("new buffer is writable")
+                .set_duration(gst::ClockTime::from_useconds(frame.duration_us));
+            if self.appsrc.push_buffer(buffer).is_err() {
+                return;
+            }
+        }
+        let _ = self.appsrc.end_of_stream();
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
@@ -0,0 +1,106 @@
+//! Per-target instance locks, so a second pipe2moq (or a hung one that
+//! never exited) can't publish the same sink or broadcast alongside the
+//! first.
+//!
+//! Locks are `flock`s on files under `$XDG_RUNTIME_DIR/pipe2moq/`, so the
+//! kernel drops them when the holder dies, however it dies.
+
+use anyhow::Result;
+use nix::fcntl::{Flock, FlockArg};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, Write};
+use std::path::PathBuf;
+use tracing::debug;
+
+/// Something only one instance should publish at a time.
+#[derive(Clone, Debug)]
+pub enum LockTarget {
+    /// A capture sink, or `@DEFAULT_SINK@`.
+    Sink(String),
+    /// A broadcast path on a relay, with any URL query (tokens) removed.
+    Broadcast { relay: String, path: String },
+}
+
+impl LockTarget {
+    pub fn broadcast(relay_url: &str, path: &str) -> Self {
+        let relay = match url::Url::parse(relay_url) {
+            Ok(mut url) => {
+                url.set_query(None);
+                url.to_string()
+            }
+            Err(_) => relay_url.to_string(),
+        };
+        LockTarget::Broadcast { relay, path: path.trim_start_matches('/').to_string() }
+    }
+
+    fn file_name(&self) -> String {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        let kind = match self {
+            LockTarget::Sink(name) => {
+                name.hash(&mut hasher);
+                "sink"
+            }
+            LockTarget::Broadcast { relay, path } => {
+                relay.hash(&mut hasher);
+                path.hash(&mut hasher);
+                "broadcast"
+            }
+        };
+        format!("{}-{:016x}.lock", kind, hasher.finish())
+    }
+}
+
+impl std::fmt::Display for LockTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockTarget::Sink(name) => write!(f, "sink {}", name),
+            LockTarget::Broadcast { relay, path } => write!(f, "broadcast {} on {}", path, relay),
+        }
+    }
+}
+
+/// Held locks; dropping it releases them.
+pub struct InstanceLock {
+    _files: Vec<Flock<File>>,
+}
+
+/// Lock every target, failing with the holder's PID if another process
+/// already has one.
+pub fn acquire(targets: &[LockTarget]) -> Result<InstanceLock> {
+    let dir = lock_dir();
+    std::fs::create_dir_all(&dir)?;
+
+    let mut files = Vec::with_capacity(targets.len());
+    for target in targets {
+        let path = dir.join(target.file_name());
+        let file = File::options().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let mut file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(file) => file,
+            Err((mut file, nix::errno::Errno::EWOULDBLOCK)) => {
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                let holder = match holder.trim() {
+                    "" => String::new(),
+                    pid => format!(" (pid {})", pid),
+                };
+                anyhow::bail!("Another pipe2moq{} is already publishing {}; stop it or pass --force", holder, target);
+            }
+            Err((_, errno)) => anyhow::bail!("Failed to lock {}: {}", path.display(), errno),
+        };
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(*file, "{}", std::process::id())?;
+        debug!("Locked {} ({})", target, path.display());
+        files.push(file);
+    }
+    Ok(InstanceLock { _files: files })
+}
+
+fn lock_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .unwrap_or_else(std::env::temp_dir)
+        .join("pipe2moq")
+}
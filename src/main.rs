@@ -1,10 +1,17 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand, CommandFactory};
 use clap_complete::{generate, Shell};
-use figment2::{Figment, providers::{Env, Format, Toml}};
-use pipe2moq::{Pipe2Moq, PipelineConfig, AudioConfig, MoqConfig};
+use pipe2moq::{Pipe2Moq, CaptureBackend, Compression, ConflictPolicy, EosPolicy, FrameFormat, Preroll, PublisherBackend, RtpPayload, NoiseSuppression, TransportKind, VideoCodec, VideoSource};
+use pipe2moq::agent::AgentConfig;
+use pipe2moq::config::{ConfigFile, Settings};
+use pipe2moq::token::{TokenConfig, TokenSource};
 use tracing_subscriber::{EnvFilter, fmt};
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 
 #[derive(Parser, Debug)]
 #[command(name = "pipe2moq")]
@@ -13,8 +20,23 @@ struct Args {
     #[command(subcommand)]
     command: Option<Commands>,
 
-    #[arg(short, long, default_value = "config.toml")]
-    config: PathBuf,
+    /// Config file or http(s) URL; repeat to layer, later ones overriding earlier ones
+    /// [default: ./config.toml, then $XDG_CONFIG_HOME/pipe2moq/config.toml,
+    /// then /etc/pipe2moq/config.toml]
+    #[arg(short, long)]
+    config: Vec<PathBuf>,
+
+    /// Re-fetch remote configs every N seconds and restart when they change
+    #[arg(long)]
+    config_refresh: Option<u64>,
+
+    /// Restart when a local config file is saved, like SIGHUP
+    #[arg(long)]
+    watch: bool,
+
+    /// Start even if another instance is publishing the same sink or broadcast
+    #[arg(long)]
+    force: bool,
 
     #[arg(short, long)]
     relay_url: Option<String>,
@@ -28,6 +50,30 @@ struct Args {
     #[arg(long)]
     sink_name: Option<String>,
 
+    /// Capture through auto, pipewire or pulse
+    #[arg(long)]
+    capture_backend: Option<CaptureBackend>,
+
+    /// Capture whichever sink is playing audio, switching when playback moves
+    #[arg(long, action)]
+    follow_active_sink: bool,
+
+    /// Capture one application's playback (e.g. spotify), re-attaching when it restarts
+    #[arg(long, value_name = "APP")]
+    follow_app: Option<String>,
+
+    /// Also publish this application's playback on its own track; repeatable
+    #[arg(long = "stem", value_name = "APP")]
+    stems: Vec<String>,
+
+    /// Mix a microphone source into the captured desktop audio
+    #[arg(long)]
+    microphone: Option<String>,
+
+    /// Cancel desktop audio echoed into the microphone (mix mode)
+    #[arg(long, action)]
+    echo_cancel: bool,
+
     #[arg(long)]
     bitrate: Option<u32>,
 
@@ -37,17 +83,222 @@ struct Args {
     #[arg(long)]
     channels: Option<u32>,
 
-    #[arg(long)]
+    /// Capture first-order ambisonics (4 channels, ACN/SN3D)
+    #[arg(long, action)]
+    ambisonics: bool,
+
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=10))]
     complexity: Option<u32>,
 
+    /// Lower the complexity at runtime to encode within this share of real time (e.g. 0.5)
+    #[arg(long, value_name = "FRACTION")]
+    complexity_budget: Option<f64>,
+
+    /// Opus frame duration in ms: 5, 10, 20, 40 or 60 (others snap to the nearest)
     #[arg(long)]
     frame_size: Option<u32>,
 
+    /// Reject audio settings Opus can't encode instead of correcting them
+    #[arg(long, action)]
+    strict_audio: bool,
+
+    /// Opus application: voip/voice, audio/generic, or lowdelay
+    #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(pipe2moq::OPUS_APPLICATIONS))]
+    application: Option<String>,
+
+    /// Pick frame size, capture buffering and frame queue to meet this capture-to-publish latency in ms
+    #[arg(long, value_name = "MS")]
+    target_latency: Option<u64>,
+
+    /// Capture buffer size in μs (1000-500000)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1000..=500000))]
+    buffer_time: Option<u32>,
+
+    /// Capture period in μs (1000-200000); must not exceed --buffer-time
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1000..=200000))]
+    latency_time: Option<u32>,
+
     #[arg(long, action)]
     verbose: bool,
 
     #[arg(long)]
     target_playtime: Option<Option<u64>>,
+
+    /// Object layout: v1 (versioned header) or raw (legacy bare Opus)
+    #[arg(long)]
+    frame_format: Option<FrameFormat>,
+
+    /// Publisher backend: lite or karp
+    #[arg(long)]
+    backend: Option<PublisherBackend>,
+
+    /// Publish a clock-sync object every N milliseconds on the `clock` track
+    #[arg(long)]
+    clock_interval_ms: Option<u64>,
+
+    /// Publish a liveness object every N milliseconds on the `heartbeat` track
+    #[arg(long)]
+    heartbeat_interval_ms: Option<u64>,
+
+    /// Measure relay delay by looping a probe back through the relay every N milliseconds
+    #[arg(long)]
+    probe_interval_ms: Option<u64>,
+
+    /// Queue up to N milliseconds of encoded audio ahead of the publisher
+    #[arg(long)]
+    frame_queue_ms: Option<u64>,
+
+    /// Hold up to N seconds of audio while the relay is unreachable, dropping the oldest beyond that
+    #[arg(long)]
+    reconnect_buffer_secs: Option<u64>,
+
+    /// Read the relay token from this file on every connect
+    #[arg(long, conflicts_with = "token_command")]
+    token_file: Option<PathBuf>,
+
+    /// Run this shell command on every connect for a relay token
+    #[arg(long)]
+    token_command: Option<String>,
+
+    /// Publish under <broadcast-path>/<session id> to avoid collisions on shared relays
+    #[arg(long, action)]
+    session_suffix: bool,
+
+    /// If the broadcast path is already announced: fail, takeover or alternate
+    #[arg(long)]
+    on_conflict: Option<ConflictPolicy>,
+
+    /// Start a new subscriber's first group with off, silence or buffered audio
+    #[arg(long)]
+    preroll: Option<Preroll>,
+
+    /// Carry each frame's capture and publish time in its v1 header
+    #[arg(long, action)]
+    capture_time: bool,
+
+    /// Describe the tracks on a catalog.json track for hang players and moq-web
+    #[arg(long, action)]
+    catalog: bool,
+
+    /// Compress the session, heartbeat and script tracks: none, deflate or zstd (needs --catalog)
+    #[arg(long)]
+    metadata_compression: Option<Compression>,
+
+    /// Cap outbound bits per second; lower-priority tracks are shed to stay under it
+    #[arg(long)]
+    max_bitrate: Option<u32>,
+
+    /// MoQ library used to reach the relay
+    #[arg(long)]
+    transport: Option<TransportKind>,
+
+    #[arg(long, action)]
+    no_relay: bool,
+
+    /// Enable automatic gain control
+    #[arg(long, action)]
+    agc: bool,
+
+    /// High-pass cutoff in Hz for voice streams
+    #[arg(long)]
+    highpass: Option<u32>,
+
+    /// Noise suppression for voice streams: off, webrtc or rnnoise
+    #[arg(long)]
+    noise_suppression: Option<NoiseSuppression>,
+
+    /// Insert silence for audio missing from the capture device
+    #[arg(long, action)]
+    fill_gaps: bool,
+
+    /// When capture ends unexpectedly: stop, restart, silence or fail
+    #[arg(long)]
+    on_eos: Option<EosPolicy>,
+
+    /// Also publish a video track captured from pipewire, x11 or test
+    #[arg(long)]
+    video: Option<VideoSource>,
+
+    /// Video codec: h264 or av1 (implies --video with the configured source)
+    #[arg(long)]
+    video_codec: Option<VideoCodec>,
+
+    /// Write the encoded stream to an Ogg/Opus file instead of publishing to a relay
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    #[arg(long)]
+    rtp_address: Option<String>,
+
+    #[arg(long)]
+    rtp_port: Option<u16>,
+
+    #[arg(long)]
+    rtp_payload: Option<RtpPayload>,
+
+    /// Alert once this many xruns (capture gaps/discontinuities) accumulate
+    #[arg(long)]
+    max_xruns: Option<u64>,
+
+    /// Exit non-zero once --max-xruns is exceeded
+    #[arg(long, action)]
+    exit_on_xruns: bool,
+
+    /// POST a JSON report to this http:// URL once --max-xruns is exceeded
+    #[arg(long)]
+    xrun_webhook: Option<url::Url>,
+
+    /// POST stream state changes (started, stopped, disconnects, errors) as JSON to this http:// URL
+    #[arg(long)]
+    webhook: Option<url::Url>,
+
+    /// Stop cleanly after this many seconds without non-silent input
+    #[arg(long)]
+    exit_after_silence: Option<u64>,
+
+    /// Serve Prometheus metrics on this address (e.g. 127.0.0.1:9464)
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Seconds between stats log lines (0 turns them off, default 10)
+    #[arg(long, value_name = "SECS")]
+    stats_interval: Option<u64>,
+
+    /// Lua script to run alongside the stream (requires the `scripting` feature)
+    #[arg(long, value_name = "PATH")]
+    script: Option<PathBuf>,
+
+    /// Play the encoded stream back locally, optionally to this sink (name or regex)
+    #[arg(long, value_name = "SINK")]
+    listen: Option<Option<String>>,
+
+    /// Volume of the local playback (linear, default 0.3)
+    #[arg(long)]
+    listen_volume: Option<f64>,
+
+    /// Seconds to drain buffered audio after SIGTERM before giving up
+    #[arg(long)]
+    shutdown_grace: Option<u64>,
+
+    /// Milliseconds to wait for the last frames to reach the relay when the stream ends
+    #[arg(long)]
+    flush_timeout: Option<u64>,
+
+    /// Start muted, publishing silence until unmuted
+    #[arg(long, action)]
+    muted: bool,
+
+    /// Show desktop notifications when the stream breaks (requires the `notify` feature)
+    #[arg(long, action)]
+    notify: bool,
+
+    /// Show a system tray status icon (requires the `tray` feature)
+    #[arg(long, action)]
+    tray: bool,
+
+    /// Mute/unmute with desktop media keys via MPRIS (requires the `media-keys` feature)
+    #[arg(long, action)]
+    media_keys: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -57,62 +308,512 @@ enum Commands {
         #[arg(short, long)]
         shell: Shell,
     },
+    /// Generate a man page
+    Man,
+    /// Check that a relay is reachable, without touching the audio system
+    Ping {
+        /// Relay URL; defaults to the configured relay
+        #[arg(value_parser = pipe2moq::transport::relay_url)]
+        url: Option<url::Url>,
+
+        /// Also announce a throwaway broadcast at this path
+        #[arg(long)]
+        announce: Option<String>,
+
+        /// Print the report as JSON
+        #[arg(long, action)]
+        json: bool,
+    },
+    /// List broadcasts announced on a relay
+    Browse {
+        /// Relay URL; defaults to the configured relay
+        #[arg(value_parser = pipe2moq::transport::relay_url)]
+        url: Option<url::Url>,
+
+        /// Only show broadcasts whose path starts with this prefix
+        #[arg(long, default_value = "")]
+        prefix: String,
+
+        /// Seconds to collect announcements before printing
+        #[arg(long, default_value_t = 2)]
+        wait: u64,
+
+        /// Print the list as JSON
+        #[arg(long, action)]
+        json: bool,
+    },
+    /// Subscribe to a broadcast without playing it and print its health
+    Monitor {
+        /// Broadcast path to subscribe to
+        broadcast: String,
+
+        /// Relay URL; defaults to the configured relay
+        #[arg(long, value_parser = pipe2moq::transport::relay_url)]
+        url: Option<url::Url>,
+
+        /// Track to subscribe to; defaults to the configured track name
+        #[arg(long)]
+        track: Option<String>,
+
+        /// Seconds between reports
+        #[arg(long, default_value_t = 1)]
+        interval: u64,
+
+        /// Print one JSON object per report
+        #[arg(long, action)]
+        json: bool,
+    },
+    /// Subscribe to a broadcast and play it to a local sink
+    Play {
+        /// Broadcast path to subscribe to
+        broadcast: String,
+
+        /// Relay URL; defaults to the configured relay
+        #[arg(long, value_parser = pipe2moq::transport::relay_url)]
+        url: Option<url::Url>,
+
+        /// Track to subscribe to; defaults to the configured track name
+        #[arg(long)]
+        track: Option<String>,
+
+        /// Sink to play to (name or regex); defaults to the default sink
+        #[arg(long)]
+        sink: Option<String>,
+
+        /// Playback volume (linear)
+        #[arg(long, default_value_t = 1.0)]
+        volume: f64,
+
+        /// Audio to buffer before and during playout, in milliseconds
+        #[arg(long, default_value_t = 60)]
+        latency: u64,
+
+        /// Play each frame this many milliseconds after the sender captured
+        /// it, in step with other receivers whose clocks are synchronized
+        #[arg(long, value_name = "MS")]
+        sync: Option<u64>,
+    },
+    /// Republish a broadcast from one relay on another
+    Gateway {
+        /// Relay to subscribe to
+        #[arg(long, value_parser = pipe2moq::transport::relay_url)]
+        from: url::Url,
+
+        /// Broadcast path on the source relay
+        #[arg(long)]
+        from_broadcast: String,
+
+        /// Track on the source broadcast; defaults to the configured track name
+        #[arg(long)]
+        from_track: Option<String>,
+
+        /// Re-encode at this bitrate instead of forwarding packets untouched
+        #[arg(long)]
+        reencode_bitrate: Option<u32>,
+    },
+    /// Benchmark the Opus encoder on synthetic audio
+    Bench {
+        /// Encoder complexities to try
+        #[arg(long, value_delimiter = ',', default_values_t = [0, 5, 10])]
+        complexity: Vec<u32>,
+
+        /// Bitrates to try, in bits per second
+        #[arg(long = "bitrates", value_delimiter = ',', default_values_t = [32000, 96000, 192000])]
+        bitrates: Vec<u32>,
+
+        /// Seconds of audio to encode per run
+        #[arg(long, default_value_t = 10)]
+        seconds: u64,
+
+        /// Print the results as JSON
+        #[arg(long, action)]
+        json: bool,
+    },
+    /// Report compiled features, available GStreamer elements and supported MoQ modes
+    Capabilities {
+        /// Print the report as JSON
+        #[arg(long, action)]
+        json: bool,
+    },
+    /// List the sinks, monitors and microphones capture can use
+    ListDevices {
+        /// Print the devices as JSON
+        #[arg(long, action)]
+        json: bool,
+    },
+    /// Measure capture, encode and relay latency with audible chirps
+    Calibrate {
+        /// Sink to play the chirps to and capture back (name or regex); defaults to the configured sink
+        #[arg(long)]
+        sink: Option<String>,
+
+        /// Number of chirps to time
+        #[arg(long, default_value_t = 5)]
+        chirps: u32,
+
+        /// Chirp volume (linear)
+        #[arg(long, default_value_t = 0.5)]
+        volume: f64,
+
+        /// Also loop the encoded chirps through the configured relay
+        #[arg(long, action)]
+        relay: bool,
+
+        /// Print the report as JSON
+        #[arg(long, action)]
+        json: bool,
+    },
+    /// Supervise every stream defined in a directory of config files
+    Agent {
+        /// Directory of per-stream *.toml config files
+        #[arg(default_value = "/etc/pipe2moq/streams.d")]
+        dir: PathBuf,
+
+        /// Initial delay before restarting a stopped stream, in seconds
+        #[arg(long, default_value_t = 2)]
+        restart_delay: u64,
+
+        /// Seconds between aggregate status reports
+        #[arg(long, default_value_t = 30)]
+        status_interval: u64,
+
+        /// Write aggregate status as JSON to this file on every report
+        #[arg(long)]
+        status_file: Option<PathBuf>,
+    },
+    /// Publish every sink's monitor as its own broadcast ({sink} in the broadcast path)
+    AllSinks {
+        /// Skip sinks whose name matches this regex
+        #[arg(long)]
+        exclude: Option<String>,
+
+        /// Initial delay before restarting a stopped stream, in seconds
+        #[arg(long, default_value_t = 2)]
+        restart_delay: u64,
+
+        /// Seconds between aggregate status reports
+        #[arg(long, default_value_t = 30)]
+        status_interval: u64,
+
+        /// Write aggregate status as JSON to this file on every report
+        #[arg(long)]
+        status_file: Option<PathBuf>,
+    },
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct ConfigFile {
-    #[serde(default)]
-    relay: RelayConfig,
-    #[serde(default)]
-    audio: AudioFileConfig,
-    #[serde(default)]
-    pipeline: PipelineFileConfig,
+/// Command line flags take precedence over the config file and environment.
+fn apply_args(args: &Args, settings: &mut Settings) {
+    let pipeline = &mut settings.pipeline;
+    let moq = &mut settings.moq;
+
+    if let Some(ref url) = args.relay_url {
+        moq.relay_url = url.clone();
+    }
+    if let Some(ref path) = args.broadcast_path {
+        moq.broadcast_path = path.clone();
+    }
+    if let Some(ref name) = args.track_name {
+        moq.track_name = name.clone();
+    }
+    if args.session_suffix {
+        moq.session_suffix = true;
+    }
+    if let Some(policy) = args.on_conflict {
+        moq.on_conflict = policy;
+    }
+    if let Some(preroll) = args.preroll {
+        moq.preroll = preroll;
+    }
+    if args.capture_time {
+        moq.capture_time = true;
+    }
+    if args.catalog {
+        moq.catalog = true;
+    }
+    if let Some(compression) = args.metadata_compression {
+        moq.metadata_compression = compression;
+    }
+    let token_source = match (&args.token_file, &args.token_command) {
+        (_, Some(command)) => Some(TokenSource::Command(command.clone())),
+        (Some(path), None) => Some(TokenSource::File(path.clone())),
+        (None, None) => None,
+    };
+    if let Some(source) = token_source {
+        moq.token = Some(match moq.token.take() {
+            Some(token) => TokenConfig { source, ..token },
+            None => TokenConfig::new(source),
+        });
+    }
+    if args.no_relay || args.output.is_some() {
+        moq.enabled = false;
+    }
+    moq.target_playtime_delay = args.target_playtime.map(|v| v.unwrap_or(160));
+    if let Some(format) = args.frame_format {
+        moq.frame_format = format;
+    }
+    if let Some(backend) = args.backend {
+        moq.backend = backend;
+    }
+    if let Some(transport) = args.transport {
+        moq.transport = transport;
+    }
+    if let Some(bps) = args.max_bitrate {
+        moq.max_bitrate = (bps > 0).then_some(bps);
+    }
+    if let Some(ms) = args.clock_interval_ms {
+        moq.clock_interval = (ms > 0).then(|| Duration::from_millis(ms));
+    }
+    if let Some(ms) = args.heartbeat_interval_ms {
+        moq.heartbeat_interval = (ms > 0).then(|| Duration::from_millis(ms));
+    }
+    if let Some(ms) = args.probe_interval_ms {
+        moq.probe_interval = (ms > 0).then(|| Duration::from_millis(ms));
+    }
+
+    if let Some(secs) = args.exit_after_silence {
+        pipeline.levels.exit_after_silence = (secs > 0).then(|| Duration::from_secs(secs));
+    }
+
+    if let Some(sample_rate) = args.sample_rate {
+        pipeline.audio.sample_rate = sample_rate;
+    }
+    if args.ambisonics {
+        pipeline.audio.ambisonics = true;
+        pipeline.audio.channels = pipe2moq::AMBISONIC_CHANNELS;
+    }
+    if let Some(channels) = args.channels {
+        pipeline.audio.channels = channels;
+    }
+    if let Some(bitrate) = args.bitrate {
+        pipeline.audio.bitrate = bitrate;
+    }
+    if let Some(complexity) = args.complexity {
+        pipeline.audio.complexity = complexity;
+    }
+    if let Some(budget) = args.complexity_budget {
+        pipeline.audio.auto_complexity = Some(pipe2moq::AutoComplexity {
+            budget,
+            ..pipeline.audio.auto_complexity.unwrap_or_default()
+        });
+    }
+    if args.strict_audio {
+        settings.strict_audio = true;
+    }
+    if let Some(frame_size) = args.frame_size {
+        pipeline.audio.frame_size = frame_size;
+    }
+    if let Some(ms) = args.frame_queue_ms {
+        moq.frame_queue.capacity = ms.div_ceil(pipeline.audio.frame_size.max(1) as u64) as usize;
+    }
+    if let Some(secs) = args.reconnect_buffer_secs {
+        moq.frame_queue.reconnect_buffer = (secs > 0).then(|| Duration::from_secs(secs));
+    }
+    if let Some(ref application) = args.application {
+        pipeline.audio.application = application.clone();
+    }
+    if let Some(ms) = args.target_latency {
+        settings.target_latency = Some(Duration::from_millis(ms));
+    }
+    if let Some(buffer_time) = args.buffer_time {
+        pipeline.buffer_time = buffer_time;
+    }
+    if let Some(latency_time) = args.latency_time {
+        pipeline.latency_time = latency_time;
+    }
+
+    if args.sink_name.is_some() {
+        pipeline.sink_name = args.sink_name.clone();
+    }
+    if let Some(backend) = args.capture_backend {
+        pipeline.capture_backend = backend;
+    }
+    if args.follow_active_sink {
+        pipeline.follow_active_sink = true;
+    }
+    if args.follow_app.is_some() {
+        pipeline.follow_app = args.follow_app.clone();
+    }
+    if !args.stems.is_empty() {
+        pipeline.stems = args.stems.clone();
+    }
+    if args.microphone.is_some() {
+        pipeline.microphone = args.microphone.clone();
+    }
+    if args.output.is_some() {
+        pipeline.output_file = args.output.clone();
+    }
+
+    if args.fill_gaps {
+        pipeline.fill_gaps = true;
+    }
+    if let Some(policy) = args.on_eos {
+        pipeline.on_eos = policy;
+    }
+    if let Some(source) = args.video {
+        pipeline.video.get_or_insert_with(Default::default).source = source;
+    }
+    if let Some(codec) = args.video_codec {
+        pipeline.video.get_or_insert_with(Default::default).codec = codec;
+    }
+
+    if let Some(ref address) = args.rtp_address {
+        pipeline.rtp.enabled = true;
+        pipeline.rtp.address = address.clone();
+    }
+    if let Some(port) = args.rtp_port {
+        pipeline.rtp.port = port;
+    }
+    if let Some(payload) = args.rtp_payload {
+        pipeline.rtp.payload = payload;
+    }
+
+    if args.agc {
+        pipeline.processing.agc = true;
+    }
+    if let Some(noise_suppression) = args.noise_suppression {
+        pipeline.processing.noise_suppression = noise_suppression;
+    }
+    if args.echo_cancel {
+        pipeline.processing.echo_cancel = true;
+    }
+    if args.highpass.is_some() {
+        pipeline.processing.highpass_hz = args.highpass;
+    }
+
+    if let Some(grace) = args.shutdown_grace {
+        settings.shutdown_grace = Duration::from_secs(grace);
+    }
+    if let Some(ms) = args.flush_timeout {
+        moq.flush_timeout = Duration::from_millis(ms);
+    }
+
+    if let Some(secs) = args.stats_interval {
+        settings.stats_log_interval = (secs > 0).then(|| Duration::from_secs(secs));
+    }
+    if args.metrics_addr.is_some() {
+        settings.metrics_addr = args.metrics_addr;
+    }
+
+    if args.script.is_some() {
+        settings.script = args.script.clone();
+    }
+
+    if let Some(ref sink) = args.listen {
+        let listen = settings.listen.get_or_insert_with(|| pipe2moq::playback::PlaybackConfig {
+            sink: None,
+            volume: pipe2moq::playback::LISTEN_VOLUME,
+        });
+        if sink.is_some() {
+            listen.sink = sink.clone();
+        }
+    }
+    if let (Some(volume), Some(listen)) = (args.listen_volume, settings.listen.as_mut()) {
+        listen.volume = volume;
+    }
+
+    if args.max_xruns.is_some() {
+        settings.alerts.max_xruns = args.max_xruns;
+    }
+    if args.exit_on_xruns {
+        settings.alerts.exit_on_threshold = true;
+    }
+    if args.xrun_webhook.is_some() {
+        settings.alerts.webhook_url = args.xrun_webhook.clone();
+    }
+    if let Some(ref url) = args.webhook {
+        match settings.webhook {
+            Some(ref mut webhook) => webhook.url = url.clone(),
+            None => settings.webhook = Some(pipe2moq::webhook::WebhookConfig::new(url.clone())),
+        }
+    }
 }
 
-#[derive(Debug, serde::Deserialize, Default)]
-struct RelayConfig {
-    #[serde(default)]
-    url: String,
-    #[serde(default)]
-    broadcast_path: String,
-    #[serde(default)]
-    track_name: String,
+async fn sigterm() {
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            sigterm.recv().await;
+        }
+        Err(e) => {
+            tracing::warn!("Failed to install SIGTERM handler: {e}");
+            std::future::pending::<()>().await;
+        }
+    }
 }
 
-#[derive(Debug, serde::Deserialize, Default)]
-struct AudioFileConfig {
-    #[serde(default)]
-    sample_rate: Option<u32>,
-    #[serde(default)]
-    channels: Option<u32>,
-    #[serde(default)]
-    bitrate: Option<u32>,
-    #[serde(default)]
-    application: Option<String>,
-    #[serde(default)]
-    complexity: Option<u32>,
-    #[serde(default)]
-    frame_size: Option<u32>,
+/// Resolves on SIGTERM, or on SIGINT (Ctrl-C).
+async fn terminated() {
+    tokio::select! {
+        _ = sigterm() => {}
+        _ = interrupted(1) => {}
+    }
 }
 
-#[derive(Debug, serde::Deserialize, Default)]
-struct PipelineFileConfig {
-    #[serde(default)]
-    buffer_time: Option<u32>,
-    #[serde(default)]
-    latency_time: Option<u32>,
-    #[serde(default)]
-    sink_name: Option<String>,
+/// Fails on a second SIGINT, for giving up on a drain the first one started.
+async fn interrupted_again() -> Result<()> {
+    interrupted(2).await;
+    Err(anyhow::anyhow!("Interrupted again; stopped without finishing the drain"))
+}
+
+/// Resolves on the `count`th SIGINT. Once the handler is installed,
+/// SIGINT no longer ends the process by itself.
+async fn interrupted(count: usize) {
+    for _ in 0..count {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::warn!("Failed to install SIGINT handler: {e}");
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+async fn sighup() {
+    match signal(SignalKind::hangup()) {
+        Ok(mut sighup) => {
+            sighup.recv().await;
+        }
+        Err(e) => {
+            tracing::warn!("Failed to install SIGHUP handler: {e}");
+            std::future::pending::<()>().await;
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    if let Some(Commands::Completions { shell }) = args.command {
-        let mut cmd = Args::command();
-        generate(shell, &mut cmd, "pipe2moq", &mut std::io::stdout());
-        return Ok(());
+    match args.command {
+        Some(Commands::Completions { shell }) => {
+            let mut cmd = Args::command();
+            generate(shell, &mut cmd, "pipe2moq", &mut std::io::stdout());
+            return Ok(());
+        }
+        Some(Commands::Man) => {
+            let man = clap_mangen::Man::new(Args::command());
+            man.render(&mut std::io::stdout())?;
+            return Ok(());
+        }
+        Some(Commands::Capabilities { json }) => {
+            let capabilities = pipe2moq::capabilities::detect();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&capabilities)?);
+            } else {
+                print!("{}", capabilities);
+            }
+            if !capabilities.ok() {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Commands::ListDevices { json }) => {
+            let devices = pipe2moq::devices::list().await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&devices)?);
+            } else {
+                print!("{}", pipe2moq::devices::render(&devices));
+            }
+            return Ok(());
+        }
+        _ => {}
     }
 
     let filter = if args.verbose {
@@ -123,54 +824,396 @@ async fn main() -> Result<()> {
             .add_directive("gstreamer=warn".parse()?)
     };
 
+    #[cfg(feature = "console")]
+    {
+        use tracing_subscriber::prelude::*;
+        // tokio-console connects to 127.0.0.1:6669 by default.
+        tracing_subscriber::registry()
+            .with(console_subscriber::spawn())
+            .with(fmt::layer().with_filter(filter))
+            .init();
+    }
+    #[cfg(not(feature = "console"))]
     fmt()
         .with_env_filter(filter)
         .init();
 
-    let config: ConfigFile = Figment::new()
-        .merge(Toml::file(args.config))
-        .merge(Env::prefixed("PIPE2MOQ_"))
-        .extract()?;
-
-    let relay_url = args.relay_url
-        .or_else(|| if config.relay.url.is_empty() { None } else { Some(config.relay.url) })
-        .unwrap_or_else(|| "https://localhost:4443/anon".to_string());
-
-    let broadcast_path = args.broadcast_path
-        .or_else(|| if config.relay.broadcast_path.is_empty() { None } else { Some(config.relay.broadcast_path) })
-        .unwrap_or_else(|| "/live/audio".to_string());
-
-    let track_name = args.track_name
-        .or_else(|| if config.relay.track_name.is_empty() { None } else { Some(config.relay.track_name) })
-        .unwrap_or_else(|| "audio".to_string());
-
-    let audio = AudioConfig {
-        sample_rate: args.sample_rate.or(config.audio.sample_rate).unwrap_or(48000),
-        channels: args.channels.or(config.audio.channels).unwrap_or(2),
-        bitrate: args.bitrate.or(config.audio.bitrate).unwrap_or(96000),
-        application: config.audio.application.unwrap_or_else(|| "voip".to_string()),
-        complexity: args.complexity.or(config.audio.complexity).unwrap_or(5),
-        frame_size: config.audio.frame_size.unwrap_or(20),
+    if let Some(Commands::Agent { dir, restart_delay, status_interval, status_file }) = args.command {
+        let defaults = AgentConfig::default();
+        let agent = AgentConfig {
+            config_dir: dir,
+            restart_delay: Duration::from_secs(restart_delay),
+            status_interval: Duration::from_secs(status_interval),
+            status_file,
+            metrics_addr: args.metrics_addr,
+            shutdown_grace: args.shutdown_grace.map(Duration::from_secs).unwrap_or(defaults.shutdown_grace),
+            ..defaults
+        };
+        return tokio::select! {
+            result = pipe2moq::agent::run(agent, terminated()) => result,
+            result = interrupted_again() => result,
+        };
+    }
+
+    let mut config_paths = Vec::new();
+    let mut remote_configs = Vec::new();
+    let mut local_configs = Vec::new();
+    if args.config.is_empty() {
+        config_paths.extend(pipe2moq::config::default_path());
+    }
+    for path in &args.config {
+        match pipe2moq::config::remote_url(path) {
+            Some(url) => {
+                config_paths.push(pipe2moq::config::fetch_remote(&url).await?);
+                remote_configs.push(url);
+            }
+            None => {
+                config_paths.push(path.clone());
+                local_configs.push(path.clone());
+            }
+        }
+    }
+    if args.config.is_empty() {
+        local_configs.clone_from(&config_paths);
+    }
+    for path in &config_paths {
+        tracing::debug!("Loading config from {}", path.display());
+    }
+    let mut settings = ConfigFile::load(&config_paths)?.into_settings();
+    apply_args(&args, &mut settings);
+    settings.validate()?;
+
+    if let Some(Commands::Ping { url, announce, json }) = args.command {
+        let url = match url {
+            Some(url) => url,
+            None => pipe2moq::token::relay_url(&settings.moq).await?,
+        };
+        let report = pipe2moq::ping::ping(&url, settings.moq.transport, announce).await?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print!("{}", report);
+        }
+        if !report.ok() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(Commands::Monitor { broadcast, url, track, interval, json }) = args.command {
+        let url = match url {
+            Some(url) => url,
+            None => pipe2moq::token::relay_url(&settings.moq).await?,
+        };
+        let track = track.unwrap_or(settings.moq.track_name);
+        return pipe2moq::monitor::monitor(
+            &url, settings.moq.transport, &broadcast, &track, Duration::from_secs(interval.max(1)),
+            |report| if json {
+                println!("{}", serde_json::to_string(&report).unwrap_or_default());
+            } else {
+                println!("{}", report);
+            },
+        ).await;
+    }
+
+    if let Some(Commands::Bench { complexity, bitrates, seconds, json }) = args.command {
+        let bench = pipe2moq::bench::BenchConfig {
+            audio: settings.pipeline.audio,
+            duration: Duration::from_secs(seconds.max(1)),
+            complexities: complexity,
+            bitrates,
+        };
+        let results = tokio::task::spawn_blocking(move || {
+            pipe2moq::bench::run(&bench, |result| if !json {
+                println!("{}", result);
+            })
+        })
+        .await??;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&results)?);
+        }
+        return Ok(());
+    }
+
+    if let Some(Commands::Calibrate { sink, chirps, volume, relay, json }) = args.command {
+        let transport = settings.moq.transport;
+        let calibration = pipe2moq::calibrate::CalibrationConfig {
+            audio: settings.pipeline.audio,
+            output: pipe2moq::playback::PlaybackConfig {
+                sink: sink.or(settings.pipeline.sink_name),
+                volume,
+            },
+            chirps: chirps.max(1),
+            relay: relay.then_some(settings.moq),
+        };
+        let report = pipe2moq::calibrate::run(calibration, transport).await?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print!("{}", report);
+        }
+        if report.detected == 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(Commands::Play { broadcast, url, track, sink, volume, latency, sync }) = args.command {
+        let url = match url {
+            Some(url) => url,
+            None => pipe2moq::token::relay_url(&settings.moq).await?,
+        };
+        let target_latency = Duration::from_millis(latency);
+        let jitter = pipe2moq::jitter::JitterConfig::default();
+        let player = pipe2moq::Moq2Pipe::new(pipe2moq::PlayConfig {
+            url,
+            broadcast,
+            track: track.unwrap_or(settings.moq.track_name),
+            transport: settings.moq.transport,
+            backend: settings.moq.backend,
+            audio: settings.pipeline.audio,
+            output: pipe2moq::playback::PlaybackConfig { sink, volume },
+            jitter: pipe2moq::jitter::JitterConfig {
+                target_latency,
+                max_latency: jitter.max_latency.max(target_latency * 2),
+                ..jitter
+            },
+            sync: sync.map(Duration::from_millis),
+        });
+        return tokio::select! {
+            result = player.run() => result,
+            _ = terminated() => Ok(()),
+        };
+    }
+
+    if let Some(Commands::Gateway { from, from_broadcast, from_track, reencode_bitrate }) = args.command {
+        let gateway = pipe2moq::gateway::GatewayConfig {
+            source_url: from,
+            source_broadcast: from_broadcast,
+            source_track: from_track.unwrap_or_else(|| settings.moq.track_name.clone()),
+            source_transport: settings.moq.transport,
+            audio: settings.pipeline.audio,
+            bitrate: reencode_bitrate,
+        };
+        let gateway = pipe2moq::gateway::Gateway::new(gateway, settings.moq);
+        return tokio::select! {
+            result = gateway.run() => result,
+            _ = terminated() => Ok(()),
+        };
+    }
+
+    if let Some(Commands::Browse { url, prefix, wait, json }) = args.command {
+        let url = match url {
+            Some(url) => url,
+            None => pipe2moq::token::relay_url(&settings.moq).await?,
+        };
+        let broadcasts = pipe2moq::ping::browse(&url, settings.moq.transport, &prefix, Duration::from_secs(wait)).await?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&broadcasts)?);
+        } else if broadcasts.is_empty() {
+            println!("No broadcasts announced under '{}'", prefix);
+        } else {
+            for broadcast in &broadcasts {
+                let state = if broadcast.active { "" } else { " (ended)" };
+                println!("{}{}", broadcast.path, state);
+                match broadcast.tracks {
+                    Some(ref tracks) => tracks.iter().for_each(|track| println!("  {}", track)),
+                    None if broadcast.active => println!("  (no catalog)"),
+                    None => {}
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(Commands::AllSinks { exclude, restart_delay, status_interval, status_file }) = args.command {
+        let exclude = exclude.map(|pattern| regex::Regex::new(&pattern)).transpose()?;
+        let defaults = AgentConfig::default();
+        let agent = AgentConfig {
+            restart_delay: Duration::from_secs(restart_delay),
+            status_interval: Duration::from_secs(status_interval),
+            status_file,
+            metrics_addr: settings.metrics_addr,
+            shutdown_grace: settings.shutdown_grace,
+            ..defaults
+        };
+        return tokio::select! {
+            result = pipe2moq::agent::run_all_sinks(agent, settings, exclude, terminated()) => result,
+            result = interrupted_again() => result,
+        };
+    }
+
+    let _lock = if args.force {
+        None
+    } else {
+        Some(pipe2moq::lock::acquire(&lock_targets(&settings))?)
     };
 
-    let sink_name = args.sink_name.or(config.pipeline.sink_name);
-    let buffer_time = config.pipeline.buffer_time.unwrap_or(20000);
-    let latency_time = config.pipeline.latency_time.unwrap_or(10000);
+    let notify = args.notify || settings.notifications;
+
+    let mut app = Pipe2Moq::new(settings.pipeline, settings.moq)
+        .with_alerts(settings.alerts)
+        .with_retry(settings.retry)
+        .with_stats_log(settings.stats_log_interval);
+    if let Some(script) = settings.script {
+        app = app.with_script(script);
+    }
+    if let Some(listen) = settings.listen {
+        app = app.with_listen(listen);
+    }
+    if args.muted {
+        app.controller().mute();
+    }
+
+    if notify {
+        #[cfg(feature = "notify")]
+        pipe2moq::tasks::spawn("notify", pipe2moq::notify::run(app.subscribe()));
+        #[cfg(not(feature = "notify"))]
+        tracing::warn!("Desktop notifications requested but pipe2moq was built without the `notify` feature");
+    }
+
+    if args.tray {
+        #[cfg(feature = "tray")]
+        {
+            let broadcast_url = format!("{}{}",
+                app.moq_config().relay_url.trim_end_matches('/'),
+                app.moq_config().broadcast_path);
+            pipe2moq::tasks::spawn("tray", pipe2moq::tray::run(app.controller(), broadcast_url, app.subscribe()));
+        }
+        #[cfg(not(feature = "tray"))]
+        tracing::warn!("Tray icon requested but pipe2moq was built without the `tray` feature");
+    }
+
+    if args.media_keys {
+        #[cfg(feature = "media-keys")]
+        pipe2moq::media_keys::spawn(app.controller())?;
+        #[cfg(not(feature = "media-keys"))]
+        tracing::warn!("Media keys requested but pipe2moq was built without the `media-keys` feature");
+    }
+
+    // Kept to let the final events go out before exiting.
+    let webhook = settings.webhook.map(|webhook| {
+        let broadcast_path = app.moq_config().broadcast_path.clone();
+        pipe2moq::tasks::spawn("webhook", pipe2moq::webhook::run(webhook, broadcast_path, app.stats(), app.subscribe()))
+    });
+
+    if let Some(addr) = settings.metrics_addr {
+        let stats = app.stats();
+        pipe2moq::tasks::spawn("metrics", async move {
+            if let Err(e) = pipe2moq::metrics::serve(addr, stats).await {
+                tracing::error!("Metrics endpoint failed: {e}");
+            }
+        });
+    }
+
+    let terminal = pipe2moq::hotkeys::spawn(app.controller(), app.stats());
+
+    pipe2moq::tasks::spawn("rotate", {
+        let controller = app.controller();
+        async move {
+            match signal(SignalKind::user_defined1()) {
+                Ok(mut sigusr1) => {
+                    while sigusr1.recv().await.is_some() {
+                        tracing::info!("SIGUSR1 received, rotating the broadcast");
+                        controller.rotate();
+                    }
+                }
+                Err(e) => tracing::warn!("Failed to install SIGUSR1 handler: {e}"),
+            }
+        }
+    });
 
-    let pipeline_config = PipelineConfig {
-        audio,
-        buffer_time,
-        latency_time,
-        sink_name,
+    let reload = Arc::new(AtomicBool::new(false));
+    let shutdown = {
+        let reload = reload.clone();
+        let refresh = args.config_refresh.filter(|_| !remote_configs.is_empty());
+        let watch = args.watch && !local_configs.is_empty();
+        if args.watch && !watch {
+            tracing::warn!("--watch given but no local config file is loaded");
+        }
+        async move {
+            let changed = async {
+                match refresh {
+                    Some(secs) => pipe2moq::config::watch_remote(remote_configs, Duration::from_secs(secs.max(1))).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let edited = async {
+                if watch {
+                    match pipe2moq::config::watch_local(local_configs).await {
+                        Ok(()) => return,
+                        Err(e) => tracing::warn!("Not watching config files: {e:#}"),
+                    }
+                }
+                std::future::pending().await
+            };
+            tokio::select! {
+                _ = terminated() => {}
+                _ = changed => reload.store(true, Ordering::Relaxed),
+                _ = edited => reload.store(true, Ordering::Relaxed),
+                _ = sighup() => {
+                    tracing::info!("SIGHUP received, reloading config");
+                    reload.store(true, Ordering::Relaxed);
+                }
+            }
+        }
     };
 
-    let moq_config = MoqConfig {
-        relay_url,
-        broadcast_path,
-        track_name,
-        target_playtime_delay: args.target_playtime.map(|v| v.unwrap_or(160)),
+    let stats = app.stats();
+    let result = tokio::select! {
+        result = app.run_until(shutdown, settings.shutdown_grace) => result,
+        result = interrupted_again() => result,
     };
+    if result.is_ok() {
+        print!("{}", stats.snapshot().summary());
+    }
+    if let Some(webhook) = webhook {
+        // Closes the event stream, so the webhook stops after what's queued.
+        drop(app);
+        if tokio::time::timeout(pipe2moq::webhook::FLUSH_TIMEOUT, webhook).await.is_err() {
+            tracing::warn!("Gave up delivering webhook events after {:?}", pipe2moq::webhook::FLUSH_TIMEOUT);
+        }
+    }
+    let restart_requested = result.as_ref().err()
+        .and_then(|e| e.downcast_ref::<pipe2moq::retry::RestartRequested>());
+    if let Some(requested) = restart_requested {
+        tracing::error!("{}", requested);
+    } else if reload.load(Ordering::Relaxed) {
+        tracing::info!("Restarting with the updated config");
+    }
+    if restart_requested.is_some() || reload.load(Ordering::Relaxed) {
+        drop(terminal);
+        restart()?;
+    }
+    result
+}
+
+/// What this instance must be the only publisher of. Session-suffixed
+/// broadcasts are distinct by design, and followed sinks move around, so
+/// neither is locked.
+fn lock_targets(settings: &Settings) -> Vec<pipe2moq::lock::LockTarget> {
+    use pipe2moq::lock::LockTarget;
+
+    let mut targets = Vec::new();
+    let pipeline = &settings.pipeline;
+    if !pipeline.follow_active_sink && pipeline.follow_app.is_none() {
+        targets.push(LockTarget::Sink(pipeline.sink_name.clone().unwrap_or_else(|| "@DEFAULT_SINK@".to_string())));
+    }
+    let mut moq = settings.moq.clone();
+    moq.expand_names(&pipeline.sink_label(), &pipeline.audio);
+    if moq.enabled && !moq.session_suffix && !moq.broadcast_path.contains("{session}") {
+        targets.push(LockTarget::broadcast(&moq.relay_url, &moq.broadcast_path));
+    }
+    targets
+}
+
+/// Replace this process with a fresh copy of itself, keeping the PID so
+/// service managers see one continuous run.
+fn restart() -> Result<()> {
+    use std::os::unix::process::CommandExt;
 
-    let app = Pipe2Moq::new(pipeline_config, moq_config);
-    app.run().await
+    let err = std::process::Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .exec();
+    Err(err.into())
 }
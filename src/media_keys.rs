@@ -0,0 +1,80 @@
+use anyhow::Result;
+use mpris_server::{PlaybackStatus, Player};
+use tracing::{info, warn};
+
+use crate::Controller;
+
+fn playback_status(muted: bool) -> PlaybackStatus {
+    if muted { PlaybackStatus::Paused } else { PlaybackStatus::Playing }
+}
+
+/// Expose the stream as an MPRIS player so desktop media keys
+/// (play/pause, stop) mute, unmute or stop publishing.
+///
+/// The MPRIS player is not `Send`, so it lives on its own thread with a
+/// single-threaded runtime.
+pub fn spawn(controller: Controller) -> Result<()> {
+    std::thread::Builder::new()
+        .name("media-keys".to_string())
+        .spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    warn!("Failed to start media key runtime: {e}");
+                    return;
+                }
+            };
+            let local = tokio::task::LocalSet::new();
+            if let Err(e) = local.block_on(&runtime, run(controller)) {
+                warn!("Media key integration stopped: {e}");
+            }
+        })?;
+    Ok(())
+}
+
+async fn run(controller: Controller) -> Result<()> {
+    let player = Player::builder("pipe2moq")
+        .identity("pipe2moq")
+        .can_play(true)
+        .can_pause(true)
+        .can_control(true)
+        .can_go_next(false)
+        .can_go_previous(false)
+        .can_seek(false)
+        .build()
+        .await?;
+
+    player.set_playback_status(playback_status(controller.is_muted())).await?;
+
+    player.connect_play_pause({
+        let controller = controller.clone();
+        move |_| {
+            let muted = controller.toggle_mute();
+            info!("Media key: {}", if muted { "muted" } else { "unmuted" });
+        }
+    });
+    player.connect_play({
+        let controller = controller.clone();
+        move |_| controller.unmute()
+    });
+    player.connect_pause({
+        let controller = controller.clone();
+        move |_| controller.mute()
+    });
+    player.connect_stop({
+        let controller = controller.clone();
+        move |_| controller.stop()
+    });
+
+    tokio::task::spawn_local(player.run());
+    info!("Media keys available via MPRIS (org.mpris.MediaPlayer2.pipe2moq)");
+
+    let mut controls = controller.subscribe();
+    while controls.changed().await.is_ok() {
+        let state = controls.borrow_and_update().clone();
+        let status = if state.stopping { PlaybackStatus::Stopped } else { playback_status(state.muted) };
+        player.set_playback_status(status).await?;
+    }
+
+    Ok(())
+}
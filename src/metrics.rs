@@ -0,0 +1,217 @@
+//! Minimal Prometheus text endpoint for [`StatsSnapshot`], and the
+//! agent's single endpoint for all of its streams.
+
+use anyhow::Result;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::agent::{self, StatusMap, StreamState, StreamStatus};
+use crate::stats::FRAME_SIZE_BUCKETS;
+use crate::{Stats, StatsSnapshot};
+
+const PROMETHEUS: &str = "text/plain; version=0.0.4";
+
+/// Serve `GET /metrics` on `addr` until the task is dropped.
+pub async fn serve(addr: SocketAddr, stats: Arc<Stats>) -> Result<()> {
+    listen(addr, move |path| match path {
+        "/metrics" => Some(("200 OK", PROMETHEUS, render(&stats.snapshot(), &[]))),
+        _ => None,
+    }).await
+}
+
+/// Serve every stream the agent manages on one `addr`: `GET /metrics`
+/// with per-stream labels, and `GET /health` with each stream's status as
+/// JSON, answering 503 while any stream isn't live.
+pub async fn serve_agent(addr: SocketAddr, status: StatusMap) -> Result<()> {
+    listen(addr, move |path| {
+        let streams = agent::statuses(&status);
+        match path {
+            "/metrics" => Some(("200 OK", PROMETHEUS, render_streams(&streams))),
+            "/health" => {
+                let healthy = streams.iter().all(|stream| stream.state == StreamState::Live);
+                let body = serde_json::to_string_pretty(&streams).unwrap_or_default();
+                Some((if healthy { "200 OK" } else { "503 Service Unavailable" }, "application/json", body))
+            }
+            _ => None,
+        }
+    }).await
+}
+
+/// A status line, content type and body for a request path; `None` is a
+/// 404.
+type Response = (&'static str, &'static str, String);
+
+async fn listen<F>(addr: SocketAddr, handler: F) -> Result<()>
+where
+    F: Fn(&str) -> Option<Response> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+
+    let handler = Arc::new(handler);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &*handler).await {
+                debug!("Metrics request from {} failed: {e}", peer);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, handler: &(dyn Fn(&str) -> Option<Response> + Sync)) -> Result<()> {
+    // Only the request line matters; headers and body are ignored.
+    let mut request = [0u8; 1024];
+    let n = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, content_type, body) = handler(path).unwrap_or(("404 Not Found", PROMETHEUS, String::new()));
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Render `snapshot` in the Prometheus text format, tagging every sample
+/// with `labels`.
+pub fn render(snapshot: &StatsSnapshot, labels: &[(&str, &str)]) -> String {
+    let mut exposition = Exposition::default();
+    write(&mut exposition, snapshot, labels);
+    exposition.finish()
+}
+
+/// Every managed stream's metrics on one page, labelled with the stream
+/// and broadcast, plus whether each is live and how often it restarted.
+pub fn render_streams(streams: &[StreamStatus]) -> String {
+    let mut exposition = Exposition::default();
+    for stream in streams {
+        let labels = [("stream", stream.name.as_str()), ("broadcast", stream.broadcast_path.as_str())];
+        let set = label_set(&labels);
+        let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+            exposition.family(name, kind, help).push(format!("pipe2moq_{}{} {}", name, set, value));
+        };
+        metric("stream_up", "gauge", "Stream is connected to its relay", (stream.state == StreamState::Live) as u8 as f64);
+        metric("stream_restarts_total", "counter", "Times the agent restarted the stream", stream.restarts as f64);
+        if let Some(ref snapshot) = stream.stats {
+            write(&mut exposition, snapshot, &labels);
+        }
+    }
+    exposition.finish()
+}
+
+/// `{k="v",...}`, or nothing without labels.
+fn label_set(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels.iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Samples grouped by metric, so each `# HELP`/`# TYPE` pair appears once
+/// however many streams share the page.
+#[derive(Default)]
+struct Exposition {
+    families: Vec<(String, Vec<String>)>,
+}
+
+impl Exposition {
+    /// The sample lines of `pipe2moq_<name>`, added on first use.
+    fn family(&mut self, name: &str, kind: &str, help: &str) -> &mut Vec<String> {
+        let header = format!("# HELP pipe2moq_{} {}\n# TYPE pipe2moq_{} {}", name, help, name, kind);
+        let index = match self.families.iter().position(|(existing, _)| *existing == header) {
+            Some(index) => index,
+            None => {
+                self.families.push((header, Vec::new()));
+                self.families.len() - 1
+            }
+        };
+        &mut self.families[index].1
+    }
+
+    fn finish(self) -> String {
+        let mut out = String::new();
+        for (header, samples) in self.families {
+            let _ = writeln!(out, "{}", header);
+            for sample in samples {
+                let _ = writeln!(out, "{}", sample);
+            }
+        }
+        out
+    }
+}
+
+fn write(exposition: &mut Exposition, snapshot: &StatsSnapshot, labels: &[(&str, &str)]) {
+    let labels = label_set(labels);
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        exposition.family(name, kind, help).push(format!("pipe2moq_{}{} {}", name, labels, value));
+    };
+
+    metric("uptime_seconds", "gauge", "Time since the stream started", snapshot.uptime.as_secs_f64());
+    metric("frames_captured_total", "counter", "Encoded frames produced by the pipeline", snapshot.frames_captured as f64);
+    metric("frames_published_total", "counter", "Frames published to the relay", snapshot.frames_published as f64);
+    metric("bytes_published_total", "counter", "Bytes published to the relay", snapshot.bytes_published as f64);
+    metric("objects_shed_total", "counter", "Low-priority objects dropped by the rate cap", snapshot.objects_shed as f64);
+    metric("clipping", "gauge", "Input is clipping", snapshot.clipping as u8 as f64);
+    metric("silent", "gauge", "Input is silent", snapshot.silent as u8 as f64);
+    metric("capture_discontinuities_total", "counter", "Capture source discontinuities", snapshot.capture_discontinuities as f64);
+    metric("capture_gaps_total", "counter", "Forward jumps in capture timestamps", snapshot.capture_gaps as f64);
+    metric("capture_overlaps_total", "counter", "Backward jumps in capture timestamps", snapshot.capture_overlaps as f64);
+    metric("xruns_total", "counter", "Capture xrun equivalents", snapshot.xruns() as f64);
+    metric("frame_queue_frames", "gauge", "Encoded frames waiting for the publisher", snapshot.frame_queue as f64);
+    metric("frame_queue_peak_frames", "gauge", "Most encoded frames ever waiting for the publisher", snapshot.frame_queue_peak as f64);
+    metric("pipeline_latency_seconds", "gauge", "Reported pipeline latency", snapshot.pipeline_latency_us as f64 / 1e6);
+    if let Some(subscribers) = snapshot.subscribers {
+        metric("subscribers", "gauge", "Subscriptions to the audio track", subscribers as f64);
+    }
+    if snapshot.relay_delay_us > 0 {
+        metric("relay_delay_seconds", "gauge", "Publish-to-receive time of probes looped through the relay", snapshot.relay_delay_us as f64 / 1e6);
+    }
+
+    metric("encoded_bitrate_bps", "gauge", "Encoder output bitrate over the last 10 s of audio", snapshot.encoded_bitrate as f64);
+    metric("peak_bitrate_bps", "gauge", "Highest encoder output bitrate over 10 s of audio", snapshot.peak_bitrate as f64);
+    metric("relay_reconnects_total", "counter", "Reconnects to the relay", snapshot.reconnects as f64);
+    metric("dtx_frames_total", "counter", "Encoded frames small enough to be DTX", snapshot.dtx_frames() as f64);
+
+    if let Some(ref usage) = snapshot.resources {
+        metric("process_cpu_percent", "gauge", "CPU used by pipe2moq, in percent of one core", usage.cpu_percent);
+        metric("process_resident_memory_bytes", "gauge", "Resident memory of the pipe2moq process", usage.rss_bytes as f64);
+        metric("process_threads", "gauge", "Threads in the pipe2moq process", usage.threads as f64);
+    }
+
+    if let Some(ref path) = snapshot.path {
+        metric("quic_rtt_seconds", "gauge", "QUIC path round-trip time", path.rtt.as_secs_f64());
+        metric("quic_cwnd_bytes", "gauge", "QUIC congestion window", path.cwnd as f64);
+        metric("quic_sent_packets_total", "counter", "QUIC packets sent", path.sent_packets as f64);
+        metric("quic_lost_packets_total", "counter", "QUIC packets lost", path.lost_packets as f64);
+        metric("quic_congestion_events_total", "counter", "QUIC congestion events", path.congestion_events as f64);
+    }
+
+    // Histogram lines need the `le` label merged into the common ones.
+    let with_le = |le: &str| if labels.is_empty() {
+        format!("{{le=\"{}\"}}", le)
+    } else {
+        format!("{},le=\"{}\"}}", &labels[..labels.len() - 1], le)
+    };
+    let histogram = exposition.family("encoded_frame_bytes", "histogram", "Encoded Opus frame sizes");
+    let mut cumulative = 0;
+    for (i, count) in snapshot.frame_sizes.iter().enumerate() {
+        cumulative += count;
+        let le = FRAME_SIZE_BUCKETS.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+        histogram.push(format!("pipe2moq_encoded_frame_bytes_bucket{} {}", with_le(&le), cumulative));
+    }
+    histogram.push(format!("pipe2moq_encoded_frame_bytes_sum{} {}", labels, snapshot.encoded_bytes));
+    histogram.push(format!("pipe2moq_encoded_frame_bytes_count{} {}", labels, cumulative));
+}
@@ -0,0 +1,169 @@
+//! `pipe2moq monitor`: subscribe to a broadcast without playing it and
+//! report receiver-side stream health.
+
+use anyhow::Result;
+use bytes::Bytes;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use url::Url;
+
+use crate::frame::FrameHeader;
+use crate::transport::{self, TrackSubscriber, Transport};
+use crate::TransportKind;
+
+/// Stream health over one reporting interval.
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct HealthReport {
+    pub interval: Duration,
+    pub frames: u64,
+    pub frames_per_sec: f64,
+    pub bitrate: u64,
+    /// Frames missing by sequence number (v1 frame headers only).
+    pub gaps: u64,
+    /// RFC 3550 interarrival jitter, in milliseconds.
+    pub jitter_ms: f64,
+    /// Mean capture-to-publish delay in the sender, for frames carrying
+    /// their capture time.
+    pub pipeline_delay_ms: Option<f64>,
+    /// Whole-session totals.
+    pub total_frames: u64,
+    pub total_gaps: u64,
+}
+
+impl std::fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:6.1} fps  {:6.1} kbps  {} gaps ({} total)  jitter {:.2} ms",
+               self.frames_per_sec,
+               self.bitrate as f64 / 1000.0,
+               self.gaps,
+               self.total_gaps,
+               self.jitter_ms)?;
+        if let Some(delay) = self.pipeline_delay_ms {
+            write!(f, "  sender delay {:.1} ms", delay)?;
+        }
+        Ok(())
+    }
+}
+
+/// Receiver-side statistics over the objects of one track.
+#[derive(Default)]
+pub(crate) struct StreamHealth {
+    frames: u64,
+    bytes: u64,
+    gaps: u64,
+    total_frames: u64,
+    total_gaps: u64,
+    next_sequence: Option<u64>,
+    /// Previous (arrival, media timestamp) pair for jitter.
+    last: Option<(Instant, Option<u64>)>,
+    jitter_us: f64,
+    /// Sum and count of sender pipeline delays this interval.
+    pipeline_delay: (Duration, u32),
+}
+
+impl StreamHealth {
+    pub(crate) fn record(&mut self, arrival: Instant, object: &Bytes) {
+        self.frames += 1;
+        self.total_frames += 1;
+        self.bytes += object.len() as u64;
+
+        let header = FrameHeader::decode(object).map(|(header, _)| header);
+        if let Some(header) = header {
+            if let Some(expected) = self.next_sequence {
+                if header.sequence > expected {
+                    self.gaps += header.sequence - expected;
+                    self.total_gaps += header.sequence - expected;
+                }
+            }
+            self.next_sequence = Some(header.sequence + 1);
+            if let Some(capture) = header.capture {
+                self.pipeline_delay.0 += capture.pipeline_delay();
+                self.pipeline_delay.1 += 1;
+            }
+        }
+
+        // Without media timestamps only arrival spacing is known, which
+        // can't separate jitter from the frame rate; skip it.
+        let timestamp_us = header.map(|header| header.timestamp_us);
+        if let (Some((last_arrival, Some(last_ts))), Some(ts)) = (self.last, timestamp_us) {
+            let arrival_delta = arrival.duration_since(last_arrival).as_micros() as f64;
+            let media_delta = ts as f64 - last_ts as f64;
+            let d = (arrival_delta - media_delta).abs();
+            self.jitter_us += (d - self.jitter_us) / 16.0;
+        }
+        self.last = Some((arrival, timestamp_us));
+    }
+
+    /// Report and reset the per-interval counters.
+    pub(crate) fn report(&mut self, interval: Duration) -> HealthReport {
+        let secs = interval.as_secs_f64().max(f64::EPSILON);
+        let report = HealthReport {
+            interval,
+            frames: self.frames,
+            frames_per_sec: self.frames as f64 / secs,
+            bitrate: (self.bytes as f64 * 8.0 / secs) as u64,
+            gaps: self.gaps,
+            jitter_ms: self.jitter_us / 1000.0,
+            pipeline_delay_ms: match self.pipeline_delay {
+                (_, 0) => None,
+                (sum, count) => Some(sum.as_secs_f64() * 1000.0 / count as f64),
+            },
+            total_frames: self.total_frames,
+            total_gaps: self.total_gaps,
+        };
+        self.frames = 0;
+        self.bytes = 0;
+        self.gaps = 0;
+        self.pipeline_delay = Default::default();
+        report
+    }
+}
+
+/// Subscribe to `track` of `broadcast_path` and call `on_report` every
+/// `interval` until the track ends or the session drops.
+pub async fn monitor(
+    url: &Url,
+    kind: TransportKind,
+    broadcast_path: &str,
+    track: &str,
+    interval: Duration,
+    on_report: impl FnMut(HealthReport),
+) -> Result<()> {
+    match kind {
+        TransportKind::MoqLite => {
+            monitor_with::<transport::MoqLite>(url, broadcast_path, track, interval, on_report).await
+        }
+    }
+}
+
+async fn monitor_with<T: Transport>(
+    url: &Url,
+    broadcast_path: &str,
+    track: &str,
+    interval: Duration,
+    mut on_report: impl FnMut(HealthReport),
+) -> Result<()> {
+    let mut subscription = T::subscribe(url, broadcast_path, track, Duration::from_secs(10)).await?;
+
+    let mut health = StreamHealth::default();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    let mut reported = Instant::now();
+    loop {
+        tokio::select! {
+            object = subscription.next_object() => match object? {
+                Some(object) => health.record(Instant::now(), &object),
+                None => {
+                    warn!("Track {} ended", track);
+                    break;
+                }
+            },
+            _ = ticker.tick() => {
+                on_report(health.report(reported.elapsed()));
+                reported = Instant::now();
+            }
+        }
+    }
+    on_report(health.report(reported.elapsed()));
+    Ok(())
+}
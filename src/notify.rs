@@ -0,0 +1,40 @@
+use notify_rust::{Notification, Urgency};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+use crate::Event;
+
+/// Show a desktop notification for every event a streamer should not miss.
+///
+/// Runs until the event channel closes.
+pub async fn run(mut events: broadcast::Receiver<Event>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                debug!("Notification listener skipped {} events", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        let urgency = match event {
+            Event::RelayDisconnected { .. } | Event::DeviceLost { .. } | Event::PipelineError { .. }
+            | Event::CaptureEnded { .. } | Event::XrunThresholdExceeded { .. } => Urgency::Critical,
+            Event::Stopped | Event::ClippingDetected { .. } | Event::SilenceDetected { .. } | Event::IdleTimeout { .. } => Urgency::Normal,
+            _ => continue,
+        };
+
+        let result = Notification::new()
+            .appname("pipe2moq")
+            .summary(&format!("pipe2moq: {}", event.summary()))
+            .body(&event.detail())
+            .icon("audio-card")
+            .urgency(urgency)
+            .show();
+
+        if let Err(e) = result {
+            warn!("Failed to show desktop notification: {e}");
+        }
+    }
+}
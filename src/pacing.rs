@@ -0,0 +1,124 @@
+//! Outbound rate limiting for the publisher.
+//!
+//! Pushing more than the uplink can carry only grows queues in the QUIC
+//! stack and the kernel, adding latency to every track. The pacer keeps
+//! the publisher under a configured rate by shedding low-priority objects
+//! instead.
+
+use std::time::{Duration, Instant};
+
+/// How much unused budget may accumulate, as time at the full rate.
+const BURST: Duration = Duration::from_millis(200);
+
+/// Token bucket over published bytes.
+pub(crate) struct Pacer {
+    bytes_per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Pacer {
+    pub(crate) fn new(max_bitrate: u32) -> Self {
+        let bytes_per_sec = max_bitrate as f64 / 8.0;
+        let burst = bytes_per_sec * BURST.as_secs_f64();
+        Self {
+            bytes_per_sec,
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.refilled = now;
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.burst);
+    }
+
+    /// Charge `bytes` of a track that must always be sent. Returns false
+    /// when it is already over budget.
+    ///
+    /// The bucket may go into debt, which starves lower-priority tracks
+    /// until the essential track's overshoot has been paid back.
+    pub(crate) fn charge(&mut self, bytes: usize) -> bool {
+        self.charge_at(bytes, Instant::now())
+    }
+
+    fn charge_at(&mut self, bytes: usize, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens = (self.tokens - bytes as f64).max(-self.burst);
+        self.tokens >= 0.0
+    }
+
+    /// Admit `bytes` of a sheddable track only if the budget allows it.
+    pub(crate) fn admit(&mut self, bytes: usize) -> bool {
+        self.admit_at(bytes, Instant::now())
+    }
+
+    fn admit_at(&mut self, bytes: usize, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= bytes as f64 {
+            self.tokens -= bytes as f64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 64 kbps: 8000 bytes a second, a 1600 byte burst.
+    fn pacer() -> (Pacer, Instant) {
+        let pacer = Pacer::new(64_000);
+        let start = pacer.refilled;
+        (pacer, start)
+    }
+
+    fn ms(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn starts_with_a_burst_of_budget() {
+        let (mut pacer, start) = pacer();
+        assert!(pacer.admit_at(1_600, start));
+        assert!(!pacer.admit_at(1, start));
+    }
+
+    #[test]
+    fn releases_frames_on_schedule() {
+        let (mut pacer, start) = pacer();
+        assert!(pacer.admit_at(1_600, start));
+        // 160 bytes every 20 ms is exactly the rate.
+        for frame in 1..=50 {
+            assert!(!pacer.admit_at(160, ms(start, frame * 20 - 1)), "frame {} early", frame);
+            assert!(pacer.admit_at(160, ms(start, frame * 20)), "frame {} on time", frame);
+        }
+    }
+
+    #[test]
+    fn catches_up_after_a_stall_without_a_burst() {
+        let (mut pacer, start) = pacer();
+        assert!(pacer.admit_at(1_600, start));
+        // Ten idle seconds earn no more than the burst allowance.
+        let resumed = ms(start, 10_000);
+        let admitted = (0..100).take_while(|_| pacer.admit_at(160, resumed)).count();
+        assert_eq!(admitted, 10);
+        assert!(!pacer.admit_at(160, ms(start, 10_019)));
+        assert!(pacer.admit_at(160, ms(start, 10_020)));
+    }
+
+    #[test]
+    fn essential_tracks_go_into_bounded_debt() {
+        let (mut pacer, start) = pacer();
+        assert!(pacer.charge_at(1_600, start));
+        assert!(!pacer.charge_at(10_000, start));
+        // The debt is capped at one burst, so it's paid back in 200 ms.
+        assert!(!pacer.admit_at(1, ms(start, 199)));
+        assert!(pacer.admit_at(1, ms(start, 201)));
+    }
+}
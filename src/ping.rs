@@ -0,0 +1,130 @@
+//! `pipe2moq ping` and `browse`: check that a relay is reachable and
+//! see what it carries, without touching the audio system.
+
+use anyhow::Result;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::transport::{self, Announced, BroadcastPublisher, PathStats, Transport};
+use crate::{MoqConfig, TransportKind};
+
+/// How long an announced throwaway broadcast must survive to count as accepted.
+const ANNOUNCE_SETTLE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, serde::Serialize)]
+pub struct PingReport {
+    pub url: String,
+    pub transport: String,
+    pub handshake: Duration,
+    pub path: Option<PathStats>,
+    /// `anonymous`, `token` or `rejected`.
+    pub auth: &'static str,
+    /// Broadcast announced, if requested, and whether the relay kept it.
+    pub announced: Option<String>,
+    pub announce_accepted: Option<bool>,
+    pub error: Option<String>,
+}
+
+impl std::fmt::Display for PingReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Relay:      {}", self.url)?;
+        writeln!(f, "Transport:  {}", self.transport)?;
+        writeln!(f, "Handshake:  {:.1} ms", self.handshake.as_secs_f64() * 1000.0)?;
+        if let Some(ref path) = self.path {
+            writeln!(f, "RTT:        {:.1} ms", path.rtt.as_secs_f64() * 1000.0)?;
+            writeln!(f, "Cwnd:       {} bytes", path.cwnd)?;
+        }
+        writeln!(f, "Auth:       {}", self.auth)?;
+        if let Some(ref path) = self.announced {
+            let verdict = match self.announce_accepted {
+                Some(true) => "accepted",
+                Some(false) => "rejected",
+                None => "not attempted",
+            };
+            writeln!(f, "Announce:   {} ({})", path, verdict)?;
+        }
+        if let Some(ref error) = self.error {
+            writeln!(f, "Error:      {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl PingReport {
+    pub fn ok(&self) -> bool {
+        self.error.is_none() && self.announce_accepted != Some(false)
+    }
+}
+
+/// Handshake with `url` and, with `announce`, publish an empty broadcast
+/// at that path to check the relay lets us announce.
+pub async fn ping(url: &Url, kind: TransportKind, announce: Option<String>) -> Result<PingReport> {
+    match kind {
+        TransportKind::MoqLite => ping_with::<transport::MoqLite>(url, "moq-lite", announce).await,
+    }
+}
+
+async fn ping_with<T: Transport>(url: &Url, name: &str, announce: Option<String>) -> Result<PingReport> {
+    let has_token = url.query_pairs().any(|(key, _)| key == "jwt" || key == "token");
+    let mut report = PingReport {
+        url: url.to_string(),
+        transport: name.to_string(),
+        handshake: Duration::ZERO,
+        path: None,
+        auth: if has_token { "token" } else { "anonymous" },
+        announced: announce.clone(),
+        announce_accepted: None,
+        error: None,
+    };
+
+    let started = Instant::now();
+    let result = match announce {
+        None => T::handshake(url).await.map(|path| {
+            report.handshake = started.elapsed();
+            report.path = path;
+        }),
+        Some(broadcast_path) => {
+            let config = MoqConfig {
+                relay_url: url.to_string(),
+                broadcast_path,
+                ..MoqConfig::default()
+            };
+            match T::publish(&config).await {
+                Ok(broadcast) => {
+                    report.handshake = started.elapsed();
+                    // A relay that refuses the announce tears the session down.
+                    let closed = tokio::time::timeout(ANNOUNCE_SETTLE, broadcast.closed()).await;
+                    report.path = broadcast.path_stats();
+                    match closed {
+                        Ok(err) => {
+                            report.announce_accepted = Some(false);
+                            Err(err)
+                        }
+                        Err(_) => {
+                            report.announce_accepted = Some(true);
+                            Ok(())
+                        }
+                    }
+                }
+                Err(e) => Err(e),
+            }
+        }
+    };
+
+    if let Err(e) = result {
+        let message = format!("{e:#}");
+        let lower = message.to_lowercase();
+        if ["unauthorized", "forbidden", "401", "403"].iter().any(|needle| lower.contains(needle)) {
+            report.auth = "rejected";
+        }
+        report.error = Some(message);
+    }
+    Ok(report)
+}
+
+/// List broadcasts announced on the relay under `prefix`.
+pub async fn browse(url: &Url, kind: TransportKind, prefix: &str, wait: Duration) -> Result<Vec<Announced>> {
+    match kind {
+        TransportKind::MoqLite => transport::MoqLite::browse(url, prefix, wait).await,
+    }
+}
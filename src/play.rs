@@ -0,0 +1,286 @@
+//! `pipe2moq play`: the receiving end. Subscribe to a broadcast, smooth
+//! arrival through a jitter buffer and play it to a local sink.
+
+use anyhow::Result;
+use bytes::Bytes;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSrc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::clock::{ClockSample, CLOCK_TRACK};
+use crate::frame::{self, Frame};
+use crate::jitter::{JitterBuffer, JitterConfig, JitterStats, Playout};
+use crate::playback::{self, PlaybackConfig};
+use crate::stats::STATS_LOG_INTERVAL;
+use crate::sync::{self, Schedule, SyncScheduler};
+use crate::transport::{self, TrackSubscriber, Transport};
+use crate::{AudioConfig, PublisherBackend, TransportKind};
+
+/// How far playout may fall behind its schedule before it is reset
+/// instead of caught up with a burst of frames.
+const MAX_PLAYOUT_LAG: Duration = Duration::from_millis(200);
+
+#[derive(Clone)]
+pub struct PlayConfig {
+    pub url: Url,
+    pub broadcast: String,
+    pub track: String,
+    pub transport: TransportKind,
+    /// How the sender framed its objects; karp objects carry a varint
+    /// timestamp instead of a v1 header.
+    pub backend: PublisherBackend,
+    /// Channels and sample rate of the stream; the frame size times bare
+    /// Opus objects, which carry no timestamps of their own.
+    pub audio: AudioConfig,
+    pub output: PlaybackConfig,
+    pub jitter: JitterConfig,
+    /// Play each frame this long after the sender captured it, by the
+    /// sender's wall clock, so receivers with synchronized clocks play in
+    /// step. Needs the sender's clock track or target playtimes.
+    pub sync: Option<Duration>,
+}
+
+/// Plays a MoQ broadcast to a PipeWire/Pulse sink, the reverse of
+/// [`crate::Pipe2Moq`].
+pub struct Moq2Pipe {
+    config: PlayConfig,
+}
+
+impl Moq2Pipe {
+    pub fn new(config: PlayConfig) -> Self {
+        Self { config }
+    }
+
+    /// Play until the track ends. Fails if the relay session drops or the
+    /// output pipeline errors.
+    pub async fn run(&self) -> Result<()> {
+        match self.config.transport {
+            TransportKind::MoqLite => self.run_with::<transport::MoqLite>().await,
+        }
+    }
+
+    async fn run_with<T: Transport>(&self) -> Result<()> {
+        let config = &self.config;
+        let player = Player::new(&config.audio, &config.output)?;
+        let mut subscription = T::subscribe(&config.url, &config.broadcast, &config.track, Duration::from_secs(10)).await?;
+        info!("Playing {} from {}{}", config.track, config.url, config.broadcast);
+
+        let mut scheduler = config.sync.map(|delay| {
+            info!("Playing in sync, {} ms after capture", delay.as_millis());
+            SyncScheduler::new(delay, sync::DEFAULT_TOLERANCE)
+        });
+        let mut clock = match scheduler {
+            Some(_) => Some(T::subscribe(&config.url, &config.broadcast, CLOCK_TRACK, Duration::from_secs(10)).await?),
+            None => None,
+        };
+        let started = Instant::now();
+
+        let frame_duration = Duration::from_millis(config.audio.frame_size as u64);
+        let mut jitter = JitterBuffer::new(config.jitter.clone());
+        let mut next_timestamp_us = 0;
+        // In sync, the frame taken from the buffer and waiting for its time.
+        let mut due: Option<Frame> = None;
+        let mut dropped = 0u64;
+        let mut implausible_reported = false;
+        let mut deadline = Instant::now() + frame_duration;
+        let tick = tokio::time::sleep_until(deadline);
+        tokio::pin!(tick);
+        let mut logged = Instant::now();
+        loop {
+            tokio::select! {
+                object = subscription.next_object() => match object? {
+                    Some(object) => jitter.push(frame::unframe(object, config.backend, &mut next_timestamp_us, frame_duration.as_micros() as u64)),
+                    None => {
+                        warn!("Track {} ended", config.track);
+                        break;
+                    }
+                },
+                object = async {
+                    match clock.as_mut() {
+                        Some(clock) => clock.next_object().await,
+                        None => std::future::pending().await,
+                    }
+                } => match object? {
+                    Some(object) => {
+                        let Some(scheduler) = scheduler.as_mut() else { continue };
+                        let Some(sample) = ClockSample::decode(&object) else { continue };
+                        scheduler.add_clock_sample(sample, started.elapsed().as_micros() as u64, crate::wall_clock_ns());
+                        if !scheduler.clocks_plausible() && !implausible_reported {
+                            warn!("This machine's wall clock is more than a second off the sender's; is it synchronized?");
+                            implausible_reported = true;
+                        }
+                    }
+                    None => {
+                        warn!("Clock track ended; only frames with a target playtime stay in sync");
+                        clock = None;
+                    }
+                },
+                _ = &mut tick => {
+                    let now = Instant::now();
+                    deadline = match scheduler.as_ref() {
+                        Some(scheduler) => {
+                            let next = Self::play_in_sync(&player, scheduler, &mut jitter, &mut due, &mut dropped)?;
+                            now + next.unwrap_or(frame_duration)
+                        }
+                        None => {
+                            let played = match jitter.pop() {
+                                Playout::Frame(frame) => {
+                                    player.push(frame.data, frame.duration_us)?;
+                                    Duration::from_micros(frame.duration_us)
+                                }
+                                Playout::Conceal { duration_us } => {
+                                    player.push(Bytes::new(), duration_us)?;
+                                    Duration::from_micros(duration_us)
+                                }
+                                Playout::Buffering => frame_duration,
+                            };
+                            // Following the buffer's rate absorbs sender clock drift.
+                            let deadline = deadline + played.div_f64(jitter.rate());
+                            if now.saturating_duration_since(deadline) > MAX_PLAYOUT_LAG { now } else { deadline }
+                        }
+                    };
+                    tick.as_mut().reset(deadline);
+
+                    if logged.elapsed() >= STATS_LOG_INTERVAL {
+                        logged = Instant::now();
+                        log_stats(&jitter.stats());
+                        if dropped > 0 {
+                            info!("Sync: {} frames dropped for being past due", dropped);
+                        }
+                    }
+                }
+            }
+        }
+        log_stats(&jitter.stats());
+        player.finish();
+        Ok(())
+    }
+
+    /// Play, hold back or drop the next frame by its due time. Returns how
+    /// soon to come back: at once after a drop, when it's due after a
+    /// wait, or after what was played; `None` while buffering.
+    fn play_in_sync(
+        player: &Player,
+        scheduler: &SyncScheduler,
+        jitter: &mut JitterBuffer,
+        due: &mut Option<Frame>,
+        dropped: &mut u64,
+    ) -> Result<Option<Duration>> {
+        let frame = match due.take() {
+            Some(frame) => frame,
+            None => match jitter.pop() {
+                Playout::Frame(frame) => frame,
+                Playout::Conceal { duration_us } => {
+                    player.push(Bytes::new(), duration_us)?;
+                    return Ok(Some(Duration::from_micros(duration_us)));
+                }
+                Playout::Buffering => return Ok(None),
+            },
+        };
+        // Without a clock sample yet, there's no telling when it's due.
+        let Some(due_wall_ns) = scheduler.due_wall_ns(frame.timestamp_us, frame.target_playtime_ns) else {
+            *due = Some(frame);
+            return Ok(None);
+        };
+        match scheduler.schedule(due_wall_ns, crate::wall_clock_ns()) {
+            Schedule::Wait(wait) => {
+                *due = Some(frame);
+                Ok(Some(wait))
+            }
+            Schedule::Play => {
+                let duration = Duration::from_micros(frame.duration_us);
+                player.push(frame.data, frame.duration_us)?;
+                Ok(Some(duration))
+            }
+            Schedule::Drop => {
+                *dropped += 1;
+                Ok(Some(Duration::ZERO))
+            }
+        }
+    }
+}
+
+fn log_stats(stats: &JitterStats) {
+    info!("Playout: buffered {:.0}/{:.0} ms, rate {:.4}, {} played, {} concealed, {} late, {} underruns, {} overflowed",
+          stats.occupancy.as_secs_f64() * 1000.0, stats.target.as_secs_f64() * 1000.0, stats.rate,
+          stats.played, stats.concealed, stats.late, stats.underruns, stats.overflow);
+}
+
+/// `appsrc ! opusdec ! audioconvert ! audioresample ! volume ! pulsesink`.
+struct Player {
+    pipeline: gst::Pipeline,
+    appsrc: AppSrc,
+}
+
+impl Player {
+    fn new(audio: &AudioConfig, output: &PlaybackConfig) -> Result<Self> {
+        if audio.ambisonics || audio.channels > 2 {
+            anyhow::bail!("Playback is only supported for mono and stereo streams");
+        }
+        gst::init()?;
+        let pipeline = gst::Pipeline::default();
+
+        let caps = gst::Caps::builder("audio/x-opus")
+            .field("channel-mapping-family", 0i32)
+            .field("channels", audio.channels as i32)
+            .field("rate", audio.sample_rate as i32)
+            .build();
+        // Timestamped on push: playout is paced here, and the sender's
+        // timestamps belong to its pipeline clock, not this one's.
+        let appsrc = AppSrc::builder()
+            .caps(&caps)
+            .format(gst::Format::Time)
+            .is_live(true)
+            .do_timestamp(true)
+            .build();
+
+        let mut chain = vec![
+            appsrc.upcast_ref::<gst::Element>().clone(),
+            gst::ElementFactory::make("opusdec").property("plc", true).build()?,
+            gst::ElementFactory::make("audioconvert").build()?,
+            gst::ElementFactory::make("audioresample").build()?,
+        ];
+        chain.extend(playback::output_elements(output)?);
+        pipeline.add_many(&chain)?;
+        gst::Element::link_many(&chain)?;
+
+        pipeline.set_state(gst::State::Playing)?;
+        Ok(Self { pipeline, appsrc })
+    }
+
+    /// Queue one packet for playout. An empty packet makes opusdec conceal
+    /// `duration_us` of lost audio.
+    fn push(&self, data: Bytes, duration_us: u64) -> Result<()> {
+        if let Some(msg) = self.pipeline.bus().and_then(|bus| bus.pop_filtered(&[gst::MessageType::Error])) {
+            if let gst::MessageView::Error(err) = msg.view() {
+                return Err(anyhow::anyhow!("Playback error: {}", err.error()));
+            }
+        }
+        let mut buffer = gst::Buffer::from_slice(data);
+        buffer.get_mut()
+            .expect("new buffer is writable")
+            .set_duration(gst::ClockTime::from_useconds(duration_us));
+        self.appsrc.push_buffer(buffer)?;
+        Ok(())
+    }
+
+    /// Let what is queued play out before stopping.
+    fn finish(self) {
+        let _ = self.appsrc.end_of_stream();
+        if let Some(bus) = self.pipeline.bus() {
+            tokio::task::block_in_place(|| {
+                bus.timed_pop_filtered(gst::ClockTime::from_seconds(2), &[gst::MessageType::Eos, gst::MessageType::Error]);
+            });
+        }
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
@@ -0,0 +1,72 @@
+//! Output side for receivers: choosing a sink and a playback volume.
+
+use anyhow::Result;
+use gstreamer as gst;
+use regex::Regex;
+use std::process::Command;
+use tracing::{info, warn};
+
+/// Default gain when monitoring a published stream locally, low enough
+/// not to drown out the source it's played next to.
+pub const LISTEN_VOLUME: f64 = 0.3;
+
+#[derive(Clone, Debug)]
+pub struct PlaybackConfig {
+    /// Sink name, or a regex matched against sink names and descriptions.
+    /// `None` plays to the default sink.
+    pub sink: Option<String>,
+    /// Linear gain; 1.0 leaves the stream untouched.
+    pub volume: f64,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        Self {
+            sink: None,
+            volume: 1.0,
+        }
+    }
+}
+
+/// Resolve `pattern` to a sink name: an exact name wins, otherwise the
+/// first sink whose name or description matches it as a regex.
+pub fn resolve_sink(pattern: &str) -> Result<String> {
+    let output = Command::new("pactl").args(["-f", "json", "list", "sinks"]).output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("pactl list sinks failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    let sinks: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)?;
+    let field = |sink: &serde_json::Value, key: &str| sink.get(key).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+
+    if sinks.iter().any(|sink| field(sink, "name") == pattern) {
+        return Ok(pattern.to_string());
+    }
+
+    let regex = Regex::new(pattern).map_err(|e| anyhow::anyhow!("Invalid sink pattern '{}': {}", pattern, e))?;
+    let matches: Vec<String> = sinks.iter()
+        .filter(|sink| regex.is_match(&field(sink, "name")) || regex.is_match(&field(sink, "description")))
+        .map(|sink| field(sink, "name"))
+        .collect();
+    match matches.as_slice() {
+        [] => Err(anyhow::anyhow!("No sink matches '{}'", pattern)),
+        [only] => Ok(only.clone()),
+        [first, ..] => {
+            warn!("Sink pattern '{}' matches {} sinks, using {}", pattern, matches.len(), first);
+            Ok(first.clone())
+        }
+    }
+}
+
+/// `volume ! pulsesink` for the configured sink and gain.
+pub fn output_elements(config: &PlaybackConfig) -> Result<Vec<gst::Element>> {
+    let volume = gst::ElementFactory::make("volume")
+        .property("volume", config.volume)
+        .build()?;
+    let mut sink = gst::ElementFactory::make("pulsesink");
+    if let Some(ref pattern) = config.sink {
+        let name = resolve_sink(pattern)?;
+        info!("Playing to {}", name);
+        sink = sink.property("device", name);
+    }
+    Ok(vec![volume, sink.build()?])
+}
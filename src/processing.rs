@@ -0,0 +1,121 @@
+use anyhow::Result;
+use gstreamer as gst;
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoiseSuppression {
+    Off,
+    /// `webrtcdsp` noise suppression
+    Webrtc,
+    /// `audiornnoise` from gst-plugins-rs
+    Rnnoise,
+}
+
+impl std::str::FromStr for NoiseSuppression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" | "none" => Ok(Self::Off),
+            "webrtc" => Ok(Self::Webrtc),
+            "rnnoise" => Ok(Self::Rnnoise),
+            other => Err(anyhow::anyhow!("Unknown noise suppression '{}', expected 'off', 'webrtc' or 'rnnoise'", other)),
+        }
+    }
+}
+
+/// Optional filters inserted between capture and the encoder.
+#[derive(Clone)]
+pub struct ProcessingConfig {
+    pub agc: bool,
+    pub agc_target_level_dbfs: u32,
+    pub noise_suppression: NoiseSuppression,
+    pub noise_suppression_level: String,
+    pub echo_cancel: bool,
+    pub highpass_hz: Option<u32>,
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        Self {
+            agc: false,
+            agc_target_level_dbfs: 3,
+            noise_suppression: NoiseSuppression::Off,
+            noise_suppression_level: "moderate".to_string(),
+            echo_cancel: false,
+            highpass_hz: None,
+        }
+    }
+}
+
+impl ProcessingConfig {
+    fn needs_webrtcdsp(&self, voice: bool, echo_probe: Option<&str>) -> bool {
+        self.agc || echo_probe.is_some() || (voice && self.noise_suppression == NoiseSuppression::Webrtc)
+    }
+}
+
+/// Build the processing elements in link order; empty when nothing is enabled.
+///
+/// Noise suppression only applies to voice streams, where it helps; on
+/// music it audibly eats quiet passages. When `echo_probe` names a
+/// `webrtcechoprobe` on the far-end signal, echo cancellation is enabled
+/// against it.
+pub(crate) fn build_elements(
+    config: &ProcessingConfig,
+    voice: bool,
+    echo_probe: Option<&str>,
+) -> Result<Vec<gst::Element>> {
+    let mut elements = Vec::new();
+
+    if config.noise_suppression != NoiseSuppression::Off && !voice {
+        warn!("Noise suppression is only applied when application = \"voice\"");
+    }
+    if config.highpass_hz.is_some() && !voice {
+        warn!("High-pass filter is only applied when application = \"voice\"");
+    }
+
+    // Cut rumble first so nothing downstream spends effort (or bits) on it.
+    if let Some(cutoff) = config.highpass_hz.filter(|_| voice) {
+        elements.push(gst::ElementFactory::make("audiocheblimit")
+            .property_from_str("mode", "high-pass")
+            .property("cutoff", cutoff as f32)
+            .property("poles", 4i32)
+            .build()?);
+        info!("High-pass filter enabled at {} Hz", cutoff);
+    }
+    let webrtc_ns = voice && config.noise_suppression == NoiseSuppression::Webrtc;
+
+    if voice && config.noise_suppression == NoiseSuppression::Rnnoise {
+        elements.push(gst::ElementFactory::make("audiornnoise").build()?);
+        info!("RNNoise noise suppression enabled");
+    }
+
+    if config.needs_webrtcdsp(voice, echo_probe) {
+        // webrtcdsp enables echo cancellation and friends by default;
+        // switch everything off and opt back in per feature.
+        let mut webrtcdsp = gst::ElementFactory::make("webrtcdsp")
+            .property("echo-cancel", echo_probe.is_some())
+            .property("noise-suppression", webrtc_ns)
+            .property_from_str("noise-suppression-level", &config.noise_suppression_level)
+            .property("high-pass-filter", false)
+            .property("voice-detection", false)
+            .property("gain-control", config.agc)
+            .property("target-level-dbfs", config.agc_target_level_dbfs);
+        if let Some(probe) = echo_probe {
+            webrtcdsp = webrtcdsp.property("probe", probe);
+            info!("Echo cancellation enabled against the desktop monitor");
+        }
+        let webrtcdsp = webrtcdsp.build()?;
+        if config.agc {
+            info!("Automatic gain control enabled (target -{} dBFS)", config.agc_target_level_dbfs);
+        }
+        if webrtc_ns {
+            info!("WebRTC noise suppression enabled ({})", config.noise_suppression_level);
+        }
+        elements.push(webrtcdsp);
+        elements.push(gst::ElementFactory::make("audioconvert").build()?);
+    }
+
+    Ok(elements)
+}
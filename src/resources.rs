@@ -0,0 +1,59 @@
+//! The process's own CPU, memory and thread usage, for the stats snapshot.
+
+use anyhow::Result;
+use nix::sys::resource::{getrusage, UsageWho};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Default, serde::Serialize)]
+pub struct ResourceUsage {
+    /// CPU time used since the previous sample, as a percentage of one core.
+    pub cpu_percent: f64,
+    pub rss_bytes: u64,
+    pub threads: u64,
+}
+
+/// User plus system CPU time consumed by this process so far.
+pub(crate) fn cpu_time() -> Result<Duration> {
+    let usage = getrusage(UsageWho::RUSAGE_SELF)?;
+    let to_duration = |t: nix::sys::time::TimeVal| {
+        Duration::from_secs(t.tv_sec() as u64) + Duration::from_micros(t.tv_usec() as u64)
+    };
+    Ok(to_duration(usage.user_time()) + to_duration(usage.system_time()))
+}
+
+/// Resident set size and thread count from `/proc/self/status`.
+fn proc_status() -> Result<(u64, u64)> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let field = |name: &str| {
+        status.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+    Ok((field("VmRSS:") * 1024, field("Threads:")))
+}
+
+/// Turns cumulative CPU time into a usage rate between calls.
+#[derive(Default)]
+pub(crate) struct ResourceSampler {
+    last: Option<(Instant, Duration)>,
+}
+
+impl ResourceSampler {
+    pub(crate) fn sample(&mut self) -> Result<ResourceUsage> {
+        let now = Instant::now();
+        let cpu = cpu_time()?;
+        let cpu_percent = match self.last {
+            Some((at, before)) => {
+                let wall = now.duration_since(at).as_secs_f64().max(f64::EPSILON);
+                cpu.saturating_sub(before).as_secs_f64() / wall * 100.0
+            }
+            None => 0.0,
+        };
+        self.last = Some((now, cpu));
+
+        let (rss_bytes, threads) = proc_status()?;
+        Ok(ResourceUsage { cpu_percent, rss_bytes, threads })
+    }
+}
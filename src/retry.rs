@@ -0,0 +1,200 @@
+//! Retry policies shared by everything that recovers from failure: relay
+//! connections, lost capture devices and pipeline rebuilds.
+
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+use tracing::warn;
+
+/// What to do once a policy's attempts are used up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GiveUp {
+    /// Stop with the last error, so the process exits non-zero.
+    Fail,
+    /// Log the last error and stop cleanly.
+    Stop,
+    /// Re-execute the process from scratch (see [`RestartRequested`]).
+    Restart,
+}
+
+impl std::str::FromStr for GiveUp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "stop" => Ok(Self::Stop),
+            "restart" => Ok(Self::Restart),
+            _ => Err(anyhow::anyhow!("Unknown give-up action '{}', expected fail, stop or restart", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Retries before giving up; `None` retries forever, 0 never retries.
+    pub max_attempts: Option<u32>,
+    pub initial_delay: Duration,
+    /// Delay doubles after each failure up to this. A run that lasted
+    /// longer than this starts again from `initial_delay`.
+    pub max_delay: Duration,
+    /// Random spread applied to each delay, as a fraction of it.
+    pub jitter: f64,
+    pub give_up: GiveUp,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Some(0),
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.2,
+            give_up: GiveUp::Fail,
+        }
+    }
+}
+
+/// One policy per kind of failure.
+#[derive(Clone, Debug, Default)]
+pub struct RetryConfig {
+    /// Connecting to the relay, and reconnecting after the session drops.
+    pub relay: RetryPolicy,
+    /// Rebuilding the pipeline after its capture device disappeared.
+    pub device: RetryPolicy,
+    /// Rebuilding the pipeline after any other GStreamer error.
+    pub pipeline: RetryPolicy,
+}
+
+/// Returned by a policy that gives up with [`GiveUp::Restart`]; the
+/// binary re-executes itself when it finds this in an error's chain.
+#[derive(Debug, thiserror::Error)]
+#[error("{what} failed too often; restarting")]
+pub struct RestartRequested {
+    pub what: String,
+}
+
+impl RetryPolicy {
+    /// Apply the give-up action to the error that exhausted the policy.
+    pub(crate) fn give_up(&self, what: &str, err: anyhow::Error) -> anyhow::Result<()> {
+        match self.give_up {
+            GiveUp::Fail => Err(err),
+            GiveUp::Stop => {
+                warn!("Giving up on {} and stopping: {err:#}", what);
+                Ok(())
+            }
+            GiveUp::Restart => Err(err.context(RestartRequested { what: what.to_string() })),
+        }
+    }
+}
+
+/// Attempt counter and delay for one thing being retried.
+pub(crate) struct Backoff {
+    policy: RetryPolicy,
+    attempts: u32,
+    delay: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(policy: &RetryPolicy) -> Self {
+        Self { policy: policy.clone(), attempts: 0, delay: policy.initial_delay }
+    }
+
+    pub(crate) fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    pub(crate) fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Delay before the next attempt after a failed one that ran for
+    /// `ran_for`, or `None` once the attempts are used up.
+    pub(crate) fn next(&mut self, ran_for: Duration) -> Option<Duration> {
+        // Something that ran for a while earns a fresh start.
+        if ran_for > self.policy.max_delay {
+            self.attempts = 0;
+            self.delay = self.policy.initial_delay;
+        }
+        if self.policy.max_attempts.is_some_and(|max| self.attempts >= max) {
+            return None;
+        }
+        self.attempts += 1;
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(self.policy.max_delay);
+        Some(jittered(delay, self.policy.jitter))
+    }
+}
+
+fn jittered(delay: Duration, jitter: f64) -> Duration {
+    if jitter <= 0.0 {
+        return delay;
+    }
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(delay.as_nanos());
+    let unit = (hasher.finish() as f64 / u64::MAX as f64) * 2.0 - 1.0;
+    delay.mul_f64((1.0 + unit * jitter.min(1.0)).max(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: Option<u32>, jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            jitter,
+            give_up: GiveUp::Fail,
+        }
+    }
+
+    fn delays(backoff: &mut Backoff, count: usize) -> Vec<u64> {
+        (0..count).map(|_| backoff.next(Duration::ZERO).unwrap().as_secs()).collect()
+    }
+
+    #[test]
+    fn doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new(&policy(None, 0.0));
+        assert_eq!(delays(&mut backoff, 6), [1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.attempts(), 6);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut backoff = Backoff::new(&policy(Some(2), 0.0));
+        assert_eq!(delays(&mut backoff, 2), [1, 2]);
+        assert_eq!(backoff.next(Duration::ZERO), None);
+
+        let mut never = Backoff::new(&policy(Some(0), 0.0));
+        assert_eq!(never.next(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn a_long_run_starts_over() {
+        let mut backoff = Backoff::new(&policy(Some(3), 0.0));
+        assert_eq!(delays(&mut backoff, 3), [1, 2, 4]);
+        // Up to max_delay still counts as failing straight away.
+        assert_eq!(backoff.next(Duration::from_secs(10)), None);
+        assert_eq!(backoff.next(Duration::from_secs(11)), Some(Duration::from_secs(1)));
+        assert_eq!(backoff.attempts(), 1);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let mut backoff = Backoff::new(&policy(None, 0.2));
+        for (attempt, nominal) in [1.0, 2.0, 4.0, 8.0, 10.0, 10.0].into_iter().enumerate() {
+            let delay = backoff.next(Duration::ZERO).unwrap().as_secs_f64();
+            assert!(delay >= nominal * 0.8 && delay <= nominal * 1.2, "attempt {}: {} s", attempt, delay);
+        }
+    }
+
+    #[test]
+    fn jitter_is_capped_at_the_whole_delay() {
+        for _ in 0..100 {
+            let delay = jittered(Duration::from_secs(1), 5.0);
+            assert!(delay <= Duration::from_secs(2));
+        }
+    }
+}
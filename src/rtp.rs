@@ -0,0 +1,92 @@
+use anyhow::Result;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+
+/// Payload carried by the RTP multicast output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RtpPayload {
+    /// The same Opus frames that are published to MoQ (RFC 7587).
+    Opus,
+    /// Uncompressed 16-bit big-endian PCM (L16), tapped before the encoder.
+    Pcm,
+}
+
+impl std::str::FromStr for RtpPayload {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "opus" => Ok(Self::Opus),
+            "pcm" | "l16" => Ok(Self::Pcm),
+            other => Err(anyhow::anyhow!("Unknown RTP payload '{}', expected 'opus' or 'pcm'", other)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RtpConfig {
+    pub enabled: bool,
+    pub address: String,
+    pub port: u16,
+    pub ttl: u32,
+    pub payload: RtpPayload,
+    pub interface: Option<String>,
+}
+
+impl Default for RtpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "239.255.77.77".to_string(),
+            port: 5004,
+            ttl: 1,
+            payload: RtpPayload::Opus,
+            interface: None,
+        }
+    }
+}
+
+/// Hang an RTP payloader and multicast `udpsink` off `tee`.
+///
+/// For [`RtpPayload::Opus`] the tee must carry encoded Opus; for
+/// [`RtpPayload::Pcm`] it must carry raw audio.
+pub(crate) fn add_branch(pipeline: &gst::Pipeline, tee: &gst::Element, config: &RtpConfig) -> Result<()> {
+    let queue = gst::ElementFactory::make("queue").build()?;
+
+    let mut payload_chain = Vec::new();
+    match config.payload {
+        RtpPayload::Opus => {
+            payload_chain.push(gst::ElementFactory::make("rtpopuspay")
+                .property("pt", 96u32)
+                .build()?);
+        }
+        RtpPayload::Pcm => {
+            payload_chain.push(gst::ElementFactory::make("audioconvert").build()?);
+            payload_chain.push(gst::ElementFactory::make("rtpL16pay")
+                .property("pt", 97u32)
+                .build()?);
+        }
+    }
+
+    let mut udpsink = gst::ElementFactory::make("udpsink")
+        .property("host", &config.address)
+        .property("port", config.port as i32)
+        .property("auto-multicast", true)
+        .property("ttl-mc", config.ttl as i32)
+        .property("sync", false)
+        .property("async", false);
+    if let Some(ref iface) = config.interface {
+        udpsink = udpsink.property("multicast-iface", iface);
+    }
+    let udpsink = udpsink.build()?;
+
+    let mut branch = vec![queue];
+    branch.extend(payload_chain);
+    branch.push(udpsink);
+
+    pipeline.add_many(&branch)?;
+    gst::Element::link_many(std::iter::once(tee).chain(branch.iter()))?;
+
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! Lua automation hook: a script sees stream events and per-frame
+//! metadata, and can adjust the running stream or publish objects of its
+//! own on [`crate::SCRIPT_TRACK`].
+//!
+//! ```lua
+//! function on_event(event)   -- event.kind, event.summary, event.detail
+//!   if event.kind == "silence_detected" then pipe2moq.set_bitrate(16000) end
+//! end
+//!
+//! function on_frame(frame)   -- frame.sequence, timestamp_us, duration_us, bytes
+//!   if frame.sequence % 500 == 0 then pipe2moq.emit("marker " .. frame.sequence) end
+//! end
+//! ```
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use mlua::{Function, Lua};
+use std::path::Path;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info, warn};
+
+use crate::control::Controller;
+use crate::events::Event;
+use crate::frame::FrameInfo;
+
+pub(crate) struct ScriptHost {
+    lua: Lua,
+    on_event: Option<Function>,
+    on_frame: Option<Function>,
+}
+
+impl ScriptHost {
+    /// Run the script at `path` once, exposing the `pipe2moq` table to it,
+    /// and pick up the hooks it defines.
+    pub(crate) fn load(path: &Path, controller: Controller, objects: mpsc::Sender<Bytes>) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read script {}", path.display()))?;
+
+        let lua = Lua::new();
+        let api = lua.create_table()?;
+        let control = controller.clone();
+        api.set("set_bitrate", lua.create_function(move |_, bitrate: u32| {
+            control.set_bitrate(bitrate);
+            Ok(())
+        })?)?;
+        let control = controller.clone();
+        api.set("bitrate", lua.create_function(move |_, ()| Ok(control.state().bitrate))?)?;
+        let control = controller.clone();
+        api.set("set_frame_size", lua.create_function(move |_, frame_size: u32| {
+            control.set_frame_size(frame_size).map_err(mlua::Error::external)
+        })?)?;
+        let control = controller.clone();
+        api.set("frame_size", lua.create_function(move |_, ()| Ok(control.state().frame_size))?)?;
+        let control = controller.clone();
+        api.set("mute", lua.create_function(move |_, ()| {
+            control.mute();
+            Ok(())
+        })?)?;
+        let control = controller.clone();
+        api.set("unmute", lua.create_function(move |_, ()| {
+            control.unmute();
+            Ok(())
+        })?)?;
+        api.set("muted", lua.create_function(move |_, ()| Ok(controller.is_muted()))?)?;
+        api.set("emit", lua.create_function(move |_, object: mlua::String| {
+            // Dropped rather than blocking the hook when the publisher is
+            // behind or not running.
+            if objects.try_send(Bytes::copy_from_slice(&object.as_bytes())).is_err() {
+                debug!("Script object dropped");
+            }
+            Ok(())
+        })?)?;
+        lua.globals().set("pipe2moq", api)?;
+
+        lua.load(source)
+            .set_name(path.display().to_string())
+            .exec()
+            .with_context(|| format!("Failed to run script {}", path.display()))?;
+
+        let on_event: Option<Function> = lua.globals().get("on_event")?;
+        let on_frame: Option<Function> = lua.globals().get("on_frame")?;
+        if on_event.is_none() && on_frame.is_none() {
+            warn!("Script {} defines neither on_event nor on_frame", path.display());
+        }
+        info!("Loaded script {}", path.display());
+        Ok(Self { lua, on_event, on_frame })
+    }
+
+    /// Feed events and frames to the hooks until the event stream closes.
+    /// A hook that raises an error is disabled, so a broken script can't
+    /// flood the log at the frame rate.
+    pub(crate) async fn run(mut self, mut events: broadcast::Receiver<Event>, mut frames: broadcast::Receiver<FrameInfo>) {
+        let mut frames_open = true;
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        if let Err(e) = self.call_on_event(&event) {
+                            warn!("Script on_event failed, disabling it: {e:#}");
+                            self.on_event = None;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => warn!("Script missed {} events", missed),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                frame = frames.recv(), if frames_open => match frame {
+                    Ok(frame) => {
+                        if let Err(e) = self.call_on_frame(&frame) {
+                            warn!("Script on_frame failed, disabling it: {e:#}");
+                            self.on_frame = None;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => debug!("Script missed {} frames", missed),
+                    // No publisher (or it finished); events still flow.
+                    Err(broadcast::error::RecvError::Closed) => frames_open = false,
+                },
+            }
+        }
+    }
+
+    fn call_on_event(&self, event: &Event) -> Result<()> {
+        let Some(ref hook) = self.on_event else { return Ok(()) };
+        let table = self.lua.create_table()?;
+        table.set("kind", event.kind())?;
+        table.set("summary", event.summary())?;
+        table.set("detail", event.detail())?;
+        hook.call::<()>(table)?;
+        Ok(())
+    }
+
+    fn call_on_frame(&self, frame: &FrameInfo) -> Result<()> {
+        let Some(ref hook) = self.on_frame else { return Ok(()) };
+        let table = self.lua.create_table()?;
+        table.set("sequence", frame.sequence)?;
+        table.set("timestamp_us", frame.timestamp_us)?;
+        table.set("duration_us", frame.duration_us)?;
+        table.set("bytes", frame.bytes)?;
+        if let Some(capture) = frame.capture {
+            table.set("capture_us", capture.capture_us)?;
+            table.set("publish_us", capture.publish_us)?;
+        }
+        hook.call::<()>(table)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::resources::ResourceUsage;
+use crate::transport::PathStats;
+
+/// Upper bounds, in bytes, of the encoded frame size histogram buckets;
+/// a final bucket holds everything larger. Frames in the first bucket are
+/// DTX/comfort-noise frames.
+pub const FRAME_SIZE_BUCKETS: [usize; 7] = [2, 32, 64, 128, 256, 512, 1024];
+
+/// Span of encoded audio the effective bitrate is computed over.
+const BITRATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Default interval of the periodic stats log line.
+pub const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Counters shared between the pipeline thread and the publisher.
+pub struct Stats {
+    started: Instant,
+    frames_captured: AtomicU64,
+    frames_published: AtomicU64,
+    bytes_published: AtomicU64,
+    objects_shed: AtomicU64,
+    clipping: AtomicBool,
+    silent: AtomicBool,
+    capture_discontinuities: AtomicU64,
+    capture_warnings: AtomicU64,
+    capture_gaps: AtomicU64,
+    capture_gap_us: AtomicU64,
+    capture_overlaps: AtomicU64,
+    capture_buffer_time_us: AtomicU64,
+    capture_latency_time_us: AtomicU64,
+    pipeline_latency_us: AtomicU64,
+    relay_delay_us: AtomicU64,
+    frame_queue: AtomicU64,
+    frame_queue_peak: AtomicU64,
+    frame_sizes: [AtomicU64; FRAME_SIZE_BUCKETS.len() + 1],
+    encoded_bytes: AtomicU64,
+    peak_bitrate: AtomicU64,
+    reconnects: AtomicU64,
+    /// (bytes, duration in μs) of recently encoded frames.
+    recent_frames: Mutex<VecDeque<(usize, u64)>>,
+    resources: Mutex<Option<ResourceUsage>>,
+    path: Mutex<Option<PathStats>>,
+    subscribers: Mutex<Option<u64>>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct StatsSnapshot {
+    pub uptime: Duration,
+    pub frames_captured: u64,
+    pub frames_published: u64,
+    pub bytes_published: u64,
+    /// Low-priority objects dropped to stay under the outbound rate cap.
+    pub objects_shed: u64,
+    pub clipping: bool,
+    pub silent: bool,
+    /// Gaps in captured audio: the source could not keep up and dropped samples.
+    pub capture_discontinuities: u64,
+    /// Warnings posted by the capture source (typically "can't record fast enough").
+    pub capture_warnings: u64,
+    /// Forward jumps in the capture timestamps, and the audio they skipped.
+    pub capture_gaps: u64,
+    pub capture_gap_us: u64,
+    /// Backward jumps in the capture timestamps.
+    pub capture_overlaps: u64,
+    /// Buffer and period sizes the audio server actually granted.
+    pub capture_buffer_time_us: u64,
+    pub capture_latency_time_us: u64,
+    /// Minimum latency reported by the live pipeline.
+    pub pipeline_latency_us: u64,
+    /// Latest publish-to-receive time of a probe looped back through the
+    /// relay; 0 until one arrives.
+    pub relay_delay_us: u64,
+    /// Encoded frames waiting for the publisher, now and at most so far.
+    pub frame_queue: u64,
+    pub frame_queue_peak: u64,
+    /// Encoded frames per [`FRAME_SIZE_BUCKETS`] bucket.
+    pub frame_sizes: Vec<u64>,
+    pub encoded_bytes: u64,
+    /// Encoder output bitrate over the last 10 seconds of audio.
+    pub encoded_bitrate: u64,
+    /// Highest `encoded_bitrate` over a full 10 seconds so far.
+    pub peak_bitrate: u64,
+    /// Reconnects to the relay after the session dropped.
+    pub reconnects: u64,
+    /// This process's CPU, memory and threads, sampled every 10 seconds.
+    pub resources: Option<ResourceUsage>,
+    /// QUIC path to the relay, once connected.
+    pub path: Option<PathStats>,
+    /// Subscriptions to the audio track, if the transport reports them.
+    pub subscribers: Option<u64>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            frames_captured: AtomicU64::new(0),
+            frames_published: AtomicU64::new(0),
+            bytes_published: AtomicU64::new(0),
+            objects_shed: AtomicU64::new(0),
+            clipping: AtomicBool::new(false),
+            silent: AtomicBool::new(false),
+            capture_discontinuities: AtomicU64::new(0),
+            capture_warnings: AtomicU64::new(0),
+            capture_gaps: AtomicU64::new(0),
+            capture_gap_us: AtomicU64::new(0),
+            capture_overlaps: AtomicU64::new(0),
+            capture_buffer_time_us: AtomicU64::new(0),
+            capture_latency_time_us: AtomicU64::new(0),
+            pipeline_latency_us: AtomicU64::new(0),
+            relay_delay_us: AtomicU64::new(0),
+            frame_queue: AtomicU64::new(0),
+            frame_queue_peak: AtomicU64::new(0),
+            frame_sizes: Default::default(),
+            encoded_bytes: AtomicU64::new(0),
+            peak_bitrate: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            recent_frames: Mutex::new(VecDeque::new()),
+            resources: Mutex::new(None),
+            path: Mutex::new(None),
+            subscribers: Mutex::new(None),
+        }
+    }
+}
+
+impl Stats {
+    pub(crate) fn record_captured(&self) {
+        self.frames_captured.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_encoded(&self, bytes: usize, duration_us: u64) {
+        let bucket = FRAME_SIZE_BUCKETS.iter().position(|&bound| bytes <= bound).unwrap_or(FRAME_SIZE_BUCKETS.len());
+        self.frame_sizes[bucket].fetch_add(1, Ordering::Relaxed);
+        self.encoded_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+
+        let mut recent = self.recent_frames.lock().unwrap();
+        recent.push_back((bytes, duration_us));
+        let window_us = BITRATE_WINDOW.as_micros() as u64;
+        let mut full = false;
+        while recent.iter().map(|&(_, d)| d).sum::<u64>() > window_us {
+            recent.pop_front();
+            full = true;
+        }
+        // Only full windows count, so the first frames can't set the peak.
+        if full {
+            let bytes: u64 = recent.iter().map(|&(bytes, _)| bytes as u64).sum();
+            let duration_us: u64 = recent.iter().map(|&(_, d)| d).sum();
+            if duration_us > 0 {
+                self.peak_bitrate.fetch_max(bytes * 8 * 1_000_000 / duration_us, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(crate) fn record_published(&self, bytes: usize) {
+        self.frames_published.fetch_add(1, Ordering::Relaxed);
+        self.bytes_published.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_shed(&self) {
+        self.objects_shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_levels(&self, clipping: bool, silent: bool) {
+        self.clipping.store(clipping, Ordering::Relaxed);
+        self.silent.store(silent, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_capture_discontinuity(&self) {
+        self.capture_discontinuities.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_capture_warning(&self) {
+        self.capture_warnings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_capture_gap(&self, missing: Duration) {
+        self.capture_gaps.fetch_add(1, Ordering::Relaxed);
+        self.capture_gap_us.fetch_add(missing.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_capture_overlap(&self) {
+        self.capture_overlaps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_capture_buffering(&self, buffer_time_us: u64, latency_time_us: u64, pipeline_latency_us: u64) {
+        self.capture_buffer_time_us.store(buffer_time_us, Ordering::Relaxed);
+        self.capture_latency_time_us.store(latency_time_us, Ordering::Relaxed);
+        self.pipeline_latency_us.store(pipeline_latency_us, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_relay_delay(&self, delay: Duration) {
+        self.relay_delay_us.store(delay.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_frame_queue(&self, queued: usize) {
+        self.frame_queue.store(queued as u64, Ordering::Relaxed);
+        self.frame_queue_peak.fetch_max(queued as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_resources(&self, usage: ResourceUsage) {
+        *self.resources.lock().unwrap() = Some(usage);
+    }
+
+    pub(crate) fn set_path(&self, path: Option<PathStats>) {
+        *self.path.lock().unwrap() = path;
+    }
+
+    pub(crate) fn set_subscribers(&self, subscribers: Option<usize>) {
+        *self.subscribers.lock().unwrap() = subscribers.map(|count| count as u64);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            uptime: self.started.elapsed(),
+            frames_captured: self.frames_captured.load(Ordering::Relaxed),
+            frames_published: self.frames_published.load(Ordering::Relaxed),
+            bytes_published: self.bytes_published.load(Ordering::Relaxed),
+            objects_shed: self.objects_shed.load(Ordering::Relaxed),
+            clipping: self.clipping.load(Ordering::Relaxed),
+            silent: self.silent.load(Ordering::Relaxed),
+            capture_discontinuities: self.capture_discontinuities.load(Ordering::Relaxed),
+            capture_warnings: self.capture_warnings.load(Ordering::Relaxed),
+            capture_gaps: self.capture_gaps.load(Ordering::Relaxed),
+            capture_gap_us: self.capture_gap_us.load(Ordering::Relaxed),
+            capture_overlaps: self.capture_overlaps.load(Ordering::Relaxed),
+            capture_buffer_time_us: self.capture_buffer_time_us.load(Ordering::Relaxed),
+            capture_latency_time_us: self.capture_latency_time_us.load(Ordering::Relaxed),
+            pipeline_latency_us: self.pipeline_latency_us.load(Ordering::Relaxed),
+            relay_delay_us: self.relay_delay_us.load(Ordering::Relaxed),
+            frame_queue: self.frame_queue.load(Ordering::Relaxed),
+            frame_queue_peak: self.frame_queue_peak.load(Ordering::Relaxed),
+            frame_sizes: self.frame_sizes.iter().map(|count| count.load(Ordering::Relaxed)).collect(),
+            encoded_bytes: self.encoded_bytes.load(Ordering::Relaxed),
+            encoded_bitrate: {
+                let recent = self.recent_frames.lock().unwrap();
+                let (bytes, duration_us) = recent.iter()
+                    .fold((0u64, 0u64), |(b, d), &(bytes, duration)| (b + bytes as u64, d + duration));
+                if duration_us > 0 { bytes * 8 * 1_000_000 / duration_us } else { 0 }
+            },
+            peak_bitrate: self.peak_bitrate.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            resources: *self.resources.lock().unwrap(),
+            path: self.path.lock().unwrap().clone(),
+            subscribers: *self.subscribers.lock().unwrap(),
+        }
+    }
+}
+
+impl StatsSnapshot {
+    /// Capture xrun-equivalents: source discontinuities plus timeline gaps and overlaps.
+    pub fn xruns(&self) -> u64 {
+        self.capture_discontinuities + self.capture_gaps + self.capture_overlaps
+    }
+
+    /// Frames small enough to be DTX/comfort noise.
+    pub fn dtx_frames(&self) -> u64 {
+        self.frame_sizes.first().copied().unwrap_or(0)
+    }
+
+    /// Average published bitrate over the whole session, in bits per second.
+    pub fn average_bitrate(&self) -> u64 {
+        let secs = self.uptime.as_secs_f64();
+        if secs > 0.0 {
+            (self.bytes_published as f64 * 8.0 / secs) as u64
+        } else {
+            0
+        }
+    }
+
+    /// Totals for the end of a session.
+    pub fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            duration: self.uptime,
+            frames_captured: self.frames_captured,
+            frames_published: self.frames_published,
+            bytes_published: self.bytes_published,
+            average_bitrate: self.average_bitrate(),
+            // Sessions shorter than the window never fill it.
+            peak_bitrate: if self.peak_bitrate > 0 { self.peak_bitrate } else { self.encoded_bitrate },
+            capture_drops: self.xruns(),
+            capture_gap_us: self.capture_gap_us,
+            frames_unpublished: self.frames_captured.saturating_sub(self.frames_published),
+            objects_shed: self.objects_shed,
+            reconnects: self.reconnects,
+        }
+    }
+}
+
+/// What a session used and lost, printed when pipe2moq exits cleanly.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct SessionSummary {
+    pub duration: Duration,
+    pub frames_captured: u64,
+    pub frames_published: u64,
+    pub bytes_published: u64,
+    /// Published bytes over the whole session, in bits per second.
+    pub average_bitrate: u64,
+    /// Encoder output over the busiest 10 seconds, in bits per second.
+    pub peak_bitrate: u64,
+    /// Capture xrun-equivalents, and the audio lost to timeline gaps.
+    pub capture_drops: u64,
+    pub capture_gap_us: u64,
+    /// Encoded frames that never reached the relay.
+    pub frames_unpublished: u64,
+    pub objects_shed: u64,
+    pub reconnects: u64,
+}
+
+impl std::fmt::Display for SessionSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Session summary")?;
+        writeln!(f, "Duration:      {:.1}s", self.duration.as_secs_f64())?;
+        writeln!(f, "Frames:        {} captured, {} published, {} unpublished",
+                 self.frames_captured, self.frames_published, self.frames_unpublished)?;
+        writeln!(f, "Published:     {} bytes ({:.2} MiB)", self.bytes_published, self.bytes_published as f64 / (1024.0 * 1024.0))?;
+        writeln!(f, "Bitrate:       {} kbps average, {} kbps peak", self.average_bitrate / 1000, self.peak_bitrate / 1000)?;
+        writeln!(f, "Capture drops: {} ({:.1} ms missing)", self.capture_drops, self.capture_gap_us as f64 / 1000.0)?;
+        writeln!(f, "Objects shed:  {}", self.objects_shed)?;
+        writeln!(f, "Reconnects:    {}", self.reconnects)
+    }
+}
+
+impl std::fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "uptime {:.1}s, captured {} frames, published {} frames ({} bytes, {} kbps avg)",
+               self.uptime.as_secs_f64(),
+               self.frames_captured,
+               self.frames_published,
+               self.bytes_published,
+               self.average_bitrate() / 1000)?;
+        write!(f, ", capture buffer {}/{} μs, latency {:.1} ms, {} discontinuities",
+               self.capture_buffer_time_us,
+               self.capture_latency_time_us,
+               self.pipeline_latency_us as f64 / 1000.0,
+               self.capture_discontinuities)?;
+        if self.capture_gaps > 0 || self.capture_overlaps > 0 {
+            write!(f, ", {} gaps ({:.1} ms missing), {} overlaps",
+                   self.capture_gaps,
+                   self.capture_gap_us as f64 / 1000.0,
+                   self.capture_overlaps)?;
+        }
+        if self.encoded_bitrate > 0 {
+            write!(f, ", encoder {} kbps", self.encoded_bitrate / 1000)?;
+            if self.dtx_frames() > 0 {
+                write!(f, " ({} DTX frames)", self.dtx_frames())?;
+            }
+        }
+        if self.frame_queue_peak > 0 {
+            write!(f, ", queue {} frames (peak {})", self.frame_queue, self.frame_queue_peak)?;
+        }
+        if self.objects_shed > 0 {
+            write!(f, ", {} objects shed", self.objects_shed)?;
+        }
+        if let Some(ref path) = self.path {
+            write!(f, ", rtt {:.1} ms, cwnd {} bytes, {}/{} packets lost",
+                   path.rtt.as_secs_f64() * 1000.0,
+                   path.cwnd,
+                   path.lost_packets,
+                   path.sent_packets)?;
+        }
+        if let Some(subscribers) = self.subscribers {
+            write!(f, ", {} subscribers", subscribers)?;
+        }
+        if self.relay_delay_us > 0 {
+            write!(f, ", relay delay {:.1} ms", self.relay_delay_us as f64 / 1000.0)?;
+        }
+        if let Some(ref usage) = self.resources {
+            write!(f, ", cpu {:.1}%, rss {:.1} MiB, {} threads",
+                   usage.cpu_percent,
+                   usage.rss_bytes as f64 / (1024.0 * 1024.0),
+                   usage.threads)?;
+        }
+        if self.clipping {
+            write!(f, ", CLIPPING")?;
+        }
+        if self.silent {
+            write!(f, ", SILENT")?;
+        }
+        Ok(())
+    }
+}
+
+/// Log what changed over each `interval`. Rates are over wall-clock time,
+/// so they are right at any frame size and through DTX gaps.
+pub(crate) async fn log_periodically(stats: Arc<Stats>, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last = stats.snapshot();
+    loop {
+        ticker.tick().await;
+        let now = stats.snapshot();
+        let secs = (now.uptime - last.uptime).as_secs_f64();
+        let bytes = now.bytes_published - last.bytes_published;
+        let mut line = format!("Published {} frames, {} bytes ({:.1} kbps) in {:.0}s",
+                               now.frames_published - last.frames_published,
+                               bytes,
+                               bytes as f64 * 8.0 / secs.max(f64::EPSILON) / 1000.0,
+                               secs);
+        let _ = write!(line, ", {} capture drops, {} objects shed",
+                       now.xruns() - last.xruns(),
+                       now.objects_shed - last.objects_shed);
+        if let Some(ref path) = now.path {
+            let _ = write!(line, ", rtt {:.1} ms", path.rtt.as_secs_f64() * 1000.0);
+        }
+        info!("{}", line);
+        last = now;
+    }
+}
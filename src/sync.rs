@@ -0,0 +1,146 @@
+//! Synchronized playout across receivers: map each frame's media time onto
+//! the sender's wall clock and play it a fixed delay later, so every
+//! receiver with a synchronized (NTP/PTP) wall clock plays in step.
+
+use std::time::Duration;
+
+use crate::clock::{ClockEstimator, ClockSample};
+
+/// How far from its due time a frame may start and still count as on time.
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_millis(5);
+/// Receivers whose clock appears this far off the sender's can't sync.
+const MAX_PLAUSIBLE_OFFSET: Duration = Duration::from_secs(1);
+
+/// What to do with the frame at the head of the buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    /// Too early; start it after this long.
+    Wait(Duration),
+    Play,
+    /// Already past due; skip it to catch up.
+    Drop,
+}
+
+pub struct SyncScheduler {
+    delay: Duration,
+    tolerance: Duration,
+    anchor: Option<ClockSample>,
+    estimator: ClockEstimator,
+}
+
+impl SyncScheduler {
+    /// Play each frame `delay` after the sender captured it. The delay must
+    /// cover the network path and jitter buffering of the slowest receiver.
+    pub fn new(delay: Duration, tolerance: Duration) -> Self {
+        Self {
+            delay,
+            tolerance,
+            anchor: None,
+            estimator: ClockEstimator::new(16),
+        }
+    }
+
+    /// Feed a sample from the clock track, received at the given local times.
+    pub fn add_clock_sample(&mut self, sample: ClockSample, local_monotonic_us: u64, local_wall_ns: i64) {
+        self.estimator.add(&sample, local_monotonic_us, local_wall_ns);
+        self.anchor = Some(sample);
+    }
+
+    /// False when the local wall clock is too far from the sender's for
+    /// wall-clock scheduling to mean anything.
+    pub fn clocks_plausible(&self) -> bool {
+        self.estimator.offset_ns()
+            .is_none_or(|offset| offset.unsigned_abs() < MAX_PLAUSIBLE_OFFSET.as_nanos() as u64)
+    }
+
+    /// Local wall time (ns since the epoch) at which the frame with this
+    /// media time should start. A sender `target_playtime_ns` takes
+    /// precedence; otherwise a clock sample is needed.
+    pub fn due_wall_ns(&self, media_us: u64, target_playtime_ns: Option<i64>) -> Option<i64> {
+        if let Some(target) = target_playtime_ns {
+            return Some(target);
+        }
+        let anchor = self.anchor?;
+        let media_offset_ns = (media_us as i64 - anchor.media_us as i64) * 1000;
+        Some(anchor.wall_ns + media_offset_ns + self.delay.as_nanos() as i64)
+    }
+
+    pub fn schedule(&self, due_wall_ns: i64, now_wall_ns: i64) -> Schedule {
+        let tolerance = self.tolerance.as_nanos() as i64;
+        let early = due_wall_ns - now_wall_ns;
+        if early > tolerance {
+            Schedule::Wait(Duration::from_nanos(early as u64))
+        } else if early < -tolerance {
+            Schedule::Drop
+        } else {
+            Schedule::Play
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WALL_NS: i64 = 1_700_000_000_000_000_000;
+
+    fn scheduler() -> SyncScheduler {
+        let mut scheduler = SyncScheduler::new(Duration::from_millis(200), DEFAULT_TOLERANCE);
+        let sample = ClockSample { monotonic_us: 0, wall_ns: WALL_NS, media_us: 1_000_000 };
+        scheduler.add_clock_sample(sample, 0, WALL_NS);
+        scheduler
+    }
+
+    #[test]
+    fn needs_a_clock_sample_or_target() {
+        let scheduler = SyncScheduler::new(Duration::from_millis(200), DEFAULT_TOLERANCE);
+        assert_eq!(scheduler.due_wall_ns(1_000_000, None), None);
+        assert_eq!(scheduler.due_wall_ns(1_000_000, Some(42)), Some(42));
+    }
+
+    #[test]
+    fn frames_are_due_a_delay_after_capture() {
+        let scheduler = scheduler();
+        assert_eq!(scheduler.due_wall_ns(1_000_000, None), Some(WALL_NS + 200_000_000));
+        assert_eq!(scheduler.due_wall_ns(1_020_000, None), Some(WALL_NS + 220_000_000));
+        // Frames from before the sample are due earlier.
+        assert_eq!(scheduler.due_wall_ns(980_000, None), Some(WALL_NS + 180_000_000));
+        // A sender-set target wins over the clock track.
+        assert_eq!(scheduler.due_wall_ns(1_000_000, Some(WALL_NS)), Some(WALL_NS));
+    }
+
+    #[test]
+    fn waits_for_early_frames() {
+        let scheduler = scheduler();
+        let due = scheduler.due_wall_ns(1_000_000, None).unwrap();
+        assert_eq!(scheduler.schedule(due, due - 30_000_000), Schedule::Wait(Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn plays_frames_within_tolerance() {
+        let scheduler = scheduler();
+        let due = scheduler.due_wall_ns(1_000_000, None).unwrap();
+        let tolerance = DEFAULT_TOLERANCE.as_nanos() as i64;
+        for now in [due - tolerance, due, due + tolerance] {
+            assert_eq!(scheduler.schedule(due, now), Schedule::Play);
+        }
+    }
+
+    #[test]
+    fn drops_late_frames() {
+        let scheduler = scheduler();
+        let due = scheduler.due_wall_ns(1_000_000, None).unwrap();
+        let late = due + DEFAULT_TOLERANCE.as_nanos() as i64 + 1;
+        assert_eq!(scheduler.schedule(due, late), Schedule::Drop);
+        assert_eq!(scheduler.schedule(due, due + 1_000_000_000), Schedule::Drop);
+    }
+
+    #[test]
+    fn flags_implausible_clocks() {
+        assert!(scheduler().clocks_plausible());
+        let mut scheduler = SyncScheduler::new(Duration::from_millis(200), DEFAULT_TOLERANCE);
+        let sample = ClockSample { monotonic_us: 0, wall_ns: WALL_NS, media_us: 0 };
+        scheduler.add_clock_sample(sample, 0, WALL_NS + 2_000_000_000);
+        assert!(!scheduler.clocks_plausible());
+    }
+}
@@ -0,0 +1,116 @@
+//! Named tasks and threads, so they can be told apart in tokio-console
+//! (with the `console` feature) and in `top -H`/debuggers.
+
+use anyhow::Result;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::task::JoinHandle;
+
+/// Why a task or thread ended without producing its result.
+#[derive(Debug, thiserror::Error)]
+pub enum TaskError {
+    #[error("{task} panicked: {message}")]
+    Panicked { task: String, message: String },
+    #[error("{task} was cancelled")]
+    Cancelled { task: String },
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "non-string panic payload".to_string())
+}
+
+/// `tokio::spawn` with a task name. Names only reach tokio-console when
+/// built with the `console` feature and `RUSTFLAGS="--cfg tokio_unstable"`.
+pub fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "console", tokio_unstable))]
+    {
+        tokio::task::Builder::new()
+            .name(name)
+            .spawn(future)
+            .expect("failed to spawn task")
+    }
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::spawn(future)
+    }
+}
+
+/// Aborts the task when dropped, for helpers that live only as long as
+/// whatever spawned them.
+pub(crate) struct AbortOnDrop<T>(pub(crate) JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Await a task from [`spawn`], turning a panic or cancellation into a
+/// [`TaskError`] naming it.
+pub(crate) async fn join<T>(name: &str, handle: JoinHandle<T>) -> Result<T> {
+    match handle.await {
+        Ok(value) => Ok(value),
+        Err(e) if e.is_panic() => Err(TaskError::Panicked {
+            task: name.to_string(),
+            message: panic_message(&*e.into_panic()),
+        }.into()),
+        Err(_) => Err(TaskError::Cancelled { task: name.to_string() }.into()),
+    }
+}
+
+/// Set once the future awaiting a [`spawn_thread`] thread is dropped, so
+/// the thread can stop instead of running unobserved.
+#[derive(Clone, Default)]
+pub(crate) struct Cancelled(Arc<AtomicBool>);
+
+impl Cancelled {
+    pub(crate) fn is_set(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct CancelOnDrop(Cancelled);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        (self.0).0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Run blocking `f` on a dedicated named OS thread and await its result.
+/// A panic in `f` comes back as [`TaskError::Panicked`]. Dropping the
+/// returned future sets the [`Cancelled`] flag passed to `f`, which should
+/// check it regularly.
+pub(crate) fn spawn_thread<T, F>(name: &str, f: F) -> Result<impl Future<Output = Result<T>>>
+where
+    T: Send + 'static,
+    F: FnOnce(Cancelled) -> T + Send + 'static,
+{
+    let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+    let name = name.to_string();
+    let cancelled = Cancelled::default();
+    let guard = CancelOnDrop(cancelled.clone());
+    std::thread::Builder::new()
+        .name(name.clone())
+        .spawn(move || {
+            let _ = result_tx.send(std::panic::catch_unwind(AssertUnwindSafe(|| f(cancelled))));
+        })?;
+    Ok(async move {
+        let _guard = guard;
+        match result_rx.await {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(payload)) => Err(TaskError::Panicked { task: name, message: panic_message(&*payload) }.into()),
+            Err(_) => Err(TaskError::Cancelled { task: name }.into()),
+        }
+    })
+}
@@ -0,0 +1,126 @@
+//! Relay auth tokens read from a file or minted by a command on every
+//! connect, so a broadcast can outlive any one token: the publisher
+//! reconnects with a fresh token shortly before the current one expires.
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use url::Url;
+
+use crate::MoqConfig;
+
+/// Query parameter the relay reads the token from.
+pub const TOKEN_PARAM: &str = "jwt";
+
+pub const DEFAULT_REFRESH_BEFORE: Duration = Duration::from_secs(60);
+
+/// Shortest time a token is used for, so one that is already about to
+/// expire doesn't have the publisher reconnecting in a tight loop.
+const MIN_REFRESH: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub enum TokenSource {
+    /// Re-read on every connect, for tokens something else renews.
+    File(PathBuf),
+    /// Run with `sh -c` on every connect; prints the token on stdout.
+    Command(String),
+}
+
+impl std::fmt::Display for TokenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenSource::File(path) => write!(f, "token file {}", path.display()),
+            TokenSource::Command(command) => write!(f, "token command '{}'", command),
+        }
+    }
+}
+
+impl TokenSource {
+    fn read(&self) -> Result<String> {
+        let token = match self {
+            TokenSource::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the relay token from {}", path.display()))?,
+            TokenSource::Command(command) => {
+                let output = std::process::Command::new("sh").arg("-c").arg(command).output()
+                    .with_context(|| format!("Failed to run token command '{}'", command))?;
+                if !output.status.success() {
+                    anyhow::bail!("Token command '{}' failed ({}): {}",
+                                  command, output.status, String::from_utf8_lossy(&output.stderr).trim());
+                }
+                String::from_utf8(output.stdout)
+                    .with_context(|| format!("Token command '{}' printed something other than text", command))?
+            }
+        };
+        let token = token.trim();
+        if token.is_empty() {
+            anyhow::bail!("The {} gave an empty relay token", self);
+        }
+        Ok(token.to_string())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenConfig {
+    pub source: TokenSource,
+    /// Reconnect this long before a token's `exp` claim.
+    pub refresh_before: Duration,
+    /// How long to use tokens that have no `exp` claim; by default, until
+    /// the session drops.
+    pub lifetime: Option<Duration>,
+}
+
+impl TokenConfig {
+    pub fn new(source: TokenSource) -> Self {
+        Self { source, refresh_before: DEFAULT_REFRESH_BEFORE, lifetime: None }
+    }
+
+    /// Fetch a token and put it in `relay_url`, replacing any token
+    /// already there. Also returns how long to use it before refreshing.
+    pub(crate) async fn authorize(&self, relay_url: &str) -> Result<(Url, Option<Duration>)> {
+        let source = self.source.clone();
+        let token = tokio::task::spawn_blocking(move || source.read()).await??;
+        let refresh = match expiry(&token) {
+            Some(expires) => {
+                let remaining = expires.duration_since(SystemTime::now()).unwrap_or_default();
+                if remaining <= self.refresh_before {
+                    warn!("The relay token from the {} expires in {}s, within the {}s it should be refreshed before",
+                          self.source, remaining.as_secs(), self.refresh_before.as_secs());
+                }
+                Some(remaining.saturating_sub(self.refresh_before).max(MIN_REFRESH))
+            }
+            None => self.lifetime.map(|lifetime| lifetime.max(MIN_REFRESH)),
+        };
+        Ok((with_token(relay_url, &token)?, refresh))
+    }
+}
+
+/// The configured relay URL, with a fresh token if a token source is
+/// configured, for commands that connect once.
+pub async fn relay_url(config: &MoqConfig) -> Result<Url> {
+    match config.token {
+        Some(ref token) => Ok(token.authorize(&config.relay_url).await?.0),
+        None => crate::transport::relay_url(&config.relay_url),
+    }
+}
+
+fn with_token(relay_url: &str, token: &str) -> Result<Url> {
+    let mut url = crate::transport::relay_url(relay_url)?;
+    let kept: Vec<(String, String)> = url.query_pairs()
+        .filter(|(key, _)| key != TOKEN_PARAM)
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    url.query_pairs_mut().clear().extend_pairs(kept).append_pair(TOKEN_PARAM, token);
+    Ok(url)
+}
+
+/// The `exp` claim of `token`, if it is a JWT that has one.
+fn expiry(token: &str) -> Option<SystemTime> {
+    let payload = token.split('.').nth(1)?;
+    let claims = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&claims).ok()?;
+    let exp = Duration::try_from_secs_f64(claims.get("exp")?.as_f64()?).ok()?;
+    UNIX_EPOCH.checked_add(exp)
+}
@@ -0,0 +1,565 @@
+//! The publisher talks to MoQ through these traits so the wire library
+//! can be swapped (e.g. for an IETF moq-transport implementation)
+//! without touching the publishing loop.
+
+use anyhow::Result;
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, LazyLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use url::Url;
+
+use crate::{MoqConfig, RetentionConfig};
+
+/// MoQ implementation used to reach the relay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+pub enum TransportKind {
+    /// moq-native / moq-lite
+    #[serde(rename = "moq-lite")]
+    MoqLite,
+}
+
+impl std::str::FromStr for TransportKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "moq-lite" | "lite" => Ok(Self::MoqLite),
+            other => Err(anyhow::anyhow!("Unknown MoQ transport '{}', expected 'moq-lite'", other)),
+        }
+    }
+}
+
+/// Parse a relay URL as people write them: `https://relay:4443/anon`,
+/// `moq://relay:4443/anon` and a bare `relay:4443/anon` all mean the
+/// first. `http://` is kept, for local relays that serve their
+/// certificate fingerprint. Anything else is refused with the https
+/// form to use instead.
+pub fn relay_url(input: &str) -> Result<Url> {
+    let input = input.trim();
+    let (scheme, rest) = match input.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme.to_ascii_lowercase()), rest),
+        None => (None, input),
+    };
+    let corrected = format!("https://{}", rest);
+    let url = match scheme.as_deref() {
+        Some("https" | "http") => Url::parse(input),
+        Some("moq") | None => Url::parse(&corrected),
+        Some(other) => anyhow::bail!("Unsupported relay URL scheme '{}' in '{}'; relays are reached over QUIC, try '{}'",
+                                     other, input, corrected),
+    };
+    let url = url.map_err(|e| anyhow::anyhow!("Invalid relay URL '{}' ({}); expected something like 'https://relay.example.com:4443/anon'", input, e))?;
+    if url.host_str().is_none_or(str::is_empty) {
+        anyhow::bail!("Relay URL '{}' has no host; expected something like 'https://relay.example.com:4443/anon'", input);
+    }
+    Ok(url)
+}
+
+/// Congestion state of the QUIC path to the relay.
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct PathStats {
+    pub rtt: Duration,
+    /// Congestion window, in bytes.
+    pub cwnd: u64,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    pub lost_bytes: u64,
+    pub congestion_events: u64,
+}
+
+/// How long a flush gives the last streams to be written out. moq-lite
+/// doesn't report when they have been, or acked.
+const FLUSH_SETTLE: Duration = Duration::from_millis(250);
+
+/// Move a moq-native session into a task that holds it open, yielding a
+/// receiver for the reason it closed and the task.
+fn own_session(session: moq_native::moq_lite::Session) -> (watch::Receiver<Option<String>>, tokio::task::JoinHandle<()>) {
+    let (closed_tx, closed) = watch::channel(None);
+    let task = crate::tasks::spawn("moq-session", async move {
+        let reason = match session.closed().await {
+            Err(e) => e.to_string(),
+            Ok(()) => "session closed".to_string(),
+        };
+        let _ = closed_tx.send(Some(reason));
+    });
+    (closed, task)
+}
+
+/// Connects to a relay and announces a broadcast.
+pub(crate) trait Transport {
+    type Broadcast: BroadcastPublisher;
+    type Subscription: TrackSubscriber;
+
+    async fn publish(config: &MoqConfig) -> Result<Self::Broadcast>;
+
+    /// Complete a session handshake with `url` and hang up, returning the
+    /// first path statistics sample if the transport exposes them.
+    async fn handshake(url: &Url) -> Result<Option<PathStats>>;
+
+    /// Collect broadcasts announced under `prefix` for `wait`, reading
+    /// each live broadcast's catalog to list its tracks.
+    async fn browse(url: &Url, prefix: &str, wait: Duration) -> Result<Vec<Announced>>;
+
+    /// Wait up to `wait` for `broadcast_path` to be announced, then subscribe to `track`.
+    async fn subscribe(url: &Url, broadcast_path: &str, track: &str, wait: Duration) -> Result<Self::Subscription>;
+}
+
+pub(crate) trait TrackSubscriber {
+    /// Next object in delivery order, or `None` once the track ends.
+    /// Fails once the relay session is gone.
+    async fn next_object(&mut self) -> Result<Option<Bytes>>;
+}
+
+/// A broadcast seen on the relay.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Announced {
+    pub path: String,
+    /// False when the broadcast was announced and then withdrawn while browsing.
+    pub active: bool,
+    /// Track names from the broadcast's catalog, if it publishes one.
+    pub tracks: Option<Vec<String>>,
+}
+
+/// Catalog track names to look for, newest convention first.
+const CATALOG_TRACKS: &[&str] = &["catalog.json", "catalog"];
+
+/// Track names mentioned by a hang/karp catalog: keys of `renditions`
+/// maps and `name` fields of `track` objects, wherever they appear.
+fn catalog_tracks(catalog: &serde_json::Value, tracks: &mut Vec<String>) {
+    match catalog {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                match (key.as_str(), value) {
+                    ("renditions", serde_json::Value::Object(renditions)) => {
+                        tracks.extend(renditions.keys().cloned());
+                    }
+                    ("track", serde_json::Value::Object(track)) => {
+                        if let Some(name) = track.get("name").and_then(|n| n.as_str()) {
+                            tracks.push(name.to_string());
+                        }
+                    }
+                    _ => {}
+                }
+                catalog_tracks(value, tracks);
+            }
+        }
+        serde_json::Value::Array(items) => items.iter().for_each(|item| catalog_tracks(item, tracks)),
+        _ => {}
+    }
+}
+
+/// An announced broadcast; dropping it ends the broadcast and the session.
+pub(crate) trait BroadcastPublisher {
+    type Track: TrackPublisher;
+
+    fn create_track(&mut self, name: &str, priority: u8) -> Self::Track;
+
+    /// Resolves with the reason once the relay session is gone.
+    async fn closed(&self) -> anyhow::Error;
+
+    /// Latest QUIC path statistics, if the transport exposes them.
+    fn path_stats(&self) -> Option<PathStats> {
+        None
+    }
+
+    /// Resolves once what was written so far has reached the relay, as far
+    /// as the transport can tell. Callers bound it with a timeout.
+    async fn flush(&self);
+}
+
+pub(crate) trait TrackPublisher {
+    /// Publish `objects` as one new group.
+    fn write_group(&mut self, objects: impl IntoIterator<Item = Bytes>);
+
+    /// Open a new group with `object`, closing the one opened before.
+    /// Later objects are added with [`Self::append`].
+    fn start_group(&mut self, object: Bytes);
+
+    /// Add `object` to the group opened last; dropped if there is none.
+    fn append(&mut self, object: Bytes);
+
+    /// Number the next group `sequence`, continuing an earlier session's track.
+    fn resume_at(&mut self, sequence: u64);
+
+    /// Open subscriptions to this track, or `None` if the transport can't
+    /// tell. A relay subscribes once on behalf of all its own subscribers.
+    fn subscribers(&self) -> Option<usize> {
+        None
+    }
+}
+
+pub(crate) struct MoqLite;
+
+pub(crate) struct LiteBroadcast {
+    // Dropped first, withdrawing the broadcast before the session may close.
+    broadcast: moq_native::moq_lite::BroadcastProducer,
+    retention: RetentionConfig,
+    session: Arc<LiteSession>,
+}
+
+/// A publishing session to one relay. Broadcasts come and go on it
+/// independently; it closes when the last one using it is dropped.
+struct LiteSession {
+    origin: moq_native::moq_lite::OriginProducer,
+    closed: watch::Receiver<Option<String>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+/// Live shared sessions by relay URL. The URL includes any token, so
+/// broadcasts authorized differently never share a session.
+static SHARED_SESSIONS: LazyLock<tokio::sync::Mutex<HashMap<String, Weak<LiteSession>>>> =
+    LazyLock::new(Default::default);
+
+impl LiteSession {
+    async fn connect(relay_url: &str) -> Result<Arc<Self>> {
+        let origin = moq_native::moq_lite::Origin::produce();
+        let client = moq_native::Client::new(moq_native::ClientConfig::default())?
+            .with_publish(origin.consumer);
+        let session = client.connect(self::relay_url(relay_url)?).await?;
+
+        let (closed, task) = own_session(session);
+        Ok(Arc::new(Self { origin: origin.producer, closed, task }))
+    }
+
+    /// The open session to `relay_url` if another broadcast already has
+    /// one, otherwise a new one. The lock is held while connecting so
+    /// broadcasts starting together don't each open a session.
+    async fn shared(relay_url: &str) -> Result<Arc<Self>> {
+        let mut sessions = SHARED_SESSIONS.lock().await;
+        sessions.retain(|_, session| session.strong_count() > 0);
+        let open = sessions.get(relay_url)
+            .and_then(Weak::upgrade)
+            .filter(|session| session.closed.borrow().is_none());
+        if let Some(session) = open {
+            tracing::debug!("Reusing the session to {} ({} broadcasts)", relay_url, Arc::strong_count(&session) - 1);
+            return Ok(session);
+        }
+        let session = Self::connect(relay_url).await?;
+        sessions.insert(relay_url.to_string(), Arc::downgrade(&session));
+        Ok(session)
+    }
+}
+
+impl Drop for LiteSession {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Transport for MoqLite {
+    type Broadcast = LiteBroadcast;
+    type Subscription = LiteSubscription;
+
+    async fn publish(config: &MoqConfig) -> Result<LiteBroadcast> {
+        let session = if config.share_session {
+            LiteSession::shared(&config.relay_url).await?
+        } else {
+            LiteSession::connect(&config.relay_url).await?
+        };
+
+        let broadcast = session.origin.create_broadcast(&config.broadcast_path)
+            .ok_or_else(|| anyhow::anyhow!("Failed to create broadcast {}", config.broadcast_path))?;
+
+        Ok(LiteBroadcast {
+            broadcast,
+            retention: config.retention,
+            session,
+        })
+    }
+
+    async fn handshake(url: &Url) -> Result<Option<PathStats>> {
+        let client = moq_native::Client::new(moq_native::ClientConfig::default())?;
+        // moq-lite keeps the QUIC connection, and its statistics, to itself.
+        let _session = client.connect(url.clone()).await?;
+        Ok(None)
+    }
+
+    async fn browse(url: &Url, prefix: &str, wait: Duration) -> Result<Vec<Announced>> {
+        use moq_native::moq_lite;
+
+        let origin = moq_lite::Origin::produce();
+        let client = moq_native::Client::new(moq_native::ClientConfig::default())?
+            .with_consume(origin.producer);
+        let _session = client.connect(url.clone()).await?;
+
+        let mut announced = origin.consumer;
+        let mut found: Vec<(Announced, Option<moq_lite::BroadcastConsumer>)> = Vec::new();
+        let deadline = tokio::time::sleep(wait);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                next = announced.announced() => {
+                    let Some((path, broadcast)) = next else { break };
+                    // moq-lite trims the leading slash that broadcast paths
+                    // are written with here.
+                    if !path.as_str().starts_with(prefix.trim_start_matches('/')) {
+                        continue;
+                    }
+                    let path = format!("/{}", path.as_str());
+                    let active = broadcast.is_some();
+                    match found.iter_mut().find(|(entry, _)| entry.path == path) {
+                        Some(existing) => *existing = (Announced { path, active, tracks: None }, broadcast),
+                        None => found.push((Announced { path, active, tracks: None }, broadcast)),
+                    }
+                }
+                _ = &mut deadline => break,
+            }
+        }
+
+        let mut entries = Vec::with_capacity(found.len());
+        for (mut entry, broadcast) in found {
+            if let Some(broadcast) = broadcast {
+                for name in CATALOG_TRACKS {
+                    let mut track = broadcast.subscribe_track(&moq_lite::Track {
+                        name: name.to_string(),
+                        priority: 0,
+                    });
+                    let frame = tokio::time::timeout(Duration::from_secs(1), async {
+                        let mut group = track.next_group().await.ok()??;
+                        group.read_frame().await.ok()?
+                    })
+                    .await;
+                    let catalog = frame.ok().flatten()
+                        .and_then(|frame| serde_json::from_slice::<serde_json::Value>(&frame).ok());
+                    if let Some(catalog) = catalog {
+                        let mut tracks = Vec::new();
+                        catalog_tracks(&catalog, &mut tracks);
+                        tracks.insert(0, name.to_string());
+                        tracks.dedup();
+                        entry.tracks = Some(tracks);
+                        break;
+                    }
+                }
+            }
+            entries.push(entry);
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    async fn subscribe(url: &Url, broadcast_path: &str, track: &str, wait: Duration) -> Result<LiteSubscription> {
+        use moq_native::moq_lite;
+
+        let origin = moq_lite::Origin::produce();
+        let client = moq_native::Client::new(moq_native::ClientConfig::default())?
+            .with_consume(origin.producer);
+        let session = client.connect(url.clone()).await?;
+        let (closed, session) = own_session(session);
+
+        let mut announced = origin.consumer;
+        let broadcast = tokio::time::timeout(wait, async {
+            while let Some((path, broadcast)) = announced.announced().await {
+                if path.as_str() == moq_lite::Path::new(broadcast_path).as_str() && broadcast.is_some() {
+                    return broadcast;
+                }
+            }
+            None
+        })
+        .await
+        .ok()
+        .flatten()
+        .ok_or_else(|| anyhow::anyhow!("Broadcast {} was not announced within {:?}", broadcast_path, wait))?;
+
+        let track = broadcast.subscribe_track(&moq_lite::Track {
+            name: track.to_string(),
+            priority: 0,
+        });
+        Ok(LiteSubscription {
+            track,
+            group: None,
+            _announced: announced,
+            closed,
+            session,
+        })
+    }
+}
+
+pub(crate) struct LiteSubscription {
+    track: moq_native::moq_lite::TrackConsumer,
+    group: Option<moq_native::moq_lite::GroupConsumer>,
+    _announced: moq_native::moq_lite::OriginConsumer,
+    closed: watch::Receiver<Option<String>>,
+    session: tokio::task::JoinHandle<()>,
+}
+
+impl LiteSubscription {
+    async fn read(&mut self) -> Result<Option<Bytes>> {
+        loop {
+            if let Some(ref mut group) = self.group {
+                if let Some(object) = group.read_frame().await? {
+                    return Ok(Some(object));
+                }
+                self.group = None;
+            }
+            match self.track.next_group().await? {
+                Some(group) => self.group = Some(group),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl TrackSubscriber for LiteSubscription {
+    async fn next_object(&mut self) -> Result<Option<Bytes>> {
+        let mut closed = self.closed.clone();
+        tokio::select! {
+            object = self.read() => object,
+            reason = closed.wait_for(Option::is_some) => Err(match reason {
+                Ok(reason) => anyhow::anyhow!("{}", reason.as_deref().unwrap_or_default()),
+                Err(_) => anyhow::anyhow!("MoQ session task ended"),
+            }),
+        }
+    }
+}
+
+impl Drop for LiteSubscription {
+    fn drop(&mut self) {
+        self.session.abort();
+    }
+}
+
+impl BroadcastPublisher for LiteBroadcast {
+    type Track = LiteTrack;
+
+    fn create_track(&mut self, name: &str, priority: u8) -> Self::Track {
+        let track = self.broadcast.create_track(moq_native::moq_lite::Track {
+            name: name.to_string(),
+            priority,
+        });
+        LiteTrack {
+            track,
+            next_group: 0,
+            retention: self.retention,
+            retained: VecDeque::new(),
+            open: None,
+        }
+    }
+
+    async fn closed(&self) -> anyhow::Error {
+        let mut closed = self.session.closed.clone();
+        match closed.wait_for(Option::is_some).await {
+            Ok(reason) => anyhow::anyhow!("{}", reason.as_deref().unwrap_or_default()),
+            Err(_) => anyhow::anyhow!("MoQ session task ended"),
+        }
+    }
+
+    async fn flush(&self) {
+        // A session that's already gone has nothing left to flush.
+        let mut closed = self.session.closed.clone();
+        let _ = tokio::time::timeout(FLUSH_SETTLE, closed.wait_for(Option::is_some)).await;
+    }
+}
+
+/// A moq-lite track that holds on to recent groups so late joiners can
+/// still fetch them, within the configured [`RetentionConfig`].
+pub(crate) struct LiteTrack {
+    track: moq_native::moq_lite::TrackProducer,
+    next_group: u64,
+    retention: RetentionConfig,
+    retained: VecDeque<(Instant, moq_native::moq_lite::GroupConsumer)>,
+    /// Group left open by `start_group`.
+    open: Option<moq_native::moq_lite::GroupProducer>,
+}
+
+impl LiteTrack {
+    fn create_group(&mut self) -> Option<moq_native::moq_lite::GroupProducer> {
+        if let Some(open) = self.open.take() {
+            open.close();
+        }
+        let sequence = self.next_group;
+        self.next_group += 1;
+        let Some(group) = self.track.create_group(moq_native::moq_lite::Group { sequence }) else {
+            tracing::warn!("Group {} already exists on the track; skipping", sequence);
+            return None;
+        };
+        let now = Instant::now();
+        self.retained.push_back((now, group.consume()));
+        self.expire(now);
+        Some(group)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.retained.len() > self.retention.max_groups {
+            self.retained.pop_front();
+        }
+        if let Some(max_age) = self.retention.max_age {
+            while self.retained.front().is_some_and(|(written, _)| now.duration_since(*written) > max_age) {
+                self.retained.pop_front();
+            }
+        }
+    }
+}
+
+impl TrackPublisher for LiteTrack {
+    fn write_group(&mut self, objects: impl IntoIterator<Item = Bytes>) {
+        let Some(mut group) = self.create_group() else { return };
+        for object in objects {
+            group.write_frame(object);
+        }
+        group.close();
+    }
+
+    fn start_group(&mut self, object: Bytes) {
+        self.open = self.create_group().map(|mut group| {
+            group.write_frame(object);
+            group
+        });
+    }
+
+    fn append(&mut self, object: Bytes) {
+        if let Some(group) = self.open.as_mut() {
+            group.write_frame(object);
+        }
+    }
+
+    fn resume_at(&mut self, sequence: u64) {
+        self.next_group = sequence;
+    }
+}
+
+impl Drop for LiteTrack {
+    fn drop(&mut self) {
+        if let Some(open) = self.open.take() {
+            open.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(input: &str) -> String {
+        relay_url(input).unwrap().to_string()
+    }
+
+    #[test]
+    fn defaults_the_scheme_to_https() {
+        assert_eq!(parsed("relay.example.com:4443/anon"), "https://relay.example.com:4443/anon");
+        assert_eq!(parsed("moq://relay.example.com:4443/anon"), "https://relay.example.com:4443/anon");
+        assert_eq!(parsed("MOQ://relay.example.com/anon"), "https://relay.example.com/anon");
+        assert_eq!(parsed("  https://relay.example.com/anon \n"), "https://relay.example.com/anon");
+    }
+
+    #[test]
+    fn keeps_http_for_local_relays() {
+        assert_eq!(parsed("http://localhost:4443/anon"), "http://localhost:4443/anon");
+    }
+
+    #[test]
+    fn keeps_paths_slashes_and_queries() {
+        assert_eq!(parsed("relay.example.com"), "https://relay.example.com/");
+        assert_eq!(parsed("relay.example.com/anon/"), "https://relay.example.com/anon/");
+        assert_eq!(parsed("https://relay.example.com/a/b?jwt=abc&x=1"), "https://relay.example.com/a/b?jwt=abc&x=1");
+        assert_eq!(parsed("moq://relay.example.com/anon?jwt=abc"), "https://relay.example.com/anon?jwt=abc");
+    }
+
+    #[test]
+    fn refuses_other_schemes_and_missing_hosts() {
+        let err = relay_url("wss://relay.example.com/anon").unwrap_err().to_string();
+        assert!(err.contains("'https://relay.example.com/anon'"), "{}", err);
+        assert!(relay_url("moq://:4443/anon").is_err());
+        assert!(relay_url("").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use ksni::menu::StandardItem;
+use ksni::{MenuItem, Tray, TrayService};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::{Controller, Event};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StreamStatus {
+    Starting,
+    Live,
+    Disconnected,
+    Stopped,
+}
+
+struct StatusTray {
+    controller: Controller,
+    broadcast_url: String,
+    status: StreamStatus,
+    muted: bool,
+    // Held for the lifetime of the tray: on X11 the copied text is only
+    // served while the clipboard owner is alive.
+    clipboard: Option<arboard::Clipboard>,
+}
+
+impl Tray for StatusTray {
+    fn id(&self) -> String {
+        "pipe2moq".to_string()
+    }
+
+    fn title(&self) -> String {
+        "pipe2moq".to_string()
+    }
+
+    fn icon_name(&self) -> String {
+        match (self.status, self.muted) {
+            (StreamStatus::Live, false) => "audio-volume-high",
+            (StreamStatus::Live, true) => "audio-volume-muted",
+            (StreamStatus::Starting, _) => "network-transmit",
+            (StreamStatus::Disconnected, _) => "network-error",
+            (StreamStatus::Stopped, _) => "media-playback-stop",
+        }
+        .to_string()
+    }
+
+    fn tool_tip(&self) -> ksni::ToolTip {
+        let status = match self.status {
+            StreamStatus::Starting => "Connecting",
+            StreamStatus::Live if self.muted => "Streaming (muted)",
+            StreamStatus::Live => "Streaming",
+            StreamStatus::Disconnected => "Relay disconnected",
+            StreamStatus::Stopped => "Stopped",
+        };
+        ksni::ToolTip {
+            title: format!("pipe2moq: {}", status),
+            description: self.broadcast_url.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn menu(&self) -> Vec<MenuItem<Self>> {
+        vec![
+            StandardItem {
+                label: if self.muted { "Unmute".into() } else { "Mute".into() },
+                icon_name: "audio-volume-muted".into(),
+                activate: Box::new(|tray: &mut Self| {
+                    tray.muted = tray.controller.toggle_mute();
+                }),
+                ..Default::default()
+            }
+            .into(),
+            StandardItem {
+                label: "Copy broadcast URL".into(),
+                icon_name: "edit-copy".into(),
+                activate: Box::new(|tray: &mut Self| tray.copy_broadcast_url()),
+                ..Default::default()
+            }
+            .into(),
+            MenuItem::Separator,
+            StandardItem {
+                label: "Stop".into(),
+                icon_name: "media-playback-stop".into(),
+                enabled: self.status != StreamStatus::Stopped,
+                activate: Box::new(|tray: &mut Self| tray.controller.stop()),
+                ..Default::default()
+            }
+            .into(),
+        ]
+    }
+}
+
+impl StatusTray {
+    fn copy_broadcast_url(&mut self) {
+        if self.clipboard.is_none() {
+            match arboard::Clipboard::new() {
+                Ok(clipboard) => self.clipboard = Some(clipboard),
+                Err(e) => {
+                    warn!("Clipboard unavailable: {e}");
+                    return;
+                }
+            }
+        }
+        if let Some(clipboard) = self.clipboard.as_mut() {
+            if let Err(e) = clipboard.set_text(self.broadcast_url.clone()) {
+                warn!("Failed to copy broadcast URL: {e}");
+            }
+        }
+    }
+}
+
+/// Show a tray icon reflecting stream state until the event channel closes.
+pub async fn run(controller: Controller, broadcast_url: String, mut events: broadcast::Receiver<Event>) {
+    let muted = controller.is_muted();
+    // Mute changes come from hotkeys, signals and scripts as well as the
+    // tray's own menu, and none of them posts an event.
+    let mut controls = Some(controller.subscribe());
+    let service = TrayService::new(StatusTray {
+        controller,
+        broadcast_url,
+        status: StreamStatus::Starting,
+        muted,
+        clipboard: None,
+    });
+    let handle = service.handle();
+    service.spawn();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let status = match event {
+                    Ok(Event::Started) => StreamStatus::Starting,
+                    Ok(Event::RelayConnected { .. }) => StreamStatus::Live,
+                    Ok(Event::RelayDisconnected { .. }) => StreamStatus::Disconnected,
+                    Ok(Event::Stopped) => StreamStatus::Stopped,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                handle.update(|tray| {
+                    tray.status = status;
+                    tray.muted = tray.controller.is_muted();
+                });
+            }
+            changed = async {
+                match controls.as_mut() {
+                    Some(controls) => controls.changed().await,
+                    None => std::future::pending().await,
+                }
+            } => match changed {
+                Ok(()) => {
+                    let muted = controls.as_mut().is_some_and(|controls| controls.borrow_and_update().muted);
+                    handle.update(|tray| tray.muted = muted);
+                }
+                Err(_) => controls = None,
+            },
+        }
+    }
+
+    handle.shutdown();
+}
@@ -0,0 +1,391 @@
+//! Screen capture published as a video track next to the audio. Each
+//! group of pictures is one MoQ group, starting at a keyframe, so a
+//! subscriber can join at the start of any group.
+
+use anyhow::Result;
+use bytes::Bytes;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::{AppSink, AppSinkCallbacks};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, error, warn};
+
+use crate::frame::{FrameFormat, FrameHeader};
+use crate::pacing::Pacer;
+use crate::transport::TrackPublisher;
+
+/// Where the pictures come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoSource {
+    /// A PipeWire video node, such as a screencast shared through the
+    /// desktop portal.
+    Pipewire,
+    /// An X11 display, through `ximagesrc`.
+    X11,
+    /// `videotestsrc`, for trying out receivers.
+    Test,
+}
+
+impl std::str::FromStr for VideoSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pipewire" => Ok(Self::Pipewire),
+            "x11" => Ok(Self::X11),
+            "test" => Ok(Self::Test),
+            other => Err(anyhow::anyhow!("Unknown video source '{}', expected 'pipewire', 'x11' or 'test'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    /// `x264enc`; objects are Annex B access units.
+    H264,
+    /// `svtav1enc`; objects are temporal units of OBUs.
+    Av1,
+}
+
+impl std::str::FromStr for VideoCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "h264" => Ok(Self::H264),
+            "av1" => Ok(Self::Av1),
+            other => Err(anyhow::anyhow!("Unknown video codec '{}', expected 'h264' or 'av1'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VideoConfig {
+    pub source: VideoSource,
+    /// PipeWire node or X11 display name; the default node or `$DISPLAY`
+    /// when unset.
+    pub device: Option<String>,
+    pub codec: VideoCodec,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: u32,
+    /// Target bits per second.
+    pub bitrate: u32,
+    /// Longest time between keyframes, and so the longest a new
+    /// subscriber waits for a group to start.
+    pub keyframe_interval: Duration,
+    pub track_name: String,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            source: VideoSource::Pipewire,
+            device: None,
+            codec: VideoCodec::H264,
+            width: 1280,
+            height: 720,
+            framerate: 30,
+            bitrate: 2_000_000,
+            keyframe_interval: Duration::from_secs(2),
+            track_name: "video".to_string(),
+        }
+    }
+}
+
+impl VideoConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 || !self.width.is_multiple_of(2) || !self.height.is_multiple_of(2) {
+            anyhow::bail!("Video size must be even and non-zero, not {}x{}", self.width, self.height);
+        }
+        if !(1..=120).contains(&self.framerate) {
+            anyhow::bail!("Video framerate must be between 1 and 120, not {}", self.framerate);
+        }
+        if self.keyframe_interval.is_zero() {
+            anyhow::bail!("keyframe_interval_ms must be positive");
+        }
+        Ok(())
+    }
+
+    /// Frames from one keyframe to the next.
+    fn keyframe_distance(&self) -> u32 {
+        (self.keyframe_interval.as_secs_f64() * self.framerate as f64).round().max(1.0) as u32
+    }
+
+    /// Encoded frames to hold while the relay reconnects: one group.
+    pub(crate) fn queue_capacity(&self) -> usize {
+        self.keyframe_distance() as usize
+    }
+
+    /// The WebCodecs codec string for the catalog. H.264's profile and
+    /// level come from the SPS, so it needs a `keyframe`; AV1's are
+    /// worked out from the settings: main profile, 8-bit, and the lowest
+    /// level that fits the size and rate.
+    pub(crate) fn codec_string(&self, keyframe: Option<&[u8]>) -> Option<String> {
+        match self.codec {
+            VideoCodec::H264 => {
+                let sps = annex_b_units(keyframe?).find(|unit| unit.first().is_some_and(|&header| header & 0x1f == 7))?;
+                let [profile, constraints, level]: [u8; 3] = sps.get(1..4)?.try_into().ok()?;
+                Some(format!("avc1.{:02x}{:02x}{:02x}", profile, constraints, level))
+            }
+            VideoCodec::Av1 => {
+                let picture = self.width as u64 * self.height as u64;
+                let rate = picture * self.framerate as u64;
+                let level = AV1_LEVELS.iter()
+                    .find(|&&(_, max_picture, max_rate)| picture <= max_picture && rate <= max_rate)
+                    .map_or(31, |&(level, _, _)| level);
+                Some(format!("av01.0.{:02}M.08", level))
+            }
+        }
+    }
+}
+
+/// AV1 `seq_level_idx` with its largest picture and luma sample rate.
+const AV1_LEVELS: &[(u8, u64, u64)] = &[
+    (0, 147_456, 4_423_680),
+    (4, 278_784, 8_363_520),
+    (5, 665_856, 19_975_680),
+    (8, 2_228_224, 34_816_000),
+    (9, 2_228_224, 69_632_000),
+    (12, 8_912_896, 133_693_440),
+    (13, 8_912_896, 267_386_880),
+    (14, 8_912_896, 534_773_760),
+    (16, 35_651_584, 1_069_547_520),
+    (17, 35_651_584, 2_139_095_040),
+];
+
+/// The NAL units of an Annex B access unit, without start codes.
+fn annex_b_units(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let start_code = |data: &[u8]| data.windows(3).position(|window| window == [0, 0, 1]);
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        rest = &rest[start_code(rest)? + 3..];
+        Some(&rest[..start_code(rest).unwrap_or(rest.len())])
+    })
+}
+
+/// One encoded picture.
+#[derive(Clone, Debug)]
+pub struct VideoFrame {
+    pub data: Bytes,
+    /// Pipeline PTS in microseconds, on the same timeline as the audio.
+    pub timestamp_us: u64,
+    pub duration_us: u64,
+    pub keyframe: bool,
+}
+
+fn source(config: &VideoConfig) -> Result<gst::Element> {
+    let device = config.device.as_deref();
+    Ok(match config.source {
+        VideoSource::Pipewire => {
+            let source = gst::ElementFactory::make("pipewiresrc")
+                .property("do-timestamp", true)
+                .build()?;
+            if let Some(node) = device {
+                source.set_property("target-object", node);
+            }
+            source
+        }
+        VideoSource::X11 => {
+            let source = gst::ElementFactory::make("ximagesrc")
+                .property("use-damage", false)
+                .build()?;
+            if let Some(display) = device {
+                source.set_property("display-name", display);
+            }
+            source
+        }
+        VideoSource::Test => gst::ElementFactory::make("videotestsrc")
+            .property("is-live", true)
+            .property_from_str("pattern", "ball")
+            .build()?,
+    })
+}
+
+/// The encoder, its parser and the caps the frames leave them in.
+fn encoder(config: &VideoConfig) -> Result<[gst::Element; 3]> {
+    let kbps = (config.bitrate / 1000).max(1);
+    let distance = config.keyframe_distance();
+    Ok(match config.codec {
+        VideoCodec::H264 => {
+            let encoder = gst::ElementFactory::make("x264enc")
+                .property("bitrate", kbps)
+                .property("key-int-max", distance)
+                .property_from_str("tune", "zerolatency")
+                .property_from_str("speed-preset", "veryfast")
+                .build()?;
+            // Parameter sets before every keyframe, so each group decodes
+            // on its own.
+            let parser = gst::ElementFactory::make("h264parse")
+                .property("config-interval", -1i32)
+                .build()?;
+            let caps = gst::Caps::builder("video/x-h264")
+                .field("stream-format", "byte-stream")
+                .field("alignment", "au")
+                .build();
+            [encoder, parser, gst::ElementFactory::make("capsfilter").property("caps", &caps).build()?]
+        }
+        VideoCodec::Av1 => {
+            let encoder = gst::ElementFactory::make("svtav1enc")
+                .property("target-bitrate", kbps)
+                .property("intra-period-length", distance as i32)
+                .build()?;
+            let parser = gst::ElementFactory::make("av1parse").build()?;
+            let caps = gst::Caps::builder("video/x-av1")
+                .field("stream-format", "obu-stream")
+                .field("alignment", "tu")
+                .build();
+            [encoder, parser, gst::ElementFactory::make("capsfilter").property("caps", &caps).build()?]
+        }
+    })
+}
+
+/// `source ! videoconvert ! videoscale ! videorate ! caps ! queue !
+/// encoder ! parser ! appsink`, handing each encoded picture to `sender`.
+///
+/// A full channel drops frames up to the next keyframe, since the ones
+/// in between can't be decoded without what was dropped.
+pub(crate) fn add_branch(pipeline: &gst::Pipeline, config: &VideoConfig, sender: mpsc::Sender<VideoFrame>) -> Result<()> {
+    let caps = gst::Caps::builder("video/x-raw")
+        .field("width", config.width as i32)
+        .field("height", config.height as i32)
+        .field("framerate", gst::Fraction::new(config.framerate as i32, 1))
+        .build();
+    // The encoder falling behind drops raw pictures here instead of
+    // stalling capture.
+    let queue = gst::ElementFactory::make("queue")
+        .property("max-size-buffers", 2u32)
+        .property("max-size-time", 0u64)
+        .property("max-size-bytes", 0u32)
+        .property_from_str("leaky", "downstream")
+        .build()?;
+    let appsink = AppSink::builder().sync(false).build();
+
+    let mut chain = vec![
+        source(config)?,
+        gst::ElementFactory::make("videoconvert").build()?,
+        gst::ElementFactory::make("videoscale").build()?,
+        gst::ElementFactory::make("videorate").build()?,
+        gst::ElementFactory::make("capsfilter").property("caps", &caps).build()?,
+        queue,
+    ];
+    chain.extend(encoder(config)?);
+    chain.push(appsink.upcast_ref::<gst::Element>().clone());
+    pipeline.add_many(&chain)?;
+    gst::Element::link_many(&chain)?;
+
+    let mut waiting_for_keyframe = true;
+    appsink.set_callbacks(
+        AppSinkCallbacks::builder()
+            .new_sample(move |appsink| {
+                let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                let buffer = sample.buffer().ok_or_else(|| {
+                    error!("Failed to get buffer from video sample");
+                    gst::FlowError::Error
+                })?;
+                let keyframe = !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT);
+                if waiting_for_keyframe && !keyframe {
+                    return Ok(gst::FlowSuccess::Ok);
+                }
+                let map = buffer.map_readable().map_err(|_| {
+                    error!("Failed to map video buffer readable");
+                    gst::FlowError::Error
+                })?;
+                let frame = VideoFrame {
+                    data: Bytes::copy_from_slice(map.as_slice()),
+                    timestamp_us: buffer.pts().unwrap_or(gst::ClockTime::ZERO).useconds(),
+                    duration_us: buffer.duration().map(|d| d.useconds()).unwrap_or(0),
+                    keyframe,
+                };
+                match sender.try_send(frame) {
+                    Ok(()) => waiting_for_keyframe = false,
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        if !waiting_for_keyframe {
+                            debug!("Video queue full; dropping frames until the next keyframe");
+                        }
+                        waiting_for_keyframe = true;
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => {
+                        error!("Failed to send video frame to MoQ publisher");
+                        return Err(gst::FlowError::Error);
+                    }
+                }
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+    Ok(())
+}
+
+/// Where the video track's numbering continues after a reconnect.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct VideoPosition {
+    next_sequence: u64,
+    next_group: u64,
+}
+
+/// What became of a frame handed to [`VideoTrack::publish`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Published {
+    Yes,
+    /// Not a keyframe, and no intact group to add it to.
+    Skipped,
+    /// Over the outbound cap.
+    Shed,
+}
+
+/// The video track of one relay session.
+pub(crate) struct VideoTrack<'a, T: TrackPublisher> {
+    track: T,
+    position: &'a mut VideoPosition,
+    /// Whether the group open on the track is intact, so later frames
+    /// of it can be appended.
+    in_group: bool,
+}
+
+impl<'a, T: TrackPublisher> VideoTrack<'a, T> {
+    pub(crate) fn new(mut track: T, position: &'a mut VideoPosition) -> Self {
+        track.resume_at(position.next_group);
+        Self { track, position, in_group: false }
+    }
+
+    /// Publish `frame`: a keyframe starts a group, anything else joins
+    /// the open one. Video is shed under the rate cap, and once a frame
+    /// is shed the rest of its group goes too.
+    pub(crate) fn publish(&mut self, frame: VideoFrame, format: FrameFormat, pacer: Option<&mut Pacer>) -> Published {
+        let sequence = self.position.next_sequence;
+        self.position.next_sequence += 1;
+        if !frame.keyframe && !self.in_group {
+            return Published::Skipped;
+        }
+        let object = match format {
+            FrameFormat::V1 => FrameHeader {
+                sequence,
+                timestamp_us: frame.timestamp_us,
+                duration_us: frame.duration_us as u32,
+                target_playtime_ns: None,
+                capture: None,
+            }
+            .encode(&frame.data),
+            FrameFormat::Raw => frame.data,
+        };
+        if !pacer.is_none_or(|pacer| pacer.admit(object.len())) {
+            if self.in_group {
+                warn!("Video over the outbound cap; skipping to the next keyframe");
+            }
+            self.in_group = false;
+            return Published::Shed;
+        }
+        if frame.keyframe {
+            self.track.start_group(object);
+            self.position.next_group += 1;
+        } else {
+            self.track.append(object);
+        }
+        self.in_group = true;
+        Published::Yes
+    }
+}
@@ -0,0 +1,120 @@
+//! Webhook notifications: POST a JSON description of stream state
+//! changes, so an unattended publisher can page someone when it dies.
+
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+use crate::{Event, Stats};
+
+/// Name posted for a relay connection that follows a disconnect.
+const RECONNECTED: &str = "relay_reconnected";
+
+/// How long to keep delivering queued events once the stream has ended.
+pub const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Events posted when none are configured.
+pub const DEFAULT_EVENTS: &[&str] = &[
+    "started", "stopped", "relay_disconnected", RECONNECTED, "device_lost", "pipeline_error", "capture_ended",
+];
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    /// Plain `http://` only.
+    pub url: url::Url,
+    /// [`Event::kind`] names to post, plus `relay_reconnected`; `*` posts
+    /// every event.
+    pub events: Vec<String>,
+}
+
+impl WebhookConfig {
+    pub fn new(url: url::Url) -> Self {
+        Self { url, events: DEFAULT_EVENTS.iter().map(|kind| kind.to_string()).collect() }
+    }
+
+    fn wants(&self, kind: &str) -> bool {
+        self.events.iter().any(|wanted| wanted == "*" || wanted == kind)
+    }
+}
+
+/// Post the configured events for stream `broadcast_path`, one at a
+/// time and in order, until the event channel closes.
+pub async fn run(config: WebhookConfig, broadcast_path: String, stats: Arc<Stats>, mut events: broadcast::Receiver<Event>) {
+    let host = nix::unistd::gethostname().ok().and_then(|host| host.into_string().ok()).unwrap_or_default();
+    let mut disconnected = false;
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Webhook skipped {} events", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        let kind = match event {
+            Event::RelayConnected { .. } if disconnected => RECONNECTED,
+            _ => event.kind(),
+        };
+        match event {
+            Event::RelayConnected { .. } => disconnected = false,
+            Event::RelayDisconnected { .. } => disconnected = true,
+            _ => {}
+        }
+        if !config.wants(kind) {
+            continue;
+        }
+
+        let body = serde_json::json!({
+            "event": kind,
+            "summary": event.summary(),
+            "detail": event.detail(),
+            "broadcast_path": broadcast_path,
+            "host": host,
+            "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            "stats": stats.snapshot(),
+        });
+        match post_json(&config.url, &body).await {
+            Ok(()) => debug!("Posted {} to {}", kind, config.url),
+            Err(e) => warn!("Failed to post {} to {}: {e:#}", kind, config.url),
+        }
+    }
+}
+
+pub(crate) async fn post_json(url: &url::Url, body: &serde_json::Value) -> Result<()> {
+    anyhow::ensure!(url.scheme() == "http", "Unsupported webhook scheme '{}', expected http", url.scheme());
+    let host = url.host_str().context("Webhook URL has no host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let body = serde_json::to_vec(body)?;
+
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path, host, body.len()
+    );
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let mut stream = TcpStream::connect((host, port)).await?;
+        stream.write_all(request.as_bytes()).await?;
+        stream.write_all(&body).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let status_line = String::from_utf8_lossy(&response);
+        let status_line = status_line.lines().next().unwrap_or_default();
+        let ok = status_line.split_whitespace().nth(1).is_some_and(|code| code.starts_with('2'));
+        anyhow::ensure!(ok, "Webhook responded with '{}'", status_line);
+        Ok(())
+    })
+    .await
+    .context("Webhook timed out")?
+}